@@ -72,7 +72,9 @@ pub unsafe extern "C" fn dpi_fetch_decode(
 			Err(_) => panic!("Failed to read ELF"),
 		};
 		let mut memdump_contents = vec![];
-		EMULATOR.setup_program(elf_contents, memdump_contents);
+		if let Err(e) = EMULATOR.setup_program(elf_contents, memdump_contents) {
+			panic!("Failed to set up program: {}", e);
+		}
 	}
 
 	// Check input signals
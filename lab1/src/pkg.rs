@@ -2,10 +2,14 @@
 // use crate::utils::*;
 use fnv::FnvHashMap;
 use riscv_emu_rust::cpu::*;
+use riscv_emu_rust::device::*;
 use riscv_emu_rust::l1cache::*;
 use riscv_emu_rust::l2cache::*;
 use riscv_emu_rust::memory::*;
 use riscv_emu_rust::mmu::*;
+use riscv_emu_rust::page_walk_cache::*;
+use riscv_emu_rust::pmp::*;
+use riscv_emu_rust::watchpoint::*;
 use riscv_emu_rust::Emulator;
 use std::fs::File;
 use std::io::Read;
@@ -30,27 +34,42 @@ pub static mut EMULATOR: Emulator = Emulator {
 			addressing_mode: AddressingMode::None,
 			privilege_mode: PrivilegeMode::User,
 			memory: MemoryWrapper {
-				memory: Memory { data: vec![] },
+				regions: vec![],
+				backend_type: MemoryBackendType::Sparse,
 			},
+			bus: Bus::new(),
 			l1_cache: L1Cache::static_new(),
 			l2_cache: L2Cache::static_new(),
+			pmp: Pmp::static_new(),
 
 			memory_access_trace: vec![],
 
 			mstatus: 0,
+			menvcfg: 0,
 			tlb_tag: [0; TLB_ENTRY_NUM],
 			tlb_value: [0; TLB_ENTRY_NUM],
 			tlb_bitnum: 0,
+			tlb_hit_num: 0,
+			tlb_miss_num: 0,
+			page_walk_cache: PageWalkCache::static_new(),
+			page_walk_latency: 0,
 
 			dram_latency: 0,
+			dram_model: None,
+			memory_controller: None,
+			uncached_num: 0,
+			memory_error: None,
+
+			watchpoints: Watchpoints::new(),
 		},
 		reservation: 0,
 		is_reservation_set: false,
 		_dump_flag: false,
 		unsigned_data_mask: 0xffffffffffffffff,
-		tohost_addr: 0,
 
 		exit_signal: false,
+		memdump_signal: false,
+		watchpoint_signal: false,
 	},
 
 	symbol_map: None,
@@ -63,6 +82,9 @@ pub static mut EMULATOR: Emulator = Emulator {
 	tohost_addr: 0,
 
 	run_time: 0.0,
+
+	memdump_trigger: None,
+	memdump_path: String::new(),
 };
 
 pub const COSIM_INSTRUCTIONS: [&'static str; 75] = [
@@ -7,12 +7,19 @@ extern crate lab1;
 
 use riscv_emu_rust::cpu::Xlen;
 #[cfg(feature = "dramsim")]
-use riscv_emu_rust::dram::{setup_pipe, terminate_pipe};
+use riscv_emu_rust::dram::{
+	setup_pipe, setup_recorder, setup_replay, terminate_pipe, DRAMSIM_TIMEOUT,
+};
+use riscv_emu_rust::dram_model::{AddressMapping, DramConfig, PagePolicy};
+use riscv_emu_rust::memdump::MemdumpTrigger;
+use riscv_emu_rust::memory_controller::{MemoryControllerConfig, SchedulingPolicy};
+use riscv_emu_rust::watchpoint::Watchpoint;
 
 use getopts::Options;
 use std::env;
 use std::fs::File;
 use std::io::Read;
+use std::process;
 
 use lab1::pkg::*;
 
@@ -21,6 +28,11 @@ fn run_elf(
 	trace_path: &str,
 	trace_memory_access: bool,
 	mem_dump: &str,
+	memdump_trigger: Option<MemdumpTrigger>,
+	memdump_output: &str,
+	watchpoints: Vec<Watchpoint>,
+	dram_config: Option<DramConfig>,
+	memory_controller_config: Option<MemoryControllerConfig>,
 ) -> std::io::Result<()> {
 	let mut memdump_contents = vec![];
 	#[cfg(feature = "memdump")]
@@ -32,13 +44,36 @@ fn run_elf(
 	let mut elf_contents = vec![];
 	elf_file.read_to_end(&mut elf_contents)?;
 	unsafe {
-		EMULATOR.setup_program(elf_contents, memdump_contents);
+		if let Err(e) = EMULATOR.setup_program(elf_contents, memdump_contents) {
+			eprintln!("Failed to set up program: {}", e);
+			process::exit(1);
+		}
 		EMULATOR.update_xlen(Xlen::Bit64);
+		if let Some(trigger) = memdump_trigger {
+			EMULATOR.set_memdump_trigger(trigger, memdump_output);
+		}
+		if let Some(config) = dram_config {
+			if let Err(e) = EMULATOR.setup_dram_model(config) {
+				eprintln!("Failed to set up DRAM model: {}", e);
+				process::exit(1);
+			}
+		}
+		if let Some(config) = memory_controller_config {
+			if let Err(e) = EMULATOR.setup_memory_controller(config) {
+				eprintln!("Failed to set up memory controller: {}", e);
+				process::exit(1);
+			}
+		}
+		for watchpoint in watchpoints {
+			EMULATOR.add_watchpoint(watchpoint);
+		}
 		EMULATOR.run_program(trace_memory_access, trace_path);
 	}
 	#[cfg(feature = "dramsim")]
 	{
-		terminate_pipe();
+		if let Err(e) = terminate_pipe(0) {
+			println!("{}", e);
+		}
 	}
 	Ok(())
 }
@@ -49,8 +84,29 @@ fn main() -> std::io::Result<()> {
 	opts.optflagopt("i", "input", "Set input ELF file", "ELF_PATH");
 	opts.optflagopt("t", "req-pipe", "DRAMSim requesting pipe", "TRACE_PATH");
 	opts.optflagopt("T", "resp-pipe", "DRAMSim responsing pipe", "TRACE_PATH");
+	opts.optopt("", "record", "Record DRAMSim responses", "LOG_PATH");
+	opts.optopt("", "replay", "Replay DRAMSim responses", "LOG_PATH");
 	opts.optflag("h", "help", "Show this help menu");
 	opts.optflagopt("m", "memory", "Set memory dump file", "MEMDUMP_PATH");
+	opts.optopt("d", "dump", "Write memory dump to the file", "MEMDUMP_PATH");
+	opts.optopt("", "dump-pc", "Dump memory at the PC (hex)", "PC");
+	opts.optopt("", "dump-after", "Dump memory after N instructions", "N");
+	opts.optflag("", "dump-magic", "Dump memory at magic instruction");
+	opts.optflagopt("", "dram-model", "Use built-in DRAM model", "open|close");
+	opts.optopt(
+		"",
+		"dram-mapping",
+		"DRAM address mapping",
+		"row-bank-column|bank-xor|channel-interleave",
+	);
+	opts.optopt("", "dram-channels", "DRAM channel number", "N");
+	opts.optopt(
+		"",
+		"memory-controller",
+		"Use memory controller scheduling",
+		"fcfs|frfcfs|bliss",
+	);
+	opts.optmulti("w", "watch", "Watch ACCESS:ADDRESS[:SIZE[:VALUE]]", "SPEC");
 	// run_elf(args[1].clone())?;
 	let mut mem_dump: String = "".to_string();
 	match opts.parse(&args[1..]) {
@@ -70,32 +126,134 @@ fn main() -> std::io::Result<()> {
 
 			#[cfg(feature = "dramsim")]
 			{
-				let request_pipe = match _args.opt_str("t") {
-					Some(trace_path) => trace_path,
-					_ => {
-						println!("{}", opts.usage(&format!("{} [options]", args[0])));
-						return Ok(());
-					}
-				};
+				let result = match _args.opt_str("replay") {
+					Some(log_path) => setup_replay(log_path.as_str()),
+					None => match (_args.opt_str("t"), _args.opt_str("T")) {
+						(Some(request_pipe), Some(response_pipe)) => setup_pipe(
+							request_pipe.as_str(),
+							response_pipe.as_str(),
+							DRAMSIM_TIMEOUT,
+						),
+						_ => {
+							println!("{}", opts.usage(&format!("{} [options]", args[0])));
+							return Ok(());
+						}
+					},
+				}
+				.and_then(|_| match _args.opt_str("record") {
+					Some(log_path) => setup_recorder(log_path.as_str()),
+					None => Ok(()),
+				});
+				if let Err(e) = result {
+					println!("{}", e);
+					println!("{}", opts.usage(&format!("{} [options]", args[0])));
+					return Ok(());
+				}
+			}
 
-				let response_pipe = match _args.opt_str("T") {
-					Some(trace_path) => trace_path,
-					_ => {
-						println!("{}", opts.usage(&format!("{} [options]", args[0])));
-						return Ok(());
-					}
-				};
-				match setup_pipe(request_pipe.as_str(), response_pipe.as_str()) {
-					0 => {}
-					_ => {
+			let memdump_output = _args.opt_str("d").unwrap_or_default();
+			let memdump_trigger = if let Some(pc) = _args.opt_str("dump-pc") {
+				match u64::from_str_radix(pc.trim_start_matches("0x"), 16) {
+					Ok(pc) => Some(MemdumpTrigger::Pc(pc)),
+					Err(_) => None,
+				}
+			} else if let Some(num) = _args.opt_str("dump-after") {
+				num.parse().ok().map(MemdumpTrigger::InstructionNum)
+			} else if _args.opt_present("dump-magic") {
+				Some(MemdumpTrigger::MagicInstruction)
+			} else {
+				None
+			};
+			// Trigger and output path are given together
+			let has_trigger = _args.opt_present("dump-pc")
+				|| _args.opt_present("dump-after")
+				|| _args.opt_present("dump-magic");
+			if has_trigger != _args.opt_present("d") || has_trigger != memdump_trigger.is_some() {
+				println!("{}", opts.usage(&format!("{} [options]", args[0])));
+				return Ok(());
+			}
+
+			let mut watchpoints = vec![];
+			for spec in _args.opt_strs("w") {
+				match Watchpoint::parse(&spec) {
+					Ok(watchpoint) => watchpoints.push(watchpoint),
+					Err(message) => {
+						println!("{}", message);
 						println!("{}", opts.usage(&format!("{} [options]", args[0])));
 						return Ok(());
 					}
-				};
+				}
 			}
 
+			let page_policy = match _args.opt_str("dram-model").as_deref() {
+				None | Some("open") => PagePolicy::Open,
+				Some("close") => PagePolicy::Close,
+				Some(_) => {
+					println!("{}", opts.usage(&format!("{} [options]", args[0])));
+					return Ok(());
+				}
+			};
+			let mapping = match _args.opt_str("dram-mapping").as_deref() {
+				None | Some("row-bank-column") => AddressMapping::RowBankColumn,
+				Some("bank-xor") => AddressMapping::BankXor,
+				Some("channel-interleave") => AddressMapping::ChannelInterleave,
+				Some(_) => {
+					println!("{}", opts.usage(&format!("{} [options]", args[0])));
+					return Ok(());
+				}
+			};
+			let channel_num = match _args.opt_str("dram-channels").map(|num| num.parse()) {
+				None => 1,
+				Some(Ok(num)) if num > 0 => num,
+				Some(_) => {
+					println!("{}", opts.usage(&format!("{} [options]", args[0])));
+					return Ok(());
+				}
+			};
+			// Mapping and channels imply the DRAM model
+			let dram_config = match _args.opt_present("dram-model")
+				|| _args.opt_present("dram-mapping")
+				|| _args.opt_present("dram-channels")
+			{
+				true => Some(DramConfig {
+					channel_num,
+					page_policy,
+					mapping,
+					..DramConfig::default()
+				}),
+				false => None,
+			};
+			let memory_controller_config = match _args.opt_str("memory-controller").as_deref() {
+				None => None,
+				Some(policy) => {
+					let policy = match policy {
+						"fcfs" => SchedulingPolicy::Fcfs,
+						"frfcfs" => SchedulingPolicy::FrFcfs,
+						"bliss" => SchedulingPolicy::Bliss,
+						_ => {
+							println!("{}", opts.usage(&format!("{} [options]", args[0])));
+							return Ok(());
+						}
+					};
+					Some(MemoryControllerConfig {
+						policy,
+						..MemoryControllerConfig::default()
+					})
+				}
+			};
+
 			match _args.opt_str("i") {
-				Some(input_path) => run_elf(input_path.as_str(), "", false, mem_dump.as_str())?,
+				Some(input_path) => run_elf(
+					input_path.as_str(),
+					"",
+					false,
+					mem_dump.as_str(),
+					memdump_trigger,
+					memdump_output.as_str(),
+					watchpoints,
+					dram_config,
+					memory_controller_config,
+				)?,
 				_ => {
 					println!("{}", opts.usage(&format!("{} [options]", args[0])));
 					return Ok(());
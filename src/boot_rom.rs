@@ -0,0 +1,432 @@
+use clint::{CLINT_BASE, CLINT_SIZE};
+use cpu::Xlen;
+use device::{Bus, Device};
+use fdt::{FdtBuilder, FDT_CPU_INTC_PHANDLE, FDT_PLIC_PHANDLE};
+use mmu::MemoryWrapper;
+use plic::{PLIC_BASE, PLIC_SIZE, PLIC_SOURCE_NUM};
+use uart::UART_BASE;
+
+/// Physical base address of boot ROM, where the core starts execution
+pub const BOOT_ROM_BASE: u64 = 0x1000;
+
+/// Size of boot ROM address range
+pub const BOOT_ROM_SIZE: u64 = 0xf000;
+
+/// Offset of the device tree blob in boot ROM
+pub const BOOT_ROM_DTB_OFFSET: u64 = 0x20;
+
+/// Offset of the 64-bit entry address the reset code jumps to
+const BOOT_ROM_ENTRY_OFFSET: usize = 0x18;
+
+/// Frequency of CLINT mtime reported to the guest
+const TIMEBASE_FREQUENCY: u32 = 10000000;
+
+// Interrupt numbers in `interrupts-extended` of CLINT and PLIC
+const IRQ_M_SOFT: u32 = 3;
+const IRQ_M_TIMER: u32 = 7;
+const IRQ_S_EXT: u32 = 9;
+const IRQ_M_EXT: u32 = 11;
+
+/// Read-only memory holding reset code and device tree blob.
+/// The reset code jumps to the program entry with the standard boot
+/// arguments, `a0` hart ID and `a1` device tree address, as OpenSBI
+/// `fw_payload` and BBL expect.
+pub struct BootRom {
+	data: Vec<u8>,
+}
+
+impl BootRom {
+	/// Creates a new `BootRom`. Returns error if the device tree blob
+	/// doesn't fit in the ROM.
+	///
+	/// # Arguments
+	/// * `xlen`
+	/// * `entry` Physical address to jump to
+	/// * `dtb` Device tree blob
+	pub fn new(xlen: &Xlen, entry: u64, dtb: &[u8]) -> Result<Self, String> {
+		if dtb.len() as u64 > BOOT_ROM_SIZE - BOOT_ROM_DTB_OFFSET {
+			return Err(format!(
+				"Device tree blob is too large for boot ROM. {} bytes",
+				dtb.len()
+			));
+		}
+		let load_entry = match xlen {
+			Xlen::Bit32 => 0x0182a283, // lw t0, 24(t0)
+			Xlen::Bit64 => 0x0182b283, // ld t0, 24(t0)
+		};
+		let code: [u32; 5] = [
+			0x00000297, // auipc t0, 0
+			0x02028593, // addi a1, t0, 32
+			0xf1402573, // csrr a0, mhartid
+			load_entry, // ld or lw t0, 24(t0)
+			0x00028067, // jr t0
+		];
+		let mut data = vec![];
+		for instruction in code.iter() {
+			data.extend_from_slice(&instruction.to_le_bytes());
+		}
+		data.resize(BOOT_ROM_ENTRY_OFFSET, 0);
+		data.extend_from_slice(&entry.to_le_bytes());
+		data.extend_from_slice(dtb);
+		Ok(BootRom { data })
+	}
+}
+
+impl Device for BootRom {
+	fn read(&mut self, offset: u64, width: u64) -> u64 {
+		let mut value = 0;
+		for i in 0..width {
+			if let Some(byte) = self.data.get((offset + i) as usize) {
+				value |= (*byte as u64) << (i * 8);
+			}
+		}
+		value
+	}
+
+	// Writes are ignored
+	fn write(&mut self, _offset: u64, _value: u64, _width: u64) {}
+}
+
+/// Generates flattened device tree describing the memory, the hart and
+/// the devices on the bus. Returns error if a device builds unbalanced
+/// nodes.
+///
+/// # Arguments
+/// * `xlen`
+/// * `memory` Memory regions are exposed as memory nodes
+/// * `bus`
+/// * `bootargs` Kernel command line
+pub fn generate_device_tree(
+	xlen: &Xlen,
+	memory: &MemoryWrapper,
+	bus: &Bus,
+	bootargs: &str,
+) -> Result<Vec<u8>, String> {
+	let mut fdt = FdtBuilder::new();
+	fdt.begin_node("");
+	fdt.property_u32("#address-cells", 2);
+	fdt.property_u32("#size-cells", 2);
+	fdt.property_string("compatible", "riscv-virtio");
+	fdt.property_string("model", "riscv-rust");
+
+	fdt.begin_node("chosen");
+	fdt.property_string("bootargs", bootargs);
+	if bus.devices.iter().any(|mapping| mapping.base == UART_BASE) {
+		fdt.property_string("stdout-path", &format!("/soc/uart@{:x}", UART_BASE));
+	}
+	fdt.end_node();
+
+	fdt.begin_node("cpus");
+	fdt.property_u32("#address-cells", 1);
+	fdt.property_u32("#size-cells", 0);
+	fdt.property_u32("timebase-frequency", TIMEBASE_FREQUENCY);
+	fdt.begin_node("cpu@0");
+	fdt.property_string("device_type", "cpu");
+	fdt.property_u32("reg", 0);
+	fdt.property_string("status", "okay");
+	fdt.property_string("compatible", "riscv");
+	match xlen {
+		Xlen::Bit32 => {
+			fdt.property_string("riscv,isa", "rv32imafdc");
+			fdt.property_string("mmu-type", "riscv,sv32");
+		}
+		Xlen::Bit64 => {
+			fdt.property_string("riscv,isa", "rv64imafdc");
+			fdt.property_string("mmu-type", "riscv,sv39");
+		}
+	};
+	fdt.begin_node("interrupt-controller");
+	fdt.property_u32("#interrupt-cells", 1);
+	fdt.property_null("interrupt-controller");
+	fdt.property_string("compatible", "riscv,cpu-intc");
+	fdt.property_u32("phandle", FDT_CPU_INTC_PHANDLE);
+	fdt.end_node();
+	fdt.end_node();
+	fdt.end_node();
+
+	for region in memory.regions.iter() {
+		fdt.begin_node(&format!("memory@{:x}", region.base));
+		fdt.property_string("device_type", "memory");
+		fdt.property_reg(region.base, region.memory.capacity());
+		fdt.end_node();
+	}
+
+	fdt.begin_node("soc");
+	fdt.property_u32("#address-cells", 2);
+	fdt.property_u32("#size-cells", 2);
+	fdt.property_string("compatible", "simple-bus");
+	fdt.property_null("ranges");
+
+	fdt.begin_node(&format!("clint@{:x}", CLINT_BASE));
+	fdt.property_strings("compatible", &["sifive,clint0", "riscv,clint0"]);
+	fdt.property_reg(CLINT_BASE, CLINT_SIZE);
+	fdt.property_cells(
+		"interrupts-extended",
+		&[
+			FDT_CPU_INTC_PHANDLE,
+			IRQ_M_SOFT,
+			FDT_CPU_INTC_PHANDLE,
+			IRQ_M_TIMER,
+		],
+	);
+	fdt.end_node();
+
+	fdt.begin_node(&format!("plic@{:x}", PLIC_BASE));
+	fdt.property_strings("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+	fdt.property_u32("#address-cells", 0);
+	fdt.property_u32("#interrupt-cells", 1);
+	fdt.property_null("interrupt-controller");
+	fdt.property_reg(PLIC_BASE, PLIC_SIZE);
+	// Context 0 is machine mode and context 1 is supervisor mode
+	fdt.property_cells(
+		"interrupts-extended",
+		&[
+			FDT_CPU_INTC_PHANDLE,
+			IRQ_M_EXT,
+			FDT_CPU_INTC_PHANDLE,
+			IRQ_S_EXT,
+		],
+	);
+	fdt.property_u32("riscv,ndev", PLIC_SOURCE_NUM as u32 - 1);
+	fdt.property_u32("phandle", FDT_PLIC_PHANDLE);
+	fdt.end_node();
+
+	for mapping in bus.devices.iter() {
+		mapping
+			.device
+			.build_fdt_node(&mut fdt, mapping.base, mapping.size, mapping.irq);
+	}
+	fdt.end_node();
+
+	fdt.end_node();
+	fdt.finish()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use memory::{new_memory_backend, MemoryBackendType};
+	use mmu::{MemoryRegion, DRAM_BASE};
+	use terminal::BufferTerminal;
+	use uart::{Uart, UART_IRQ, UART_SIZE};
+
+	const MEMORY_CAPACITY: u64 = 0x100000;
+
+	struct Node {
+		path: String,
+		properties: Vec<(String, Vec<u8>)>,
+	}
+
+	impl Node {
+		fn get_property(&self, name: &str) -> &[u8] {
+			match self.properties.iter().find(|(n, _)| n == name) {
+				Some((_, value)) => value,
+				None => panic!("{} has no {}", self.path, name),
+			}
+		}
+	}
+
+	fn read_u32(blob: &[u8], offset: usize) -> u32 {
+		u32::from_be_bytes([
+			blob[offset],
+			blob[offset + 1],
+			blob[offset + 2],
+			blob[offset + 3],
+		])
+	}
+
+	fn read_string(blob: &[u8], offset: usize) -> String {
+		let end = offset + blob[offset..].iter().position(|b| *b == 0).unwrap();
+		String::from_utf8(blob[offset..end].to_vec()).unwrap()
+	}
+
+	fn get_cells(value: &[u8]) -> Vec<u32> {
+		(0..value.len() / 4)
+			.map(|i| read_u32(value, i * 4))
+			.collect()
+	}
+
+	/// Checks the header and walks the structure block tokens
+	fn parse_dtb(blob: &[u8]) -> Vec<Node> {
+		assert_eq!(read_u32(blob, 0), 0xd00dfeed);
+		assert_eq!(read_u32(blob, 4) as usize, blob.len());
+		let off_dt_struct = read_u32(blob, 8) as usize;
+		let off_dt_strings = read_u32(blob, 12) as usize;
+		let size_dt_strings = read_u32(blob, 32) as usize;
+		let size_dt_struct = read_u32(blob, 36) as usize;
+		assert_eq!(off_dt_struct + size_dt_struct, off_dt_strings);
+		assert_eq!(off_dt_strings + size_dt_strings, blob.len());
+
+		let mut nodes: Vec<Node> = vec![];
+		let mut stack: Vec<usize> = vec![];
+		let mut offset = off_dt_struct;
+		loop {
+			let token = read_u32(blob, offset);
+			offset += 4;
+			match token {
+				1 => {
+					let name = read_string(blob, offset);
+					offset = (offset + name.len() + 1 + 3) & !3;
+					let path = match stack.last() {
+						Some(parent) if nodes[*parent].path == "/" => format!("/{}", name),
+						Some(parent) => format!("{}/{}", nodes[*parent].path, name),
+						None => "/".to_string(),
+					};
+					stack.push(nodes.len());
+					nodes.push(Node {
+						path,
+						properties: vec![],
+					});
+				}
+				2 => {
+					stack.pop().unwrap();
+				}
+				3 => {
+					let length = read_u32(blob, offset) as usize;
+					let name =
+						read_string(blob, off_dt_strings + read_u32(blob, offset + 4) as usize);
+					let value = blob[offset + 8..offset + 8 + length].to_vec();
+					offset = (offset + 8 + length + 3) & !3;
+					nodes[*stack.last().unwrap()].properties.push((name, value));
+				}
+				9 => break,
+				_ => panic!("Unknown token {} at {:x}", token, offset - 4),
+			}
+		}
+		assert!(stack.is_empty());
+		assert_eq!(offset, off_dt_strings);
+		nodes
+	}
+
+	fn find_node<'a>(nodes: &'a [Node], path: &str) -> &'a Node {
+		match nodes.iter().find(|node| node.path == path) {
+			Some(node) => node,
+			None => panic!("No node {}", path),
+		}
+	}
+
+	fn create_memory() -> MemoryWrapper {
+		let backend_type = MemoryBackendType::Sparse;
+		MemoryWrapper {
+			regions: vec![MemoryRegion {
+				base: DRAM_BASE,
+				memory: new_memory_backend(&backend_type, MEMORY_CAPACITY),
+			}],
+			backend_type,
+		}
+	}
+
+	#[test]
+	fn device_tree_describes_system() {
+		let mut bus = Bus::new();
+		let uart = Uart::new(Box::new(BufferTerminal::new()));
+		bus.add_device_with_irq(UART_BASE, UART_SIZE, UART_IRQ, Box::new(uart))
+			.unwrap();
+		let dtb =
+			generate_device_tree(&Xlen::Bit64, &create_memory(), &bus, "console=ttyS0").unwrap();
+		let nodes = parse_dtb(&dtb);
+
+		let chosen = find_node(&nodes, "/chosen");
+		assert_eq!(chosen.get_property("bootargs"), b"console=ttyS0\0");
+		assert_eq!(chosen.get_property("stdout-path"), b"/soc/uart@10000000\0");
+
+		let cpu = find_node(&nodes, "/cpus/cpu@0");
+		assert_eq!(cpu.get_property("device_type"), b"cpu\0");
+		assert_eq!(cpu.get_property("riscv,isa"), b"rv64imafdc\0");
+		assert_eq!(cpu.get_property("mmu-type"), b"riscv,sv39\0");
+		let intc = find_node(&nodes, "/cpus/cpu@0/interrupt-controller");
+		assert_eq!(
+			get_cells(intc.get_property("phandle")),
+			vec![FDT_CPU_INTC_PHANDLE]
+		);
+
+		let memory = find_node(&nodes, "/memory@80000000");
+		assert_eq!(memory.get_property("device_type"), b"memory\0");
+		assert_eq!(
+			get_cells(memory.get_property("reg")),
+			vec![0, DRAM_BASE as u32, 0, MEMORY_CAPACITY as u32]
+		);
+
+		let clint = find_node(&nodes, "/soc/clint@2000000");
+		assert_eq!(
+			get_cells(clint.get_property("reg")),
+			vec![0, CLINT_BASE as u32, 0, CLINT_SIZE as u32]
+		);
+		let plic = find_node(&nodes, "/soc/plic@c000000");
+		assert_eq!(
+			get_cells(plic.get_property("phandle")),
+			vec![FDT_PLIC_PHANDLE]
+		);
+		assert!(plic
+			.properties
+			.iter()
+			.any(|(name, value)| name == "interrupt-controller" && value.is_empty()));
+
+		let uart = find_node(&nodes, "/soc/uart@10000000");
+		assert_eq!(uart.get_property("compatible"), b"ns16550a\0");
+		assert_eq!(
+			get_cells(uart.get_property("reg")),
+			vec![0, UART_BASE as u32, 0, UART_SIZE as u32]
+		);
+		assert_eq!(get_cells(uart.get_property("interrupts")), vec![UART_IRQ]);
+		assert_eq!(
+			get_cells(uart.get_property("interrupt-parent")),
+			vec![FDT_PLIC_PHANDLE]
+		);
+	}
+
+	#[test]
+	fn describes_32_bit_cpu_without_uart() {
+		let dtb = generate_device_tree(&Xlen::Bit32, &create_memory(), &Bus::new(), "").unwrap();
+		let nodes = parse_dtb(&dtb);
+		let chosen = find_node(&nodes, "/chosen");
+		assert!(chosen
+			.properties
+			.iter()
+			.all(|(name, _)| name != "stdout-path"));
+		let cpu = find_node(&nodes, "/cpus/cpu@0");
+		assert_eq!(cpu.get_property("riscv,isa"), b"rv32imafdc\0");
+		assert_eq!(cpu.get_property("mmu-type"), b"riscv,sv32\0");
+	}
+
+	/// Builds a node it doesn't end
+	struct UnbalancedDevice {}
+
+	impl Device for UnbalancedDevice {
+		fn read(&mut self, _offset: u64, _width: u64) -> u64 {
+			0
+		}
+
+		fn write(&mut self, _offset: u64, _value: u64, _width: u64) {}
+
+		fn build_fdt_node(&self, fdt: &mut FdtBuilder, base: u64, _size: u64, _irq: Option<u32>) {
+			fdt.begin_node(&format!("unbalanced@{:x}", base));
+		}
+	}
+
+	#[test]
+	fn rejects_unbalanced_nodes() {
+		let mut bus = Bus::new();
+		bus.add_device(UART_BASE, UART_SIZE, Box::new(UnbalancedDevice {}))
+			.unwrap();
+		assert!(generate_device_tree(&Xlen::Bit64, &create_memory(), &bus, "").is_err());
+	}
+
+	#[test]
+	fn places_entry_and_dtb() {
+		let entry = 0x80200000;
+		let dtb = vec![0xa5; 0x100];
+		let mut rom = BootRom::new(&Xlen::Bit64, entry, &dtb).unwrap();
+		assert_eq!(rom.read(BOOT_ROM_ENTRY_OFFSET as u64, 8), entry);
+		assert_eq!(rom.read(BOOT_ROM_DTB_OFFSET, 1), 0xa5);
+		assert_eq!(rom.read(BOOT_ROM_DTB_OFFSET + 0xff, 1), 0xa5);
+		assert_eq!(rom.read(BOOT_ROM_DTB_OFFSET + 0x100, 1), 0);
+		// Writes are ignored
+		rom.write(BOOT_ROM_ENTRY_OFFSET as u64, 0, 8);
+		assert_eq!(rom.read(BOOT_ROM_ENTRY_OFFSET as u64, 8), entry);
+
+		let max_size = (BOOT_ROM_SIZE - BOOT_ROM_DTB_OFFSET) as usize;
+		assert!(BootRom::new(&Xlen::Bit32, entry, &vec![0; max_size]).is_ok());
+		assert!(BootRom::new(&Xlen::Bit32, entry, &vec![0; max_size + 1]).is_err());
+	}
+}
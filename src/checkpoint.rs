@@ -0,0 +1,170 @@
+use std::fmt;
+
+/// Magic number at the beginning of checkpoint
+pub const CHECKPOINT_MAGIC: [u8; 8] = *b"RVEMUCKP";
+
+/// Checkpoint format version. Needs to be bumped whenever saved state
+/// is changed.
+pub const CHECKPOINT_VERSION: u32 = 5;
+
+/// Error in checkpoint
+#[derive(Debug)]
+pub struct CheckpointError {
+	pub message: String,
+}
+
+impl fmt::Display for CheckpointError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "checkpoint: {}", self.message)
+	}
+}
+
+/// Serializes emulator state into checkpoint. Components write their
+/// state in a fixed order and `CheckpointReader` reads it back in the
+/// same order. Values are little-endian.
+pub struct CheckpointWriter {
+	data: Vec<u8>,
+}
+
+impl Default for CheckpointWriter {
+	fn default() -> Self {
+		CheckpointWriter::new()
+	}
+}
+
+impl CheckpointWriter {
+	/// Creates a new `CheckpointWriter`.
+	pub fn new() -> Self {
+		CheckpointWriter { data: vec![] }
+	}
+
+	pub fn write_u8(&mut self, value: u8) {
+		self.data.push(value);
+	}
+
+	pub fn write_bool(&mut self, value: bool) {
+		self.write_u8(value as u8);
+	}
+
+	pub fn write_u32(&mut self, value: u32) {
+		self.data.extend_from_slice(&value.to_le_bytes());
+	}
+
+	pub fn write_u64(&mut self, value: u64) {
+		self.data.extend_from_slice(&value.to_le_bytes());
+	}
+
+	/// Writes bytes without length. The reader needs to know the length.
+	pub fn write_bytes(&mut self, values: &[u8]) {
+		self.data.extend_from_slice(values);
+	}
+
+	/// Writes doublewords without length. The reader needs to know the length.
+	pub fn write_u64s(&mut self, values: &[u64]) {
+		for value in values.iter() {
+			self.write_u64(*value);
+		}
+	}
+
+	/// Finishes writing and returns the checkpoint.
+	pub fn finish(self) -> Vec<u8> {
+		self.data
+	}
+}
+
+/// Deserializes emulator state from checkpoint written by
+/// `CheckpointWriter`.
+pub struct CheckpointReader<'a> {
+	data: &'a [u8],
+	position: usize,
+}
+
+impl<'a> CheckpointReader<'a> {
+	/// Creates a new `CheckpointReader`.
+	///
+	/// # Arguments
+	/// * `data` Checkpoint content
+	pub fn new(data: &'a [u8]) -> Self {
+		CheckpointReader { data, position: 0 }
+	}
+
+	/// Returns an error with the current position.
+	///
+	/// # Arguments
+	/// * `message`
+	pub fn error(&self, message: String) -> CheckpointError {
+		CheckpointError {
+			message: format!("{} at offset {:x}", message, self.position),
+		}
+	}
+
+	fn take(&mut self, length: usize) -> Result<&'a [u8], CheckpointError> {
+		if self.data.len() - self.position < length {
+			return Err(self.error("Unexpected end of checkpoint".to_string()));
+		}
+		let data = &self.data[self.position..self.position + length];
+		self.position += length;
+		Ok(data)
+	}
+
+	pub fn read_u8(&mut self) -> Result<u8, CheckpointError> {
+		Ok(self.take(1)?[0])
+	}
+
+	pub fn read_bool(&mut self) -> Result<bool, CheckpointError> {
+		match self.read_u8()? {
+			0 => Ok(false),
+			1 => Ok(true),
+			value => Err(self.error(format!("Invalid boolean {}", value))),
+		}
+	}
+
+	pub fn read_u32(&mut self) -> Result<u32, CheckpointError> {
+		let mut bytes = [0; 4];
+		bytes.copy_from_slice(self.take(4)?);
+		Ok(u32::from_le_bytes(bytes))
+	}
+
+	pub fn read_u64(&mut self) -> Result<u64, CheckpointError> {
+		let mut bytes = [0; 8];
+		bytes.copy_from_slice(self.take(8)?);
+		Ok(u64::from_le_bytes(bytes))
+	}
+
+	/// Reads as many bytes as `values` holds.
+	pub fn read_bytes(&mut self, values: &mut [u8]) -> Result<(), CheckpointError> {
+		values.copy_from_slice(self.take(values.len())?);
+		Ok(())
+	}
+
+	/// Reads as many doublewords as `values` holds.
+	pub fn read_u64s(&mut self, values: &mut [u64]) -> Result<(), CheckpointError> {
+		for value in values.iter_mut() {
+			*value = self.read_u64()?;
+		}
+		Ok(())
+	}
+
+	/// Reads a value which must match the current configuration, e.g.
+	/// the base address of a device. Checkpoint can be restored only to
+	/// the emulator set up in the same way.
+	///
+	/// # Arguments
+	/// * `expected` Value of the current configuration
+	/// * `name` Name of the value for error message
+	pub fn expect_u64(&mut self, expected: u64, name: &str) -> Result<(), CheckpointError> {
+		let value = self.read_u64()?;
+		match value == expected {
+			true => Ok(()),
+			false => Err(self.error(format!(
+				"{} {:x} doesn't match the emulator's {:x}",
+				name, value, expected
+			))),
+		}
+	}
+
+	/// Returns true if all the content has been read.
+	pub fn is_end(&self) -> bool {
+		self.position == self.data.len()
+	}
+}
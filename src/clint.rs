@@ -0,0 +1,243 @@
+use checkpoint::{CheckpointError, CheckpointReader, CheckpointWriter};
+use cpu::{MIP_MSIP, MIP_MTIP};
+
+/// Physical base address of CLINT
+pub const CLINT_BASE: u64 = 0x02000000;
+
+/// Size of CLINT address range
+pub const CLINT_SIZE: u64 = 0x10000;
+
+/// Default ratio of core clock to mtime clock
+pub const DEFAULT_MTIME_CLOCK_RATIO: u64 = 8;
+
+// Register offsets, SiFive compatible with a single hart
+const CLINT_MSIP_OFFSET: u64 = 0x0000;
+const CLINT_MTIMECMP_OFFSET: u64 = 0x4000;
+const CLINT_MTIME_OFFSET: u64 = 0xbff8;
+
+/// Emulates CLINT (Core Local Interruptor). It drives machine timer
+/// interrupt from `mtime`/`mtimecmp` and machine software interrupt
+/// from `msip`.
+pub struct Clint {
+	/// Core clock cycles per mtime increment
+	clock_ratio: u64,
+	/// Core clock at the last tick
+	last_clock: u64,
+	/// Core clock cycles not yet reflected to mtime
+	residual_cycles: u64,
+	msip: u32,
+	mtimecmp: u64,
+	mtime: u64,
+}
+
+impl Default for Clint {
+	fn default() -> Self {
+		Clint::new()
+	}
+}
+
+impl Clint {
+	/// Creates a new `Clint`.
+	pub const fn new() -> Self {
+		Clint {
+			clock_ratio: DEFAULT_MTIME_CLOCK_RATIO,
+			last_clock: 0,
+			residual_cycles: 0,
+			msip: 0,
+			mtimecmp: 0xffffffffffffffff,
+			mtime: 0,
+		}
+	}
+
+	/// Updates the ratio of core clock to mtime clock. Returns error if
+	/// the ratio is zero.
+	///
+	/// # Arguments
+	/// * `ratio` Core clock cycles per mtime increment, must be non-zero
+	pub fn set_clock_ratio(&mut self, ratio: u64) -> Result<(), String> {
+		if ratio == 0 {
+			return Err("mtime clock ratio must be non-zero".to_string());
+		}
+		self.clock_ratio = ratio;
+		Ok(())
+	}
+
+	/// Advances mtime up to the core clock and updates MTIP and MSIP
+	/// bits of `mip`.
+	///
+	/// # Arguments
+	/// * `clock` Current core clock
+	/// * `mip`
+	pub fn tick(&mut self, clock: u64, mip: &mut u64) {
+		self.residual_cycles += clock.wrapping_sub(self.last_clock);
+		self.last_clock = clock;
+		self.mtime = self
+			.mtime
+			.wrapping_add(self.residual_cycles / self.clock_ratio);
+		self.residual_cycles %= self.clock_ratio;
+
+		match (self.msip & 1) != 0 {
+			true => *mip |= MIP_MSIP,
+			false => *mip &= !MIP_MSIP,
+		}
+		match self.mtime >= self.mtimecmp {
+			true => *mip |= MIP_MTIP,
+			false => *mip &= !MIP_MTIP,
+		}
+	}
+
+	/// Reads CLINT register.
+	///
+	/// # Arguments
+	/// * `offset` Offset from `CLINT_BASE`
+	/// * `width` Must be 1, 2, 4, or 8
+	pub fn read(&self, offset: u64, width: u64) -> u64 {
+		let (register, register_offset) = match offset {
+			CLINT_MSIP_OFFSET..=0x0003 => (self.msip as u64, offset - CLINT_MSIP_OFFSET),
+			CLINT_MTIMECMP_OFFSET..=0x4007 => (self.mtimecmp, offset - CLINT_MTIMECMP_OFFSET),
+			CLINT_MTIME_OFFSET..=0xbfff => (self.mtime, offset - CLINT_MTIME_OFFSET),
+			_ => return 0,
+		};
+		let value = register >> (register_offset * 8);
+		match width {
+			8 => value,
+			_ => value & ((1 << (width * 8)) - 1),
+		}
+	}
+
+	/// Writes CLINT register.
+	///
+	/// # Arguments
+	/// * `offset` Offset from `CLINT_BASE`
+	/// * `value`
+	/// * `width` Must be 1, 2, 4, or 8
+	pub fn write(&mut self, offset: u64, value: u64, width: u64) {
+		let mask = match width {
+			8 => 0xffffffffffffffff,
+			_ => (1 << (width * 8)) - 1,
+		};
+		let update = |register: u64, register_offset: u64| -> u64 {
+			let shift = register_offset * 8;
+			(register & !(mask << shift)) | ((value & mask) << shift)
+		};
+		match offset {
+			CLINT_MSIP_OFFSET..=0x0003 => {
+				// Only the lowest bit is writable
+				self.msip = (update(self.msip as u64, offset - CLINT_MSIP_OFFSET) & 1) as u32;
+			}
+			CLINT_MTIMECMP_OFFSET..=0x4007 => {
+				self.mtimecmp = update(self.mtimecmp, offset - CLINT_MTIMECMP_OFFSET);
+			}
+			CLINT_MTIME_OFFSET..=0xbfff => {
+				self.mtime = update(self.mtime, offset - CLINT_MTIME_OFFSET);
+			}
+			_ => {}
+		}
+	}
+
+	/// Reads mtime, e.g. for `time` CSR.
+	pub fn read_mtime(&self) -> u64 {
+		self.mtime
+	}
+
+	/// Writes mtime.
+	///
+	/// # Arguments
+	/// * `value`
+	pub fn write_mtime(&mut self, value: u64) {
+		self.mtime = value;
+	}
+
+	/// Saves the state to checkpoint.
+	///
+	/// # Arguments
+	/// * `writer`
+	pub fn save_state(&self, writer: &mut CheckpointWriter) {
+		writer.write_u64(self.clock_ratio);
+		writer.write_u64(self.last_clock);
+		writer.write_u64(self.residual_cycles);
+		writer.write_u32(self.msip);
+		writer.write_u64(self.mtimecmp);
+		writer.write_u64(self.mtime);
+	}
+
+	/// Restores the state saved by `save_state()`.
+	///
+	/// # Arguments
+	/// * `reader`
+	pub fn restore_state(&mut self, reader: &mut CheckpointReader) -> Result<(), CheckpointError> {
+		let clock_ratio = reader.read_u64()?;
+		if clock_ratio == 0 {
+			return Err(reader.error("Zero mtime clock ratio".to_string()));
+		}
+		self.clock_ratio = clock_ratio;
+		self.last_clock = reader.read_u64()?;
+		self.residual_cycles = reader.read_u64()?;
+		self.msip = reader.read_u32()?;
+		self.mtimecmp = reader.read_u64()?;
+		self.mtime = reader.read_u64()?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn mtime_advances_by_ratio() {
+		let mut clint = Clint::new();
+		let mut mip = 0;
+		assert!(clint.set_clock_ratio(0).is_err());
+		clint.set_clock_ratio(4).unwrap();
+		clint.tick(3, &mut mip);
+		assert_eq!(clint.read_mtime(), 0);
+		// Residual cycles carry over to the next tick
+		clint.tick(5, &mut mip);
+		assert_eq!(clint.read_mtime(), 1);
+		clint.tick(8, &mut mip);
+		assert_eq!(clint.read_mtime(), 2);
+		clint.tick(48, &mut mip);
+		assert_eq!(clint.read_mtime(), 12);
+		assert_eq!(clint.read(CLINT_MTIME_OFFSET, 8), 12);
+		assert_eq!(clint.read(CLINT_MTIME_OFFSET + 4, 4), 0);
+	}
+
+	#[test]
+	fn mtip_follows_mtimecmp() {
+		let mut clint = Clint::new();
+		let mut mip = 0;
+		clint.set_clock_ratio(1).unwrap();
+		clint.write(CLINT_MTIMECMP_OFFSET, 10, 4);
+		clint.write(CLINT_MTIMECMP_OFFSET + 4, 0, 4);
+		assert_eq!(clint.read(CLINT_MTIMECMP_OFFSET, 8), 10);
+		clint.tick(9, &mut mip);
+		assert_eq!(mip & MIP_MTIP, 0);
+		clint.tick(10, &mut mip);
+		assert_ne!(mip & MIP_MTIP, 0);
+		clint.tick(11, &mut mip);
+		assert_ne!(mip & MIP_MTIP, 0);
+		// Writing a later mtimecmp clears MTIP
+		clint.write(CLINT_MTIMECMP_OFFSET, 20, 8);
+		clint.tick(12, &mut mip);
+		assert_eq!(mip & MIP_MTIP, 0);
+		clint.write(CLINT_MTIME_OFFSET, 20, 8);
+		clint.tick(12, &mut mip);
+		assert_ne!(mip & MIP_MTIP, 0);
+	}
+
+	#[test]
+	fn msip_drives_mip() {
+		let mut clint = Clint::new();
+		let mut mip = 0;
+		clint.write(CLINT_MSIP_OFFSET, 1, 4);
+		clint.tick(1, &mut mip);
+		assert_ne!(mip & MIP_MSIP, 0);
+		assert_eq!(mip & MIP_MTIP, 0);
+		// Only the lowest bit is writable
+		clint.write(CLINT_MSIP_OFFSET, 2, 4);
+		assert_eq!(clint.read(CLINT_MSIP_OFFSET, 4), 0);
+		clint.tick(2, &mut mip);
+		assert_eq!(mip & MIP_MSIP, 0);
+	}
+}
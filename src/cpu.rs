@@ -1,15 +1,20 @@
 extern crate fnv;
 
-use mmu::{AddressingMode, MemoryAccessType, Mmu};
+use checkpoint::{CheckpointError, CheckpointReader, CheckpointWriter};
+use mmu::{AddressingMode, MemoryAccessType, Mmu, MENVCFG_ADUE, MENVCFG_PBMTE};
+use softfloat::{
+	FloatContext, RoundingMode, FLAG_DZ, FLAG_NV, FLAG_NX, FLAG_OF, FLAG_UF, FLOAT32, FLOAT64,
+};
 use std::fs::OpenOptions;
 use std::io::prelude::*;
+use std::num::FpCategory;
 
 pub const CSR_CAPACITY: usize = 4096;
 
 const CSR_USTATUS_ADDRESS: u16 = 0x000;
 const CSR_FFLAGS_ADDRESS: u16 = 0x001;
 const CSR_FRM_ADDRESS: u16 = 0x002;
-const CSR_FCSR_ADDRESS: u16 = 0x003;
+pub const CSR_FCSR_ADDRESS: u16 = 0x003;
 const CSR_UIE_ADDRESS: u16 = 0x004;
 const CSR_UTVEC_ADDRESS: u16 = 0x005;
 const _CSR_USCRATCH_ADDRESS: u16 = 0x040;
@@ -22,12 +27,12 @@ const CSR_SEDELEG_ADDRESS: u16 = 0x102;
 const CSR_SIDELEG_ADDRESS: u16 = 0x103;
 const CSR_SIE_ADDRESS: u16 = 0x104;
 const CSR_STVEC_ADDRESS: u16 = 0x105;
-const _CSR_SSCRATCH_ADDRESS: u16 = 0x140;
+const CSR_SSCRATCH_ADDRESS: u16 = 0x140;
 const CSR_SEPC_ADDRESS: u16 = 0x141;
 const CSR_SCAUSE_ADDRESS: u16 = 0x142;
 const CSR_STVAL_ADDRESS: u16 = 0x143;
 const CSR_SIP_ADDRESS: u16 = 0x144;
-const CSR_SATP_ADDRESS: u16 = 0x180;
+pub const CSR_SATP_ADDRESS: u16 = 0x180;
 const CSR_MSTATUS_ADDRESS: u16 = 0x300;
 const CSR_MISA_ADDRESS: u16 = 0x301;
 const CSR_MEDELEG_ADDRESS: u16 = 0x302;
@@ -35,33 +40,78 @@ const CSR_MIDELEG_ADDRESS: u16 = 0x303;
 const CSR_MIE_ADDRESS: u16 = 0x304;
 
 const CSR_MTVEC_ADDRESS: u16 = 0x305;
-const _CSR_MSCRATCH_ADDRESS: u16 = 0x340;
+const CSR_MENVCFG_ADDRESS: u16 = 0x30a;
+const CSR_MENVCFGH_ADDRESS: u16 = 0x31a;
+const CSR_MSCRATCH_ADDRESS: u16 = 0x340;
 const CSR_MEPC_ADDRESS: u16 = 0x341;
 const CSR_MCAUSE_ADDRESS: u16 = 0x342;
 const CSR_MTVAL_ADDRESS: u16 = 0x343;
 const CSR_MIP_ADDRESS: u16 = 0x344;
-const _CSR_PMPCFG0_ADDRESS: u16 = 0x3a0;
-const _CSR_PMPADDR0_ADDRESS: u16 = 0x3b0;
+const CSR_PMPCFG0_ADDRESS: u16 = 0x3a0;
+const CSR_PMPCFG15_ADDRESS: u16 = 0x3af;
+const CSR_PMPADDR0_ADDRESS: u16 = 0x3b0;
+const CSR_PMPADDR63_ADDRESS: u16 = 0x3ef;
 pub const CSR_MCYCLE_ADDRESS: u16 = 0xb00;
+pub const CSR_MINSTRET_ADDRESS: u16 = 0xb02;
 const _CSR_CYCLE_ADDRESS: u16 = 0xc00;
 const CSR_TIME_ADDRESS: u16 = 0xc01;
-const _CSR_INSERT_ADDRESS: u16 = 0xc02;
+const CSR_TIMEH_ADDRESS: u16 = 0xc81;
+const CSR_INSTRET_ADDRESS: u16 = 0xc02;
 
 pub const CSR_HPMCOUNTER3_ADDRESS: u16 = 0xc03;
 pub const CSR_HPMCOUNTER4_ADDRESS: u16 = 0xc04;
 pub const CSR_HPMCOUNTER5_ADDRESS: u16 = 0xc05;
 pub const CSR_HPMCOUNTER6_ADDRESS: u16 = 0xc06;
-pub const _CSR_HPMCOUNTER7_ADDRESS: u16 = 0xc07;
+/// Memory controller reads, writes, read latency, and queue occupancy
+pub const CSR_HPMCOUNTER7_ADDRESS: u16 = 0xc07;
+pub const CSR_HPMCOUNTER8_ADDRESS: u16 = 0xc08;
+pub const CSR_HPMCOUNTER9_ADDRESS: u16 = 0xc09;
+pub const CSR_HPMCOUNTER10_ADDRESS: u16 = 0xc0a;
+/// DRAM row buffer hits and misses
+pub const CSR_HPMCOUNTER11_ADDRESS: u16 = 0xc0b;
+pub const CSR_HPMCOUNTER12_ADDRESS: u16 = 0xc0c;
 
 const _CSR_MHARTID_ADDRESS: u16 = 0xf14;
 
-const MIP_MEIP: u64 = 0x800;
+pub const MIP_MEIP: u64 = 0x800;
 pub const MIP_MTIP: u64 = 0x080;
 pub const MIP_MSIP: u64 = 0x008;
 pub const MIP_SEIP: u64 = 0x200;
 const MIP_STIP: u64 = 0x020;
 const MIP_SSIP: u64 = 0x002;
 
+/// Upper 32 bits of NaN-boxed single-precision value
+const F32_NAN_BOX: u64 = 0xffffffff00000000;
+const F32_CANONICAL_NAN: u32 = 0x7fc00000;
+const F32_SIGN_BIT: u64 = 0x80000000;
+const F64_SIGN_BIT: u64 = 0x8000000000000000;
+
+const MENVCFG_WRITABLE_MASK: u64 = MENVCFG_PBMTE | MENVCFG_ADUE;
+
+/// CSRs saved in memdump, in the order to restore. satp and fcsr are saved
+/// separately.
+pub const MEMDUMP_CSR_ADDRESSES: [u16; 15] = [
+	CSR_STVEC_ADDRESS,
+	CSR_SSCRATCH_ADDRESS,
+	CSR_SEPC_ADDRESS,
+	CSR_SCAUSE_ADDRESS,
+	CSR_STVAL_ADDRESS,
+	CSR_MSTATUS_ADDRESS,
+	CSR_MEDELEG_ADDRESS,
+	CSR_MIDELEG_ADDRESS,
+	CSR_MIE_ADDRESS,
+	CSR_MTVEC_ADDRESS,
+	CSR_MENVCFG_ADDRESS,
+	CSR_MSCRATCH_ADDRESS,
+	CSR_MEPC_ADDRESS,
+	CSR_MCAUSE_ADDRESS,
+	CSR_MTVAL_ADDRESS,
+];
+
+/// `slli zero, zero, 31`, a HINT instruction programs execute to request
+/// memdump
+pub const MEMDUMP_MAGIC_INSTRUCTION: u32 = 0x01f01013;
+
 /// Emulates a RISC-V CPU core
 pub struct Cpu {
 	pub clock: u64,
@@ -80,10 +130,13 @@ pub struct Cpu {
 	pub is_reservation_set: bool,
 	pub _dump_flag: bool,
 	pub unsigned_data_mask: u64,
-	pub tohost_addr: u64,
 
 	// Exit signal
 	pub exit_signal: bool,
+	/// Set when `MEMDUMP_MAGIC_INSTRUCTION` is executed
+	pub memdump_signal: bool,
+	/// Set when a watchpoint which stops emulation is hit
+	pub watchpoint_signal: bool,
 }
 
 #[derive(Clone)]
@@ -144,8 +197,8 @@ fn _get_privilege_mode_name(mode: &PrivilegeMode) -> &'static str {
 	}
 }
 
-// bigger number is higher privilege level
-fn get_privilege_encoding(mode: &PrivilegeMode) -> u8 {
+/// Returns encoded privilege mode bits. Bigger number is higher privilege level.
+pub fn get_privilege_encoding(mode: &PrivilegeMode) -> u8 {
 	match mode {
 		PrivilegeMode::User => 0,
 		PrivilegeMode::Supervisor => 1,
@@ -242,11 +295,11 @@ impl Cpu {
 			is_reservation_set: false,
 			_dump_flag: false,
 			unsigned_data_mask: 0xffffffffffffffff,
-			tohost_addr: 0,
 
 			exit_signal: false,
+			memdump_signal: false,
+			watchpoint_signal: false,
 		};
-		cpu.x[0xb] = 0x1020; // I don't know why but Linux boot seems to require this initialization
 		cpu.write_csr_raw(CSR_MISA_ADDRESS, 0x800000008014312f);
 		cpu
 	}
@@ -284,6 +337,18 @@ impl Cpu {
 		self.mmu.update_xlen(xlen.clone());
 	}
 
+	/// Sets up the machine state which M-mode firmware is expected to set up
+	/// before it hands over control to a lower privilege mode. Used for
+	/// programs started without such a firmware.
+	pub fn setup_firmware_state(&mut self) {
+		let xlen = self.xlen.clone();
+		self.mmu.pmp.grant_all(&xlen);
+		// Enables Svpbmt and hardware A/D bits update (Svadu)
+		self.csr[CSR_MENVCFG_ADDRESS as usize] = MENVCFG_PBMTE | MENVCFG_ADUE;
+		self.mmu
+			.update_menvcfg(self.csr[CSR_MENVCFG_ADDRESS as usize]);
+	}
+
 	/// Reads integer register content
 	///
 	/// # Arguments
@@ -301,21 +366,83 @@ impl Cpu {
 		self.pc
 	}
 
+	/// Saves the architectural state and `Mmu` state to checkpoint.
+	///
+	/// # Arguments
+	/// * `writer`
+	/// * `include_caches` Whether to save L1 and L2 cache lines
+	pub fn save_state(&self, writer: &mut CheckpointWriter, include_caches: bool) {
+		writer.write_u64(self.clock);
+		writer.write_u8(match self.xlen {
+			Xlen::Bit32 => 32,
+			Xlen::Bit64 => 64,
+		});
+		writer.write_u8(get_privilege_encoding(&self.privilege_mode));
+		writer.write_bool(self.wfi);
+		for value in self.x.iter() {
+			writer.write_u64(*value as u64);
+		}
+		for value in self.f.iter() {
+			writer.write_u64(value.to_bits());
+		}
+		writer.write_u64(self.pc);
+		writer.write_u64s(&self.csr);
+		writer.write_u64(self.reservation);
+		writer.write_bool(self.is_reservation_set);
+		self.mmu.save_state(writer, include_caches);
+	}
+
+	/// Restores the state saved by `save_state()`.
+	///
+	/// # Arguments
+	/// * `reader`
+	pub fn restore_state(&mut self, reader: &mut CheckpointReader) -> Result<(), CheckpointError> {
+		self.clock = reader.read_u64()?;
+		let xlen = match reader.read_u8()? {
+			32 => Xlen::Bit32,
+			64 => Xlen::Bit64,
+			xlen => return Err(reader.error(format!("Invalid XLEN {}", xlen))),
+		};
+		self.update_xlen(xlen);
+		self.privilege_mode = match reader.read_u8()? {
+			mode @ (0 | 1 | 3) => get_privilege_mode(mode as u64),
+			mode => return Err(reader.error(format!("Invalid privilege mode {}", mode))),
+		};
+		self.wfi = reader.read_bool()?;
+		for value in self.x.iter_mut() {
+			*value = reader.read_u64()? as i64;
+		}
+		for value in self.f.iter_mut() {
+			*value = f64::from_bits(reader.read_u64()?);
+		}
+		self.pc = reader.read_u64()?;
+		reader.read_u64s(&mut self.csr)?;
+		self.reservation = reader.read_u64()?;
+		self.is_reservation_set = reader.read_bool()?;
+		self.mmu.restore_state(reader)
+	}
+
 	/// Runs program one cycle. Fetch, decode, and execution are completed in a cycle so far.
 	pub fn tick(&mut self, trace_memory_access: bool, trace_path: &str) {
 		let instruction_address = self.pc;
 
 		if self.wfi {
+			// @TODO: determine WFI latency
+			// Devices keep running to wake up the core
+			self.mmu.clock = self.mmu.clock.wrapping_add(1);
+			self.clock = self.mmu.clock;
+			self.mmu.tick(&mut self.csr[CSR_MIP_ADDRESS as usize]);
 			if (self.read_csr_raw(CSR_MIE_ADDRESS) & self.read_csr_raw(CSR_MIP_ADDRESS)) != 0 {
 				self.wfi = false;
 			}
-			// @TODO: determine WFI latency
 			return;
 		}
 
 		let word = match self.fetch_uncompress() {
 			Ok(word) => word,
 			Err(e) => {
+				// Bytes fetched before the fault aren't executed
+				self.mmu.watchpoints.discard_pending_hits();
 				// Handle instruction page fault
 				self.handle_exception(e, instruction_address);
 				// @TODO: fix
@@ -326,9 +453,8 @@ impl Cpu {
 
 		let decode_result = self.decode(word, instruction_address);
 
-		// Extra exit after decode stage, because we handle to/from host operation in decode stage.
+		// Extra exit after decode stage.
 		// Currently detect as exit when
-		// * tohost address is set
 		// * JAL to the identical address
 		// * ECALL
 		let pipeline_result = match decode_result {
@@ -345,6 +471,13 @@ impl Cpu {
 				//
 				// Currently used for exitting.
 				self.exit_signal = true;
+				// Execute watchpoint on the undecodable instruction is still
+				// hit by the fetch
+				if self.mmu.watchpoints.has_pending_hits()
+					&& self.mmu.watchpoints.report_hits(instruction_address)
+				{
+					self.watchpoint_signal = true;
+				}
 				return;
 			}
 		};
@@ -353,6 +486,11 @@ impl Cpu {
 			Ok(()) => {
 				self.mmu.clock = self.mmu.clock.wrapping_add(pipeline_result.1 as u64);
 				self.clock = self.mmu.clock;
+				self.csr[CSR_MINSTRET_ADDRESS as usize] =
+					self.csr[CSR_MINSTRET_ADDRESS as usize].wrapping_add(1);
+				if word == MEMDUMP_MAGIC_INSTRUCTION {
+					self.memdump_signal = true;
+				}
 			}
 			Err(e) => {
 				// Handle pipeline traps
@@ -361,20 +499,50 @@ impl Cpu {
 			}
 		}
 
+		// Main memory can't be accessed any more
+		if self.mmu.memory_error.is_some() {
+			self.exit_signal = true;
+			return;
+		}
+
+		if self.mmu.watchpoints.has_pending_hits()
+			&& self.mmu.watchpoints.report_hits(instruction_address)
+		{
+			self.watchpoint_signal = true;
+		}
+
 		if trace_memory_access == true {
 			for i in 0..self.mmu.memory_access_trace.len() {
 				let mut file = OpenOptions::new().append(true).open(trace_path).unwrap();
 
+				// DRAM location `channel rank bank row column` follows if DRAM
+				// model is set up
+				let location = match self.mmu.get_dram_model() {
+					Some(dram_model) => {
+						let address =
+							dram_model.decode_address(self.mmu.memory_access_trace[i].address);
+						format!(
+							" {} {} {} {} {}",
+							address.channel,
+							address.rank,
+							address.bank,
+							address.row,
+							address.column
+						)
+					}
+					None => String::new(),
+				};
 				file.write(
 					format!(
-						"0x{:016x} {} {}\n",
+						"0x{:016x} {} {}{}\n",
 						self.mmu.memory_access_trace[i].address,
 						match self.mmu.memory_access_trace[i].operation {
 							MemoryAccessType::Read => "READ",
 							MemoryAccessType::Write => "WRITE",
 							_ => "",
 						},
-						self.mmu.memory_access_trace[i].cycle
+						self.mmu.memory_access_trace[i].cycle,
+						location
 					)
 					.as_bytes(),
 				)
@@ -382,18 +550,30 @@ impl Cpu {
 			}
 		}
 		self.mmu.tick(&mut self.csr[CSR_MIP_ADDRESS as usize]);
+		if self.mmu.get_htif().get_exit_code().is_some() {
+			self.exit_signal = true;
+			return;
+		}
 		self.handle_interrupt(self.pc);
 		// self.clock = self.clock.wrapping_add(1);
 
-		// cpu core clock : mtime clock in clint = 8 : 1 is
-		// just an arbiraty ratio.
-		// @TODO: Implement more properly
 		// self.write_csr_raw(CSR_CYCLE_ADDRESS, self.clock * 8);
 		self.write_csr_raw(CSR_MCYCLE_ADDRESS, self.clock);
 		self.write_csr_raw(CSR_HPMCOUNTER3_ADDRESS, self.mmu.l1_cache.hit_num);
 		self.write_csr_raw(CSR_HPMCOUNTER4_ADDRESS, self.mmu.l1_cache.miss_num);
 		self.write_csr_raw(CSR_HPMCOUNTER5_ADDRESS, self.mmu.l2_cache.hit_num);
 		self.write_csr_raw(CSR_HPMCOUNTER6_ADDRESS, self.mmu.l2_cache.miss_num);
+		if let Some(memory_controller) = self.mmu.memory_controller.as_ref() {
+			self.csr[CSR_HPMCOUNTER7_ADDRESS as usize] = memory_controller.read_num;
+			self.csr[CSR_HPMCOUNTER8_ADDRESS as usize] = memory_controller.write_num;
+			self.csr[CSR_HPMCOUNTER9_ADDRESS as usize] = memory_controller.read_latency;
+			self.csr[CSR_HPMCOUNTER10_ADDRESS as usize] = memory_controller.queue_occupancy;
+		}
+		if let Some(dram_model) = self.mmu.get_dram_model() {
+			self.csr[CSR_HPMCOUNTER11_ADDRESS as usize] = dram_model.row_hit_num;
+			self.csr[CSR_HPMCOUNTER12_ADDRESS as usize] =
+				dram_model.row_empty_num + dram_model.row_conflict_num;
+		}
 	}
 
 	// @TODO: Rename?
@@ -432,69 +612,9 @@ impl Cpu {
 	pub fn decode(&mut self, word: u32, instruction_address: u64) -> Result<&Instruction, ()> {
 		match self.decode_and_get_instruction_index(word) {
 			Ok(index) => {
-				// Handle to/from host
+				// Handle exit conditions
 				let inst = &INSTRUCTIONS[index];
 				match inst.name {
-					"SD" | "SW" | "SH" | "SB" => {
-						let f = parse_format_s(word);
-						if self.x[f.rs1].wrapping_add(f.imm) == (self.tohost_addr as i64) {
-							#[cfg(feature = "debug-tohost")]
-							println!(
-								"[Tohost] {} {:x} to {:x}",
-								inst.name,
-								self.x[f.rs2],
-								self.x[f.rs1].wrapping_add(f.imm)
-							);
-							let tohost_addr = self.tohost_addr;
-							let tohost_data_addr = self.x[f.rs2];
-							match tohost_data_addr {
-								0..=0x80000000 => {
-									// Exit for riscv-test on tohost getting 1/0
-									//
-									// @TODO: Add pass of fail printing
-									self.exit_signal = true;
-									return Err(());
-								}
-								_ => {
-									// Tohost I/O stream
-									//
-									// @TODO: optimize
-									let flag1 = self
-										.get_mut_mmu()
-										.load_word_raw((24 + tohost_data_addr) as u64)
-										!= 0;
-									let flag2 = self
-										.get_mut_mmu()
-										.load_word_raw((28 + tohost_data_addr) as u64)
-										!= 0;
-									if flag1 || flag2 {
-										let base = self
-											.get_mut_mmu()
-											.load_word_raw((4 * 4 + tohost_data_addr) as u64);
-										let length = self
-											.get_mut_mmu()
-											.load_word_raw((6 * 4 + tohost_data_addr) as u64);
-										for i in 0..length {
-											let data =
-												self.get_mut_mmu().load_raw((i + base) as u64);
-											print!("{}", data as char);
-										}
-									}
-
-									// After printf, set 1 to fromhost and set 0 to tohost
-									// Note: host needs to access cache instead of memory!
-									self.get_mut_mmu().store_word_raw(tohost_addr + 0x40, 1);
-									self.get_mut_mmu().store_word_raw(tohost_addr, 0);
-
-									// Exit after print to host
-									//
-									// @TODO: verify
-									self.exit_signal = true;
-									return Err(());
-								}
-							};
-						}
-					}
 					"JAL" => {
 						// Exit on iterative JAL
 						//
@@ -928,6 +1048,19 @@ impl Cpu {
 		}
 	}
 
+	/// Writes CSR from the host regardless of privilege, e.g. to restore
+	/// a snapshot. Updates MMU state depending on the CSR as well.
+	///
+	/// # Arguments
+	/// * `address` CSR address
+	/// * `value`
+	pub fn write_csr_from_host(&mut self, address: u16, value: u64) {
+		self.write_csr_raw(address, value);
+		if address == CSR_SATP_ADDRESS {
+			self.update_addressing_mode(value);
+		}
+	}
+
 	// SSTATUS, SIE, and SIP are subsets of MSTATUS, MIE, and MIP
 	pub fn read_csr_raw(&self, address: u16) -> u64 {
 		match address {
@@ -937,7 +1070,25 @@ impl Cpu {
 			CSR_SSTATUS_ADDRESS => self.csr[CSR_MSTATUS_ADDRESS as usize] & 0x80000003000de162,
 			CSR_SIE_ADDRESS => self.csr[CSR_MIE_ADDRESS as usize] & 0x222,
 			CSR_SIP_ADDRESS => self.csr[CSR_MIP_ADDRESS as usize] & 0x222,
-			CSR_TIME_ADDRESS => self.csr[CSR_TIME_ADDRESS as usize],
+			CSR_TIME_ADDRESS => match self.xlen {
+				Xlen::Bit32 => self.mmu.get_clint().read_mtime() & 0xffffffff,
+				Xlen::Bit64 => self.mmu.get_clint().read_mtime(),
+			},
+			CSR_TIMEH_ADDRESS => self.mmu.get_clint().read_mtime() >> 32,
+			CSR_INSTRET_ADDRESS => self.csr[CSR_MINSTRET_ADDRESS as usize],
+			CSR_MENVCFG_ADDRESS => match self.xlen {
+				Xlen::Bit32 => self.csr[CSR_MENVCFG_ADDRESS as usize] & 0xffffffff,
+				Xlen::Bit64 => self.csr[CSR_MENVCFG_ADDRESS as usize],
+			},
+			CSR_MENVCFGH_ADDRESS => self.csr[CSR_MENVCFG_ADDRESS as usize] >> 32,
+			CSR_PMPCFG0_ADDRESS..=CSR_PMPCFG15_ADDRESS => self
+				.mmu
+				.pmp
+				.read_cfg((address - CSR_PMPCFG0_ADDRESS) as usize, &self.xlen),
+			CSR_PMPADDR0_ADDRESS..=CSR_PMPADDR63_ADDRESS => self
+				.mmu
+				.pmp
+				.read_addr((address - CSR_PMPADDR0_ADDRESS) as usize),
 			_ => self.csr[address as usize],
 		}
 	}
@@ -975,8 +1126,37 @@ impl Cpu {
 					.update_mstatus(self.read_csr_raw(CSR_MSTATUS_ADDRESS));
 			}
 			CSR_TIME_ADDRESS => {
-				// self.mmu.get_mut_clint().write_mtime(value);
-				self.csr[address as usize] = value;
+				self.mmu.get_mut_clint().write_mtime(value);
+			}
+			CSR_MENVCFG_ADDRESS => {
+				// menvcfg in RV32 holds only the lower half
+				let mask = match self.xlen {
+					Xlen::Bit32 => MENVCFG_WRITABLE_MASK & 0xffffffff,
+					Xlen::Bit64 => MENVCFG_WRITABLE_MASK,
+				};
+				self.csr[address as usize] &= !mask;
+				self.csr[address as usize] |= value & mask;
+				self.mmu
+					.update_menvcfg(self.csr[CSR_MENVCFG_ADDRESS as usize]);
+			}
+			CSR_MENVCFGH_ADDRESS => {
+				let mask = MENVCFG_WRITABLE_MASK & !0xffffffff;
+				self.csr[CSR_MENVCFG_ADDRESS as usize] &= !mask;
+				self.csr[CSR_MENVCFG_ADDRESS as usize] |= (value << 32) & mask;
+				self.mmu
+					.update_menvcfg(self.csr[CSR_MENVCFG_ADDRESS as usize]);
+			}
+			CSR_PMPCFG0_ADDRESS..=CSR_PMPCFG15_ADDRESS => {
+				self.mmu
+					.pmp
+					.write_cfg((address - CSR_PMPCFG0_ADDRESS) as usize, value, &self.xlen);
+			}
+			CSR_PMPADDR0_ADDRESS..=CSR_PMPADDR63_ADDRESS => {
+				self.mmu.pmp.write_addr(
+					(address - CSR_PMPADDR0_ADDRESS) as usize,
+					value,
+					&self.xlen,
+				);
 			}
 			_ => {
 				self.csr[address as usize] = value;
@@ -984,7 +1164,7 @@ impl Cpu {
 		};
 	}
 
-	fn _set_fcsr_nv(&mut self) {
+	fn set_fcsr_nv(&mut self) {
 		self.csr[CSR_FCSR_ADDRESS as usize] |= 0x10;
 	}
 
@@ -992,18 +1172,198 @@ impl Cpu {
 		self.csr[CSR_FCSR_ADDRESS as usize] |= 0x8;
 	}
 
-	fn _set_fcsr_of(&mut self) {
+	fn set_fcsr_of(&mut self) {
 		self.csr[CSR_FCSR_ADDRESS as usize] |= 0x4;
 	}
 
-	fn _set_fcsr_uf(&mut self) {
+	fn set_fcsr_uf(&mut self) {
 		self.csr[CSR_FCSR_ADDRESS as usize] |= 0x2;
 	}
 
-	fn _set_fcsr_nx(&mut self) {
+	fn set_fcsr_nx(&mut self) {
 		self.csr[CSR_FCSR_ADDRESS as usize] |= 0x1;
 	}
 
+	/// Accrues exception flags raised by softfloat operations.
+	///
+	/// # Arguments
+	/// * `flags`
+	fn accrue_fflags(&mut self, flags: u8) {
+		if (flags & FLAG_NV) != 0 {
+			self.set_fcsr_nv();
+		}
+		if (flags & FLAG_DZ) != 0 {
+			self.set_fcsr_dz();
+		}
+		if (flags & FLAG_OF) != 0 {
+			self.set_fcsr_of();
+		}
+		if (flags & FLAG_UF) != 0 {
+			self.set_fcsr_uf();
+		}
+		if (flags & FLAG_NX) != 0 {
+			self.set_fcsr_nx();
+		}
+	}
+
+	/// Executes a floating-point operation in software with the rounding
+	/// mode of an instruction, and accrues its exception flags.
+	///
+	/// # Arguments
+	/// * `word` Instruction
+	/// * `operation`
+	fn operate_float<F: FnOnce(&mut FloatContext) -> u64>(
+		&mut self,
+		word: u32,
+		operation: F,
+	) -> Result<u64, Trap> {
+		let mut context = FloatContext::new(self.get_rounding_mode(word)?);
+		let result = operation(&mut context);
+		self.accrue_fflags(context.flags);
+		Ok(result)
+	}
+
+	/// Reads a single-precision value NaN-boxed in a floating-point
+	/// register. A value not properly NaN-boxed reads as canonical NaN.
+	///
+	/// # Arguments
+	/// * `register`
+	fn read_f32(&self, register: usize) -> f32 {
+		let bits = self.f[register].to_bits();
+		match bits & F32_NAN_BOX == F32_NAN_BOX {
+			true => f32::from_bits(bits as u32),
+			false => f32::from_bits(F32_CANONICAL_NAN),
+		}
+	}
+
+	/// Writes a single-precision value to a floating-point register
+	/// NaN-boxing it.
+	///
+	/// # Arguments
+	/// * `register`
+	/// * `value`
+	fn write_f32(&mut self, register: usize, value: f32) {
+		self.f[register] = f64::from_bits(F32_NAN_BOX | value.to_bits() as u64);
+	}
+
+	/// Reads the bits of a single-precision value in a floating-point
+	/// register as `read_f32()`.
+	///
+	/// # Arguments
+	/// * `register`
+	fn read_f32_bits(&self, register: usize) -> u64 {
+		self.read_f32(register).to_bits() as u64
+	}
+
+	/// Returns the smaller or larger of single-precision values for
+	/// FMIN.S/FMAX.S. -0 is smaller than +0. If one of them is NaN the other
+	/// is returned, and canonical NaN if both are. Signaling NaN sets NV.
+	///
+	/// # Arguments
+	/// * `value1`
+	/// * `value2`
+	/// * `is_max`
+	fn select_f32(&mut self, value1: f32, value2: f32, is_max: bool) -> f32 {
+		if is_signaling_nan_f32(value1) || is_signaling_nan_f32(value2) {
+			self.set_fcsr_nv();
+		}
+		match (value1.is_nan(), value2.is_nan()) {
+			(true, true) => f32::from_bits(F32_CANONICAL_NAN),
+			(true, false) => value2,
+			(false, true) => value1,
+			(false, false) => {
+				let is_value1_smaller =
+					value1 < value2 || (value1 == value2 && value1.is_sign_negative());
+				match is_value1_smaller != is_max {
+					true => value1,
+					false => value2,
+				}
+			}
+		}
+	}
+
+	/// Returns the smaller or larger of double-precision values for
+	/// FMIN.D/FMAX.D as `select_f32()`.
+	///
+	/// # Arguments
+	/// * `value1`
+	/// * `value2`
+	/// * `is_max`
+	fn select_f64(&mut self, value1: f64, value2: f64, is_max: bool) -> f64 {
+		if is_signaling_nan_f64(value1) || is_signaling_nan_f64(value2) {
+			self.set_fcsr_nv();
+		}
+		match (value1.is_nan(), value2.is_nan()) {
+			(true, true) => f64::NAN,
+			(true, false) => value2,
+			(false, true) => value1,
+			(false, false) => {
+				let is_value1_smaller =
+					value1 < value2 || (value1 == value2 && value1.is_sign_negative());
+				match is_value1_smaller != is_max {
+					true => value1,
+					false => value2,
+				}
+			}
+		}
+	}
+
+	/// Returns the rounding mode of an instruction, or `frm` for dynamic
+	/// rounding mode. Reserved rounding mode is illegal instruction.
+	///
+	/// # Arguments
+	/// * `word` Instruction
+	fn get_rounding_mode(&self, word: u32) -> Result<RoundingMode, Trap> {
+		let rounding_mode = match (word >> 12) & 0x7 {
+			7 => self.read_csr_raw(CSR_FRM_ADDRESS),
+			rounding_mode => rounding_mode as u64,
+		};
+		match RoundingMode::from_encoding(rounding_mode) {
+			Some(rounding_mode) => Ok(rounding_mode),
+			None => Err(Trap {
+				trap_type: TrapType::IllegalInstruction,
+				value: word as u64,
+			}),
+		}
+	}
+
+	/// Converts a floating-point value to integer in `[min, max]` with a
+	/// rounding mode. NaN and out of range values saturate and set NV,
+	/// NaN to `max`. Inexact conversion sets NX.
+	///
+	/// # Arguments
+	/// * `value`
+	/// * `rounding_mode`
+	/// * `min`
+	/// * `max`
+	fn convert_to_integer(
+		&mut self,
+		value: f64,
+		rounding_mode: RoundingMode,
+		min: i128,
+		max: i128,
+	) -> i128 {
+		let rounded = match rounding_mode {
+			RoundingMode::NearestEven => value.round_ties_even(),
+			RoundingMode::TowardZero => value.trunc(),
+			RoundingMode::Down => value.floor(),
+			RoundingMode::Up => value.ceil(),
+			RoundingMode::NearestMaxMagnitude => value.round(),
+		};
+		if value.is_nan() || rounded >= (max + 1) as f64 {
+			self.set_fcsr_nv();
+			return max;
+		}
+		if rounded < min as f64 {
+			self.set_fcsr_nv();
+			return min;
+		}
+		if rounded != value {
+			self.set_fcsr_nx();
+		}
+		rounded as i128
+	}
+
 	pub fn update_addressing_mode(&mut self, value: u64) {
 		let addressing_mode = match self.xlen {
 			Xlen::Bit32 => match value & 0x80000000 {
@@ -1026,6 +1386,7 @@ impl Cpu {
 		};
 		self.mmu.update_addressing_mode(addressing_mode);
 		self.mmu.update_ppn(ppn);
+		self.mmu.flush_tlb();
 	}
 
 	// @TODO: Rename to better name?
@@ -1422,10 +1783,7 @@ impl Cpu {
 						let offset = ((halfword >> 7) & 0x20) | // offset[5] <= [12]
 							((halfword >> 2) & 0x18) | // offset[4:3] <= [6:5]
 							((halfword << 4) & 0x1c0); // offset[8:6] <= [4:2]
-						if rd != 0 {
-							return (offset << 20) | (2 << 15) | (3 << 12) | (rd << 7) | 0x7;
-						}
-						// rd == 0 is reseved instruction
+						return (offset << 20) | (2 << 15) | (3 << 12) | (rd << 7) | 0x7;
 					}
 					2 => {
 						// C.LWSP
@@ -1496,7 +1854,6 @@ impl Cpu {
 						};
 					}
 					5 => {
-						// @TODO: Implement
 						// C.FSDSP
 						// fsd rs2, offset(x2)
 						let rs2 = (halfword >> 2) & 0x1f; // [6:2]
@@ -1547,7 +1904,10 @@ impl Cpu {
 		// for example updating page table entry or update peripheral hardware registers.
 		// But ideally disassembling doesn't want to cause any side effect.
 		// How can we avoid side effect?
-		let mut original_word = match self.mmu.fetch_word(self.pc) {
+		let fetch_result = self.mmu.fetch_word(self.pc);
+		// Fetch for disassembly isn't a guest access
+		self.mmu.watchpoints.discard_pending_hits();
+		let mut original_word = match fetch_result {
 			Ok(data) => data,
 			Err(_e) => {
 				return format!("PC:{:016x}, InstructionPageFault Trap!\n", self.pc);
@@ -1587,6 +1947,11 @@ impl Cpu {
 		s
 	}
 
+	/// Returns immutable `Mmu`
+	pub fn get_mmu(&self) -> &Mmu {
+		&self.mmu
+	}
+
 	/// Returns mutable `Mmu`
 	pub fn get_mut_mmu(&mut self) -> &mut Mmu {
 		&mut self.mmu
@@ -1797,6 +2162,18 @@ fn dump_format_r(cpu: &mut Cpu, word: u32, _address: u64, evaluate: bool) -> Str
 	s
 }
 
+fn dump_format_amo(cpu: &mut Cpu, word: u32, address: u64, evaluate: bool) -> String {
+	let mut s = dump_format_r(cpu, word, address, evaluate);
+	// aq and rl bits for memory ordering
+	s += match (word >> 25) & 0x3 {
+		1 => " rl",
+		2 => " aq",
+		3 => " aqrl",
+		_ => "",
+	};
+	s
+}
+
 // has rs3
 pub struct FormatR2 {
 	pub rd: usize,
@@ -1907,7 +2284,11 @@ fn dump_empty(_cpu: &mut Cpu, _word: u32, _address: u64, _evaluate: bool) -> Str
 	String::new()
 }
 
-fn get_register_name(num: usize) -> &'static str {
+/// Returns ABI name of integer register
+///
+/// # Arguments
+/// * `num` Register number. Must be 0-31
+pub fn get_register_name(num: usize) -> &'static str {
 	match num {
 		0 => "zero",
 		1 => "ra",
@@ -1945,7 +2326,42 @@ fn get_register_name(num: usize) -> &'static str {
 	}
 }
 
-const INSTRUCTION_NUM: usize = 116;
+fn is_signaling_nan_f32(value: f32) -> bool {
+	value.is_nan() && (value.to_bits() & 0x00400000) == 0
+}
+
+fn is_signaling_nan_f64(value: f64) -> bool {
+	value.is_nan() && (value.to_bits() & 0x0008000000000000) == 0
+}
+
+/// Returns the FCLASS result, a bit set for one of negative infinity,
+/// negative normal, negative subnormal, negative zero, positive zero,
+/// positive subnormal, positive normal, positive infinity, signaling NaN,
+/// and quiet NaN from the least significant bit.
+///
+/// # Arguments
+/// * `category`
+/// * `is_negative`
+/// * `is_signaling` Whether NaN is signaling
+fn get_fclass(category: FpCategory, is_negative: bool, is_signaling: bool) -> i64 {
+	let bit = match (category, is_negative) {
+		(FpCategory::Infinite, true) => 0,
+		(FpCategory::Normal, true) => 1,
+		(FpCategory::Subnormal, true) => 2,
+		(FpCategory::Zero, true) => 3,
+		(FpCategory::Zero, false) => 4,
+		(FpCategory::Subnormal, false) => 5,
+		(FpCategory::Normal, false) => 6,
+		(FpCategory::Infinite, false) => 7,
+		(FpCategory::Nan, _) => match is_signaling {
+			true => 8,
+			false => 9,
+		},
+	};
+	1 << bit
+}
+
+const INSTRUCTION_NUM: usize = 161;
 
 // @TODO: Reorder in often used order as
 const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
@@ -2018,7 +2434,7 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 			cpu.x[f.rd] = tmp;
 			Ok(())
 		},
-		disassemble: dump_format_r,
+		disassemble: dump_format_amo,
 	},
 	Instruction {
 		mask: 0xf800707f,
@@ -2041,7 +2457,7 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 			cpu.x[f.rd] = tmp;
 			Ok(())
 		},
-		disassemble: dump_format_r,
+		disassemble: dump_format_amo,
 	},
 	Instruction {
 		mask: 0xf800707f,
@@ -2064,7 +2480,7 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 			cpu.x[f.rd] = tmp;
 			Ok(())
 		},
-		disassemble: dump_format_r,
+		disassemble: dump_format_amo,
 	},
 	Instruction {
 		mask: 0xf800707f,
@@ -2087,7 +2503,55 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 			cpu.x[f.rd] = tmp;
 			Ok(())
 		},
-		disassemble: dump_format_r,
+		disassemble: dump_format_amo,
+	},
+	Instruction {
+		mask: 0xf800707f,
+		data: 0xa000302f,
+		name: "AMOMAX.D",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			let tmp = match cpu.mmu.load_doubleword(cpu.x[f.rs1] as u64) {
+				Ok(data) => data as i64,
+				Err(e) => return Err(e),
+			};
+			let max = match cpu.x[f.rs2] >= tmp {
+				true => cpu.x[f.rs2],
+				false => tmp,
+			};
+			match cpu.mmu.store_doubleword(cpu.x[f.rs1] as u64, max as u64) {
+				Ok(()) => {}
+				Err(e) => return Err(e),
+			};
+			cpu.x[f.rd] = tmp;
+			Ok(())
+		},
+		disassemble: dump_format_amo,
+	},
+	Instruction {
+		mask: 0xf800707f,
+		data: 0xa000202f,
+		name: "AMOMAX.W",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			let tmp = match cpu.mmu.load_word(cpu.x[f.rs1] as u64) {
+				Ok(data) => data as i32,
+				Err(e) => return Err(e),
+			};
+			let max = match cpu.x[f.rs2] as i32 >= tmp {
+				true => cpu.x[f.rs2] as i32,
+				false => tmp,
+			};
+			match cpu.mmu.store_word(cpu.x[f.rs1] as u64, max as u32) {
+				Ok(()) => {}
+				Err(e) => return Err(e),
+			};
+			cpu.x[f.rd] = tmp as i64;
+			Ok(())
+		},
+		disassemble: dump_format_amo,
 	},
 	Instruction {
 		mask: 0xf800707f,
@@ -2111,7 +2575,7 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 			cpu.x[f.rd] = tmp as i64;
 			Ok(())
 		},
-		disassemble: dump_format_r,
+		disassemble: dump_format_amo,
 	},
 	Instruction {
 		mask: 0xf800707f,
@@ -2135,12 +2599,12 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 			cpu.x[f.rd] = tmp as i32 as i64;
 			Ok(())
 		},
-		disassemble: dump_format_r,
+		disassemble: dump_format_amo,
 	},
 	Instruction {
 		mask: 0xf800707f,
-		data: 0x4000302f,
-		name: "AMOOR.D",
+		data: 0x8000302f,
+		name: "AMOMIN.D",
 		cycles: 1,
 		operation: |cpu, word, _address| {
 			let f = parse_format_r(word);
@@ -2148,105 +2612,247 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 				Ok(data) => data as i64,
 				Err(e) => return Err(e),
 			};
-			match cpu
-				.mmu
-				.store_doubleword(cpu.x[f.rs1] as u64, (cpu.x[f.rs2] | tmp) as u64)
-			{
+			let min = match cpu.x[f.rs2] <= tmp {
+				true => cpu.x[f.rs2],
+				false => tmp,
+			};
+			match cpu.mmu.store_doubleword(cpu.x[f.rs1] as u64, min as u64) {
 				Ok(()) => {}
 				Err(e) => return Err(e),
 			};
 			cpu.x[f.rd] = tmp;
 			Ok(())
 		},
-		disassemble: dump_format_r,
+		disassemble: dump_format_amo,
 	},
 	Instruction {
 		mask: 0xf800707f,
-		data: 0x4000202f,
-		name: "AMOOR.W",
+		data: 0x8000202f,
+		name: "AMOMIN.W",
 		cycles: 1,
 		operation: |cpu, word, _address| {
 			let f = parse_format_r(word);
 			let tmp = match cpu.mmu.load_word(cpu.x[f.rs1] as u64) {
-				Ok(data) => data as i32 as i64,
+				Ok(data) => data as i32,
 				Err(e) => return Err(e),
 			};
-			match cpu
-				.mmu
-				.store_word(cpu.x[f.rs1] as u64, (cpu.x[f.rs2] | tmp) as u32)
-			{
+			let min = match cpu.x[f.rs2] as i32 <= tmp {
+				true => cpu.x[f.rs2] as i32,
+				false => tmp,
+			};
+			match cpu.mmu.store_word(cpu.x[f.rs1] as u64, min as u32) {
 				Ok(()) => {}
 				Err(e) => return Err(e),
 			};
-			cpu.x[f.rd] = tmp;
+			cpu.x[f.rd] = tmp as i64;
 			Ok(())
 		},
-		disassemble: dump_format_r,
+		disassemble: dump_format_amo,
 	},
 	Instruction {
 		mask: 0xf800707f,
-		data: 0x0800302f,
-		name: "AMOSWAP.D",
+		data: 0xc000302f,
+		name: "AMOMINU.D",
 		cycles: 1,
 		operation: |cpu, word, _address| {
 			let f = parse_format_r(word);
 			let tmp = match cpu.mmu.load_doubleword(cpu.x[f.rs1] as u64) {
-				Ok(data) => data as i64,
+				Ok(data) => data,
 				Err(e) => return Err(e),
 			};
-			match cpu
-				.mmu
-				.store_doubleword(cpu.x[f.rs1] as u64, cpu.x[f.rs2] as u64)
-			{
+			let min = match cpu.x[f.rs2] as u64 <= tmp {
+				true => cpu.x[f.rs2] as u64,
+				false => tmp,
+			};
+			match cpu.mmu.store_doubleword(cpu.x[f.rs1] as u64, min) {
 				Ok(()) => {}
 				Err(e) => return Err(e),
 			};
-			cpu.x[f.rd] = tmp;
+			cpu.x[f.rd] = tmp as i64;
 			Ok(())
 		},
-		disassemble: dump_format_r,
+		disassemble: dump_format_amo,
 	},
 	Instruction {
 		mask: 0xf800707f,
-		data: 0x0800202f,
-		name: "AMOSWAP.W",
+		data: 0xc000202f,
+		name: "AMOMINU.W",
 		cycles: 1,
 		operation: |cpu, word, _address| {
 			let f = parse_format_r(word);
 			let tmp = match cpu.mmu.load_word(cpu.x[f.rs1] as u64) {
-				Ok(data) => data as i32 as i64,
+				Ok(data) => data,
 				Err(e) => return Err(e),
 			};
-			match cpu.mmu.store_word(cpu.x[f.rs1] as u64, cpu.x[f.rs2] as u32) {
+			let min = match cpu.x[f.rs2] as u32 <= tmp {
+				true => cpu.x[f.rs2] as u32,
+				false => tmp,
+			};
+			match cpu.mmu.store_word(cpu.x[f.rs1] as u64, min) {
 				Ok(()) => {}
 				Err(e) => return Err(e),
 			};
-			cpu.x[f.rd] = tmp;
+			cpu.x[f.rd] = tmp as i32 as i64;
 			Ok(())
 		},
-		disassemble: dump_format_r,
+		disassemble: dump_format_amo,
 	},
 	Instruction {
-		mask: 0xfe00707f,
-		data: 0x00007033,
-		name: "AND",
+		mask: 0xf800707f,
+		data: 0x4000302f,
+		name: "AMOOR.D",
 		cycles: 1,
 		operation: |cpu, word, _address| {
 			let f = parse_format_r(word);
-			cpu.x[f.rd] = cpu.sign_extend(cpu.x[f.rs1] & cpu.x[f.rs2]);
+			let tmp = match cpu.mmu.load_doubleword(cpu.x[f.rs1] as u64) {
+				Ok(data) => data as i64,
+				Err(e) => return Err(e),
+			};
+			match cpu
+				.mmu
+				.store_doubleword(cpu.x[f.rs1] as u64, (cpu.x[f.rs2] | tmp) as u64)
+			{
+				Ok(()) => {}
+				Err(e) => return Err(e),
+			};
+			cpu.x[f.rd] = tmp;
 			Ok(())
 		},
-		disassemble: dump_format_r,
+		disassemble: dump_format_amo,
 	},
 	Instruction {
-		mask: 0x0000707f,
-		data: 0x00007013,
-		name: "ANDI",
+		mask: 0xf800707f,
+		data: 0x4000202f,
+		name: "AMOOR.W",
 		cycles: 1,
 		operation: |cpu, word, _address| {
-			let f = parse_format_i(word);
-			cpu.x[f.rd] = cpu.sign_extend(cpu.x[f.rs1] & f.imm);
-			Ok(())
+			let f = parse_format_r(word);
+			let tmp = match cpu.mmu.load_word(cpu.x[f.rs1] as u64) {
+				Ok(data) => data as i32 as i64,
+				Err(e) => return Err(e),
+			};
+			match cpu
+				.mmu
+				.store_word(cpu.x[f.rs1] as u64, (cpu.x[f.rs2] | tmp) as u32)
+			{
+				Ok(()) => {}
+				Err(e) => return Err(e),
+			};
+			cpu.x[f.rd] = tmp;
+			Ok(())
+		},
+		disassemble: dump_format_amo,
+	},
+	Instruction {
+		mask: 0xf800707f,
+		data: 0x0800302f,
+		name: "AMOSWAP.D",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			let tmp = match cpu.mmu.load_doubleword(cpu.x[f.rs1] as u64) {
+				Ok(data) => data as i64,
+				Err(e) => return Err(e),
+			};
+			match cpu
+				.mmu
+				.store_doubleword(cpu.x[f.rs1] as u64, cpu.x[f.rs2] as u64)
+			{
+				Ok(()) => {}
+				Err(e) => return Err(e),
+			};
+			cpu.x[f.rd] = tmp;
+			Ok(())
+		},
+		disassemble: dump_format_amo,
+	},
+	Instruction {
+		mask: 0xf800707f,
+		data: 0x0800202f,
+		name: "AMOSWAP.W",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			let tmp = match cpu.mmu.load_word(cpu.x[f.rs1] as u64) {
+				Ok(data) => data as i32 as i64,
+				Err(e) => return Err(e),
+			};
+			match cpu.mmu.store_word(cpu.x[f.rs1] as u64, cpu.x[f.rs2] as u32) {
+				Ok(()) => {}
+				Err(e) => return Err(e),
+			};
+			cpu.x[f.rd] = tmp;
+			Ok(())
+		},
+		disassemble: dump_format_amo,
+	},
+	Instruction {
+		mask: 0xf800707f,
+		data: 0x2000302f,
+		name: "AMOXOR.D",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			let tmp = match cpu.mmu.load_doubleword(cpu.x[f.rs1] as u64) {
+				Ok(data) => data as i64,
+				Err(e) => return Err(e),
+			};
+			match cpu
+				.mmu
+				.store_doubleword(cpu.x[f.rs1] as u64, (cpu.x[f.rs2] ^ tmp) as u64)
+			{
+				Ok(()) => {}
+				Err(e) => return Err(e),
+			};
+			cpu.x[f.rd] = tmp;
+			Ok(())
+		},
+		disassemble: dump_format_amo,
+	},
+	Instruction {
+		mask: 0xf800707f,
+		data: 0x2000202f,
+		name: "AMOXOR.W",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			let tmp = match cpu.mmu.load_word(cpu.x[f.rs1] as u64) {
+				Ok(data) => data as i32 as i64,
+				Err(e) => return Err(e),
+			};
+			match cpu
+				.mmu
+				.store_word(cpu.x[f.rs1] as u64, (cpu.x[f.rs2] ^ tmp) as u32)
+			{
+				Ok(()) => {}
+				Err(e) => return Err(e),
+			};
+			cpu.x[f.rd] = tmp;
+			Ok(())
+		},
+		disassemble: dump_format_amo,
+	},
+	Instruction {
+		mask: 0xfe00707f,
+		data: 0x00007033,
+		name: "AND",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			cpu.x[f.rd] = cpu.sign_extend(cpu.x[f.rs1] & cpu.x[f.rs2]);
+			Ok(())
+		},
+		disassemble: dump_format_r,
+	},
+	Instruction {
+		mask: 0x0000707f,
+		data: 0x00007013,
+		name: "ANDI",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_i(word);
+			cpu.x[f.rd] = cpu.sign_extend(cpu.x[f.rs1] & f.imm);
+			Ok(())
 		},
 		disassemble: dump_format_i,
 	},
@@ -2582,7 +3188,59 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 		cycles: 1,
 		operation: |cpu, word, _address| {
 			let f = parse_format_r(word);
-			cpu.f[f.rd] = cpu.f[f.rs1] + cpu.f[f.rs2];
+			let (value1, value2) = (cpu.f[f.rs1].to_bits(), cpu.f[f.rs2].to_bits());
+			let result =
+				cpu.operate_float(word, |context| context.add(&FLOAT64, value1, value2))?;
+			cpu.f[f.rd] = f64::from_bits(result);
+			Ok(())
+		},
+		disassemble: dump_format_r,
+	},
+	Instruction {
+		mask: 0xfe00007f,
+		data: 0x00000053,
+		name: "FADD.S",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			let (value1, value2) = (cpu.read_f32_bits(f.rs1), cpu.read_f32_bits(f.rs2));
+			let result =
+				cpu.operate_float(word, |context| context.add(&FLOAT32, value1, value2))?;
+			cpu.write_f32(f.rd, f32::from_bits(result as u32));
+			Ok(())
+		},
+		disassemble: dump_format_r,
+	},
+	Instruction {
+		mask: 0xfff0707f,
+		data: 0xe2001053,
+		name: "FCLASS.D",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			let value = cpu.f[f.rs1];
+			cpu.x[f.rd] = get_fclass(
+				value.classify(),
+				value.is_sign_negative(),
+				is_signaling_nan_f64(value),
+			);
+			Ok(())
+		},
+		disassemble: dump_format_r,
+	},
+	Instruction {
+		mask: 0xfff0707f,
+		data: 0xe0001053,
+		name: "FCLASS.S",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			let value = cpu.read_f32(f.rs1);
+			cpu.x[f.rd] = get_fclass(
+				value.classify(),
+				value.is_sign_negative(),
+				is_signaling_nan_f32(value),
+			);
 			Ok(())
 		},
 		disassemble: dump_format_r,
@@ -2594,7 +3252,27 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 		cycles: 1,
 		operation: |cpu, word, _address| {
 			let f = parse_format_r(word);
-			cpu.f[f.rd] = cpu.x[f.rs1] as f64;
+			let value = cpu.x[f.rs1] as i128;
+			let result = cpu.operate_float(word, |context| {
+				context.convert_from_integer(&FLOAT64, value)
+			})?;
+			cpu.f[f.rd] = f64::from_bits(result);
+			Ok(())
+		},
+		disassemble: dump_format_r,
+	},
+	Instruction {
+		mask: 0xfff0007f,
+		data: 0xd2300053,
+		name: "FCVT.D.LU",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			let value = cpu.x[f.rs1] as u64 as i128;
+			let result = cpu.operate_float(word, |context| {
+				context.convert_from_integer(&FLOAT64, value)
+			})?;
+			cpu.f[f.rd] = f64::from_bits(result);
 			Ok(())
 		},
 		disassemble: dump_format_r,
@@ -2606,8 +3284,10 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 		cycles: 1,
 		operation: |cpu, word, _address| {
 			let f = parse_format_r(word);
-			// Is this implementation correct?
-			cpu.f[f.rd] = f32::from_bits(cpu.f[f.rs1].to_bits() as u32) as f64;
+			let value = cpu.read_f32_bits(f.rs1);
+			let result =
+				cpu.operate_float(word, |context| context.convert(&FLOAT32, &FLOAT64, value))?;
+			cpu.f[f.rd] = f64::from_bits(result);
 			Ok(())
 		},
 		disassemble: dump_format_r,
@@ -2619,7 +3299,11 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 		cycles: 1,
 		operation: |cpu, word, _address| {
 			let f = parse_format_r(word);
-			cpu.f[f.rd] = cpu.x[f.rs1] as i32 as f64;
+			let value = cpu.x[f.rs1] as i32 as i128;
+			let result = cpu.operate_float(word, |context| {
+				context.convert_from_integer(&FLOAT64, value)
+			})?;
+			cpu.f[f.rd] = f64::from_bits(result);
 			Ok(())
 		},
 		disassemble: dump_format_r,
@@ -2631,7 +3315,71 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 		cycles: 1,
 		operation: |cpu, word, _address| {
 			let f = parse_format_r(word);
-			cpu.f[f.rd] = cpu.x[f.rs1] as u32 as f64;
+			let value = cpu.x[f.rs1] as u32 as i128;
+			let result = cpu.operate_float(word, |context| {
+				context.convert_from_integer(&FLOAT64, value)
+			})?;
+			cpu.f[f.rd] = f64::from_bits(result);
+			Ok(())
+		},
+		disassemble: dump_format_r,
+	},
+	Instruction {
+		mask: 0xfff0007f,
+		data: 0xc2200053,
+		name: "FCVT.L.D",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			let rounding_mode = cpu.get_rounding_mode(word)?;
+			let value = cpu.f[f.rs1];
+			let (min, max) = (i64::MIN as i128, i64::MAX as i128);
+			cpu.x[f.rd] = cpu.convert_to_integer(value, rounding_mode, min, max) as i64;
+			Ok(())
+		},
+		disassemble: dump_format_r,
+	},
+	Instruction {
+		mask: 0xfff0007f,
+		data: 0xc0200053,
+		name: "FCVT.L.S",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			let rounding_mode = cpu.get_rounding_mode(word)?;
+			let value = cpu.read_f32(f.rs1) as f64;
+			let (min, max) = (i64::MIN as i128, i64::MAX as i128);
+			cpu.x[f.rd] = cpu.convert_to_integer(value, rounding_mode, min, max) as i64;
+			Ok(())
+		},
+		disassemble: dump_format_r,
+	},
+	Instruction {
+		mask: 0xfff0007f,
+		data: 0xc2300053,
+		name: "FCVT.LU.D",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			let rounding_mode = cpu.get_rounding_mode(word)?;
+			let value = cpu.f[f.rs1];
+			let (min, max) = (0, u64::MAX as i128);
+			cpu.x[f.rd] = cpu.convert_to_integer(value, rounding_mode, min, max) as u64 as i64;
+			Ok(())
+		},
+		disassemble: dump_format_r,
+	},
+	Instruction {
+		mask: 0xfff0007f,
+		data: 0xc0300053,
+		name: "FCVT.LU.S",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			let rounding_mode = cpu.get_rounding_mode(word)?;
+			let value = cpu.read_f32(f.rs1) as f64;
+			let (min, max) = (0, u64::MAX as i128);
+			cpu.x[f.rd] = cpu.convert_to_integer(value, rounding_mode, min, max) as u64 as i64;
 			Ok(())
 		},
 		disassemble: dump_format_r,
@@ -2643,159 +3391,461 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 		cycles: 1,
 		operation: |cpu, word, _address| {
 			let f = parse_format_r(word);
-			// Is this implementation correct?
-			cpu.f[f.rd] = cpu.f[f.rs1] as f32 as f64;
+			let value = cpu.f[f.rs1].to_bits();
+			let result =
+				cpu.operate_float(word, |context| context.convert(&FLOAT64, &FLOAT32, value))?;
+			cpu.write_f32(f.rd, f32::from_bits(result as u32));
+			Ok(())
+		},
+		disassemble: dump_format_r,
+	},
+	Instruction {
+		mask: 0xfff0007f,
+		data: 0xd0200053,
+		name: "FCVT.S.L",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			let value = cpu.x[f.rs1] as i128;
+			let result = cpu.operate_float(word, |context| {
+				context.convert_from_integer(&FLOAT32, value)
+			})?;
+			cpu.write_f32(f.rd, f32::from_bits(result as u32));
+			Ok(())
+		},
+		disassemble: dump_format_r,
+	},
+	Instruction {
+		mask: 0xfff0007f,
+		data: 0xd0300053,
+		name: "FCVT.S.LU",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			let value = cpu.x[f.rs1] as u64 as i128;
+			let result = cpu.operate_float(word, |context| {
+				context.convert_from_integer(&FLOAT32, value)
+			})?;
+			cpu.write_f32(f.rd, f32::from_bits(result as u32));
+			Ok(())
+		},
+		disassemble: dump_format_r,
+	},
+	Instruction {
+		mask: 0xfff0007f,
+		data: 0xd0000053,
+		name: "FCVT.S.W",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			let value = cpu.x[f.rs1] as i32 as i128;
+			let result = cpu.operate_float(word, |context| {
+				context.convert_from_integer(&FLOAT32, value)
+			})?;
+			cpu.write_f32(f.rd, f32::from_bits(result as u32));
+			Ok(())
+		},
+		disassemble: dump_format_r,
+	},
+	Instruction {
+		mask: 0xfff0007f,
+		data: 0xd0100053,
+		name: "FCVT.S.WU",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			let value = cpu.x[f.rs1] as u32 as i128;
+			let result = cpu.operate_float(word, |context| {
+				context.convert_from_integer(&FLOAT32, value)
+			})?;
+			cpu.write_f32(f.rd, f32::from_bits(result as u32));
+			Ok(())
+		},
+		disassemble: dump_format_r,
+	},
+	Instruction {
+		mask: 0xfff0007f,
+		data: 0xc2000053,
+		name: "FCVT.W.D",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			let rounding_mode = cpu.get_rounding_mode(word)?;
+			let value = cpu.f[f.rs1];
+			let (min, max) = (i32::MIN as i128, i32::MAX as i128);
+			cpu.x[f.rd] = cpu.convert_to_integer(value, rounding_mode, min, max) as i32 as i64;
+			Ok(())
+		},
+		disassemble: dump_format_r,
+	},
+	Instruction {
+		mask: 0xfff0007f,
+		data: 0xc0000053,
+		name: "FCVT.W.S",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			let rounding_mode = cpu.get_rounding_mode(word)?;
+			let value = cpu.read_f32(f.rs1) as f64;
+			let (min, max) = (i32::MIN as i128, i32::MAX as i128);
+			cpu.x[f.rd] = cpu.convert_to_integer(value, rounding_mode, min, max) as i32 as i64;
+			Ok(())
+		},
+		disassemble: dump_format_r,
+	},
+	Instruction {
+		mask: 0xfff0007f,
+		data: 0xc2100053,
+		name: "FCVT.WU.D",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			let rounding_mode = cpu.get_rounding_mode(word)?;
+			let value = cpu.f[f.rs1];
+			let (min, max) = (0, u32::MAX as i128);
+			cpu.x[f.rd] =
+				cpu.convert_to_integer(value, rounding_mode, min, max) as u32 as i32 as i64;
+			Ok(())
+		},
+		disassemble: dump_format_r,
+	},
+	Instruction {
+		mask: 0xfff0007f,
+		data: 0xc0100053,
+		name: "FCVT.WU.S",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			let rounding_mode = cpu.get_rounding_mode(word)?;
+			let value = cpu.read_f32(f.rs1) as f64;
+			let (min, max) = (0, u32::MAX as i128);
+			cpu.x[f.rd] =
+				cpu.convert_to_integer(value, rounding_mode, min, max) as u32 as i32 as i64;
+			Ok(())
+		},
+		disassemble: dump_format_r,
+	},
+	Instruction {
+		mask: 0xfe00007f,
+		data: 0x1a000053,
+		name: "FDIV.D",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			let (dividend, divisor) = (cpu.f[f.rs1].to_bits(), cpu.f[f.rs2].to_bits());
+			let result =
+				cpu.operate_float(word, |context| context.div(&FLOAT64, dividend, divisor))?;
+			cpu.f[f.rd] = f64::from_bits(result);
+			Ok(())
+		},
+		disassemble: dump_format_r,
+	},
+	Instruction {
+		mask: 0xfe00007f,
+		data: 0x18000053,
+		name: "FDIV.S",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			let (dividend, divisor) = (cpu.read_f32_bits(f.rs1), cpu.read_f32_bits(f.rs2));
+			let result =
+				cpu.operate_float(word, |context| context.div(&FLOAT32, dividend, divisor))?;
+			cpu.write_f32(f.rd, f32::from_bits(result as u32));
+			Ok(())
+		},
+		disassemble: dump_format_r,
+	},
+	Instruction {
+		mask: 0x0000707f,
+		data: 0x0000000f,
+		name: "FENCE",
+		cycles: 1,
+		operation: |cpu, _word, _address| {
+			// Flush write back L1 cache
+			cpu.get_mut_mmu().l1_flush();
+			cpu.get_mut_mmu().l2_flush();
+			Ok(())
+		},
+		disassemble: dump_empty,
+	},
+	Instruction {
+		mask: 0x0000707f,
+		data: 0x0000100f,
+		name: "FENCE.I",
+		cycles: 1,
+		operation: |_cpu, _word, _address| {
+			// Do nothing?
+			Ok(())
+		},
+		disassemble: dump_empty,
+	},
+	Instruction {
+		mask: 0xfe00707f,
+		data: 0xa2002053,
+		name: "FEQ.D",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			let (value1, value2) = (cpu.f[f.rs1], cpu.f[f.rs2]);
+			if is_signaling_nan_f64(value1) || is_signaling_nan_f64(value2) {
+				cpu.set_fcsr_nv();
+			}
+			cpu.x[f.rd] = match value1 == value2 {
+				true => 1,
+				false => 0,
+			};
+			Ok(())
+		},
+		disassemble: dump_empty,
+	},
+	Instruction {
+		mask: 0xfe00707f,
+		data: 0xa0002053,
+		name: "FEQ.S",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			let (value1, value2) = (cpu.read_f32(f.rs1), cpu.read_f32(f.rs2));
+			if is_signaling_nan_f32(value1) || is_signaling_nan_f32(value2) {
+				cpu.set_fcsr_nv();
+			}
+			cpu.x[f.rd] = match value1 == value2 {
+				true => 1,
+				false => 0,
+			};
+			Ok(())
+		},
+		disassemble: dump_format_r,
+	},
+	Instruction {
+		mask: 0x0000707f,
+		data: 0x00003007,
+		name: "FLD",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_i(word);
+			cpu.f[f.rd] = match cpu
+				.mmu
+				.load_doubleword(cpu.x[f.rs1].wrapping_add(f.imm) as u64)
+			{
+				Ok(data) => f64::from_bits(data),
+				Err(e) => return Err(e),
+			};
+			Ok(())
+		},
+		disassemble: dump_format_i,
+	},
+	Instruction {
+		mask: 0xfe00707f,
+		data: 0xa2000053,
+		name: "FLE.D",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			let (value1, value2) = (cpu.f[f.rs1], cpu.f[f.rs2]);
+			if value1.is_nan() || value2.is_nan() {
+				cpu.set_fcsr_nv();
+			}
+			cpu.x[f.rd] = match value1 <= value2 {
+				true => 1,
+				false => 0,
+			};
+			Ok(())
+		},
+		disassemble: dump_format_r,
+	},
+	Instruction {
+		mask: 0xfe00707f,
+		data: 0xa0000053,
+		name: "FLE.S",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			let (value1, value2) = (cpu.read_f32(f.rs1), cpu.read_f32(f.rs2));
+			if value1.is_nan() || value2.is_nan() {
+				cpu.set_fcsr_nv();
+			}
+			cpu.x[f.rd] = match value1 <= value2 {
+				true => 1,
+				false => 0,
+			};
+			Ok(())
+		},
+		disassemble: dump_format_r,
+	},
+	Instruction {
+		mask: 0xfe00707f,
+		data: 0xa2001053,
+		name: "FLT.D",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			let (value1, value2) = (cpu.f[f.rs1], cpu.f[f.rs2]);
+			if value1.is_nan() || value2.is_nan() {
+				cpu.set_fcsr_nv();
+			}
+			cpu.x[f.rd] = match value1 < value2 {
+				true => 1,
+				false => 0,
+			};
 			Ok(())
 		},
 		disassemble: dump_format_r,
 	},
 	Instruction {
-		mask: 0xfff0007f,
-		data: 0xc2000053,
-		name: "FCVT.W.D",
+		mask: 0xfe00707f,
+		data: 0xa0001053,
+		name: "FLT.S",
 		cycles: 1,
 		operation: |cpu, word, _address| {
 			let f = parse_format_r(word);
-			// Is this implementation correct?
-			cpu.x[f.rd] = cpu.f[f.rs1] as u32 as i32 as i64;
+			let (value1, value2) = (cpu.read_f32(f.rs1), cpu.read_f32(f.rs2));
+			if value1.is_nan() || value2.is_nan() {
+				cpu.set_fcsr_nv();
+			}
+			cpu.x[f.rd] = match value1 < value2 {
+				true => 1,
+				false => 0,
+			};
 			Ok(())
 		},
 		disassemble: dump_format_r,
 	},
 	Instruction {
-		mask: 0xfe00007f,
-		data: 0x1a000053,
-		name: "FDIV.D",
+		mask: 0x0000707f,
+		data: 0x00002007,
+		name: "FLW",
 		cycles: 1,
 		operation: |cpu, word, _address| {
-			let f = parse_format_r(word);
-			let dividend = cpu.f[f.rs1];
-			let divisor = cpu.f[f.rs2];
-			// Is this implementation correct?
-			if divisor == 0.0 {
-				cpu.f[f.rd] = std::f64::INFINITY;
-				cpu.set_fcsr_dz();
-			} else if divisor == -0.0 {
-				cpu.f[f.rd] = std::f64::NEG_INFINITY;
-				cpu.set_fcsr_dz();
-			} else {
-				cpu.f[f.rd] = dividend / divisor;
-			}
+			let f = parse_format_i(word);
+			let data = cpu.mmu.load_word(cpu.x[f.rs1].wrapping_add(f.imm) as u64)?;
+			cpu.write_f32(f.rd, f32::from_bits(data));
 			Ok(())
 		},
-		disassemble: dump_format_r,
+		disassemble: dump_format_i_mem,
 	},
 	Instruction {
-		mask: 0x0000707f,
-		data: 0x0000000f,
-		name: "FENCE",
+		mask: 0x0600007f,
+		data: 0x02000043,
+		name: "FMADD.D",
 		cycles: 1,
-		operation: |cpu, _word, _address| {
-			// Flush write back L1 cache
-			cpu.get_mut_mmu().l1_flush();
-			cpu.get_mut_mmu().l2_flush();
+		operation: |cpu, word, _address| {
+			let f = parse_format_r2(word);
+			let value1 = cpu.f[f.rs1].to_bits();
+			let value2 = cpu.f[f.rs2].to_bits();
+			let value3 = cpu.f[f.rs3].to_bits();
+			let result = cpu.operate_float(word, |context| {
+				context.fused_multiply_add(&FLOAT64, value1, value2, value3)
+			})?;
+			cpu.f[f.rd] = f64::from_bits(result);
 			Ok(())
 		},
-		disassemble: dump_empty,
+		disassemble: dump_format_r2,
 	},
 	Instruction {
-		mask: 0x0000707f,
-		data: 0x0000100f,
-		name: "FENCE.I",
+		mask: 0x0600007f,
+		data: 0x00000043,
+		name: "FMADD.S",
 		cycles: 1,
-		operation: |_cpu, _word, _address| {
-			// Do nothing?
+		operation: |cpu, word, _address| {
+			let f = parse_format_r2(word);
+			let value1 = cpu.read_f32_bits(f.rs1);
+			let value2 = cpu.read_f32_bits(f.rs2);
+			let value3 = cpu.read_f32_bits(f.rs3);
+			let result = cpu.operate_float(word, |context| {
+				context.fused_multiply_add(&FLOAT32, value1, value2, value3)
+			})?;
+			cpu.write_f32(f.rd, f32::from_bits(result as u32));
 			Ok(())
 		},
-		disassemble: dump_empty,
+		disassemble: dump_format_r2,
 	},
 	Instruction {
 		mask: 0xfe00707f,
-		data: 0xa2002053,
-		name: "FEQ.D",
+		data: 0x2a001053,
+		name: "FMAX.D",
 		cycles: 1,
 		operation: |cpu, word, _address| {
 			let f = parse_format_r(word);
-			cpu.x[f.rd] = match cpu.f[f.rs1] == cpu.f[f.rs2] {
-				true => 1,
-				false => 0,
-			};
+			let (value1, value2) = (cpu.f[f.rs1], cpu.f[f.rs2]);
+			cpu.f[f.rd] = cpu.select_f64(value1, value2, true);
 			Ok(())
 		},
-		disassemble: dump_empty,
+		disassemble: dump_format_r,
 	},
 	Instruction {
-		mask: 0x0000707f,
-		data: 0x00003007,
-		name: "FLD",
+		mask: 0xfe00707f,
+		data: 0x28001053,
+		name: "FMAX.S",
 		cycles: 1,
 		operation: |cpu, word, _address| {
-			let f = parse_format_i(word);
-			cpu.f[f.rd] = match cpu
-				.mmu
-				.load_doubleword(cpu.x[f.rs1].wrapping_add(f.imm) as u64)
-			{
-				Ok(data) => f64::from_bits(data),
-				Err(e) => return Err(e),
-			};
+			let f = parse_format_r(word);
+			let (value1, value2) = (cpu.read_f32(f.rs1), cpu.read_f32(f.rs2));
+			let result = cpu.select_f32(value1, value2, true);
+			cpu.write_f32(f.rd, result);
 			Ok(())
 		},
-		disassemble: dump_format_i,
+		disassemble: dump_format_r,
 	},
 	Instruction {
 		mask: 0xfe00707f,
-		data: 0xa2000053,
-		name: "FLE.D",
+		data: 0x2a000053,
+		name: "FMIN.D",
 		cycles: 1,
 		operation: |cpu, word, _address| {
 			let f = parse_format_r(word);
-			cpu.x[f.rd] = match cpu.f[f.rs1] <= cpu.f[f.rs2] {
-				true => 1,
-				false => 0,
-			};
+			let (value1, value2) = (cpu.f[f.rs1], cpu.f[f.rs2]);
+			cpu.f[f.rd] = cpu.select_f64(value1, value2, false);
 			Ok(())
 		},
 		disassemble: dump_format_r,
 	},
 	Instruction {
 		mask: 0xfe00707f,
-		data: 0xa2001053,
-		name: "FLT.D",
+		data: 0x28000053,
+		name: "FMIN.S",
 		cycles: 1,
 		operation: |cpu, word, _address| {
 			let f = parse_format_r(word);
-			cpu.x[f.rd] = match cpu.f[f.rs1] < cpu.f[f.rs2] {
-				true => 1,
-				false => 0,
-			};
+			let (value1, value2) = (cpu.read_f32(f.rs1), cpu.read_f32(f.rs2));
+			let result = cpu.select_f32(value1, value2, false);
+			cpu.write_f32(f.rd, result);
 			Ok(())
 		},
 		disassemble: dump_format_r,
 	},
 	Instruction {
-		mask: 0x0000707f,
-		data: 0x00002007,
-		name: "FLW",
+		mask: 0x0600007f,
+		data: 0x02000047,
+		name: "FMSUB.D",
 		cycles: 1,
 		operation: |cpu, word, _address| {
-			let f = parse_format_i(word);
-			cpu.f[f.rd] = match cpu.mmu.load_word(cpu.x[f.rs1].wrapping_add(f.imm) as u64) {
-				Ok(data) => f64::from_bits(data as i32 as i64 as u64),
-				Err(e) => return Err(e),
-			};
+			let f = parse_format_r2(word);
+			let value1 = cpu.f[f.rs1].to_bits();
+			let value2 = cpu.f[f.rs2].to_bits();
+			let value3 = cpu.f[f.rs3].to_bits();
+			let result = cpu.operate_float(word, |context| {
+				context.fused_multiply_add(&FLOAT64, value1, value2, value3 ^ F64_SIGN_BIT)
+			})?;
+			cpu.f[f.rd] = f64::from_bits(result);
 			Ok(())
 		},
-		disassemble: dump_format_i_mem,
+		disassemble: dump_format_r2,
 	},
 	Instruction {
 		mask: 0x0600007f,
-		data: 0x02000043,
-		name: "FMADD.D",
+		data: 0x00000047,
+		name: "FMSUB.S",
 		cycles: 1,
 		operation: |cpu, word, _address| {
-			// @TODO: Update fcsr if needed?
 			let f = parse_format_r2(word);
-			cpu.f[f.rd] = cpu.f[f.rs1] * cpu.f[f.rs2] + cpu.f[f.rs3];
+			let value1 = cpu.read_f32_bits(f.rs1);
+			let value2 = cpu.read_f32_bits(f.rs2);
+			let value3 = cpu.read_f32_bits(f.rs3);
+			let result = cpu.operate_float(word, |context| {
+				context.fused_multiply_add(&FLOAT32, value1, value2, value3 ^ F32_SIGN_BIT)
+			})?;
+			cpu.write_f32(f.rd, f32::from_bits(result as u32));
 			Ok(())
 		},
 		disassemble: dump_format_r2,
@@ -2806,9 +3856,26 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 		name: "FMUL.D",
 		cycles: 1,
 		operation: |cpu, word, _address| {
-			// @TODO: Update fcsr if needed?
 			let f = parse_format_r(word);
-			cpu.f[f.rd] = cpu.f[f.rs1] * cpu.f[f.rs2];
+			let (value1, value2) = (cpu.f[f.rs1].to_bits(), cpu.f[f.rs2].to_bits());
+			let result =
+				cpu.operate_float(word, |context| context.mul(&FLOAT64, value1, value2))?;
+			cpu.f[f.rd] = f64::from_bits(result);
+			Ok(())
+		},
+		disassemble: dump_format_r,
+	},
+	Instruction {
+		mask: 0xfe00007f,
+		data: 0x10000053,
+		name: "FMUL.S",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			let (value1, value2) = (cpu.read_f32_bits(f.rs1), cpu.read_f32_bits(f.rs2));
+			let result =
+				cpu.operate_float(word, |context| context.mul(&FLOAT32, value1, value2))?;
+			cpu.write_f32(f.rd, f32::from_bits(result as u32));
 			Ok(())
 		},
 		disassemble: dump_format_r,
@@ -2856,11 +3923,57 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 		cycles: 1,
 		operation: |cpu, word, _address| {
 			let f = parse_format_r(word);
-			cpu.f[f.rd] = f64::from_bits(cpu.x[f.rs1] as u32 as u64);
+			cpu.write_f32(f.rd, f32::from_bits(cpu.x[f.rs1] as u32));
 			Ok(())
 		},
 		disassemble: dump_format_r,
 	},
+	Instruction {
+		mask: 0x0600007f,
+		data: 0x0200004f,
+		name: "FNMADD.D",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r2(word);
+			let value1 = cpu.f[f.rs1].to_bits();
+			let value2 = cpu.f[f.rs2].to_bits();
+			let value3 = cpu.f[f.rs3].to_bits();
+			let result = cpu.operate_float(word, |context| {
+				context.fused_multiply_add(
+					&FLOAT64,
+					value1 ^ F64_SIGN_BIT,
+					value2,
+					value3 ^ F64_SIGN_BIT,
+				)
+			})?;
+			cpu.f[f.rd] = f64::from_bits(result);
+			Ok(())
+		},
+		disassemble: dump_format_r2,
+	},
+	Instruction {
+		mask: 0x0600007f,
+		data: 0x0000004f,
+		name: "FNMADD.S",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r2(word);
+			let value1 = cpu.read_f32_bits(f.rs1);
+			let value2 = cpu.read_f32_bits(f.rs2);
+			let value3 = cpu.read_f32_bits(f.rs3);
+			let result = cpu.operate_float(word, |context| {
+				context.fused_multiply_add(
+					&FLOAT32,
+					value1 ^ F32_SIGN_BIT,
+					value2,
+					value3 ^ F32_SIGN_BIT,
+				)
+			})?;
+			cpu.write_f32(f.rd, f32::from_bits(result as u32));
+			Ok(())
+		},
+		disassemble: dump_format_r2,
+	},
 	Instruction {
 		mask: 0x0600007f,
 		data: 0x0200004b,
@@ -2868,7 +3981,31 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 		cycles: 1,
 		operation: |cpu, word, _address| {
 			let f = parse_format_r2(word);
-			cpu.f[f.rd] = -(cpu.f[f.rs1] * cpu.f[f.rs2]) + cpu.f[f.rs3];
+			let value1 = cpu.f[f.rs1].to_bits();
+			let value2 = cpu.f[f.rs2].to_bits();
+			let value3 = cpu.f[f.rs3].to_bits();
+			let result = cpu.operate_float(word, |context| {
+				context.fused_multiply_add(&FLOAT64, value1 ^ F64_SIGN_BIT, value2, value3)
+			})?;
+			cpu.f[f.rd] = f64::from_bits(result);
+			Ok(())
+		},
+		disassemble: dump_format_r2,
+	},
+	Instruction {
+		mask: 0x0600007f,
+		data: 0x0000004b,
+		name: "FNMSUB.S",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r2(word);
+			let value1 = cpu.read_f32_bits(f.rs1);
+			let value2 = cpu.read_f32_bits(f.rs2);
+			let value3 = cpu.read_f32_bits(f.rs3);
+			let result = cpu.operate_float(word, |context| {
+				context.fused_multiply_add(&FLOAT32, value1 ^ F32_SIGN_BIT, value2, value3)
+			})?;
+			cpu.write_f32(f.rd, f32::from_bits(result as u32));
 			Ok(())
 		},
 		disassemble: dump_format_r2,
@@ -2902,6 +4039,51 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 		},
 		disassemble: dump_format_r,
 	},
+	Instruction {
+		mask: 0xfe00707f,
+		data: 0x20000053,
+		name: "FSGNJ.S",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			let rs1_bits = cpu.read_f32(f.rs1).to_bits();
+			let rs2_bits = cpu.read_f32(f.rs2).to_bits();
+			let sign_bit = rs2_bits & 0x80000000;
+			cpu.write_f32(f.rd, f32::from_bits(sign_bit | (rs1_bits & 0x7fffffff)));
+			Ok(())
+		},
+		disassemble: dump_format_r,
+	},
+	Instruction {
+		mask: 0xfe00707f,
+		data: 0x22001053,
+		name: "FSGNJN.D",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			let rs1_bits = cpu.f[f.rs1].to_bits();
+			let rs2_bits = cpu.f[f.rs2].to_bits();
+			let sign_bit = !rs2_bits & 0x8000000000000000;
+			cpu.f[f.rd] = f64::from_bits(sign_bit | (rs1_bits & 0x7fffffffffffffff));
+			Ok(())
+		},
+		disassemble: dump_format_r,
+	},
+	Instruction {
+		mask: 0xfe00707f,
+		data: 0x20001053,
+		name: "FSGNJN.S",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			let rs1_bits = cpu.read_f32(f.rs1).to_bits();
+			let rs2_bits = cpu.read_f32(f.rs2).to_bits();
+			let sign_bit = !rs2_bits & 0x80000000;
+			cpu.write_f32(f.rd, f32::from_bits(sign_bit | (rs1_bits & 0x7fffffff)));
+			Ok(())
+		},
+		disassemble: dump_format_r,
+	},
 	Instruction {
 		mask: 0xfe00707f,
 		data: 0x22002053,
@@ -2917,6 +4099,49 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 		},
 		disassemble: dump_format_r,
 	},
+	Instruction {
+		mask: 0xfe00707f,
+		data: 0x20002053,
+		name: "FSGNJX.S",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			let rs1_bits = cpu.read_f32(f.rs1).to_bits();
+			let rs2_bits = cpu.read_f32(f.rs2).to_bits();
+			let sign_bit = (rs1_bits ^ rs2_bits) & 0x80000000;
+			cpu.write_f32(f.rd, f32::from_bits(sign_bit | (rs1_bits & 0x7fffffff)));
+			Ok(())
+		},
+		disassemble: dump_format_r,
+	},
+	Instruction {
+		mask: 0xfff0007f,
+		data: 0x5a000053,
+		name: "FSQRT.D",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			let value = cpu.f[f.rs1].to_bits();
+			let result = cpu.operate_float(word, |context| context.sqrt(&FLOAT64, value))?;
+			cpu.f[f.rd] = f64::from_bits(result);
+			Ok(())
+		},
+		disassemble: dump_format_r,
+	},
+	Instruction {
+		mask: 0xfff0007f,
+		data: 0x58000053,
+		name: "FSQRT.S",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			let value = cpu.read_f32_bits(f.rs1);
+			let result = cpu.operate_float(word, |context| context.sqrt(&FLOAT32, value))?;
+			cpu.write_f32(f.rd, f32::from_bits(result as u32));
+			Ok(())
+		},
+		disassemble: dump_format_r,
+	},
 	Instruction {
 		mask: 0xfe00007f,
 		data: 0x0a000053,
@@ -2924,8 +4149,25 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 		cycles: 1,
 		operation: |cpu, word, _address| {
 			let f = parse_format_r(word);
-			// @TODO: Update fcsr if needed?
-			cpu.f[f.rd] = cpu.f[f.rs1] - cpu.f[f.rs2];
+			let (value1, value2) = (cpu.f[f.rs1].to_bits(), cpu.f[f.rs2].to_bits());
+			let result =
+				cpu.operate_float(word, |context| context.sub(&FLOAT64, value1, value2))?;
+			cpu.f[f.rd] = f64::from_bits(result);
+			Ok(())
+		},
+		disassemble: dump_format_r,
+	},
+	Instruction {
+		mask: 0xfe00007f,
+		data: 0x08000053,
+		name: "FSUB.S",
+		cycles: 1,
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			let (value1, value2) = (cpu.read_f32_bits(f.rs1), cpu.read_f32_bits(f.rs2));
+			let result =
+				cpu.operate_float(word, |context| context.sub(&FLOAT32, value1, value2))?;
+			cpu.write_f32(f.rd, f32::from_bits(result as u32));
 			Ok(())
 		},
 		disassemble: dump_format_r,
@@ -3086,7 +4328,7 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 			};
 			Ok(())
 		},
-		disassemble: dump_format_r,
+		disassemble: dump_format_amo,
 	},
 	Instruction {
 		mask: 0xf9f0707f,
@@ -3106,7 +4348,7 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 			};
 			Ok(())
 		},
-		disassemble: dump_format_r,
+		disassemble: dump_format_amo,
 	},
 	Instruction {
 		mask: 0x0000007f,
@@ -3394,7 +4636,7 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 			};
 			Ok(())
 		},
-		disassemble: dump_format_r,
+		disassemble: dump_format_amo,
 	},
 	Instruction {
 		mask: 0xf800707f,
@@ -3416,7 +4658,7 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 			};
 			Ok(())
 		},
-		disassemble: dump_format_r,
+		disassemble: dump_format_amo,
 	},
 	Instruction {
 		mask: 0x0000707f,
@@ -3435,8 +4677,9 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 		data: 0x12000073,
 		name: "SFENCE.VMA",
 		cycles: 1,
-		operation: |_cpu, _word, _address| {
-			// Do nothing?
+		operation: |cpu, _word, _address| {
+			// @TODO: Flush only the entries of specified address and ASID
+			cpu.mmu.flush_tlb();
 			Ok(())
 		},
 		disassemble: dump_empty,
@@ -3797,3 +5040,247 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 		disassemble: dump_format_i,
 	},
 ];
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use mmu::DRAM_BASE;
+	use std::thread;
+
+	const TEST_MEMORY_CAPACITY: u64 = 1024 * 1024;
+
+	// Cpu holds the caches inline and needs a large stack
+	fn run_with_large_stack(test: fn()) {
+		thread::Builder::new()
+			.stack_size(256 * 1024 * 1024)
+			.spawn(test)
+			.unwrap()
+			.join()
+			.unwrap();
+	}
+
+	fn create_cpu() -> Cpu {
+		let mut cpu = Cpu::new();
+		cpu.get_mut_mmu().init_memory(TEST_MEMORY_CAPACITY).unwrap();
+		cpu.setup_firmware_state();
+		cpu.privilege_mode = PrivilegeMode::Machine;
+		cpu
+	}
+
+	/// Executes an uncompressed instruction.
+	fn execute(cpu: &mut Cpu, word: u32) {
+		let operation = cpu.decode_raw(word).unwrap().operation;
+		operation(cpu, word, cpu.pc).unwrap();
+	}
+
+	fn get_fflags(cpu: &Cpu) -> u64 {
+		cpu.read_csr_raw(CSR_FFLAGS_ADDRESS)
+	}
+
+	fn check_unboxed_single() {
+		let mut cpu = create_cpu();
+		// Double-precision 1.0 isn't NaN-boxed
+		cpu.f[1] = 1.0;
+		cpu.write_f32(2, 1.0);
+		execute(&mut cpu, 0x002081d3); // fadd.s f3, f1, f2
+		assert_eq!(cpu.f[3].to_bits(), F32_NAN_BOX | F32_CANONICAL_NAN as u64);
+		// Quiet NaN input doesn't raise NV
+		assert_eq!(get_fflags(&cpu), 0);
+		execute(&mut cpu, 0xe0018353); // fmv.x.w t1, f3
+		assert_eq!(cpu.x[6], F32_CANONICAL_NAN as i64);
+	}
+
+	#[test]
+	fn unboxed_single_reads_as_canonical_nan() {
+		run_with_large_stack(check_unboxed_single);
+	}
+
+	fn check_single_moves() {
+		let mut cpu = create_cpu();
+		// Signaling NaN with the upper bits set, and a positive value
+		let values: [u64; 2] = [0xffffffffff800001, 0x7f800001];
+		for bits in values.iter() {
+			cpu.x[5] = *bits as i64;
+			execute(&mut cpu, 0xf0028253); // fmv.w.x f4, t0
+			assert_eq!(cpu.f[4].to_bits(), F32_NAN_BOX | (*bits & 0xffffffff));
+			execute(&mut cpu, 0xe0020353); // fmv.x.w t1, f4
+			assert_eq!(cpu.x[6], *bits as u32 as i32 as i64);
+		}
+		assert_eq!(get_fflags(&cpu), 0);
+	}
+
+	#[test]
+	fn single_moves_preserve_bits() {
+		run_with_large_stack(check_single_moves);
+	}
+
+	fn check_single_nan_conversion() {
+		let mut cpu = create_cpu();
+		cpu.write_f32(1, f32::NAN);
+		execute(&mut cpu, 0xc00092d3); // fcvt.w.s t0, f1, rtz
+		assert_eq!(cpu.x[5], i32::MAX as i64);
+		assert_eq!(get_fflags(&cpu), 0x10);
+	}
+
+	#[test]
+	fn single_nan_converts_to_max_word() {
+		run_with_large_stack(check_single_nan_conversion);
+	}
+
+	fn check_single_min_max_zeros() {
+		let mut cpu = create_cpu();
+		cpu.write_f32(1, -0.0);
+		cpu.write_f32(2, 0.0);
+		execute(&mut cpu, 0x282081d3); // fmin.s f3, f1, f2
+		assert_eq!(cpu.read_f32_bits(3), 0x80000000);
+		execute(&mut cpu, 0x281101d3); // fmin.s f3, f2, f1
+		assert_eq!(cpu.read_f32_bits(3), 0x80000000);
+		execute(&mut cpu, 0x282091d3); // fmax.s f3, f1, f2
+		assert_eq!(cpu.read_f32_bits(3), 0);
+		assert_eq!(get_fflags(&cpu), 0);
+	}
+
+	#[test]
+	fn single_min_max_order_zeros() {
+		run_with_large_stack(check_single_min_max_zeros);
+	}
+
+	/// Converts `value` in f1 by an instruction and returns the result
+	/// and the raised flags.
+	fn convert_double(cpu: &mut Cpu, word: u32, value: f64) -> (i64, u64) {
+		cpu.f[1] = value;
+		cpu.write_csr_raw(CSR_FFLAGS_ADDRESS, 0);
+		execute(cpu, word);
+		(cpu.x[5], get_fflags(cpu))
+	}
+
+	fn check_double_conversions() {
+		let mut cpu = create_cpu();
+		let fcvt_l_d = 0xc22092d3; // fcvt.l.d t0, f1, rtz
+		assert_eq!(convert_double(&mut cpu, fcvt_l_d, 1e20), (i64::MAX, 0x10));
+		assert_eq!(convert_double(&mut cpu, fcvt_l_d, -1e20), (i64::MIN, 0x10));
+		assert_eq!(
+			convert_double(&mut cpu, fcvt_l_d, f64::NAN),
+			(i64::MAX, 0x10)
+		);
+		assert_eq!(convert_double(&mut cpu, fcvt_l_d, -1.5), (-1, 0x1));
+
+		let fcvt_lu_d = 0xc23092d3; // fcvt.lu.d t0, f1, rtz
+		assert_eq!(convert_double(&mut cpu, fcvt_lu_d, 1e20), (-1, 0x10));
+		assert_eq!(convert_double(&mut cpu, fcvt_lu_d, f64::NAN), (-1, 0x10));
+		assert_eq!(convert_double(&mut cpu, fcvt_lu_d, -1.0), (0, 0x10));
+		// Rounds to zero before the range check
+		assert_eq!(convert_double(&mut cpu, fcvt_lu_d, -0.5), (0, 0x1));
+
+		// Results are sign extended from 32 bits
+		let fcvt_wu_d = 0xc21092d3; // fcvt.wu.d t0, f1, rtz
+		assert_eq!(convert_double(&mut cpu, fcvt_wu_d, 5e9), (-1, 0x10));
+		assert_eq!(convert_double(&mut cpu, fcvt_wu_d, -1.0), (0, 0x10));
+		assert_eq!(
+			convert_double(&mut cpu, fcvt_wu_d, 3e9),
+			(3000000000u32 as i32 as i64, 0)
+		);
+	}
+
+	#[test]
+	fn double_conversions_saturate() {
+		run_with_large_stack(check_double_conversions);
+	}
+
+	fn check_double_sign_injection_and_fused() {
+		let mut cpu = create_cpu();
+		let fsgnjn_d = 0x222091d3; // fsgnjn.d f3, f1, f2
+		cpu.f[1] = 2.5;
+		cpu.f[2] = -1.0;
+		execute(&mut cpu, fsgnjn_d);
+		assert_eq!(cpu.f[3], 2.5);
+		cpu.f[2] = 1.0;
+		execute(&mut cpu, fsgnjn_d);
+		assert_eq!(cpu.f[3], -2.5);
+		// NaN payload is kept without raising NV
+		cpu.f[1] = f64::from_bits(0x7ff0000000000001);
+		execute(&mut cpu, fsgnjn_d);
+		assert_eq!(cpu.f[3].to_bits(), 0xfff0000000000001);
+		assert_eq!(get_fflags(&cpu), 0);
+
+		// Products are rounded only once. Unfused ones round to 1.
+		let epsilon = f64::EPSILON;
+		cpu.f[1] = 1.0 + epsilon;
+		cpu.f[2] = 1.0 - epsilon;
+		cpu.f[3] = 1.0;
+		execute(&mut cpu, 0x1a208247); // fmsub.d f4, f1, f2, f3
+		assert_eq!(cpu.f[4], -epsilon * epsilon);
+		cpu.f[3] = -1.0;
+		execute(&mut cpu, 0x1a20824f); // fnmadd.d f4, f1, f2, f3
+		assert_eq!(cpu.f[4], epsilon * epsilon);
+		cpu.f[1] = 2.0;
+		cpu.f[2] = 3.0;
+		cpu.f[3] = 1.0;
+		execute(&mut cpu, 0x1a208247); // fmsub.d f4, f1, f2, f3
+		assert_eq!(cpu.f[4], 5.0);
+		execute(&mut cpu, 0x1a20824f); // fnmadd.d f4, f1, f2, f3
+		assert_eq!(cpu.f[4], -7.0);
+	}
+
+	#[test]
+	fn double_sign_injection_and_fused_operations() {
+		run_with_large_stack(check_double_sign_injection_and_fused);
+	}
+
+	fn check_compressed_double_loads_and_stores() {
+		let mut cpu = create_cpu();
+		// c.fldsp f8, 0x1f8(sp) / c.fldsp f0, 8(sp) / c.fsdsp f9, 0x1f8(sp)
+		assert_eq!(cpu.uncompress(0x347e), 0x1f813407);
+		assert_eq!(cpu.uncompress(0x2022), 0x00813007);
+		assert_eq!(cpu.uncompress(0xbfa6), 0x1e913c27);
+		assert_eq!(cpu.decode_raw(0x1f813407).unwrap().name, "FLD");
+		assert_eq!(cpu.decode_raw(0x1e913c27).unwrap().name, "FSD");
+
+		cpu.x[2] = (DRAM_BASE + 0x1000) as i64;
+		cpu.f[9] = -1.25;
+		execute(&mut cpu, 0x1e913c27); // fsd f9, 0x1f8(sp)
+		let stored = cpu.get_mut_mmu().load_doubleword(DRAM_BASE + 0x11f8);
+		assert_eq!(stored.unwrap(), (-1.25f64).to_bits());
+		execute(&mut cpu, 0x1f813407); // fld f8, 0x1f8(sp)
+		assert_eq!(cpu.f[8], -1.25);
+	}
+
+	#[test]
+	fn compressed_double_loads_and_stores() {
+		run_with_large_stack(check_compressed_double_loads_and_stores);
+	}
+
+	fn check_min_max_amos() {
+		let mut cpu = create_cpu();
+		let address = DRAM_BASE + 0x100;
+		cpu.x[7] = address as i64;
+		// Upper bits of rs2 are ignored by word AMOs
+		cpu.x[6] = 0x100000003;
+
+		cpu.get_mut_mmu().store_word(address, -5i32 as u32).unwrap();
+		execute(&mut cpu, 0x8063a2af); // amomin.w t0, t1, (t2)
+		assert_eq!(cpu.x[5], -5);
+		assert_eq!(cpu.get_mut_mmu().load_word(address).unwrap(), -5i32 as u32);
+		execute(&mut cpu, 0xc063a2af); // amominu.w t0, t1, (t2)
+		assert_eq!(cpu.x[5], -5);
+		assert_eq!(cpu.get_mut_mmu().load_word(address).unwrap(), 3);
+
+		// Unsigned comparison would keep i64::MIN in memory
+		let amomax_d = 0xa063b2af; // amomax.d t0, t1, (t2)
+		let mmu = cpu.get_mut_mmu();
+		mmu.store_doubleword(address, i64::MIN as u64).unwrap();
+		cpu.x[6] = 1;
+		execute(&mut cpu, amomax_d);
+		assert_eq!(cpu.x[5], i64::MIN);
+		assert_eq!(cpu.get_mut_mmu().load_doubleword(address).unwrap(), 1);
+		cpu.x[6] = i64::MIN;
+		execute(&mut cpu, amomax_d);
+		assert_eq!(cpu.x[5], 1);
+		assert_eq!(cpu.get_mut_mmu().load_doubleword(address).unwrap(), 1);
+	}
+
+	#[test]
+	fn min_max_amos_compare_signedness() {
+		run_with_large_stack(check_min_max_amos);
+	}
+}
@@ -0,0 +1,497 @@
+use checkpoint::{CheckpointError, CheckpointReader, CheckpointWriter};
+use clint::{Clint, CLINT_BASE, CLINT_SIZE};
+use fdt::FdtBuilder;
+use htif::Htif;
+use plic::{Plic, PLIC_BASE, PLIC_SIZE, PLIC_SOURCE_NUM};
+
+/// Peripheral device connected to the system bus. Address passed to the
+/// device is the offset from the base address it is mapped at.
+pub trait Device {
+	/// Reads from the device.
+	///
+	/// # Arguments
+	/// * `offset` Offset from the base address
+	/// * `width` Must be 1, 2, 4, or 8
+	fn read(&mut self, offset: u64, width: u64) -> u64;
+
+	/// Writes to the device.
+	///
+	/// # Arguments
+	/// * `offset` Offset from the base address
+	/// * `value`
+	/// * `width` Must be 1, 2, 4, or 8
+	fn write(&mut self, offset: u64, value: u64, width: u64);
+
+	/// Runs one cycle of the device.
+	///
+	/// # Arguments
+	/// * `mip` CPU `mip` register the device may raise interrupts through
+	fn tick(&mut self, _mip: &mut u64) {}
+
+	/// Runs DMA of the device after `tick`, for devices accessing
+	/// main memory.
+	///
+	/// # Arguments
+	/// * `memory`
+	fn dma(&mut self, _memory: &mut dyn DmaMemory) {}
+
+	/// Returns the level of the interrupt line to PLIC. Only used if
+	/// the device is added with an interrupt source number.
+	fn is_interrupting(&self) -> bool {
+		false
+	}
+
+	/// Adds the device node to the device tree passed to the guest.
+	/// Devices without a node don't need to implement this.
+	///
+	/// # Arguments
+	/// * `fdt`
+	/// * `base` Physical base address the device is mapped at
+	/// * `size` Size of the address range in bytes
+	/// * `irq` PLIC interrupt source number
+	fn build_fdt_node(&self, _fdt: &mut FdtBuilder, _base: u64, _size: u64, _irq: Option<u32>) {}
+
+	/// Saves the device state to checkpoint. Devices without state
+	/// don't need to implement this.
+	///
+	/// # Arguments
+	/// * `writer`
+	fn save_state(&self, _writer: &mut CheckpointWriter) {}
+
+	/// Restores the device state saved by `save_state`.
+	///
+	/// # Arguments
+	/// * `reader`
+	fn restore_state(&mut self, _reader: &mut CheckpointReader) -> Result<(), CheckpointError> {
+		Ok(())
+	}
+}
+
+/// Main memory seen from devices. Accesses are coherent with caches.
+pub trait DmaMemory {
+	/// Reads a byte.
+	///
+	/// # Arguments
+	/// * `p_address` Physical address
+	fn read_byte(&mut self, p_address: u64) -> u8;
+
+	/// Writes a byte.
+	///
+	/// # Arguments
+	/// * `p_address` Physical address
+	/// * `value`
+	fn write_byte(&mut self, p_address: u64, value: u8);
+
+	/// Reads little-endian multiple bytes.
+	///
+	/// # Arguments
+	/// * `p_address` Physical address
+	/// * `width` up to eight
+	fn read(&mut self, p_address: u64, width: u64) -> u64 {
+		let mut data = 0;
+		for i in 0..width {
+			data |= (self.read_byte(p_address.wrapping_add(i)) as u64) << (i * 8);
+		}
+		data
+	}
+
+	/// Writes little-endian multiple bytes.
+	///
+	/// # Arguments
+	/// * `p_address` Physical address
+	/// * `value`
+	/// * `width` up to eight
+	fn write(&mut self, p_address: u64, value: u64, width: u64) {
+		for i in 0..width {
+			self.write_byte(p_address.wrapping_add(i), (value >> (i * 8)) as u8);
+		}
+	}
+
+	/// Reads bytes into buffer.
+	///
+	/// # Arguments
+	/// * `p_address` Physical address
+	/// * `buffer`
+	fn read_bytes(&mut self, p_address: u64, buffer: &mut [u8]) {
+		for (i, byte) in buffer.iter_mut().enumerate() {
+			*byte = self.read_byte(p_address.wrapping_add(i as u64));
+		}
+	}
+
+	/// Writes bytes from buffer.
+	///
+	/// # Arguments
+	/// * `p_address` Physical address
+	/// * `buffer`
+	fn write_bytes(&mut self, p_address: u64, buffer: &[u8]) {
+		for (i, byte) in buffer.iter().enumerate() {
+			self.write_byte(p_address.wrapping_add(i as u64), *byte);
+		}
+	}
+}
+
+/// Device mapped to a physical address range
+pub struct DeviceMapping {
+	pub base: u64,
+	pub size: u64,
+	/// PLIC interrupt source number
+	pub irq: Option<u32>,
+	pub device: Box<dyn Device>,
+}
+
+/// System bus dispatching physical address to memory-mapped devices.
+/// CLINT and PLIC are always mapped, the other devices are added by user.
+/// HTIF is not mapped but polls main memory.
+pub struct Bus {
+	pub clint: Clint,
+	pub plic: Plic,
+	pub htif: Htif,
+	pub devices: Vec<DeviceMapping>,
+}
+
+impl Default for Bus {
+	fn default() -> Self {
+		Bus::new()
+	}
+}
+
+impl Bus {
+	/// Creates a new `Bus` with only CLINT and PLIC.
+	pub const fn new() -> Self {
+		Bus {
+			clint: Clint::new(),
+			plic: Plic::new(),
+			htif: Htif::new(),
+			devices: vec![],
+		}
+	}
+
+	/// Maps a device at a physical address range. Returns error if the
+	/// range is empty, exceeds the address space, or overlaps with the
+	/// other devices.
+	///
+	/// # Arguments
+	/// * `base` Physical base address
+	/// * `size` Size of the address range in bytes
+	/// * `device`
+	pub fn add_device(
+		&mut self,
+		base: u64,
+		size: u64,
+		device: Box<dyn Device>,
+	) -> Result<(), String> {
+		self.map_device(base, size, None, device)
+	}
+
+	/// Maps a device whose interrupt line is connected to PLIC. Returns
+	/// error if the interrupt source is invalid or already used, or the
+	/// range can't be mapped.
+	///
+	/// # Arguments
+	/// * `base` Physical base address
+	/// * `size` Size of the address range in bytes
+	/// * `irq` PLIC interrupt source number
+	/// * `device`
+	pub fn add_device_with_irq(
+		&mut self,
+		base: u64,
+		size: u64,
+		irq: u32,
+		device: Box<dyn Device>,
+	) -> Result<(), String> {
+		if irq == 0 || (irq as usize) >= PLIC_SOURCE_NUM {
+			return Err(format!("Invalid PLIC interrupt source {}", irq));
+		}
+		if self.devices.iter().any(|mapping| mapping.irq == Some(irq)) {
+			return Err(format!("Interrupt source {} is already used", irq));
+		}
+		self.map_device(base, size, Some(irq), device)
+	}
+
+	fn map_device(
+		&mut self,
+		base: u64,
+		size: u64,
+		irq: Option<u32>,
+		device: Box<dyn Device>,
+	) -> Result<(), String> {
+		if size == 0 || base.checked_add(size).is_none() {
+			return Err(format!(
+				"Device at {:X} with size {:X} is not in the address space",
+				base, size
+			));
+		}
+		if let Some(other_base) = self.find_overlapping_device(base, size) {
+			return Err(format!(
+				"Device at {:X} overlaps with device at {:X}",
+				base, other_base
+			));
+		}
+		self.devices.push(DeviceMapping {
+			base,
+			size,
+			irq,
+			device,
+		});
+		Ok(())
+	}
+
+	/// Returns the base address of the device, including CLINT and PLIC,
+	/// overlapping with an address range.
+	///
+	/// # Arguments
+	/// * `base` Physical base address
+	/// * `size` Size of the address range in bytes
+	pub fn find_overlapping_device(&self, base: u64, size: u64) -> Option<u64> {
+		let devices = self
+			.devices
+			.iter()
+			.map(|mapping| (mapping.base, mapping.size));
+		[(CLINT_BASE, CLINT_SIZE), (PLIC_BASE, PLIC_SIZE)]
+			.iter()
+			.cloned()
+			.chain(devices)
+			.find(|(other_base, other_size)| overlaps(base, size, *other_base, *other_size))
+			.map(|(other_base, _)| other_base)
+	}
+
+	fn find_device(&self, p_address: u64, width: u64) -> Option<usize> {
+		self.devices
+			.iter()
+			.position(|mapping| is_in_range(p_address, width, mapping.base, mapping.size))
+	}
+
+	/// Checks if physical address range is mapped to a device
+	///
+	/// # Arguments
+	/// * `p_address`
+	/// * `width` Access width in bytes
+	pub fn is_mapped(&self, p_address: u64, width: u64) -> bool {
+		is_in_range(p_address, width, CLINT_BASE, CLINT_SIZE)
+			|| is_in_range(p_address, width, PLIC_BASE, PLIC_SIZE)
+			|| self.find_device(p_address, width).is_some()
+	}
+
+	/// Reads from the device mapped at the physical address. Returns zero
+	/// if no device is mapped.
+	///
+	/// # Arguments
+	/// * `p_address`
+	/// * `width` Must be 1, 2, 4, or 8
+	pub fn read(&mut self, p_address: u64, width: u64) -> u64 {
+		if is_in_range(p_address, width, CLINT_BASE, CLINT_SIZE) {
+			return self.clint.read(p_address - CLINT_BASE, width);
+		}
+		if is_in_range(p_address, width, PLIC_BASE, PLIC_SIZE) {
+			return self.plic.read(p_address - PLIC_BASE, width);
+		}
+		match self.find_device(p_address, width) {
+			Some(index) => {
+				let mapping = &mut self.devices[index];
+				mapping.device.read(p_address - mapping.base, width)
+			}
+			None => 0,
+		}
+	}
+
+	/// Writes to the device mapped at the physical address. Ignored if no
+	/// device is mapped.
+	///
+	/// # Arguments
+	/// * `p_address`
+	/// * `value`
+	/// * `width` Must be 1, 2, 4, or 8
+	pub fn write(&mut self, p_address: u64, value: u64, width: u64) {
+		if is_in_range(p_address, width, CLINT_BASE, CLINT_SIZE) {
+			self.clint.write(p_address - CLINT_BASE, value, width);
+			return;
+		}
+		if is_in_range(p_address, width, PLIC_BASE, PLIC_SIZE) {
+			self.plic.write(p_address - PLIC_BASE, value, width);
+			return;
+		}
+		if let Some(index) = self.find_device(p_address, width) {
+			let mapping = &mut self.devices[index];
+			mapping.device.write(p_address - mapping.base, value, width);
+		}
+	}
+
+	/// Runs one cycle of all the devices but HTIF and forwards interrupt
+	/// lines to PLIC.
+	///
+	/// # Arguments
+	/// * `clock` Current core clock
+	/// * `mip`
+	/// * `memory` Main memory for DMA
+	pub fn tick(&mut self, clock: u64, mip: &mut u64, memory: &mut dyn DmaMemory) {
+		self.clint.tick(clock, mip);
+		for mapping in self.devices.iter_mut() {
+			mapping.device.tick(mip);
+			mapping.device.dma(memory);
+			if let Some(irq) = mapping.irq {
+				self.plic
+					.set_interrupt_line(irq, mapping.device.is_interrupting());
+			}
+		}
+		self.plic.tick(mip);
+	}
+
+	/// Saves the state of all the devices to checkpoint.
+	///
+	/// # Arguments
+	/// * `writer`
+	pub fn save_state(&self, writer: &mut CheckpointWriter) {
+		self.clint.save_state(writer);
+		self.plic.save_state(writer);
+		self.htif.save_state(writer);
+		writer.write_u64(self.devices.len() as u64);
+		for mapping in self.devices.iter() {
+			writer.write_u64(mapping.base);
+			writer.write_u64(mapping.size);
+			mapping.device.save_state(writer);
+		}
+	}
+
+	/// Restores the state saved by `save_state()`. The same devices
+	/// need to be mapped at the same addresses.
+	///
+	/// # Arguments
+	/// * `reader`
+	pub fn restore_state(&mut self, reader: &mut CheckpointReader) -> Result<(), CheckpointError> {
+		self.clint.restore_state(reader)?;
+		self.plic.restore_state(reader)?;
+		self.htif.restore_state(reader)?;
+		reader.expect_u64(self.devices.len() as u64, "Device number")?;
+		for mapping in self.devices.iter_mut() {
+			reader.expect_u64(mapping.base, "Device base address")?;
+			reader.expect_u64(mapping.size, "Device size")?;
+			mapping.device.restore_state(reader)?;
+		}
+		Ok(())
+	}
+}
+
+/// Checks if two address ranges overlap.
+///
+/// # Arguments
+/// * `base`
+/// * `size`
+/// * `other_base`
+/// * `other_size`
+pub fn overlaps(base: u64, size: u64, other_base: u64, other_size: u64) -> bool {
+	match other_base >= base {
+		true => other_base - base < size,
+		false => base - other_base < other_size,
+	}
+}
+
+fn is_in_range(p_address: u64, width: u64, base: u64, size: u64) -> bool {
+	p_address >= base && width <= size && p_address - base <= size - width
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Little-endian registers readable and writable at any offset
+	struct TestDevice {
+		data: Vec<u8>,
+	}
+
+	impl TestDevice {
+		fn new(size: u64) -> Box<Self> {
+			Box::new(TestDevice {
+				data: vec![0; size as usize],
+			})
+		}
+	}
+
+	impl Device for TestDevice {
+		fn read(&mut self, offset: u64, width: u64) -> u64 {
+			(0..width).fold(0, |value, i| {
+				value | ((self.data[(offset + i) as usize] as u64) << (i * 8))
+			})
+		}
+
+		fn write(&mut self, offset: u64, value: u64, width: u64) {
+			for i in 0..width {
+				self.data[(offset + i) as usize] = (value >> (i * 8)) as u8;
+			}
+		}
+	}
+
+	const DEVICE_BASE: u64 = 0x10000000;
+
+	#[test]
+	fn routes_accesses() {
+		let mut bus = Bus::new();
+		bus.add_device(DEVICE_BASE, 0x100, TestDevice::new(0x100))
+			.unwrap();
+		bus.add_device_with_irq(DEVICE_BASE + 0x1000, 0x10, 3, TestDevice::new(0x10))
+			.unwrap();
+		bus.write(DEVICE_BASE + 8, 0x12345678, 4);
+		bus.write(DEVICE_BASE + 0x1008, 0xabcd, 2);
+		assert_eq!(bus.read(DEVICE_BASE + 8, 4), 0x12345678);
+		assert_eq!(bus.read(DEVICE_BASE + 0xa, 1), 0x34);
+		assert_eq!(bus.read(DEVICE_BASE + 0x1008, 8), 0xabcd);
+		assert_eq!(bus.devices[0].device.read(8, 4), 0x12345678);
+		assert_eq!(bus.devices[1].device.read(8, 2), 0xabcd);
+		assert_eq!(bus.devices[1].irq, Some(3));
+		// CLINT and PLIC are always mapped
+		bus.write(CLINT_BASE + 0x4000, 0x1000, 8);
+		assert_eq!(bus.clint.read(0x4000, 8), 0x1000);
+		bus.write(PLIC_BASE + 3 * 4, 5, 4);
+		assert_eq!(bus.read(PLIC_BASE + 3 * 4, 4), 5);
+	}
+
+	#[test]
+	fn rejects_invalid_mappings() {
+		let mut bus = Bus::new();
+		bus.add_device_with_irq(DEVICE_BASE, 0x100, 3, TestDevice::new(0x100))
+			.unwrap();
+		let invalid_mappings = [
+			(DEVICE_BASE + 0xf0, 0x20),
+			(DEVICE_BASE - 0x10, 0x20),
+			(DEVICE_BASE - 0x10, 0x1000),
+			(CLINT_BASE + 0x10, 0x10),
+			(PLIC_BASE - 0x10, 0x20),
+			(0x20000000, 0),
+			(u64::MAX - 0xf, 0x20),
+		];
+		for (base, size) in invalid_mappings.iter() {
+			let device = TestDevice::new(*size);
+			assert!(bus.add_device(*base, *size, device).is_err());
+		}
+		// Invalid or used interrupt source
+		for irq in [0, 3, PLIC_SOURCE_NUM as u32].iter() {
+			let device = TestDevice::new(0x10);
+			assert!(bus
+				.add_device_with_irq(0x20000000, 0x10, *irq, device)
+				.is_err());
+		}
+		assert_eq!(bus.devices.len(), 1);
+		// Adjacent range is fine
+		bus.add_device(DEVICE_BASE + 0x100, 0x10, TestDevice::new(0x10))
+			.unwrap();
+		assert_eq!(
+			bus.find_overlapping_device(DEVICE_BASE + 0xff, 2),
+			Some(DEVICE_BASE)
+		);
+		assert_eq!(bus.find_overlapping_device(DEVICE_BASE + 0x110, 0x10), None);
+	}
+
+	#[test]
+	fn ignores_unmapped_accesses() {
+		let mut bus = Bus::new();
+		bus.add_device(DEVICE_BASE, 0x100, TestDevice::new(0x100))
+			.unwrap();
+		assert!(bus.is_mapped(DEVICE_BASE + 0xf8, 8));
+		// Accesses crossing the end of the range aren't mapped
+		assert!(!bus.is_mapped(DEVICE_BASE + 0xfc, 8));
+		assert!(!bus.is_mapped(DEVICE_BASE + 0x100, 1));
+		assert!(!bus.is_mapped(u64::MAX - 3, 8));
+		bus.write(DEVICE_BASE + 0x100, 0xff, 1);
+		assert_eq!(bus.read(DEVICE_BASE + 0x100, 1), 0);
+		assert_eq!(bus.read(DEVICE_BASE + 0xfc, 8), 0);
+	}
+}
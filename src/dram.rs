@@ -1,64 +1,643 @@
-use std::ffi::{CStr, CString};
-use std::os::raw::{c_char, c_int};
+extern crate libc;
 
-#[link(name = "interface", kind = "static")]
-extern "C" {
-	pub fn Setup(rqst_to_memory: *const c_char, resp_to_cpu: *const c_char) -> c_int;
-	pub fn SendRqst(trace_str: *const c_char) -> c_int;
-	pub fn RecvResp() -> c_int;
-	pub fn RecvRespString() -> *const c_char;
-	pub fn Terminate();
-}
+use std::collections::VecDeque;
+use std::ffi::CString;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, LineWriter, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Size of a request record. Requests shorter than this are NUL padded.
+pub const DRAMSIM_REQUEST_SIZE: usize = 41;
+
+/// The maximum size of a response record
+pub const DRAMSIM_RESPONSE_SIZE: usize = 35;
 
-// Safe C ffi interfaces
+/// Default time to wait for DRAMSim to open the pipes or respond
+pub const DRAMSIM_TIMEOUT: Duration = Duration::from_secs(10);
 
-/// Setup linux fifo
-pub fn setup_pipe(request_pipe: &str, response_pipe: &str) -> i32 {
-	let req = CString::new(request_pipe).unwrap();
-	let resp = CString::new(response_pipe).unwrap();
+/// Error in communication with DRAMSim
+#[derive(Debug)]
+pub enum DramSimError {
+	Io(io::Error),
+	/// The pipes aren't set up
+	NotConnected,
+	/// DRAMSim didn't open the pipe or respond in time
+	Timeout,
+	/// DRAMSim closed the response pipe
+	Disconnected,
+	InvalidResponse(String),
+	/// Malformed replay log
+	InvalidLog {
+		/// Line number, starting from one
+		line: usize,
+		message: String,
+	},
+	/// Request differs from the one in replay log
+	Divergence {
+		/// The number of requests matched before
+		request_num: usize,
+		/// None if the log has no more request
+		expected: Option<String>,
+		actual: String,
+	},
+	/// Response cycle isn't later than request cycle
+	ReverseClock {
+		request_cycle: u64,
+		response_cycle: u64,
+	},
+}
 
-	unsafe {
-		Setup(
-			req.as_ptr() as *const c_char,
-			resp.as_ptr() as *const c_char,
-		)
+impl fmt::Display for DramSimError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			DramSimError::Io(e) => write!(f, "DRAMSim: {}", e),
+			DramSimError::NotConnected => write!(f, "DRAMSim: pipes aren't set up"),
+			DramSimError::Timeout => write!(f, "DRAMSim: timed out"),
+			DramSimError::Disconnected => write!(f, "DRAMSim: response pipe closed"),
+			DramSimError::InvalidResponse(response) => {
+				write!(f, "DRAMSim: invalid response {:?}", response)
+			}
+			DramSimError::InvalidLog { line, message } => {
+				write!(f, "DRAMSim replay log line {}: {}", line, message)
+			}
+			DramSimError::Divergence {
+				request_num,
+				expected,
+				actual,
+			} => write!(
+				f,
+				"DRAMSim replay diverged after {} requests: expected {:?} but got {:?}",
+				request_num,
+				expected.as_deref().unwrap_or("no request"),
+				actual
+			),
+			DramSimError::ReverseClock {
+				request_cycle,
+				response_cycle,
+			} => write!(
+				f,
+				"DRAMSim: response cycle {} isn't after request cycle {}",
+				response_cycle, request_cycle
+			),
+		}
 	}
 }
 
-/// Send a trace to pipe
-pub fn send_request(trace_str: &str) -> i32 {
-	let c_trace_str = CString::new(trace_str).unwrap();
-	unsafe { SendRqst(c_trace_str.as_ptr() as *const c_char) }
+impl From<io::Error> for DramSimError {
+	fn from(e: io::Error) -> Self {
+		DramSimError::Io(e)
+	}
 }
 
-/// Check response in pipe
-fn recieve_response() -> i32 {
-	unsafe { RecvResp() }
+/// Response from DRAMSim, `<addr> <cycle>`
+#[derive(Clone, Debug, PartialEq)]
+pub struct DramSimResponse {
+	pub address: u64,
+	/// Cycle the access completes
+	pub cycle: u64,
+}
+
+/// Connection to DRAMSim through a pair of named pipes (FIFOs). Requests
+/// are `<addr> READ|WRITE <cycle>` records and `<addr> END <cycle>` ends
+/// the simulation. Responses are `<addr> <cycle>` records terminated by
+/// newline or padded with NUL.
+pub struct DramSimPipe {
+	request: File,
+	response: File,
+	timeout: Duration,
+	/// Bytes received but not parsed yet
+	buffer: Vec<u8>,
+}
+
+impl DramSimPipe {
+	/// Creates the pipes if they don't exist and opens them. Waits for
+	/// DRAMSim to open the request pipe.
+	///
+	/// # Arguments
+	/// * `request_path` Request pipe to DRAMSim
+	/// * `response_path` Response pipe from DRAMSim
+	/// * `timeout` Time to wait for DRAMSim to open the pipe or respond
+	pub fn open(
+		request_path: &str,
+		response_path: &str,
+		timeout: Duration,
+	) -> Result<Self, DramSimError> {
+		create_fifo(request_path)?;
+		create_fifo(response_path)?;
+
+		// Opening the write end fails with ENXIO until DRAMSim opens the
+		// read end.
+		let deadline = Instant::now() + timeout;
+		let request = loop {
+			match OpenOptions::new()
+				.write(true)
+				.custom_flags(libc::O_NONBLOCK)
+				.open(request_path)
+			{
+				Ok(file) => break file,
+				Err(e) if e.raw_os_error() == Some(libc::ENXIO) => {
+					if Instant::now() >= deadline {
+						return Err(DramSimError::Timeout);
+					}
+					thread::sleep(Duration::from_millis(1));
+				}
+				Err(e) => return Err(e.into()),
+			}
+		};
+		let response = OpenOptions::new()
+			.read(true)
+			.custom_flags(libc::O_NONBLOCK)
+			.open(response_path)?;
+
+		Ok(DramSimPipe {
+			request,
+			response,
+			timeout,
+			buffer: vec![],
+		})
+	}
+
+	/// Sends a request record. Waits up to the timeout while the pipe
+	/// is full.
+	///
+	/// # Arguments
+	/// * `request` e.g. `0000000083000000 READ 100`
+	pub fn send_request(&mut self, request: &str) -> Result<(), DramSimError> {
+		if request.len() >= DRAMSIM_REQUEST_SIZE {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidInput,
+				format!("request {:?} is too long", request),
+			)
+			.into());
+		}
+		let mut record = [0; DRAMSIM_REQUEST_SIZE];
+		record[..request.len()].copy_from_slice(request.as_bytes());
+		// A record is shorter than PIPE_BUF, so it's written at once or not
+		// at all.
+		let deadline = Instant::now() + self.timeout;
+		loop {
+			match self.request.write(&record) {
+				Ok(_) => return Ok(()),
+				Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+					wait_for(&self.request, libc::POLLOUT, deadline)?
+				}
+				Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+				Err(e) => return Err(e.into()),
+			}
+		}
+	}
+
+	/// Waits for a response record up to the timeout.
+	pub fn receive_response(&mut self) -> Result<DramSimResponse, DramSimError> {
+		let deadline = Instant::now() + self.timeout;
+		loop {
+			while let Some(record) = self.take_record() {
+				let text = String::from_utf8_lossy(&record);
+				let text = text.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+				if !text.is_empty() {
+					return parse_response(text);
+				}
+			}
+
+			wait_for(&self.response, libc::POLLIN, deadline)?;
+
+			let mut bytes = [0; DRAMSIM_RESPONSE_SIZE];
+			match self.response.read(&mut bytes) {
+				// The read end sees EOF only after DRAMSim closed the write end
+				Ok(0) => return Err(DramSimError::Disconnected),
+				Ok(size) => self.buffer.extend_from_slice(&bytes[..size]),
+				Err(ref e)
+					if e.kind() == io::ErrorKind::WouldBlock
+						|| e.kind() == io::ErrorKind::Interrupted => {}
+				Err(e) => return Err(e.into()),
+			}
+		}
+	}
+
+	/// Takes a record terminated by newline, or a full size record.
+	fn take_record(&mut self) -> Option<Vec<u8>> {
+		let length = match self.buffer.iter().position(|c| *c == b'\n') {
+			Some(position) => position + 1,
+			None if self.buffer.len() >= DRAMSIM_RESPONSE_SIZE => DRAMSIM_RESPONSE_SIZE,
+			None => return None,
+		};
+		Some(self.buffer.drain(..length).collect())
+	}
 }
 
-/// Resolve response
-fn recieve_response_string() -> String {
-	unsafe {
-		CStr::from_ptr(RecvRespString())
-			.to_string_lossy()
-			.into_owned()
+fn create_fifo(path: &str) -> Result<(), DramSimError> {
+	if Path::new(path).exists() {
+		return Ok(());
+	}
+	let c_path = match CString::new(Path::new(path).as_os_str().as_bytes()) {
+		Ok(c_path) => c_path,
+		Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidInput, e).into()),
+	};
+	match unsafe { libc::mkfifo(c_path.as_ptr(), 0o666) } {
+		0 => Ok(()),
+		_ => Err(io::Error::last_os_error().into()),
 	}
 }
 
-/// Poll the pipe until reseponse available
-pub fn get_response() -> String {
-	// Poll
+/// Waits until `file` is ready for `events`.
+///
+/// # Arguments
+/// * `file`
+/// * `events` `POLLIN` or `POLLOUT`
+/// * `deadline` Returns `DramSimError::Timeout` after this
+fn wait_for(file: &File, events: libc::c_short, deadline: Instant) -> Result<(), DramSimError> {
 	loop {
-		if recieve_response() > 0 {
-			break;
+		let remaining = deadline.saturating_duration_since(Instant::now());
+		if remaining == Duration::from_secs(0) {
+			return Err(DramSimError::Timeout);
+		}
+		let mut poll_fd = libc::pollfd {
+			fd: file.as_raw_fd(),
+			events,
+			revents: 0,
+		};
+		let timeout_ms = remaining.as_millis().clamp(1, i32::MAX as u128) as i32;
+		if unsafe { libc::poll(&mut poll_fd, 1, timeout_ms) } < 0 {
+			let e = io::Error::last_os_error();
+			match e.kind() {
+				io::ErrorKind::Interrupted => continue,
+				_ => return Err(e.into()),
+			}
+		}
+		// POLLERR and POLLHUP are reported as well, and the following read
+		// or write reports them.
+		if poll_fd.revents != 0 {
+			return Ok(());
 		}
 	}
+}
 
-	// Resolve
-	recieve_response_string()
+/// Parses response `<addr> <cycle>`. Address is hexadecimal and cycle is
+/// decimal.
+///
+/// # Arguments
+/// * `text`
+pub fn parse_response(text: &str) -> Result<DramSimResponse, DramSimError> {
+	let tokens: Vec<&str> = text.split_whitespace().collect();
+	let invalid = || DramSimError::InvalidResponse(text.to_string());
+	if tokens.len() != 2 {
+		return Err(invalid());
+	}
+	Ok(DramSimResponse {
+		address: u64::from_str_radix(tokens[0], 16).map_err(|_| invalid())?,
+		cycle: tokens[1].parse().map_err(|_| invalid())?,
+	})
 }
 
-/// Terminate pipe
-pub fn terminate_pipe() {
-	unsafe { Terminate() }
+/// DRAMSim replaced with a log recorded by `setup_recorder()`. It answers
+/// the requests with the recorded responses as long as the requests are
+/// identical to the recorded ones, so that runs depending on DRAMSim can
+/// be reproduced without it.
+///
+/// ```text
+/// # Comment
+/// 0000000080010000 READ 8 -> 0000000080010000 58
+/// ```
+pub struct DramSimReplay {
+	records: VecDeque<(String, DramSimResponse)>,
+	/// The number of requests answered
+	request_num: usize,
+	response: Option<DramSimResponse>,
+}
+
+impl DramSimReplay {
+	/// Reads a log recorded by `setup_recorder()`.
+	///
+	/// # Arguments
+	/// * `path`
+	pub fn open(path: &str) -> Result<Self, DramSimError> {
+		let mut contents = String::new();
+		File::open(path)?.read_to_string(&mut contents)?;
+		DramSimReplay::parse(&contents)
+	}
+
+	/// Parses log content.
+	///
+	/// # Arguments
+	/// * `contents`
+	pub fn parse(contents: &str) -> Result<Self, DramSimError> {
+		let mut records = VecDeque::new();
+		for (i, line) in contents.lines().enumerate() {
+			let line = match line.find('#') {
+				Some(position) => &line[..position],
+				None => line,
+			};
+			if line.trim().is_empty() {
+				continue;
+			}
+			let error = |message: &str| DramSimError::InvalidLog {
+				line: i + 1,
+				message: message.to_string(),
+			};
+			let (request, response) = match line.split_once(" -> ") {
+				Some(record) => record,
+				None => return Err(error("Missing ->")),
+			};
+			let response = match parse_response(response.trim()) {
+				Ok(response) => response,
+				Err(_) => return Err(error("Invalid response")),
+			};
+			records.push_back((request.trim().to_string(), response));
+		}
+		Ok(DramSimReplay {
+			records,
+			request_num: 0,
+			response: None,
+		})
+	}
+
+	/// Checks a request against the log.
+	///
+	/// # Arguments
+	/// * `request`
+	pub fn send_request(&mut self, request: &str) -> Result<(), DramSimError> {
+		let expected = match self.records.pop_front() {
+			Some((expected, response)) if expected == request => {
+				self.request_num += 1;
+				self.response = Some(response);
+				return Ok(());
+			}
+			Some((expected, _)) => Some(expected),
+			None => None,
+		};
+		Err(DramSimError::Divergence {
+			request_num: self.request_num,
+			expected,
+			actual: request.to_string(),
+		})
+	}
+
+	/// Returns the recorded response to the last request.
+	pub fn receive_response(&mut self) -> Result<DramSimResponse, DramSimError> {
+		match self.response.take() {
+			Some(response) => Ok(response),
+			None => Err(DramSimError::InvalidResponse(
+				"no request to respond".to_string(),
+			)),
+		}
+	}
+
+	/// Returns the number of the recorded requests not made yet.
+	pub fn get_remaining_num(&self) -> usize {
+		self.records.len()
+	}
+}
+
+// Connection shared by the emulator
+
+enum DramSimBackend {
+	Pipe(DramSimPipe),
+	Replay(DramSimReplay),
+}
+
+struct DramSim {
+	backend: Option<DramSimBackend>,
+	/// Log of request/response pairs
+	recorder: Option<LineWriter<File>>,
+	/// Request waiting for response, to be recorded
+	request: Option<String>,
+}
+
+static DRAMSIM: Mutex<DramSim> = Mutex::new(DramSim {
+	backend: None,
+	recorder: None,
+	request: None,
+});
+
+fn lock_dramsim() -> MutexGuard<'static, DramSim> {
+	DRAMSIM.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Sets up the pipes the emulator talks to DRAMSim through.
+///
+/// # Arguments
+/// * `request_pipe`
+/// * `response_pipe`
+/// * `timeout` Time to wait for DRAMSim to open the pipe or respond
+pub fn setup_pipe(
+	request_pipe: &str,
+	response_pipe: &str,
+	timeout: Duration,
+) -> Result<(), DramSimError> {
+	let pipe = DramSimPipe::open(request_pipe, response_pipe, timeout)?;
+	lock_dramsim().backend = Some(DramSimBackend::Pipe(pipe));
+	Ok(())
+}
+
+/// Answers the requests from a log recorded by `setup_recorder()`
+/// instead of DRAMSim.
+///
+/// # Arguments
+/// * `path` Log file path
+pub fn setup_replay(path: &str) -> Result<(), DramSimError> {
+	let replay = DramSimReplay::open(path)?;
+	lock_dramsim().backend = Some(DramSimBackend::Replay(replay));
+	Ok(())
+}
+
+/// Records every request and its response to a log which
+/// `setup_replay()` reads.
+///
+/// # Arguments
+/// * `path` Log file path
+pub fn setup_recorder(path: &str) -> Result<(), DramSimError> {
+	lock_dramsim().recorder = Some(LineWriter::new(File::create(path)?));
+	Ok(())
+}
+
+/// Sends a request to DRAMSim.
+///
+/// # Arguments
+/// * `trace_str` e.g. `0000000083000000 READ 100`
+pub fn send_request(trace_str: &str) -> Result<(), DramSimError> {
+	let mut dramsim = lock_dramsim();
+	match dramsim.backend.as_mut() {
+		Some(DramSimBackend::Pipe(pipe)) => pipe.send_request(trace_str)?,
+		Some(DramSimBackend::Replay(replay)) => replay.send_request(trace_str)?,
+		None => return Err(DramSimError::NotConnected),
+	};
+	dramsim.request = Some(trace_str.to_string());
+	Ok(())
+}
+
+/// Waits for a response from DRAMSim.
+pub fn get_response() -> Result<DramSimResponse, DramSimError> {
+	let mut dramsim = lock_dramsim();
+	let response = match dramsim.backend.as_mut() {
+		Some(DramSimBackend::Pipe(pipe)) => pipe.receive_response()?,
+		Some(DramSimBackend::Replay(replay)) => replay.receive_response()?,
+		None => return Err(DramSimError::NotConnected),
+	};
+	let request = dramsim.request.take().unwrap_or_default();
+	if let Some(recorder) = dramsim.recorder.as_mut() {
+		writeln!(
+			recorder,
+			"{} -> {:016x} {}",
+			request, response.address, response.cycle
+		)?;
+	}
+	Ok(response)
+}
+
+/// Sends `END` to DRAMSim and closes the pipes. On replay, the log
+/// must have been consumed.
+///
+/// # Arguments
+/// * `cycle` Cycle the emulation ends
+pub fn terminate_pipe(cycle: u64) -> Result<(), DramSimError> {
+	let mut dramsim = lock_dramsim();
+	dramsim.request = None;
+	if let Some(mut recorder) = dramsim.recorder.take() {
+		recorder.flush()?;
+	}
+	match dramsim.backend.take() {
+		Some(DramSimBackend::Pipe(mut pipe)) => {
+			pipe.send_request(&format!("{:016x} END {}", 0xffff_ffff_ffff_ffffu64, cycle))
+		}
+		Some(DramSimBackend::Replay(replay)) => match replay.records.front() {
+			None => Ok(()),
+			Some((expected, _)) => Err(DramSimError::Divergence {
+				request_num: replay.request_num,
+				expected: Some(expected.clone()),
+				actual: "END".to_string(),
+			}),
+		},
+		None => Err(DramSimError::NotConnected),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::env::temp_dir;
+	use std::fs;
+
+	const LOG: &str = "# Recorded by setup_recorder()\n\
+		0000000080010000 READ 6 -> 0000000080010000 56\n\
+		\n\
+		0000000080010040 WRITE 62 -> 0000000080010040 112 # Write back\n";
+
+	#[test]
+	fn replay_parse() {
+		let mut replay = DramSimReplay::parse(LOG).unwrap();
+		assert_eq!(replay.get_remaining_num(), 2);
+		replay.send_request("0000000080010000 READ 6").unwrap();
+		assert_eq!(
+			replay.receive_response().unwrap(),
+			DramSimResponse {
+				address: 0x80010000,
+				cycle: 56,
+			}
+		);
+		replay.send_request("0000000080010040 WRITE 62").unwrap();
+		assert_eq!(replay.receive_response().unwrap().cycle, 112);
+		assert_eq!(replay.get_remaining_num(), 0);
+		// Only one response per request
+		assert!(matches!(
+			replay.receive_response(),
+			Err(DramSimError::InvalidResponse(_))
+		));
+	}
+
+	#[test]
+	fn replay_divergence() {
+		let mut replay = DramSimReplay::parse(LOG).unwrap();
+		replay.send_request("0000000080010000 READ 6").unwrap();
+		match replay.send_request("0000000080010040 READ 62") {
+			Err(DramSimError::Divergence {
+				request_num,
+				expected,
+				actual,
+			}) => {
+				assert_eq!(request_num, 1);
+				assert_eq!(expected.as_deref(), Some("0000000080010040 WRITE 62"));
+				assert_eq!(actual, "0000000080010040 READ 62");
+			}
+			result => panic!("Unexpected {:?}", result),
+		};
+
+		// Requests beyond the log
+		let mut replay = DramSimReplay::parse("").unwrap();
+		match replay.send_request("0000000080010000 READ 6") {
+			Err(DramSimError::Divergence {
+				request_num: 0,
+				expected: None,
+				..
+			}) => {}
+			result => panic!("Unexpected {:?}", result),
+		};
+	}
+
+	#[test]
+	fn replay_invalid_log() {
+		let contents = [
+			"0000000080010000 READ 6 -> 0000000080010000 56",
+			"# Comment",
+			"0000000080010040 READ 62 0000000080010040 112",
+		]
+		.join("\n");
+		match DramSimReplay::parse(&contents) {
+			Err(DramSimError::InvalidLog { line: 3, .. }) => {}
+			result => panic!("Unexpected {:?}", result.err()),
+		};
+		let contents = "\n0000000080010000 READ 6 -> 0000000080010000 cycle\n";
+		match DramSimReplay::parse(contents) {
+			Err(DramSimError::InvalidLog { line: 2, .. }) => {}
+			result => panic!("Unexpected {:?}", result.err()),
+		};
+	}
+
+	#[test]
+	fn pipe_times_out_when_full() {
+		let directory = temp_dir().join(format!("riscv_emu_dram_test_{}", std::process::id()));
+		fs::create_dir_all(&directory).unwrap();
+		let request_path = directory.join("request");
+		let response_path = directory.join("response");
+		let request_path = request_path.to_str().unwrap();
+		let response_path = response_path.to_str().unwrap();
+
+		// Nobody opens the read end
+		match DramSimPipe::open(request_path, response_path, Duration::from_millis(10)) {
+			Err(DramSimError::Timeout) => {}
+			_ => panic!("Opened without reader"),
+		}
+
+		// DRAMSim which never reads the requests
+		let _reader = OpenOptions::new()
+			.read(true)
+			.custom_flags(libc::O_NONBLOCK)
+			.open(request_path)
+			.unwrap();
+		let mut pipe =
+			DramSimPipe::open(request_path, response_path, Duration::from_millis(10)).unwrap();
+		let mut sent_num = 0;
+		let result = loop {
+			match pipe.send_request("0000000080000000 READ 1") {
+				Ok(()) => sent_num += 1,
+				Err(e) => break e,
+			}
+		};
+		match result {
+			DramSimError::Timeout => {}
+			e => panic!("Unexpected error {}", e),
+		}
+		assert!(sent_num > 0);
+		match pipe.receive_response() {
+			Err(DramSimError::Timeout) => {}
+			_ => panic!("Received response from nobody"),
+		}
+		fs::remove_dir_all(&directory).unwrap();
+	}
 }
@@ -0,0 +1,598 @@
+use checkpoint::{CheckpointError, CheckpointReader, CheckpointWriter};
+
+/// Default DDR timing parameters in DRAM clock cycles (DDR3-1600 11-11-11-28)
+pub const DRAM_T_RCD: u64 = 11;
+pub const DRAM_T_CAS: u64 = 11;
+pub const DRAM_T_RP: u64 = 11;
+pub const DRAM_T_RAS: u64 = 28;
+/// Cycles to transfer a cache line on the data bus (BL8)
+pub const DRAM_T_BURST: u64 = 4;
+
+/// Default CPU clock cycles per DRAM clock cycle
+pub const DRAM_CLOCK_RATIO: u64 = 3;
+
+/// Bytes of an access, which is a cache line
+pub const DRAM_LINE_SIZE: u64 = 64;
+
+/// DDR timing parameters in DRAM clock cycles
+#[derive(Clone, Debug)]
+pub struct DramTiming {
+	/// ACTIVATE to READ/WRITE
+	pub t_rcd: u64,
+	/// READ/WRITE to data
+	pub t_cas: u64,
+	/// PRECHARGE to ACTIVATE
+	pub t_rp: u64,
+	/// ACTIVATE to PRECHARGE
+	pub t_ras: u64,
+	/// Data transfer of a burst
+	pub t_burst: u64,
+}
+
+impl Default for DramTiming {
+	fn default() -> Self {
+		DramTiming {
+			t_rcd: DRAM_T_RCD,
+			t_cas: DRAM_T_CAS,
+			t_rp: DRAM_T_RP,
+			t_ras: DRAM_T_RAS,
+			t_burst: DRAM_T_BURST,
+		}
+	}
+}
+
+/// Row buffer management policy
+#[derive(Clone, Debug, PartialEq)]
+pub enum PagePolicy {
+	/// Keeps the row open after access so that following accesses to the
+	/// same row hit in the row buffer
+	Open,
+	/// Precharges the bank right after every access
+	Close,
+}
+
+/// Physical address to DRAM location mapping. Fields are listed from the
+/// most significant bits.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AddressMapping {
+	/// `row:rank:bank:channel:column`. Sequential accesses stay in a row.
+	RowBankColumn,
+	/// `RowBankColumn` with bank index XORed with the lower row bits, which
+	/// spreads rows of the same bank index over the banks to avoid conflicts
+	/// of strided accesses. Bank number must be power of two.
+	BankXor,
+	/// `row:rank:bank:column:channel:line offset`. Sequential cache lines
+	/// go to different channels.
+	ChannelInterleave,
+}
+
+/// DRAM organization and timing
+#[derive(Clone, Debug)]
+pub struct DramConfig {
+	pub channel_num: u64,
+	/// Ranks per channel
+	pub rank_num: u64,
+	/// Banks per rank
+	pub bank_num: u64,
+	/// Row size in bytes. Must be power of two.
+	pub row_size: u64,
+	pub timing: DramTiming,
+	pub page_policy: PagePolicy,
+	pub mapping: AddressMapping,
+	/// CPU clock cycles per DRAM clock cycle
+	pub clock_ratio: u64,
+}
+
+impl Default for DramConfig {
+	fn default() -> Self {
+		DramConfig {
+			channel_num: 1,
+			rank_num: 1,
+			bank_num: 8,
+			row_size: 8192,
+			timing: DramTiming::default(),
+			page_policy: PagePolicy::Open,
+			mapping: AddressMapping::RowBankColumn,
+			clock_ratio: DRAM_CLOCK_RATIO,
+		}
+	}
+}
+
+/// Location of a physical address in DRAM
+#[derive(Clone, Debug, PartialEq)]
+pub struct DramAddress {
+	pub channel: u64,
+	pub rank: u64,
+	pub bank: u64,
+	pub row: u64,
+	pub column: u64,
+}
+
+#[derive(Clone, Default)]
+struct Bank {
+	open_row: Option<u64>,
+	/// The earliest DRAM cycle the bank accepts the next command
+	ready_cycle: u64,
+	/// DRAM cycle the open row was activated
+	activate_cycle: u64,
+}
+
+/// Cycle-level DRAM timing model. Each bank has a row buffer, and each
+/// channel has a data bus shared by its ranks and banks. An access waits
+/// for the bank and the data bus, and the latency depends on whether it
+/// hits the open row, finds the bank precharged, or conflicts with another
+/// open row.
+pub struct DramModel {
+	config: DramConfig,
+	banks: Vec<Bank>,
+	/// The earliest DRAM cycle the data bus of each channel is free
+	bus_ready_cycles: Vec<u64>,
+	pub read_num: u64,
+	pub write_num: u64,
+	/// Accesses to the open row
+	pub row_hit_num: u64,
+	/// Accesses to precharged bank
+	pub row_empty_num: u64,
+	/// Accesses to a row other than the open one
+	pub row_conflict_num: u64,
+	/// Accesses per bank, indexed by `(channel * rank_num + rank) * bank_num + bank`
+	pub bank_access_nums: Vec<u64>,
+	/// Row conflicts per bank, indexed as `bank_access_nums`
+	pub bank_conflict_nums: Vec<u64>,
+}
+
+impl DramModel {
+	/// Creates a new `DramModel`. Returns error if the organization is
+	/// invalid.
+	///
+	/// # Arguments
+	/// * `config`
+	pub fn new(config: DramConfig) -> Result<Self, String> {
+		if !config.row_size.is_power_of_two() {
+			return Err(format!(
+				"DRAM row size must be power of two. {}",
+				config.row_size
+			));
+		}
+		if config.channel_num == 0 || config.rank_num == 0 || config.bank_num == 0 {
+			return Err("DRAM needs at least one channel, rank, and bank".to_string());
+		}
+		if config.clock_ratio == 0 {
+			return Err("DRAM clock ratio must be positive".to_string());
+		}
+		if config.mapping == AddressMapping::BankXor && !config.bank_num.is_power_of_two() {
+			return Err(format!(
+				"DRAM bank number must be power of two for bank XOR mapping. {}",
+				config.bank_num
+			));
+		}
+		if config.mapping == AddressMapping::ChannelInterleave && config.row_size < DRAM_LINE_SIZE {
+			return Err("DRAM row must hold a cache line for channel interleaving".to_string());
+		}
+		let bank_num = (config.channel_num * config.rank_num * config.bank_num) as usize;
+		Ok(DramModel {
+			banks: vec![Bank::default(); bank_num],
+			bus_ready_cycles: vec![0; config.channel_num as usize],
+			config,
+			read_num: 0,
+			write_num: 0,
+			row_hit_num: 0,
+			row_empty_num: 0,
+			row_conflict_num: 0,
+			bank_access_nums: vec![0; bank_num],
+			bank_conflict_nums: vec![0; bank_num],
+		})
+	}
+
+	pub fn get_config(&self) -> &DramConfig {
+		&self.config
+	}
+
+	/// Maps a physical address to DRAM location with the configured
+	/// `AddressMapping`.
+	///
+	/// # Arguments
+	/// * `p_address` Physical address
+	pub fn decode_address(&self, p_address: u64) -> DramAddress {
+		let config = &self.config;
+		let (channel, column, mut rest) = match config.mapping {
+			AddressMapping::RowBankColumn | AddressMapping::BankXor => {
+				let rest = p_address / config.row_size;
+				(
+					rest % config.channel_num,
+					p_address & (config.row_size - 1),
+					rest / config.channel_num,
+				)
+			}
+			AddressMapping::ChannelInterleave => {
+				let line_num = p_address / DRAM_LINE_SIZE;
+				let row_line_num = config.row_size / DRAM_LINE_SIZE;
+				let rest = line_num / config.channel_num;
+				(
+					line_num % config.channel_num,
+					(rest % row_line_num) * DRAM_LINE_SIZE + (p_address & (DRAM_LINE_SIZE - 1)),
+					rest / row_line_num,
+				)
+			}
+		};
+		let mut bank = rest % config.bank_num;
+		rest /= config.bank_num;
+		let rank = rest % config.rank_num;
+		let row = rest / config.rank_num;
+		if config.mapping == AddressMapping::BankXor {
+			bank ^= row & (config.bank_num - 1);
+		}
+		DramAddress {
+			channel,
+			rank,
+			bank,
+			row,
+			column,
+		}
+	}
+
+	/// Returns the index of the bank for the per-bank statistics.
+	///
+	/// # Arguments
+	/// * `address` DRAM location
+	pub fn get_bank_index(&self, address: &DramAddress) -> usize {
+		((address.channel * self.config.rank_num + address.rank) * self.config.bank_num
+			+ address.bank) as usize
+	}
+
+	/// Returns true if an access to a physical address hits the open row.
+	///
+	/// # Arguments
+	/// * `p_address` Physical address
+	pub fn is_row_hit(&self, p_address: u64) -> bool {
+		let address = self.decode_address(p_address);
+		self.banks[self.get_bank_index(&address)].open_row == Some(address.row)
+	}
+
+	/// Accesses a cache line and returns the CPU cycle the access completes.
+	///
+	/// # Arguments
+	/// * `p_address` Physical address
+	/// * `is_write`
+	/// * `cycle` CPU cycle the request arrives
+	pub fn access(&mut self, p_address: u64, is_write: bool, cycle: u64) -> u64 {
+		let address = self.decode_address(p_address);
+		let timing = self.config.timing.clone();
+		let index = self.get_bank_index(&address);
+		let bank = &mut self.banks[index];
+		self.bank_access_nums[index] += 1;
+
+		let now = cycle.div_ceil(self.config.clock_ratio);
+		let start = now.max(bank.ready_cycle);
+		let column_cycle = match bank.open_row {
+			Some(row) if row == address.row => {
+				self.row_hit_num += 1;
+				start
+			}
+			Some(_) => {
+				self.row_conflict_num += 1;
+				self.bank_conflict_nums[index] += 1;
+				let precharge_cycle = start.max(bank.activate_cycle + timing.t_ras);
+				bank.activate_cycle = precharge_cycle + timing.t_rp;
+				bank.activate_cycle + timing.t_rcd
+			}
+			None => {
+				self.row_empty_num += 1;
+				bank.activate_cycle = start;
+				start + timing.t_rcd
+			}
+		};
+
+		let bus_ready_cycle = &mut self.bus_ready_cycles[address.channel as usize];
+		let data_cycle = (column_cycle + timing.t_cas).max(*bus_ready_cycle);
+		let done_cycle = data_cycle + timing.t_burst;
+		*bus_ready_cycle = done_cycle;
+
+		match self.config.page_policy {
+			PagePolicy::Open => {
+				bank.open_row = Some(address.row);
+				bank.ready_cycle = column_cycle + timing.t_burst;
+			}
+			PagePolicy::Close => {
+				// Auto precharge
+				let precharge_cycle = done_cycle.max(bank.activate_cycle + timing.t_ras);
+				bank.open_row = None;
+				bank.ready_cycle = precharge_cycle + timing.t_rp;
+			}
+		};
+
+		match is_write {
+			true => self.write_num += 1,
+			false => self.read_num += 1,
+		};
+		done_cycle * self.config.clock_ratio
+	}
+
+	/// Returns the ratio of accesses hitting the open row, or zero without
+	/// accesses.
+	pub fn get_row_hit_rate(&self) -> f64 {
+		let access_num = self.row_hit_num + self.row_empty_num + self.row_conflict_num;
+		match access_num {
+			0 => 0.0,
+			_ => self.row_hit_num as f64 / access_num as f64,
+		}
+	}
+
+	/// Saves the state to checkpoint.
+	///
+	/// # Arguments
+	/// * `writer`
+	pub fn save_state(&self, writer: &mut CheckpointWriter) {
+		writer.write_u64(self.banks.len() as u64);
+		for bank in self.banks.iter() {
+			writer.write_bool(bank.open_row.is_some());
+			writer.write_u64(bank.open_row.unwrap_or(0));
+			writer.write_u64(bank.ready_cycle);
+			writer.write_u64(bank.activate_cycle);
+		}
+		writer.write_u64(self.bus_ready_cycles.len() as u64);
+		writer.write_u64s(&self.bus_ready_cycles);
+		writer.write_u64(self.read_num);
+		writer.write_u64(self.write_num);
+		writer.write_u64(self.row_hit_num);
+		writer.write_u64(self.row_empty_num);
+		writer.write_u64(self.row_conflict_num);
+		writer.write_u64s(&self.bank_access_nums);
+		writer.write_u64s(&self.bank_conflict_nums);
+	}
+
+	/// Restores the state saved by `save_state()`. The model needs to have
+	/// the same organization.
+	///
+	/// # Arguments
+	/// * `reader`
+	pub fn restore_state(&mut self, reader: &mut CheckpointReader) -> Result<(), CheckpointError> {
+		reader.expect_u64(self.banks.len() as u64, "DRAM bank number")?;
+		for bank in self.banks.iter_mut() {
+			let is_open = reader.read_bool()?;
+			let row = reader.read_u64()?;
+			bank.open_row = match is_open {
+				true => Some(row),
+				false => None,
+			};
+			bank.ready_cycle = reader.read_u64()?;
+			bank.activate_cycle = reader.read_u64()?;
+		}
+		reader.expect_u64(self.bus_ready_cycles.len() as u64, "DRAM channel number")?;
+		reader.read_u64s(&mut self.bus_ready_cycles)?;
+		self.read_num = reader.read_u64()?;
+		self.write_num = reader.read_u64()?;
+		self.row_hit_num = reader.read_u64()?;
+		self.row_empty_num = reader.read_u64()?;
+		self.row_conflict_num = reader.read_u64()?;
+		reader.read_u64s(&mut self.bank_access_nums)?;
+		reader.read_u64s(&mut self.bank_conflict_nums)?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Rows of the same bank are this far apart with the default
+	/// configuration.
+	const ROW_STRIDE: u64 = 8192 * 8;
+
+	fn create_dram_model(page_policy: PagePolicy, channel_num: u64) -> DramModel {
+		DramModel::new(DramConfig {
+			page_policy,
+			channel_num,
+			..DramConfig::default()
+		})
+		.unwrap()
+	}
+
+	/// Accesses at `cycle` and returns the latency in CPU cycles
+	fn get_latency(dram_model: &mut DramModel, p_address: u64, cycle: u64) -> u64 {
+		dram_model.access(p_address, false, cycle) - cycle
+	}
+
+	#[test]
+	fn rejects_invalid_config() {
+		let configs = [
+			DramConfig {
+				row_size: 8000,
+				..DramConfig::default()
+			},
+			DramConfig {
+				channel_num: 0,
+				..DramConfig::default()
+			},
+			DramConfig {
+				rank_num: 0,
+				..DramConfig::default()
+			},
+			DramConfig {
+				bank_num: 0,
+				..DramConfig::default()
+			},
+			DramConfig {
+				clock_ratio: 0,
+				..DramConfig::default()
+			},
+			DramConfig {
+				bank_num: 6,
+				mapping: AddressMapping::BankXor,
+				..DramConfig::default()
+			},
+			DramConfig {
+				row_size: 32,
+				mapping: AddressMapping::ChannelInterleave,
+				..DramConfig::default()
+			},
+		];
+		for config in configs.iter() {
+			assert!(DramModel::new(config.clone()).is_err(), "{:?}", config);
+		}
+		// Bank number needs to be power of two only for bank XOR
+		let config = DramConfig {
+			bank_num: 6,
+			..DramConfig::default()
+		};
+		assert!(DramModel::new(config).is_ok());
+	}
+
+	#[test]
+	fn open_page_latency_depends_on_row_buffer() {
+		let mut dram_model = create_dram_model(PagePolicy::Open, 1);
+		// Accesses far apart not to wait for the previous one
+		let empty = get_latency(&mut dram_model, 0, 0);
+		let hit = get_latency(&mut dram_model, 0x40, 3000);
+		let conflict = get_latency(&mut dram_model, ROW_STRIDE, 6000);
+		assert!(hit < empty && empty < conflict);
+		let timing = DramTiming::default();
+		assert_eq!(hit, (timing.t_cas + timing.t_burst) * DRAM_CLOCK_RATIO);
+		assert_eq!(empty, hit + timing.t_rcd * DRAM_CLOCK_RATIO);
+		assert_eq!(conflict, empty + timing.t_rp * DRAM_CLOCK_RATIO);
+		assert_eq!(dram_model.row_hit_num, 1);
+		assert_eq!(dram_model.row_empty_num, 1);
+		assert_eq!(dram_model.row_conflict_num, 1);
+		assert!(dram_model.is_row_hit(ROW_STRIDE + 0x80));
+		assert!(!dram_model.is_row_hit(0));
+		assert_eq!(dram_model.read_num, 3);
+	}
+
+	#[test]
+	fn close_page_never_hits() {
+		let mut dram_model = create_dram_model(PagePolicy::Close, 1);
+		let latency = get_latency(&mut dram_model, 0, 0);
+		for (i, address) in [0x40, 0x80, ROW_STRIDE, 0].iter().enumerate() {
+			let cycle = (i as u64 + 1) * 3000;
+			assert_eq!(get_latency(&mut dram_model, *address, cycle), latency);
+			assert!(!dram_model.is_row_hit(*address));
+		}
+		assert_eq!(dram_model.row_hit_num, 0);
+		assert_eq!(dram_model.row_conflict_num, 0);
+		assert_eq!(dram_model.row_empty_num, 5);
+		assert_eq!(dram_model.get_row_hit_rate(), 0.0);
+	}
+
+	#[test]
+	fn data_bus_serializes_banks_on_a_channel() {
+		// Bank 0 and bank 1 of a channel
+		let mut dram_model = create_dram_model(PagePolicy::Open, 1);
+		let first = dram_model.access(0, false, 0);
+		let second = dram_model.access(8192, true, 0);
+		assert_eq!(second, first + DRAM_T_BURST * DRAM_CLOCK_RATIO);
+		assert_eq!((dram_model.read_num, dram_model.write_num), (1, 1));
+
+		// Channels have their own data bus
+		let mut dram_model = create_dram_model(PagePolicy::Open, 2);
+		assert_ne!(
+			dram_model.decode_address(0).channel,
+			dram_model.decode_address(8192).channel
+		);
+		let first = dram_model.access(0, false, 0);
+		let second = dram_model.access(8192, false, 0);
+		assert_eq!(second, first);
+	}
+
+	fn create_mapped_dram_model(mapping: AddressMapping, channel_num: u64) -> DramModel {
+		DramModel::new(DramConfig {
+			mapping,
+			channel_num,
+			..DramConfig::default()
+		})
+		.unwrap()
+	}
+
+	#[test]
+	fn row_bank_column_keeps_lines_in_row() {
+		let dram_model = create_mapped_dram_model(AddressMapping::RowBankColumn, 2);
+		let row_size = dram_model.get_config().row_size;
+		for column in (0..row_size).step_by(DRAM_LINE_SIZE as usize) {
+			assert_eq!(
+				dram_model.decode_address(column),
+				DramAddress {
+					channel: 0,
+					rank: 0,
+					bank: 0,
+					row: 0,
+					column,
+				}
+			);
+		}
+		// The next row sized block goes to the other channel, then the
+		// next bank
+		assert_eq!(dram_model.decode_address(row_size).channel, 1);
+		assert_eq!(dram_model.decode_address(row_size).bank, 0);
+		assert_eq!(dram_model.decode_address(2 * row_size).channel, 0);
+		assert_eq!(dram_model.decode_address(2 * row_size).bank, 1);
+	}
+
+	#[test]
+	fn channel_interleave_alternates_channels() {
+		let dram_model = create_mapped_dram_model(AddressMapping::ChannelInterleave, 2);
+		let row_size = dram_model.get_config().row_size;
+		for line in 0..2 * row_size / DRAM_LINE_SIZE {
+			assert_eq!(
+				dram_model.decode_address(line * DRAM_LINE_SIZE + 8),
+				DramAddress {
+					channel: line % 2,
+					rank: 0,
+					bank: 0,
+					row: 0,
+					column: line / 2 * DRAM_LINE_SIZE + 8,
+				}
+			);
+		}
+		assert_eq!(dram_model.decode_address(2 * row_size).bank, 1);
+	}
+
+	#[test]
+	fn bank_xor_spreads_row_stride() {
+		let plain = create_mapped_dram_model(AddressMapping::RowBankColumn, 1);
+		let xor = create_mapped_dram_model(AddressMapping::BankXor, 1);
+		let mut banks = vec![];
+		for row in 0..8 {
+			let address = plain.decode_address(row * ROW_STRIDE);
+			assert_eq!((address.bank, address.row), (0, row));
+			let address = xor.decode_address(row * ROW_STRIDE);
+			assert_eq!(address.row, row);
+			banks.push(address.bank);
+		}
+		banks.sort();
+		assert_eq!(banks, (0..8).collect::<Vec<u64>>());
+	}
+
+	#[test]
+	fn counts_accesses_per_bank() {
+		let mut dram_model = create_mapped_dram_model(AddressMapping::RowBankColumn, 2);
+		let row_size = dram_model.get_config().row_size;
+		let bank_stride = 2 * row_size;
+		// Channel 0 bank 0: empty then conflict
+		dram_model.access(0, false, 0);
+		dram_model.access(2 * ROW_STRIDE, true, 0);
+		// Channel 0 bank 1: empty then hit
+		dram_model.access(bank_stride, false, 0);
+		dram_model.access(bank_stride + DRAM_LINE_SIZE, false, 0);
+		// Channel 1 bank 0: empty
+		dram_model.access(row_size, false, 0);
+
+		let index = dram_model.get_bank_index(&dram_model.decode_address(row_size));
+		assert_eq!(index, 8);
+		let mut access_nums = vec![0; 16];
+		access_nums[0] = 2;
+		access_nums[1] = 2;
+		access_nums[8] = 1;
+		let mut conflict_nums = vec![0; 16];
+		conflict_nums[0] = 1;
+		assert_eq!(dram_model.bank_access_nums, access_nums);
+		assert_eq!(dram_model.bank_conflict_nums, conflict_nums);
+		assert_eq!(
+			(
+				dram_model.row_hit_num,
+				dram_model.row_empty_num,
+				dram_model.row_conflict_num
+			),
+			(1, 3, 1)
+		);
+		assert_eq!((dram_model.read_num, dram_model.write_num), (4, 1));
+	}
+}
@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+
+/// phandle of the interrupt controller in CPU node
+pub const FDT_CPU_INTC_PHANDLE: u32 = 1;
+
+/// phandle of PLIC, used as `interrupt-parent` of devices
+pub const FDT_PLIC_PHANDLE: u32 = 2;
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+const FDT_HEADER_SIZE: usize = 40;
+/// Empty memory reservation block, one terminating entry
+const FDT_MEM_RSVMAP_SIZE: usize = 16;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_END: u32 = 9;
+
+/// Builds flattened device tree blob (DTB) passed to the guest.
+/// Nodes and properties are written in order. Unbalanced nodes are
+/// reported by [`finish`](#method.finish).
+pub struct FdtBuilder {
+	structure: Vec<u8>,
+	strings: Vec<u8>,
+	string_offsets: HashMap<String, u32>,
+	depth: usize,
+	/// Set if a node is ended without a node to end
+	unmatched_end: bool,
+}
+
+impl Default for FdtBuilder {
+	fn default() -> Self {
+		FdtBuilder::new()
+	}
+}
+
+impl FdtBuilder {
+	/// Creates a new `FdtBuilder`.
+	pub fn new() -> Self {
+		FdtBuilder {
+			structure: vec![],
+			strings: vec![],
+			string_offsets: HashMap::new(),
+			depth: 0,
+			unmatched_end: false,
+		}
+	}
+
+	fn push_u32(&mut self, value: u32) {
+		self.structure.extend_from_slice(&value.to_be_bytes());
+	}
+
+	fn align_structure(&mut self) {
+		while (self.structure.len() & 3) != 0 {
+			self.structure.push(0);
+		}
+	}
+
+	fn get_string_offset(&mut self, name: &str) -> u32 {
+		if let Some(offset) = self.string_offsets.get(name) {
+			return *offset;
+		}
+		let offset = self.strings.len() as u32;
+		self.strings.extend_from_slice(name.as_bytes());
+		self.strings.push(0);
+		self.string_offsets.insert(name.to_string(), offset);
+		offset
+	}
+
+	/// Begins a node. The root node name is empty.
+	///
+	/// # Arguments
+	/// * `name` e.g. "uart@10000000"
+	pub fn begin_node(&mut self, name: &str) {
+		self.push_u32(FDT_BEGIN_NODE);
+		self.structure.extend_from_slice(name.as_bytes());
+		self.structure.push(0);
+		self.align_structure();
+		self.depth += 1;
+	}
+
+	/// Ends the current node. Ending with no node to end is ignored and
+	/// makes [`finish`](#method.finish) return error.
+	pub fn end_node(&mut self) {
+		match self.depth > 0 {
+			true => {
+				self.push_u32(FDT_END_NODE);
+				self.depth -= 1;
+			}
+			false => self.unmatched_end = true,
+		}
+	}
+
+	/// Adds a property with raw value to the current node.
+	///
+	/// # Arguments
+	/// * `name`
+	/// * `value`
+	pub fn property(&mut self, name: &str, value: &[u8]) {
+		let name_offset = self.get_string_offset(name);
+		self.push_u32(FDT_PROP);
+		self.push_u32(value.len() as u32);
+		self.push_u32(name_offset);
+		self.structure.extend_from_slice(value);
+		self.align_structure();
+	}
+
+	/// Adds an empty property, e.g. "interrupt-controller".
+	///
+	/// # Arguments
+	/// * `name`
+	pub fn property_null(&mut self, name: &str) {
+		self.property(name, &[]);
+	}
+
+	/// Adds a string property.
+	///
+	/// # Arguments
+	/// * `name`
+	/// * `value`
+	pub fn property_string(&mut self, name: &str, value: &str) {
+		let mut data = value.as_bytes().to_vec();
+		data.push(0);
+		self.property(name, &data);
+	}
+
+	/// Adds a string list property, e.g. "compatible" with fallbacks.
+	///
+	/// # Arguments
+	/// * `name`
+	/// * `values`
+	pub fn property_strings(&mut self, name: &str, values: &[&str]) {
+		let mut data = vec![];
+		for value in values.iter() {
+			data.extend_from_slice(value.as_bytes());
+			data.push(0);
+		}
+		self.property(name, &data);
+	}
+
+	/// Adds a single cell property.
+	///
+	/// # Arguments
+	/// * `name`
+	/// * `value`
+	pub fn property_u32(&mut self, name: &str, value: u32) {
+		self.property_cells(name, &[value]);
+	}
+
+	/// Adds a property of cells.
+	///
+	/// # Arguments
+	/// * `name`
+	/// * `cells`
+	pub fn property_cells(&mut self, name: &str, cells: &[u32]) {
+		let mut data = vec![];
+		for cell in cells.iter() {
+			data.extend_from_slice(&cell.to_be_bytes());
+		}
+		self.property(name, &data);
+	}
+
+	/// Adds "reg" property of an address range with two address cells
+	/// and two size cells.
+	///
+	/// # Arguments
+	/// * `base`
+	/// * `size`
+	pub fn property_reg(&mut self, base: u64, size: u64) {
+		self.property_cells(
+			"reg",
+			&[
+				(base >> 32) as u32,
+				base as u32,
+				(size >> 32) as u32,
+				size as u32,
+			],
+		);
+	}
+
+	/// Finishes building and returns the blob. Returns error if nodes
+	/// are not balanced.
+	pub fn finish(mut self) -> Result<Vec<u8>, String> {
+		if self.unmatched_end {
+			return Err("No node to end in device tree".to_string());
+		}
+		if self.depth != 0 {
+			return Err(format!("{} device tree nodes are not ended", self.depth));
+		}
+		self.push_u32(FDT_END);
+
+		let off_mem_rsvmap = FDT_HEADER_SIZE;
+		let off_dt_struct = off_mem_rsvmap + FDT_MEM_RSVMAP_SIZE;
+		let off_dt_strings = off_dt_struct + self.structure.len();
+		let total_size = off_dt_strings + self.strings.len();
+
+		let mut blob = vec![];
+		for value in [
+			FDT_MAGIC,
+			total_size as u32,
+			off_dt_struct as u32,
+			off_dt_strings as u32,
+			off_mem_rsvmap as u32,
+			FDT_VERSION,
+			FDT_LAST_COMP_VERSION,
+			0, // boot_cpuid_phys
+			self.strings.len() as u32,
+			self.structure.len() as u32,
+		]
+		.iter()
+		{
+			blob.extend_from_slice(&value.to_be_bytes());
+		}
+		blob.extend_from_slice(&[0; FDT_MEM_RSVMAP_SIZE]);
+		blob.extend_from_slice(&self.structure);
+		blob.extend_from_slice(&self.strings);
+		Ok(blob)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn rejects_unbalanced_nodes() {
+		let mut fdt = FdtBuilder::new();
+		fdt.begin_node("");
+		fdt.begin_node("soc");
+		fdt.end_node();
+		assert!(fdt.finish().is_err());
+
+		let mut fdt = FdtBuilder::new();
+		fdt.begin_node("");
+		fdt.end_node();
+		fdt.end_node();
+		assert!(fdt.finish().is_err());
+
+		let mut fdt = FdtBuilder::new();
+		fdt.begin_node("");
+		fdt.end_node();
+		assert!(fdt.finish().is_ok());
+	}
+}
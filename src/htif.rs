@@ -0,0 +1,658 @@
+use checkpoint::{CheckpointError, CheckpointReader, CheckpointWriter};
+use device::DmaMemory;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{stderr, stdin, stdout, Error, Read, Write};
+use terminal::Terminal;
+
+// Devices and commands encoded in tohost
+const HTIF_DEVICE_SYSCALL: u64 = 0;
+const HTIF_DEVICE_CONSOLE: u64 = 1;
+const HTIF_CONSOLE_GETCHAR: u64 = 0;
+const HTIF_CONSOLE_PUTCHAR: u64 = 1;
+
+// Syscall numbers proxied by riscv-pk
+const SYS_OPENAT: u64 = 56;
+const SYS_CLOSE: u64 = 57;
+const SYS_READ: u64 = 63;
+const SYS_WRITE: u64 = 64;
+const SYS_FSTAT: u64 = 80;
+const SYS_EXIT: u64 = 93;
+const SYS_EXIT_GROUP: u64 = 94;
+const SYS_BRK: u64 = 214;
+
+// Open flags of the guest
+const O_ACCMODE: u64 = 3;
+const O_WRONLY: u64 = 1;
+const O_RDONLY: u64 = 0;
+const O_CREAT: u64 = 0x40;
+const O_TRUNC: u64 = 0x200;
+const O_APPEND: u64 = 0x400;
+
+const EIO: i64 = 5;
+const EBADF: i64 = 9;
+const ENAMETOOLONG: i64 = 36;
+const ENOSYS: i64 = 38;
+
+/// The number of words in syscall request, syscall number and arguments
+const MAGIC_MEM_WORDS: u64 = 8;
+
+/// The maximum bytes transferred by a read or write syscall. Lengths come
+/// from the guest, so larger requests are short as POSIX allows.
+const MAX_TRANSFER_SIZE: u64 = 0x100000;
+
+/// The maximum length of a path including the terminating null
+const PATH_MAX: u64 = 4096;
+
+/// Size of `struct stat` of the guest
+const STAT_SIZE: usize = 128;
+const S_IFCHR: u32 = 0o020000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+/// The first file descriptor for files opened by the guest, following
+/// stdin, stdout, and stderr
+const FIRST_FILE_FD: u64 = 3;
+
+/// Emulates HTIF (Host-Target Interface) of riscv-tests and riscv-pk.
+/// The guest writes a command to `tohost` in main memory and the host
+/// responds through `fromhost`. A command is encoded as device (8 bits),
+/// command (8 bits), and payload (48 bits).
+///
+/// * Device 0 is syscall proxy. Odd payload means exit with code
+///   `payload >> 1`, otherwise payload is the address of syscall request.
+/// * Device 1 is console. Command 0 is getchar and command 1 is putchar.
+pub struct Htif {
+	/// Zero if HTIF is disabled
+	tohost_addr: u64,
+	fromhost_addr: u64,
+	/// Console output goes to host stdout if not set
+	terminal: Option<Box<dyn Terminal>>,
+	/// Responses waiting for the guest to clear `fromhost`
+	responses: VecDeque<u64>,
+	/// The number of getchar requests waiting for input
+	getchar_num: u64,
+	/// Files opened by the guest. Index is file descriptor minus `FIRST_FILE_FD`
+	files: Vec<Option<File>>,
+	program_break: u64,
+	exit_code: Option<u64>,
+}
+
+impl Default for Htif {
+	fn default() -> Self {
+		Htif::new()
+	}
+}
+
+impl Htif {
+	/// Creates a new `Htif`. It is disabled until `setup()` is called.
+	pub const fn new() -> Self {
+		Htif {
+			tohost_addr: 0,
+			fromhost_addr: 0,
+			terminal: None,
+			responses: VecDeque::new(),
+			getchar_num: 0,
+			files: vec![],
+			program_break: 0,
+			exit_code: None,
+		}
+	}
+
+	/// Enables HTIF.
+	///
+	/// # Arguments
+	/// * `tohost_addr` Physical address of `tohost`
+	/// * `fromhost_addr` Physical address of `fromhost`
+	pub fn setup(&mut self, tohost_addr: u64, fromhost_addr: u64) {
+		self.tohost_addr = tohost_addr;
+		self.fromhost_addr = fromhost_addr;
+	}
+
+	/// Connects console to `terminal` instead of host stdout and stdin.
+	///
+	/// # Arguments
+	/// * `terminal`
+	pub fn set_terminal(&mut self, terminal: Box<dyn Terminal>) {
+		self.terminal = Some(terminal);
+	}
+
+	/// Sets the initial program break returned by `brk` syscall.
+	///
+	/// # Arguments
+	/// * `address` Usually the end of the program
+	pub fn set_program_break(&mut self, address: u64) {
+		self.program_break = address;
+	}
+
+	/// Returns the exit code if the guest has requested exit.
+	pub fn get_exit_code(&self) -> Option<u64> {
+		self.exit_code
+	}
+
+	/// Handles a command written to `tohost` and sends a pending response
+	/// if `fromhost` is empty.
+	///
+	/// # Arguments
+	/// * `memory`
+	pub fn tick(&mut self, memory: &mut dyn DmaMemory) {
+		if self.tohost_addr == 0 || self.exit_code.is_some() {
+			return;
+		}
+
+		let tohost = memory.read(self.tohost_addr, 8);
+		if tohost != 0 {
+			#[cfg(feature = "debug-tohost")]
+			println!("[Tohost] {:x}", tohost);
+			memory.write(self.tohost_addr, 0, 8);
+			self.handle_command(tohost, memory);
+		}
+
+		if self.getchar_num > 0 {
+			if let Some(value) = self.get_input() {
+				self.getchar_num -= 1;
+				self.responses.push_back(
+					(HTIF_DEVICE_CONSOLE << 56)
+						| (HTIF_CONSOLE_GETCHAR << 48)
+						| 0x100 | value as u64,
+				);
+			}
+		}
+
+		if !self.responses.is_empty() && memory.read(self.fromhost_addr, 8) == 0 {
+			let response = self.responses.pop_front().unwrap();
+			memory.write(self.fromhost_addr, response, 8);
+		}
+	}
+
+	/// Saves the state to checkpoint. Files opened by the guest can't be
+	/// saved and they are closed on restore.
+	///
+	/// # Arguments
+	/// * `writer`
+	pub fn save_state(&self, writer: &mut CheckpointWriter) {
+		writer.write_u64(self.tohost_addr);
+		writer.write_u64(self.fromhost_addr);
+		writer.write_u64(self.responses.len() as u64);
+		for response in self.responses.iter() {
+			writer.write_u64(*response);
+		}
+		writer.write_u64(self.getchar_num);
+		writer.write_u64(self.program_break);
+		writer.write_bool(self.exit_code.is_some());
+		writer.write_u64(self.exit_code.unwrap_or(0));
+	}
+
+	/// Restores the state saved by `save_state()`.
+	///
+	/// # Arguments
+	/// * `reader`
+	pub fn restore_state(&mut self, reader: &mut CheckpointReader) -> Result<(), CheckpointError> {
+		self.tohost_addr = reader.read_u64()?;
+		self.fromhost_addr = reader.read_u64()?;
+		let response_num = reader.read_u64()?;
+		self.responses.clear();
+		for _ in 0..response_num {
+			self.responses.push_back(reader.read_u64()?);
+		}
+		self.getchar_num = reader.read_u64()?;
+		self.files.clear();
+		self.program_break = reader.read_u64()?;
+		let has_exit_code = reader.read_bool()?;
+		let exit_code = reader.read_u64()?;
+		self.exit_code = match has_exit_code {
+			true => Some(exit_code),
+			false => None,
+		};
+		Ok(())
+	}
+
+	fn handle_command(&mut self, tohost: u64, memory: &mut dyn DmaMemory) {
+		let device = tohost >> 56;
+		let command = (tohost >> 48) & 0xff;
+		let payload = tohost & 0xffffffffffff;
+		match (device, command) {
+			(HTIF_DEVICE_SYSCALL, 0) => match (payload & 1) != 0 {
+				true => self.exit_code = Some(payload >> 1),
+				false => {
+					self.handle_syscall(payload, memory);
+					self.responses
+						.push_back((device << 56) | (command << 48) | 1);
+				}
+			},
+			(HTIF_DEVICE_CONSOLE, HTIF_CONSOLE_GETCHAR) => self.getchar_num += 1,
+			(HTIF_DEVICE_CONSOLE, HTIF_CONSOLE_PUTCHAR) => {
+				self.put_output(1, &[payload as u8]);
+				self.responses.push_back((device << 56) | (command << 48));
+			}
+			// Unknown commands are ignored
+			_ => {}
+		}
+	}
+
+	/// Handles a syscall request. The request consists of syscall number
+	/// and arguments, and the return value overwrites syscall number.
+	///
+	/// # Arguments
+	/// * `address` Physical address of the request
+	/// * `memory`
+	fn handle_syscall(&mut self, address: u64, memory: &mut dyn DmaMemory) {
+		let mut args = [0; MAGIC_MEM_WORDS as usize];
+		for (i, arg) in args.iter_mut().enumerate() {
+			*arg = memory.read(address + i as u64 * 8, 8);
+		}
+		let result = match args[0] {
+			SYS_EXIT | SYS_EXIT_GROUP => {
+				self.exit_code = Some(args[1]);
+				0
+			}
+			SYS_READ => self.sys_read(args[1], args[2], args[3], memory),
+			SYS_WRITE => self.sys_write(args[1], args[2], args[3], memory),
+			SYS_OPENAT => self.sys_openat(args[2], args[3], args[4], memory),
+			SYS_CLOSE => self.sys_close(args[1]),
+			SYS_FSTAT => self.sys_fstat(args[1], args[2], memory),
+			SYS_BRK => {
+				if args[1] != 0 {
+					self.program_break = args[1];
+				}
+				self.program_break as i64
+			}
+			_ => -ENOSYS,
+		};
+		memory.write(address, result as u64, 8);
+	}
+
+	fn sys_read(&mut self, fd: u64, buffer: u64, length: u64, memory: &mut dyn DmaMemory) -> i64 {
+		let mut data = vec![0; length.min(MAX_TRANSFER_SIZE) as usize];
+		let result = match fd {
+			0 => match self.terminal.is_some() {
+				// Reads only the available input not to block the emulator
+				true => {
+					let mut count = 0;
+					while count < data.len() {
+						match self.get_input() {
+							Some(value) => data[count] = value,
+							None => break,
+						}
+						count += 1;
+					}
+					Ok(count)
+				}
+				false => stdin().read(&mut data),
+			},
+			_ => match self.get_file(fd) {
+				Some(file) => file.read(&mut data),
+				None => return -EBADF,
+			},
+		};
+		match result {
+			Ok(count) => {
+				memory.write_bytes(buffer, &data[..count]);
+				count as i64
+			}
+			Err(e) => get_errno(e),
+		}
+	}
+
+	fn sys_write(&mut self, fd: u64, buffer: u64, length: u64, memory: &mut dyn DmaMemory) -> i64 {
+		let mut data = vec![0; length.min(MAX_TRANSFER_SIZE) as usize];
+		memory.read_bytes(buffer, &mut data);
+		match fd {
+			1 | 2 => {
+				self.put_output(fd, &data);
+				data.len() as i64
+			}
+			_ => match self.get_file(fd) {
+				Some(file) => match file.write_all(&data) {
+					Ok(()) => data.len() as i64,
+					Err(e) => get_errno(e),
+				},
+				None => -EBADF,
+			},
+		}
+	}
+
+	fn sys_openat(
+		&mut self,
+		path: u64,
+		length: u64,
+		flags: u64,
+		memory: &mut dyn DmaMemory,
+	) -> i64 {
+		if length > PATH_MAX {
+			return -ENAMETOOLONG;
+		}
+		let mut name = vec![0; length as usize];
+		memory.read_bytes(path, &mut name);
+		// Length includes the terminating null
+		if let Some(end) = name.iter().position(|c| *c == 0) {
+			name.truncate(end);
+		}
+		let name = String::from_utf8_lossy(&name).to_string();
+		let result = OpenOptions::new()
+			.read((flags & O_ACCMODE) != O_WRONLY)
+			.write((flags & O_ACCMODE) != O_RDONLY)
+			.create((flags & O_CREAT) != 0)
+			.truncate((flags & O_TRUNC) != 0)
+			.append((flags & O_APPEND) != 0)
+			.open(name);
+		match result {
+			Ok(file) => {
+				let index = match self.files.iter().position(|file| file.is_none()) {
+					Some(index) => index,
+					None => {
+						self.files.push(None);
+						self.files.len() - 1
+					}
+				};
+				self.files[index] = Some(file);
+				(index as u64 + FIRST_FILE_FD) as i64
+			}
+			Err(e) => get_errno(e),
+		}
+	}
+
+	fn sys_close(&mut self, fd: u64) -> i64 {
+		if fd < FIRST_FILE_FD {
+			return 0;
+		}
+		match self.files.get_mut((fd - FIRST_FILE_FD) as usize) {
+			Some(file) if file.is_some() => {
+				*file = None;
+				0
+			}
+			_ => -EBADF,
+		}
+	}
+
+	fn sys_fstat(&mut self, fd: u64, buffer: u64, memory: &mut dyn DmaMemory) -> i64 {
+		let (mode, size) = match fd {
+			0..=2 => (S_IFCHR | 0o620, 0),
+			_ => match self.get_file(fd) {
+				Some(file) => match file.metadata() {
+					Ok(metadata) => match metadata.is_dir() {
+						true => (S_IFDIR | 0o755, metadata.len()),
+						false => (S_IFREG | 0o644, metadata.len()),
+					},
+					Err(e) => return get_errno(e),
+				},
+				None => return -EBADF,
+			},
+		};
+		let mut stat = [0; STAT_SIZE];
+		stat[16..20].copy_from_slice(&mode.to_le_bytes()); // st_mode
+		stat[20..24].copy_from_slice(&1u32.to_le_bytes()); // st_nlink
+		stat[48..56].copy_from_slice(&size.to_le_bytes()); // st_size
+		stat[56..60].copy_from_slice(&4096u32.to_le_bytes()); // st_blksize
+		stat[64..72].copy_from_slice(&size.div_ceil(512).to_le_bytes()); // st_blocks
+		memory.write_bytes(buffer, &stat);
+		0
+	}
+
+	fn get_file(&mut self, fd: u64) -> Option<&mut File> {
+		match fd < FIRST_FILE_FD {
+			true => None,
+			false => match self.files.get_mut((fd - FIRST_FILE_FD) as usize) {
+				Some(file) => file.as_mut(),
+				None => None,
+			},
+		}
+	}
+
+	/// Returns console input if available. Without terminal there is no
+	/// input because blocking on stdin would stall the whole emulator.
+	fn get_input(&mut self) -> Option<u8> {
+		match self.terminal.as_mut() {
+			Some(terminal) => terminal.get_input(),
+			None => None,
+		}
+	}
+
+	fn put_output(&mut self, fd: u64, data: &[u8]) {
+		match self.terminal.as_mut() {
+			Some(terminal) => {
+				for value in data.iter() {
+					terminal.put_byte(*value);
+				}
+			}
+			None => {
+				let _ = match fd {
+					2 => stderr().write_all(data),
+					_ => {
+						let mut out = stdout();
+						out.write_all(data).and_then(|_| out.flush())
+					}
+				};
+			}
+		}
+	}
+}
+
+fn get_errno(error: Error) -> i64 {
+	-(error.raw_os_error().map_or(EIO, |errno| errno as i64))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::env::temp_dir;
+	use std::fs::remove_file;
+	use terminal::BufferTerminal;
+
+	const TOHOST: u64 = 0x1000;
+	const FROMHOST: u64 = 0x1040;
+	const REQUEST: u64 = 0x2000;
+	const BUFFER: u64 = 0x3000;
+	const MEMORY_SIZE: u64 = 2 * MAX_TRANSFER_SIZE;
+
+	struct TestMemory {
+		data: Vec<u8>,
+	}
+
+	impl DmaMemory for TestMemory {
+		fn read_byte(&mut self, p_address: u64) -> u8 {
+			self.data[p_address as usize]
+		}
+
+		fn write_byte(&mut self, p_address: u64, value: u8) {
+			self.data[p_address as usize] = value;
+		}
+	}
+
+	fn create_htif() -> (Htif, BufferTerminal, TestMemory) {
+		let terminal = BufferTerminal::new();
+		let mut htif = Htif::new();
+		htif.setup(TOHOST, FROMHOST);
+		htif.set_terminal(Box::new(terminal.clone()));
+		let memory = TestMemory {
+			data: vec![0; MEMORY_SIZE as usize],
+		};
+		(htif, terminal, memory)
+	}
+
+	/// Writes a command to tohost, ticks, and returns fromhost clearing it
+	fn send(htif: &mut Htif, memory: &mut TestMemory, tohost: u64) -> u64 {
+		memory.write(TOHOST, tohost, 8);
+		htif.tick(memory);
+		assert_eq!(memory.read(TOHOST, 8), 0);
+		let fromhost = memory.read(FROMHOST, 8);
+		memory.write(FROMHOST, 0, 8);
+		fromhost
+	}
+
+	/// Sends a syscall request and returns its result
+	fn syscall(htif: &mut Htif, memory: &mut TestMemory, args: &[u64]) -> i64 {
+		for i in 0..MAGIC_MEM_WORDS {
+			let arg = args.get(i as usize).cloned().unwrap_or(0);
+			memory.write(REQUEST + i * 8, arg, 8);
+		}
+		assert_eq!(send(htif, memory, REQUEST), 1);
+		memory.read(REQUEST, 8) as i64
+	}
+
+	fn console_command(command: u64, payload: u64) -> u64 {
+		(HTIF_DEVICE_CONSOLE << 56) | (command << 48) | payload
+	}
+
+	#[test]
+	fn exits_with_code() {
+		let (mut htif, terminal, mut memory) = create_htif();
+		assert_eq!(htif.get_exit_code(), None);
+		assert_eq!(send(&mut htif, &mut memory, (21 << 1) | 1), 0);
+		assert_eq!(htif.get_exit_code(), Some(21));
+		// Commands after exit are not handled
+		let putchar = console_command(HTIF_CONSOLE_PUTCHAR, b'a' as u64);
+		memory.write(TOHOST, putchar, 8);
+		htif.tick(&mut memory);
+		assert_eq!(memory.read(TOHOST, 8), putchar);
+		assert!(terminal.take_output().is_empty());
+	}
+
+	#[test]
+	fn disabled_without_setup() {
+		let mut htif = Htif::new();
+		let mut memory = TestMemory {
+			data: vec![0; 0x100],
+		};
+		memory.write(0, 1, 8);
+		htif.tick(&mut memory);
+		assert_eq!(htif.get_exit_code(), None);
+		assert_eq!(memory.read(0, 8), 1);
+	}
+
+	#[test]
+	fn console_putchar_and_getchar() {
+		let (mut htif, terminal, mut memory) = create_htif();
+		let putchar = console_command(HTIF_CONSOLE_PUTCHAR, b'h' as u64);
+		assert_eq!(send(&mut htif, &mut memory, putchar), putchar & !0xff);
+		assert_eq!(terminal.take_output(), b"h".to_vec());
+
+		// getchar responds when input arrives
+		let getchar = console_command(HTIF_CONSOLE_GETCHAR, 0);
+		assert_eq!(send(&mut htif, &mut memory, getchar), 0);
+		terminal.put_input(b"x");
+		htif.tick(&mut memory);
+		let expected = console_command(HTIF_CONSOLE_GETCHAR, 0x100 | b'x' as u64);
+		assert_eq!(memory.read(FROMHOST, 8), expected);
+
+		// Responses wait for the guest to clear fromhost
+		let putchar = console_command(HTIF_CONSOLE_PUTCHAR, b'i' as u64);
+		memory.write(TOHOST, putchar, 8);
+		htif.tick(&mut memory);
+		assert_eq!(memory.read(FROMHOST, 8), expected);
+		assert_eq!(terminal.take_output(), b"i".to_vec());
+		memory.write(FROMHOST, 0, 8);
+		htif.tick(&mut memory);
+		assert_eq!(memory.read(FROMHOST, 8), putchar & !0xff);
+	}
+
+	#[test]
+	fn proxies_syscalls() {
+		let (mut htif, terminal, mut memory) = create_htif();
+		memory.write_bytes(BUFFER, b"hello");
+		assert_eq!(
+			syscall(&mut htif, &mut memory, &[SYS_WRITE, 1, BUFFER, 5]),
+			5
+		);
+		assert_eq!(terminal.take_output(), b"hello".to_vec());
+
+		terminal.put_input(b"ab");
+		assert_eq!(
+			syscall(&mut htif, &mut memory, &[SYS_READ, 0, BUFFER, 16]),
+			2
+		);
+		assert_eq!(memory.read(BUFFER, 3), 0x6c6261);
+
+		htif.set_program_break(0x80100000);
+		assert_eq!(syscall(&mut htif, &mut memory, &[SYS_BRK, 0]), 0x80100000);
+		assert_eq!(
+			syscall(&mut htif, &mut memory, &[SYS_BRK, 0x80200000]),
+			0x80200000
+		);
+
+		assert_eq!(syscall(&mut htif, &mut memory, &[SYS_FSTAT, 1, BUFFER]), 0);
+		assert_eq!(memory.read(BUFFER + 16, 4) as u32, S_IFCHR | 0o620);
+
+		assert_eq!(syscall(&mut htif, &mut memory, &[SYS_CLOSE, 7]), -EBADF);
+		assert_eq!(
+			syscall(&mut htif, &mut memory, &[SYS_WRITE, 7, BUFFER, 1]),
+			-EBADF
+		);
+		assert_eq!(syscall(&mut htif, &mut memory, &[1234]), -ENOSYS);
+
+		assert_eq!(syscall(&mut htif, &mut memory, &[SYS_EXIT, 3]), 0);
+		assert_eq!(htif.get_exit_code(), Some(3));
+	}
+
+	#[test]
+	fn proxies_file_syscalls() {
+		let (mut htif, _terminal, mut memory) = create_htif();
+		let path = temp_dir().join(format!("riscv_emu_htif_test_{}", std::process::id()));
+		let mut name = path.to_str().unwrap().as_bytes().to_vec();
+		name.push(0);
+		memory.write_bytes(BUFFER, &name);
+		let flags = O_WRONLY | O_CREAT | O_TRUNC;
+		let open = [SYS_OPENAT, 0, BUFFER, name.len() as u64, flags];
+		let fd = syscall(&mut htif, &mut memory, &open) as u64;
+		assert_eq!(fd, FIRST_FILE_FD);
+		memory.write_bytes(BUFFER, b"data");
+		assert_eq!(
+			syscall(&mut htif, &mut memory, &[SYS_WRITE, fd, BUFFER, 4]),
+			4
+		);
+		assert_eq!(syscall(&mut htif, &mut memory, &[SYS_FSTAT, fd, BUFFER]), 0);
+		assert_eq!(memory.read(BUFFER + 16, 4) as u32, S_IFREG | 0o644);
+		assert_eq!(memory.read(BUFFER + 48, 8), 4);
+		assert_eq!(syscall(&mut htif, &mut memory, &[SYS_CLOSE, fd]), 0);
+		assert_eq!(syscall(&mut htif, &mut memory, &[SYS_CLOSE, fd]), -EBADF);
+
+		memory.write_bytes(BUFFER, &name);
+		let open = [SYS_OPENAT, 0, BUFFER, name.len() as u64, O_RDONLY];
+		let fd = syscall(&mut htif, &mut memory, &open) as u64;
+		assert_eq!(fd, FIRST_FILE_FD);
+		assert_eq!(
+			syscall(&mut htif, &mut memory, &[SYS_READ, fd, BUFFER, 16]),
+			4
+		);
+		assert_eq!(memory.read(BUFFER, 4), 0x61746164);
+		assert_eq!(
+			syscall(&mut htif, &mut memory, &[SYS_READ, fd, BUFFER, 16]),
+			0
+		);
+		remove_file(path).unwrap();
+	}
+
+	#[test]
+	fn bounds_guest_lengths() {
+		let (mut htif, terminal, mut memory) = create_htif();
+		// Writes are short at MAX_TRANSFER_SIZE
+		let write = [SYS_WRITE, 1, BUFFER, u64::MAX];
+		assert_eq!(
+			syscall(&mut htif, &mut memory, &write),
+			MAX_TRANSFER_SIZE as i64
+		);
+		assert_eq!(terminal.take_output().len() as u64, MAX_TRANSFER_SIZE);
+
+		// Reads too, and write only what they read
+		terminal.put_input(&vec![b'r'; MAX_TRANSFER_SIZE as usize + 1]);
+		let read = [SYS_READ, 0, BUFFER, u64::MAX];
+		assert_eq!(
+			syscall(&mut htif, &mut memory, &read),
+			MAX_TRANSFER_SIZE as i64
+		);
+		assert_eq!(memory.read_byte(BUFFER + MAX_TRANSFER_SIZE - 1), b'r');
+		assert_eq!(memory.read_byte(BUFFER + MAX_TRANSFER_SIZE), 0);
+		assert_eq!(syscall(&mut htif, &mut memory, &read), 1);
+
+		// Paths are bounded by PATH_MAX
+		for i in 0..PATH_MAX + 1 {
+			memory.write_byte(BUFFER + i, b'a');
+		}
+		let open = [SYS_OPENAT, 0, BUFFER, PATH_MAX + 1, O_RDONLY];
+		assert_eq!(syscall(&mut htif, &mut memory, &open), -ENAMETOOLONG);
+		memory.write_bytes(BUFFER, b"/nonexistent/file\0");
+		let open = [SYS_OPENAT, 0, BUFFER, PATH_MAX, O_RDONLY];
+		let result = syscall(&mut htif, &mut memory, &open);
+		assert!(result < 0 && result != -ENAMETOOLONG);
+	}
+}
@@ -1,5 +1,4 @@
-#[allow(unused)]
-use rand::random;
+use checkpoint::{CheckpointError, CheckpointReader, CheckpointWriter};
 
 /// 64B cache block size
 pub const L1_CACHE_BLOCK_SIZE: i32 = 64;
@@ -18,6 +17,11 @@ pub const L1_CACHE_OFFSET_BITS: i32 = 6;
 pub const L1_CACHE_INDEX_BITS: i32 = 6;
 pub const L1_CACHE_TAG_BITS: i32 = 32 - L1_CACHE_OFFSET_BITS - L1_CACHE_INDEX_BITS;
 
+/// Initial state of the pseudo-random number generator for Random
+/// placement. Placement is deterministic so that a run restored from
+/// checkpoint behaves in the same way.
+pub const CACHE_RANDOM_SEED: u64 = 0x2545f4914f6cdd1d;
+
 /// 64B cache block size
 pub const L1_CACHE_HIT_LATENCY: i32 = 1;
 pub const L1_CACHE_MISS_LATENCY: i32 = 1;
@@ -96,6 +100,8 @@ pub struct L1Cache {
 		[L1CacheSet; (L1_CACHE_SIZE / (L1_CACHE_BLOCK_SIZE * L1_SET_ASSOCIATIVE_WAY)) as usize],
 	pub hit_num: u64,
 	pub miss_num: u64,
+	/// State of the pseudo-random number generator for Random placement
+	pub random_state: u64,
 }
 
 impl L1Cache {
@@ -104,6 +110,7 @@ impl L1Cache {
 			data: [L1CacheSet::new(); L1_CACHE_SET_NUMBER as usize],
 			hit_num: 0,
 			miss_num: 0,
+			random_state: CACHE_RANDOM_SEED,
 		}
 	}
 
@@ -112,6 +119,7 @@ impl L1Cache {
 			data: [L1CacheSet::static_new(); L1_CACHE_SET_NUMBER as usize],
 			hit_num: 0,
 			miss_num: 0,
+			random_state: CACHE_RANDOM_SEED,
 		}
 	}
 
@@ -222,9 +230,11 @@ impl L1Cache {
 	///
 	/// # Arguments
 	/// * `index`: index of cache set
-	pub fn allocate_new_line(&self, _index: u64, policy: PlacementPolicy) -> u8 {
+	pub fn allocate_new_line(&mut self, _index: u64, policy: PlacementPolicy) -> u8 {
 		match policy {
-			PlacementPolicy::Random => random::<u8>() % (L1_SET_ASSOCIATIVE_WAY as u8),
+			PlacementPolicy::Random => {
+				(next_random(&mut self.random_state) % L1_SET_ASSOCIATIVE_WAY as u64) as u8
+			}
 			PlacementPolicy::LRU => {
 				// @TODO: LRU
 				0
@@ -235,4 +245,68 @@ impl L1Cache {
 			}
 		}
 	}
+
+	/// Invalidates all the lines without write back. Statistics are kept.
+	pub fn clear(&mut self) {
+		for set in self.data.iter_mut() {
+			for line in set.data.iter_mut() {
+				*line = L1CacheLine::new();
+			}
+		}
+	}
+
+	/// Saves the state to checkpoint.
+	///
+	/// # Arguments
+	/// * `writer`
+	/// * `include_lines` Whether to save cache lines. Lines are cleared
+	///   on restore if not saved.
+	pub fn save_state(&self, writer: &mut CheckpointWriter, include_lines: bool) {
+		writer.write_u64(self.hit_num);
+		writer.write_u64(self.miss_num);
+		writer.write_u64(self.random_state);
+		writer.write_bool(include_lines);
+		if include_lines {
+			for line in self.data.iter().flat_map(|set| set.data.iter()) {
+				writer.write_bool(line.valid);
+				writer.write_u64(line.tag);
+				writer.write_bytes(&line.data_blocks);
+			}
+		}
+	}
+
+	/// Restores the state saved by `save_state()`.
+	///
+	/// # Arguments
+	/// * `reader`
+	pub fn restore_state(&mut self, reader: &mut CheckpointReader) -> Result<(), CheckpointError> {
+		self.hit_num = reader.read_u64()?;
+		self.miss_num = reader.read_u64()?;
+		self.random_state = reader.read_u64()?;
+		match reader.read_bool()? {
+			true => {
+				for set in self.data.iter_mut() {
+					for line in set.data.iter_mut() {
+						line.valid = reader.read_bool()?;
+						line.tag = reader.read_u64()?;
+						reader.read_bytes(&mut line.data_blocks)?;
+					}
+				}
+			}
+			false => self.clear(),
+		};
+		Ok(())
+	}
+}
+
+/// Advances the state of xorshift64 pseudo-random number generator and
+/// returns the next number.
+///
+/// # Arguments
+/// * `state` Must be non-zero
+pub fn next_random(state: &mut u64) -> u64 {
+	*state ^= *state << 13;
+	*state ^= *state >> 7;
+	*state ^= *state << 17;
+	*state
 }
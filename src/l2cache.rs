@@ -1,7 +1,5 @@
-#[allow(unused)]
-use rand::random;
-
-use l1cache::{PlacementPolicy, L1_CACHE_SIZE};
+use checkpoint::{CheckpointError, CheckpointReader, CheckpointWriter};
+use l1cache::{next_random, PlacementPolicy, CACHE_RANDOM_SEED, L1_CACHE_SIZE};
 
 /// 64B cache block size
 pub const L2_CACHE_BLOCK_SIZE: i32 = 64;
@@ -94,6 +92,8 @@ pub struct L2Cache {
 		[L2CacheSet; (L2_CACHE_SIZE / (L2_CACHE_BLOCK_SIZE * L2_SET_ASSOCIATIVE_WAY)) as usize],
 	pub hit_num: u64,
 	pub miss_num: u64,
+	/// State of the pseudo-random number generator for Random placement
+	pub random_state: u64,
 }
 
 impl L2Cache {
@@ -102,6 +102,7 @@ impl L2Cache {
 			data: [L2CacheSet::new(); L2_CACHE_SET_NUMBER as usize],
 			hit_num: 0,
 			miss_num: 0,
+			random_state: CACHE_RANDOM_SEED,
 		}
 	}
 
@@ -110,6 +111,7 @@ impl L2Cache {
 			data: [L2CacheSet::static_new(); L2_CACHE_SET_NUMBER as usize],
 			hit_num: 0,
 			miss_num: 0,
+			random_state: CACHE_RANDOM_SEED,
 		}
 	}
 
@@ -220,7 +222,7 @@ impl L2Cache {
 	///
 	/// # Arguments
 	/// * `index`: index of cache set
-	pub fn allocate_new_line(&self, index: u64, policy: PlacementPolicy) -> u8 {
+	pub fn allocate_new_line(&mut self, index: u64, policy: PlacementPolicy) -> u8 {
 		match policy {
 			PlacementPolicy::Random => {
 				let mut non_inclusive_ways = vec![];
@@ -229,8 +231,8 @@ impl L2Cache {
 						non_inclusive_ways.push(way);
 					}
 				}
-				non_inclusive_ways[(random::<u8>() % (non_inclusive_ways.len() as u8)) as usize]
-					as u8
+				let random = next_random(&mut self.random_state);
+				non_inclusive_ways[(random % non_inclusive_ways.len() as u64) as usize] as u8
 			}
 			PlacementPolicy::LRU => {
 				// @TODO: LRU
@@ -242,4 +244,58 @@ impl L2Cache {
 			}
 		}
 	}
+
+	/// Invalidates all the lines without write back. Statistics are kept.
+	pub fn clear(&mut self) {
+		for set in self.data.iter_mut() {
+			for line in set.data.iter_mut() {
+				*line = L2CacheLine::new();
+			}
+		}
+	}
+
+	/// Saves the state to checkpoint.
+	///
+	/// # Arguments
+	/// * `writer`
+	/// * `include_lines` Whether to save cache lines. Lines are cleared
+	///   on restore if not saved.
+	pub fn save_state(&self, writer: &mut CheckpointWriter, include_lines: bool) {
+		writer.write_u64(self.hit_num);
+		writer.write_u64(self.miss_num);
+		writer.write_u64(self.random_state);
+		writer.write_bool(include_lines);
+		if include_lines {
+			for line in self.data.iter().flat_map(|set| set.data.iter()) {
+				writer.write_bool(line.l1_inclusive);
+				writer.write_bool(line.valid);
+				writer.write_u64(line.tag);
+				writer.write_bytes(&line.data_blocks);
+			}
+		}
+	}
+
+	/// Restores the state saved by `save_state()`.
+	///
+	/// # Arguments
+	/// * `reader`
+	pub fn restore_state(&mut self, reader: &mut CheckpointReader) -> Result<(), CheckpointError> {
+		self.hit_num = reader.read_u64()?;
+		self.miss_num = reader.read_u64()?;
+		self.random_state = reader.read_u64()?;
+		match reader.read_bool()? {
+			true => {
+				for set in self.data.iter_mut() {
+					for line in set.data.iter_mut() {
+						line.l1_inclusive = reader.read_bool()?;
+						line.valid = reader.read_bool()?;
+						line.tag = reader.read_u64()?;
+						reader.read_bytes(&mut line.data_blocks)?;
+					}
+				}
+			}
+			false => self.clear(),
+		};
+		Ok(())
+	}
 }
@@ -2,44 +2,87 @@
 const TEST_MEMORY_CAPACITY: u64 = 1024 * 1024 * 2048;
 const PROGRAM_MEMORY_CAPACITY: u64 = 1024 * 1024 * 2049; // big enough to run Linux and xv6
 
+// State of legacy memdump which doesn't carry it
+const LEGACY_MEMDUMP_SATP: u64 = 0x8000000000080016;
+const LEGACY_MEMDUMP_SP: i64 = 0x7f7e9b50;
+
 extern crate fnv;
 extern crate rand;
 
 use self::fnv::FnvHashMap;
 use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::Write;
 use std::process;
 use std::str;
 use std::time::SystemTime;
 
+pub mod boot_rom;
+pub mod checkpoint;
+pub mod clint;
 pub mod cpu;
+pub mod device;
 #[cfg(feature = "dramsim")]
 pub mod dram;
+pub mod dram_model;
 pub mod elf_analyzer;
+pub mod fdt;
+pub mod htif;
 pub mod l1cache;
 pub mod l2cache;
+pub mod memdump;
 pub mod memory;
+pub mod memory_controller;
 pub mod mmu;
+pub mod page_walk_cache;
+pub mod plic;
+pub mod pmp;
+pub mod softfloat;
+pub mod terminal;
+pub mod uart;
+pub mod virtio_block;
+pub mod watchpoint;
 
+use boot_rom::{generate_device_tree, BootRom, BOOT_ROM_BASE, BOOT_ROM_SIZE};
+use checkpoint::{
+	CheckpointError, CheckpointReader, CheckpointWriter, CHECKPOINT_MAGIC, CHECKPOINT_VERSION,
+};
 use cpu::{
-	Cpu, Xlen, CSR_HPMCOUNTER3_ADDRESS, CSR_HPMCOUNTER4_ADDRESS, CSR_HPMCOUNTER5_ADDRESS,
-	CSR_HPMCOUNTER6_ADDRESS, CSR_MCYCLE_ADDRESS,
+	Cpu, PrivilegeMode, Xlen, CSR_FCSR_ADDRESS, CSR_HPMCOUNTER10_ADDRESS, CSR_HPMCOUNTER11_ADDRESS,
+	CSR_HPMCOUNTER12_ADDRESS, CSR_HPMCOUNTER3_ADDRESS, CSR_HPMCOUNTER4_ADDRESS,
+	CSR_HPMCOUNTER5_ADDRESS, CSR_HPMCOUNTER6_ADDRESS, CSR_HPMCOUNTER7_ADDRESS,
+	CSR_HPMCOUNTER8_ADDRESS, CSR_HPMCOUNTER9_ADDRESS, CSR_MCYCLE_ADDRESS, CSR_MINSTRET_ADDRESS,
+	CSR_SATP_ADDRESS, MEMDUMP_CSR_ADDRESSES,
 };
+use device::Device;
 #[cfg(feature = "dramsim")]
-use dram::{send_request, terminate_pipe};
+use dram::terminate_pipe;
+use dram_model::DramConfig;
 use elf_analyzer::ElfAnalyzer;
 use l1cache::L1_CACHE_HIT_LATENCY;
 use l2cache::L2_CACHE_HIT_LATENCY;
+use memdump::{parse_memdump, Memdump, MemdumpEntry, MemdumpError, MemdumpTrigger};
+use memory::{MemoryBackendType, SPARSE_MEMORY_PAGE_SIZE};
+use memory_controller::MemoryControllerConfig;
+use terminal::Terminal;
+use uart::{Uart, UART_BASE, UART_IRQ, UART_SIZE};
+use virtio_block::{
+	DiskImage, VirtioBlockDisk, VirtioVersion, VIRTIO_BASE, VIRTIO_IRQ, VIRTIO_SIZE,
+};
+use watchpoint::Watchpoint;
 
 /// RISC-V emulator. It emulates RISC-V CPU and peripheral devices.
 ///
 /// Sample code to run the emulator.
 /// ```ignore
-/// // Creates an emulator with arbitary terminal
-/// let mut emulator = Emulator::new(Box::new(DefaultTerminal::new()));
+/// let mut emulator = Emulator::new();
+/// // Connects UART to arbitary terminal
+/// emulator.setup_uart(Box::new(StdioTerminal::new())).unwrap();
 /// // Set up program content binary
-/// emulator.setup_program(program_content);
+/// emulator.setup_program(program_content, memdump_content).unwrap();
 /// // Set up Filesystem content binary
-/// emulator.setup_filesystem(fs_content);
+/// emulator.setup_filesystem(fs_content).unwrap();
 /// // Go!
 /// emulator.run();
 /// ```
@@ -62,11 +105,16 @@ pub struct Emulator {
 	pub tohost_addr: u64,
 
 	pub run_time: f64,
+
+	/// Condition to take memdump of the running program, cleared once taken
+	pub memdump_trigger: Option<MemdumpTrigger>,
+	/// Path memdump is written to
+	pub memdump_path: String,
 }
 
 impl Emulator {
-	/// Creates a new `Emulator`. [`Terminal`](terminal/trait.Terminal.html)
-	/// is internally used for transferring input/output data to/from `Emulator`.
+	/// Creates a new `Emulator`. Call `setup_uart()` to connect a
+	/// [`Terminal`](terminal/trait.Terminal.html) for console input/output.
 	pub fn new() -> Self {
 		Emulator {
 			cpu: Cpu::new(),
@@ -81,6 +129,9 @@ impl Emulator {
 			tohost_addr: 0, // assuming tohost_addr is non-zero if exists
 
 			run_time: 0.0,
+
+			memdump_trigger: None,
+			memdump_path: String::new(),
 		}
 	}
 
@@ -136,26 +187,22 @@ impl Emulator {
 			self.put_bytes_to_terminal(disas.as_bytes());
 			self.put_bytes_to_terminal(&[10]); // new line
 
-			self.tick(false, "");
-
-			// It seems in riscv-tests ends with end code
-			// written to a certain physical memory address
-			// (0x80001000 in mose test cases) so checking
-			// the data in the address and terminating the test
-			// if non-zero data is written.
-			// End code 1 seems to mean pass.
-			let endcode = self.cpu.get_mut_mmu().load_word_raw(self.tohost_addr);
-			if endcode != 0 {
-				match endcode {
-					1 => self.put_bytes_to_terminal(
-						format!("Test Passed with {:X}\n", endcode).as_bytes(),
-					),
+			self.cpu.tick(false, "");
+
+			// riscv-tests ends with exit through HTIF. Exit code 0
+			// means pass, otherwise it is the failed test number.
+			if let Some(code) = self.cpu.get_mmu().get_htif().get_exit_code() {
+				match code {
+					0 => self.put_bytes_to_terminal(b"Test Passed\n"),
 					_ => self.put_bytes_to_terminal(
-						format!("Test Failed with {:X}\n", endcode).as_bytes(),
+						format!("Test Failed at test {}\n", code).as_bytes(),
 					),
 				};
 				break;
 			}
+			if self.cpu.exit_signal || self.cpu.watchpoint_signal {
+				self.exit();
+			}
 		}
 	}
 
@@ -180,18 +227,16 @@ impl Emulator {
 	fn exit(&mut self) {
 		// Exit
 
+		let memory_error = self.cpu.mmu.memory_error.clone();
+		// Replay log left unconsumed fails the emulation as well as a
+		// divergence in the middle
 		#[cfg(feature = "dramsim")]
-		{
-			send_request(
-				format!(
-					"{:016x} {} {}",
-					0xffff_ffff_ffff_ffffu64,
-					"END",
-					self.cpu.read_csr_raw(CSR_MCYCLE_ADDRESS)
-				)
-				.as_str(),
-			);
-			terminate_pipe();
+		let memory_error = match terminate_pipe(self.cpu.read_csr_raw(CSR_MCYCLE_ADDRESS)) {
+			Err(e) if memory_error.is_none() => Some(e.to_string()),
+			_ => memory_error,
+		};
+		if let Some(error) = memory_error.as_ref() {
+			eprintln!("{}", error);
 		}
 		//
 		println!(
@@ -227,19 +272,111 @@ impl Emulator {
 			(self.cpu.mmu.dram_latency as f32) / (l2_miss_num as f32)
 		);
 
+		// Row buffer locality of the built-in DRAM model
+		if self.cpu.mmu.get_dram_model().is_some() {
+			let row_hit_num = self.cpu.read_csr_raw(CSR_HPMCOUNTER11_ADDRESS);
+			let row_miss_num = self.cpu.read_csr_raw(CSR_HPMCOUNTER12_ADDRESS);
+			println!(
+				"DRAM Row Buffer Hit rate = {}%",
+				match row_hit_num + row_miss_num {
+					0 => 0.0,
+					access_num => (row_hit_num * 100) as f64 / access_num as f64,
+				}
+			);
+		}
+		// Bank balance of the DRAM address mapping
+		if let Some(dram_model) = self.cpu.mmu.get_dram_model() {
+			println!("DRAM Bank Accesses = {:?}", dram_model.bank_access_nums);
+			println!("DRAM Bank Conflicts = {:?}", dram_model.bank_conflict_nums);
+		}
+
+		// Memory controller queueing
+		if self.cpu.mmu.memory_controller.is_some() {
+			let read_num = self.cpu.read_csr_raw(CSR_HPMCOUNTER7_ADDRESS);
+			let write_num = self.cpu.read_csr_raw(CSR_HPMCOUNTER8_ADDRESS);
+			let read_latency = self.cpu.read_csr_raw(CSR_HPMCOUNTER9_ADDRESS);
+			let queue_occupancy = self.cpu.read_csr_raw(CSR_HPMCOUNTER10_ADDRESS);
+			println!(
+				"Memory Controller Read Latency = {} cycles",
+				match read_num {
+					0 => 0.0,
+					_ => read_latency as f64 / read_num as f64,
+				}
+			);
+			println!(
+				"Memory Controller Queue Occupancy = {}",
+				match read_num + write_num {
+					0 => 0.0,
+					request_num => queue_occupancy as f64 / request_num as f64,
+				}
+			);
+		}
+
+		// Accesses to non-cacheable pages (Svpbmt NC/IO)
+		println!("Uncached Access = {}", self.cpu.mmu.uncached_num);
+
+		// Address translation overhead
+		// Rates are zero rather than NaN if no translation happened, e.g. in
+		// bare mode
+		let tlb_hit_num = self.cpu.mmu.tlb_hit_num;
+		let tlb_access_num = tlb_hit_num + self.cpu.mmu.tlb_miss_num;
+		println!(
+			"TLB Hit rate = {}%",
+			match tlb_access_num {
+				0 => 0.0,
+				_ => (tlb_hit_num * 100) as f32 / tlb_access_num as f32,
+			}
+		);
+		let pwc_hit_num = self.cpu.mmu.page_walk_cache.hit_num;
+		let pwc_access_num = pwc_hit_num + self.cpu.mmu.page_walk_cache.miss_num;
+		println!(
+			"Page Walk Cache Hit rate = {}%",
+			match pwc_access_num {
+				0 => 0.0,
+				_ => (pwc_hit_num * 100) as f32 / pwc_access_num as f32,
+			}
+		);
+		println!(
+			"Page Walk Latency = {} cycles",
+			self.cpu.mmu.page_walk_latency
+		);
+
 		let exit_time = SystemTime::now()
 			.duration_since(SystemTime::UNIX_EPOCH)
 			.unwrap()
 			.as_secs_f64();
 		// Total run time
 		println!("Real run time = {} seconds", (exit_time - self.run_time));
-		process::exit(0);
+		// Exit code requested by the program through HTIF, or failure on a
+		// memory error
+		let code = match memory_error {
+			Some(_) => 1,
+			None => self.cpu.get_mmu().get_htif().get_exit_code().unwrap_or(0),
+		};
+		process::exit(code as i32);
 	}
 
 	/// Runs CPU one cycle
 	pub fn tick(&mut self, trace_memory_access: bool, trace_path: &str) {
+		if let Some(MemdumpTrigger::Pc(pc)) = self.memdump_trigger {
+			if self.cpu.pc == pc {
+				self.trigger_memdump();
+			}
+		}
 		self.cpu.tick(trace_memory_access, trace_path);
-		if self.cpu.exit_signal == true {
+		match self.memdump_trigger {
+			Some(MemdumpTrigger::InstructionNum(num))
+				if self.cpu.read_csr_raw(CSR_MINSTRET_ADDRESS) >= num =>
+			{
+				self.trigger_memdump()
+			}
+			Some(MemdumpTrigger::MagicInstruction) if self.cpu.memdump_signal => {
+				self.trigger_memdump()
+			}
+			_ => {}
+		};
+		self.cpu.memdump_signal = false;
+		if self.cpu.exit_signal == true || self.cpu.watchpoint_signal {
 			self.exit();
 		}
 	}
@@ -248,11 +385,19 @@ impl Emulator {
 	/// and configure CPU properly. If the passed contend doesn't seem ELF file,
 	/// it panics. This method is expected to be called only once.
 	///
+	/// Returns error if main memory can't be mapped or the memdump,
+	/// loaded with the memdump feature, is invalid.
+	///
 	/// # Arguments
 	/// * `data` Program binary
+	/// * `memdump_contents` memdump file content
 	// @TODO: Make ElfAnalyzer and move the core logic there.
 	// @TODO: Returns `Err` if the passed contend doesn't seem ELF file
-	pub fn setup_program(&mut self, data: Vec<u8>, memdump_contents: Vec<u8>) {
+	pub fn setup_program(
+		&mut self,
+		data: Vec<u8>,
+		memdump_contents: Vec<u8>,
+	) -> Result<(), String> {
 		let analyzer = ElfAnalyzer::new(data);
 
 		if !analyzer.validate() {
@@ -284,7 +429,6 @@ impl Emulator {
 			// None => 0x80001ea8,
 			None => 0x80001198,
 		};
-		self.cpu.tohost_addr = self.tohost_addr;
 
 		// Creates symbol - virtual address mapping
 		if string_table_section_headers.len() > 0 {
@@ -299,6 +443,22 @@ impl Emulator {
 			self.symbol_map = Some(_symbol_map);
 		}
 
+		// fromhost follows tohost unless the program defines it
+		let fromhost_addr = match self.symbol_map.as_ref().and_then(|map| map.get("fromhost")) {
+			Some(address) => *address,
+			None => self.tohost_addr + 0x40,
+		};
+		// Program break for brk syscall starts from the end of the program
+		let program_end = section_headers
+			.iter()
+			.filter(|header| header.sh_addr != 0)
+			.map(|header| header.sh_addr + header.sh_size)
+			.max()
+			.unwrap_or(0);
+		let htif = self.cpu.get_mut_mmu().get_mut_htif();
+		htif.setup(self.tohost_addr, fromhost_addr);
+		htif.set_program_break(program_end);
+
 		// Detected whether the elf file is riscv-tests.
 		// Setting up CPU and Memory depending on it.
 
@@ -308,74 +468,25 @@ impl Emulator {
 			_ => panic!("No happen"),
 		});
 
+		// Programs are started without M-mode firmware
+		self.cpu.setup_firmware_state();
+
 		if self.tohost_addr != 0 {
 			// @TODO : modify this rule
 			self.is_test = true;
-			self.cpu.get_mut_mmu().init_memory(TEST_MEMORY_CAPACITY);
+			self.cpu.get_mut_mmu().init_memory(TEST_MEMORY_CAPACITY)?;
 		} else {
 			self.is_test = false;
-			self.cpu.get_mut_mmu().init_memory(PROGRAM_MEMORY_CAPACITY);
+			self.cpu
+				.get_mut_mmu()
+				.init_memory(PROGRAM_MEMORY_CAPACITY)?;
 		}
+		// Memory snapshot is loaded first and overwritten by the program
 		#[cfg(feature = "memdump")]
-		{
-			let mut iter_num = 0;
-			loop {
-				let mut left_addr: u64 = 0;
-				{
-					while memdump_contents[iter_num] != 'x' as u8 {
-						iter_num = iter_num + 1;
-					}
-					for j in iter_num + 1..memdump_contents.len() {
-						if memdump_contents[j] == 32 || memdump_contents[j] == 10 {
-							iter_num = j;
-							break;
-						} else {
-							let mut tmp = memdump_contents[j];
-							if memdump_contents[j] >= 'a' as u8 && memdump_contents[j] <= 'f' as u8
-							{
-								tmp = tmp - 'a' as u8 + 10 + 48;
-							}
-							if memdump_contents[j] >= 'A' as u8 && memdump_contents[j] <= 'F' as u8
-							{
-								tmp = tmp - 'A' as u8 + 10 + 48;
-							}
-							left_addr = left_addr * 16 + tmp as u64 - 48;
-						}
-					}
-				}
-				let mut right_value: u64 = 0;
-				{
-					while memdump_contents[iter_num] != ' ' as u8 {
-						iter_num = iter_num + 1;
-					}
-					for j in iter_num + 1..memdump_contents.len() {
-						if memdump_contents[j] == 32 || memdump_contents[j] == 10 {
-							iter_num = j;
-							break;
-						} else {
-							let mut tmp = memdump_contents[j];
-							if memdump_contents[j] >= 'a' as u8 && memdump_contents[j] <= 'f' as u8
-							{
-								tmp = tmp - 'a' as u8 + 10 + 48;
-							}
-							if memdump_contents[j] >= 'A' as u8 && memdump_contents[j] <= 'F' as u8
-							{
-								tmp = tmp - 'A' as u8 + 10 + 48;
-							}
-							right_value = right_value * 16 + tmp as u64 - 48;
-						}
-					}
-				}
-				//println!("[debug log] commiting value {} to addr {}",right_value,left_addr);
-				self.cpu
-					.get_mut_mmu()
-					.store_doubleword_raw(left_addr, right_value);
-				iter_num = iter_num + 1;
-				if iter_num >= memdump_contents.len() {
-					break;
-				}
-			}
-		}
+		let memdump = parse_memdump(&memdump_contents).map_err(|e| e.to_string())?;
+		#[cfg(feature = "memdump")]
+		self.load_memdump_memory(&memdump, false)
+			.map_err(|e| e.to_string())?;
 
 		for i in 0..program_data_section_headers.len() {
 			let sh_addr = program_data_section_headers[i].sh_addr;
@@ -389,16 +500,259 @@ impl Emulator {
 				}
 			}
 		}
+		self.cpu.update_pc(header.e_entry);
 		#[cfg(feature = "memdump")]
+		self.load_memdump_state(&memdump)
+			.map_err(|e| e.to_string())?;
+		Ok(())
+	}
+
+	/// Loads memory snapshot, memdump file, and sets up architectural
+	/// state in it. Call this after `setup_program()` if a memdump is
+	/// given separately. Refer to [`Memdump`](memdump/struct.Memdump.html)
+	/// for the format.
+	///
+	/// # Arguments
+	/// * `contents` memdump file content
+	pub fn load_memdump(&mut self, contents: &[u8]) -> Result<(), MemdumpError> {
+		let memdump = parse_memdump(contents)?;
+		self.load_memdump_memory(&memdump, false)?;
+		self.load_memdump_state(&memdump)
+	}
+
+	/// Sets the condition to take memdump of the running program. The
+	/// memdump is written to `path` once when the condition is met.
+	///
+	/// # Arguments
+	/// * `trigger`
+	/// * `path` Output memdump file path
+	pub fn set_memdump_trigger(&mut self, trigger: MemdumpTrigger, path: &str) {
+		self.memdump_trigger = Some(trigger);
+		self.memdump_path = path.to_string();
+	}
+
+	/// Takes memdump of the running program. Each doubleword is read as
+	/// the core sees it, from the caches or memory, without writing back
+	/// the caches, so taking memdump doesn't change the timing. Only
+	/// non-zero doublewords in the pages touched or cached so far are
+	/// included.
+	pub fn take_memdump(&mut self) -> Memdump {
+		let mut memdump = Memdump {
+			pc: Some(self.cpu.pc),
+			privilege_mode: Some(self.cpu.privilege_mode.clone()),
+			satp: Some(self.cpu.read_csr_raw(CSR_SATP_ADDRESS)),
+			registers: (1..32)
+				.map(|register| (register, self.cpu.x[register] as u64))
+				.collect(),
+			fp_registers: (0..32)
+				.map(|register| (register, self.cpu.f[register].to_bits()))
+				.collect(),
+			fcsr: Some(self.cpu.read_csr_raw(CSR_FCSR_ADDRESS)),
+			csrs: MEMDUMP_CSR_ADDRESSES
+				.iter()
+				.map(|address| (*address, self.cpu.read_csr_raw(*address)))
+				.collect(),
+			entries: vec![],
+		};
+
+		let mmu = self.cpu.get_mut_mmu();
+		// Lines not written back yet can be in pages memory hasn't touched
+		let cached_lines = mmu.get_cached_line_addresses();
+		let mut ranges = vec![];
+		for region in mmu.memory.regions.iter() {
+			let capacity = region.memory.capacity();
+			let mut pages = region.memory.get_touched_pages();
+			pages.extend(
+				cached_lines
+					.iter()
+					.filter(|address| {
+						**address >= region.base && **address - region.base < capacity
+					})
+					.map(|address| (address - region.base) & !(SPARSE_MEMORY_PAGE_SIZE - 1)),
+			);
+			pages.sort_unstable();
+			pages.dedup();
+			for page in pages {
+				let top = (page + SPARSE_MEMORY_PAGE_SIZE).min(capacity);
+				ranges.push(region.base + page..region.base + top);
+			}
+		}
+		for range in ranges {
+			for address in range.step_by(8) {
+				let value = mmu.peek_doubleword(address);
+				if value != 0 {
+					memdump.entries.push(MemdumpEntry {
+						line: 0,
+						address,
+						value,
+						is_virtual: false,
+					});
+				}
+			}
+		}
+		memdump
+	}
+
+	/// Takes memdump of the running program and writes it to a file.
+	///
+	/// # Arguments
+	/// * `path` Output memdump file path
+	pub fn export_memdump(&mut self, path: &str) -> io::Result<()> {
+		let memdump = self.take_memdump();
+		let mut file = File::create(path)?;
+		memdump.write(&mut file)
+	}
+
+	/// Takes checkpoint of the whole machine state, CPU, MMU, memory, and
+	/// devices. Without caches, the cache lines are written back to memory
+	/// and invalidated first. Host side resources, e.g. terminal input,
+	/// disk image, and files opened through HTIF, are not saved.
+	///
+	/// # Arguments
+	/// * `include_caches` Whether to save L1 and L2 cache lines
+	pub fn take_checkpoint(&mut self, include_caches: bool) -> Vec<u8> {
+		if !include_caches {
+			let mmu = self.cpu.get_mut_mmu();
+			mmu.l1_flush();
+			mmu.l2_flush();
+			mmu.l1_cache.clear();
+			mmu.l2_cache.clear();
+		}
+		let mut writer = CheckpointWriter::new();
+		writer.write_bytes(&CHECKPOINT_MAGIC);
+		writer.write_u32(CHECKPOINT_VERSION);
+		self.cpu.save_state(&mut writer, include_caches);
+		writer.finish()
+	}
+
+	/// Takes checkpoint and writes it to a file.
+	///
+	/// # Arguments
+	/// * `path` Output checkpoint file path
+	/// * `include_caches` Whether to save L1 and L2 cache lines
+	pub fn export_checkpoint(&mut self, path: &str, include_caches: bool) -> io::Result<()> {
+		let checkpoint = self.take_checkpoint(include_caches);
+		File::create(path)?.write_all(&checkpoint)
+	}
+
+	/// Restores the machine state from checkpoint taken by
+	/// `take_checkpoint()`. The emulator needs to be set up in the same
+	/// way as the one the checkpoint was taken from, with the same
+	/// program, memory, and devices.
+	///
+	/// # Arguments
+	/// * `contents` Checkpoint content
+	pub fn load_checkpoint(&mut self, contents: &[u8]) -> Result<(), CheckpointError> {
+		let mut reader = CheckpointReader::new(contents);
+		let mut magic = [0; 8];
+		reader.read_bytes(&mut magic)?;
+		if magic != CHECKPOINT_MAGIC {
+			return Err(reader.error("Not a checkpoint".to_string()));
+		}
+		let version = reader.read_u32()?;
+		if version != CHECKPOINT_VERSION {
+			return Err(reader.error(format!(
+				"Unsupported version {}, expected {}",
+				version, CHECKPOINT_VERSION
+			)));
+		}
+		self.cpu.restore_state(&mut reader)?;
+		match reader.is_end() {
+			true => Ok(()),
+			false => Err(reader.error("Trailing data".to_string())),
+		}
+	}
+
+	/// Writes memdump to `memdump_path` and clears the trigger.
+	fn trigger_memdump(&mut self) {
+		self.memdump_trigger = None;
+		let path = self.memdump_path.clone();
+		// The program keeps running without memdump
+		if let Err(e) = self.export_memdump(&path) {
+			eprintln!("Failed to write memdump to {}: {}", path, e);
+		}
+	}
+
+	/// Stores either physical or virtual memory contents of memdump.
+	/// Virtual addresses are translated with the current `satp`.
+	///
+	/// # Arguments
+	/// * `memdump`
+	/// * `is_virtual`
+	fn load_memdump_memory(
+		&mut self,
+		memdump: &Memdump,
+		is_virtual: bool,
+	) -> Result<(), MemdumpError> {
+		let mmu = self.cpu.get_mut_mmu();
+		for entry in memdump
+			.entries
+			.iter()
+			.filter(|entry| entry.is_virtual == is_virtual)
 		{
-			// write SATP
-			match self.cpu.write_csr(0x180, 0x8000000000080016) {
-				_ => {}
+			let p_address = match is_virtual {
+				true => match mmu.translate_address_for_host(entry.address) {
+					Ok(p_address) => p_address,
+					Err(_) => {
+						return Err(MemdumpError {
+							line: entry.line,
+							message: format!("Virtual address {:x} is not mapped", entry.address),
+						})
+					}
+				},
+				false => entry.address,
 			};
-			// write sp
-			self.cpu.update_gpr(String::from("sp"), 0x7f7e9b50);
+			if !mmu.is_mapped(p_address, 8) {
+				return Err(MemdumpError {
+					line: entry.line,
+					message: format!("Physical address {:x} is not mapped", p_address),
+				});
+			}
+			mmu.store_doubleword_raw(p_address, entry.value);
 		}
-		self.cpu.update_pc(header.e_entry);
+		Ok(())
+	}
+
+	/// Sets up architectural state of memdump and then stores virtual
+	/// memory contents.
+	///
+	/// # Arguments
+	/// * `memdump`
+	fn load_memdump_state(&mut self, memdump: &Memdump) -> Result<(), MemdumpError> {
+		// Legacy memdump carries only memory. The state is the one
+		// the memdump was taken with for lab2.
+		if memdump.has_no_state() {
+			self.cpu
+				.write_csr_from_host(CSR_SATP_ADDRESS, LEGACY_MEMDUMP_SATP);
+			self.cpu.x[2] = LEGACY_MEMDUMP_SP;
+		}
+		// Virtual memory contents are translated in the privilege mode
+		if let Some(mode) = memdump.privilege_mode.as_ref() {
+			self.cpu.privilege_mode = mode.clone();
+			self.cpu.get_mut_mmu().update_privilege_mode(mode.clone());
+		}
+		if let Some(satp) = memdump.satp {
+			self.cpu.write_csr_from_host(CSR_SATP_ADDRESS, satp);
+		}
+		if let Some(fcsr) = memdump.fcsr {
+			self.cpu.write_csr_from_host(CSR_FCSR_ADDRESS, fcsr);
+		}
+		for (address, value) in memdump.csrs.iter() {
+			self.cpu.write_csr_from_host(*address, *value);
+		}
+		for (register, value) in memdump.registers.iter() {
+			// x0 is hardwired zero
+			if *register != 0 {
+				self.cpu.x[*register] = *value as i64;
+			}
+		}
+		for (register, value) in memdump.fp_registers.iter() {
+			self.cpu.f[*register] = f64::from_bits(*value);
+		}
+		if let Some(pc) = memdump.pc {
+			self.cpu.update_pc(pc);
+		}
+		self.load_memdump_memory(memdump, true)
 	}
 
 	/// Loads symbols of program and adds them to `symbol_map`.
@@ -450,6 +804,174 @@ impl Emulator {
 		self.cpu.update_xlen(xlen);
 	}
 
+	/// Selects the backend of main memory, sparse by default. This method
+	/// needs to be called before `setup_program`.
+	///
+	/// # Arguments
+	/// * `backend_type`
+	pub fn update_memory_backend_type(&mut self, backend_type: MemoryBackendType) {
+		self.cpu
+			.get_mut_mmu()
+			.update_memory_backend_type(backend_type);
+	}
+
+	/// Updates the ratio of core clock to CLINT mtime clock, 8 by default.
+	/// Returns error if the ratio is zero.
+	///
+	/// # Arguments
+	/// * `ratio` Core clock cycles per mtime increment
+	pub fn update_mtime_clock_ratio(&mut self, ratio: u64) -> Result<(), String> {
+		self.cpu
+			.get_mut_mmu()
+			.get_mut_clint()
+			.set_clock_ratio(ratio)
+	}
+
+	/// Sets up the built-in DRAM timing model for main memory accesses.
+	/// Without it every L2 cache miss takes flat latency. Returns error if
+	/// the DRAM organization is invalid.
+	///
+	/// # Arguments
+	/// * `config`
+	pub fn setup_dram_model(&mut self, config: DramConfig) -> Result<(), String> {
+		self.cpu.get_mut_mmu().setup_dram_model(config)
+	}
+
+	/// Sets up the memory controller with read and write queues between L2
+	/// cache and the DRAM model. The default DRAM model is set up unless
+	/// `setup_dram_model()` is called before.
+	///
+	/// # Arguments
+	/// * `config`
+	pub fn setup_memory_controller(
+		&mut self,
+		config: MemoryControllerConfig,
+	) -> Result<(), String> {
+		self.cpu.get_mut_mmu().setup_memory_controller(config)
+	}
+
+	/// Adds NS16550A UART at `UART_BASE` connected to PLIC and `terminal`.
+	/// Returns error if UART is already set up.
+	///
+	/// # Arguments
+	/// * `terminal`
+	pub fn setup_uart(&mut self, terminal: Box<dyn Terminal>) -> Result<(), String> {
+		self.add_device_with_irq(
+			UART_BASE,
+			UART_SIZE,
+			UART_IRQ,
+			Box::new(Uart::new(terminal)),
+		)
+	}
+
+	/// Sets up filesystem content. It is held in memory and exposed to the
+	/// guest through legacy virtio block device at `VIRTIO_BASE`.
+	///
+	/// # Arguments
+	/// * `content` Filesystem image, e.g. xv6 `fs.img`
+	pub fn setup_filesystem(&mut self, content: Vec<u8>) -> Result<(), String> {
+		self.setup_disk(Box::new(content), VirtioVersion::Legacy)
+	}
+
+	/// Adds virtio block device at `VIRTIO_BASE` connected to PLIC.
+	/// Returns error if a disk is already set up.
+	///
+	/// # Arguments
+	/// * `image` Disk content, e.g. `Vec<u8>` or `File`
+	/// * `version` virtio-mmio register layout
+	pub fn setup_disk(
+		&mut self,
+		image: Box<dyn DiskImage>,
+		version: VirtioVersion,
+	) -> Result<(), String> {
+		self.add_device_with_irq(
+			VIRTIO_BASE,
+			VIRTIO_SIZE,
+			VIRTIO_IRQ,
+			Box::new(VirtioBlockDisk::new(image, version)),
+		)
+	}
+
+	/// Sets up boot ROM to boot Linux with OpenSBI `fw_payload` or BBL.
+	/// The ROM holds reset code and device tree generated from the memory
+	/// and the devices set up so far, so call this after `setup_program()`
+	/// and the device setup. The core starts from the ROM in machine mode
+	/// and jumps to the program entry with `a0` hart ID and `a1` device
+	/// tree address. Returns error if the device tree doesn't fit in the
+	/// ROM or the ROM can't be mapped.
+	///
+	/// # Arguments
+	/// * `bootargs` Kernel command line, e.g. "console=ttyS0"
+	pub fn setup_boot_rom(&mut self, bootargs: &str) -> Result<(), String> {
+		let entry = self.cpu.read_pc();
+		let xlen = self.cpu.xlen.clone();
+		let dtb = {
+			let mmu = self.cpu.get_mmu();
+			generate_device_tree(&xlen, &mmu.memory, &mmu.bus, bootargs)?
+		};
+		let boot_rom = BootRom::new(&xlen, entry, &dtb)?;
+		self.add_device(BOOT_ROM_BASE, BOOT_ROM_SIZE, Box::new(boot_rom))?;
+		self.cpu.update_pc(BOOT_ROM_BASE);
+		self.cpu.privilege_mode = PrivilegeMode::Machine;
+		self.cpu
+			.get_mut_mmu()
+			.update_privilege_mode(PrivilegeMode::Machine);
+		Ok(())
+	}
+
+	/// Maps a peripheral device at a physical address range. Loads and
+	/// stores to the range are forwarded to the device without caching.
+	/// Returns error if the range overlaps with main memory or the other
+	/// devices.
+	///
+	/// # Arguments
+	/// * `base` Physical base address
+	/// * `size` Size of the address range in bytes
+	/// * `device`
+	pub fn add_device(
+		&mut self,
+		base: u64,
+		size: u64,
+		device: Box<dyn Device>,
+	) -> Result<(), String> {
+		self.cpu.get_mut_mmu().add_device(base, size, device)
+	}
+
+	/// Maps a peripheral device at a physical address range and connects
+	/// its interrupt line to PLIC. Returns error if the range can't be
+	/// mapped, or the interrupt source is invalid or already used.
+	///
+	/// # Arguments
+	/// * `base` Physical base address
+	/// * `size` Size of the address range in bytes
+	/// * `irq` PLIC interrupt source number
+	/// * `device`
+	pub fn add_device_with_irq(
+		&mut self,
+		base: u64,
+		size: u64,
+		irq: u32,
+		device: Box<dyn Device>,
+	) -> Result<(), String> {
+		self.cpu
+			.get_mut_mmu()
+			.add_device_with_irq(base, size, irq, device)
+	}
+
+	/// Adds a watchpoint and returns its index. Hits are printed, and
+	/// the emulator exits on a hit if the watchpoint stops emulation.
+	///
+	/// # Arguments
+	/// * `watchpoint`
+	pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> usize {
+		self.cpu.get_mut_mmu().watchpoints.add(watchpoint)
+	}
+
+	/// Removes all the watchpoints.
+	pub fn clear_watchpoints(&mut self) {
+		self.cpu.get_mut_mmu().watchpoints.clear();
+	}
+
 	/// Returns immutable reference to `Cpu`.
 	pub fn get_cpu(&self) -> &Cpu {
 		&self.cpu
@@ -471,3 +993,176 @@ impl Emulator {
 	// 	}
 	// }
 }
+
+// Main memory accesses need DRAMSim with the dramsim feature
+#[cfg(all(test, not(feature = "dramsim")))]
+mod tests {
+	use super::*;
+	use mmu::DRAM_BASE;
+	use std::thread;
+
+	/// Stores pseudo-random numbers to an array at cache line stride
+	/// forever, which keeps evicting cache lines.
+	const TEST_PROGRAM: [u32; 14] = [
+		0x00010417, // auipc s0, 0x10
+		0x00000493, // li s1, 0
+		0x00001937, // lui s2, 1
+		0x123459b7, // lui s3, 0x12345
+		0x033482b3, // loop: mul t0, s1, s3
+		0x0059c9b3, // xor s3, s3, t0
+		0x00649313, // slli t1, s1, 6
+		0x00830333, // add t1, t1, s0
+		0x00033383, // ld t2, 0(t1)
+		0x007a0a33, // add s4, s4, t2
+		0x01333023, // sd s3, 0(t1)
+		0x00148493, // addi s1, s1, 1
+		0xff24c0e3, // blt s1, s2, loop
+		0xfc0006e3, // beqz zero, _start
+	];
+
+	const TEST_MEMORY_CAPACITY: u64 = 1024 * 1024;
+
+	const TEST_CYCLES: u64 = 30000;
+
+	fn create_test_emulator() -> Emulator {
+		let mut emulator = Emulator::new();
+		let mmu = emulator.cpu.get_mut_mmu();
+		mmu.init_memory(TEST_MEMORY_CAPACITY).unwrap();
+		for (i, word) in TEST_PROGRAM.iter().enumerate() {
+			mmu.store_word_raw(DRAM_BASE + i as u64 * 4, *word);
+		}
+		emulator.cpu.setup_firmware_state();
+		emulator.cpu.update_pc(DRAM_BASE);
+		emulator
+	}
+
+	fn run(emulator: &mut Emulator, cycles: u64) {
+		for _ in 0..cycles {
+			emulator.tick(false, "");
+		}
+	}
+
+	// Emulator holds the caches inline and needs a large stack
+	fn run_with_large_stack(test: fn()) {
+		thread::Builder::new()
+			.stack_size(256 * 1024 * 1024)
+			.spawn(test)
+			.unwrap()
+			.join()
+			.unwrap();
+	}
+
+	fn check_restored_run(include_caches: bool) {
+		let mut emulator = create_test_emulator();
+		run(&mut emulator, TEST_CYCLES);
+		let checkpoint = emulator.take_checkpoint(include_caches);
+		run(&mut emulator, TEST_CYCLES);
+
+		let mut restored = create_test_emulator();
+		restored.load_checkpoint(&checkpoint).unwrap();
+		run(&mut restored, TEST_CYCLES);
+
+		assert_eq!(restored.cpu.pc, emulator.cpu.pc);
+		assert_eq!(restored.cpu.x, emulator.cpu.x);
+		assert_eq!(restored.cpu.clock, emulator.cpu.clock);
+		assert!(restored.take_checkpoint(true) == emulator.take_checkpoint(true));
+	}
+
+	#[test]
+	fn restored_run_matches_uninterrupted_run() {
+		run_with_large_stack(|| check_restored_run(true));
+	}
+
+	#[test]
+	fn restored_run_without_caches_matches_uninterrupted_run() {
+		run_with_large_stack(|| check_restored_run(false));
+	}
+
+	#[test]
+	fn load_checkpoint_rejects_invalid_content() {
+		run_with_large_stack(|| {
+			let mut emulator = create_test_emulator();
+			assert!(emulator.load_checkpoint(b"RVEMUCK").is_err());
+			let mut checkpoint = emulator.take_checkpoint(false);
+			checkpoint[8] = 0xff; // version
+			assert!(emulator.load_checkpoint(&checkpoint).is_err());
+			checkpoint.truncate(12);
+			assert!(emulator.load_checkpoint(&checkpoint).is_err());
+		});
+	}
+
+	#[test]
+	fn memdump_doesnt_change_timing() {
+		run_with_large_stack(|| {
+			let mut emulator = create_test_emulator();
+			run(&mut emulator, TEST_CYCLES);
+			let memdump = emulator.take_memdump();
+			run(&mut emulator, TEST_CYCLES);
+
+			let mut uninterrupted = create_test_emulator();
+			run(&mut uninterrupted, TEST_CYCLES * 2);
+			assert_eq!(emulator.cpu.pc, uninterrupted.cpu.pc);
+			assert_eq!(emulator.cpu.clock, uninterrupted.cpu.clock);
+			for address in [
+				CSR_MCYCLE_ADDRESS,
+				CSR_HPMCOUNTER3_ADDRESS,
+				CSR_HPMCOUNTER4_ADDRESS,
+				CSR_HPMCOUNTER5_ADDRESS,
+				CSR_HPMCOUNTER6_ADDRESS,
+			]
+			.iter()
+			{
+				assert_eq!(
+					emulator.cpu.read_csr_raw(*address),
+					uninterrupted.cpu.read_csr_raw(*address)
+				);
+			}
+			assert!(emulator.take_checkpoint(true) == uninterrupted.take_checkpoint(true));
+
+			// The memdump holds what the core loads at the time
+			let mut loaded = create_test_emulator();
+			run(&mut loaded, TEST_CYCLES);
+			let mmu = loaded.cpu.get_mut_mmu();
+			let array = DRAM_BASE + 0x10000;
+			let stored_num = memdump
+				.entries
+				.iter()
+				.filter(|entry| entry.address >= array)
+				.count();
+			assert!(stored_num > 0);
+			for entry in memdump.entries.iter() {
+				assert_eq!(mmu.load_doubleword(entry.address).unwrap(), entry.value);
+			}
+		});
+	}
+
+	#[test]
+	fn memdump_restores_state() {
+		run_with_large_stack(|| {
+			let mut emulator = create_test_emulator();
+			run(&mut emulator, TEST_CYCLES);
+			emulator.cpu.f[1] = f64::from_bits(0xffffffff3f800000); // 1.0f
+			emulator.cpu.f[31] = -2.5;
+			emulator.cpu.write_csr_from_host(CSR_FCSR_ADDRESS, 0x61);
+			emulator.cpu.privilege_mode = PrivilegeMode::Supervisor;
+			let mut expected = vec![];
+			emulator.take_memdump().write(&mut expected).unwrap();
+
+			let mut restored = create_test_emulator();
+			restored.load_memdump(&expected).unwrap();
+			assert_eq!(restored.cpu.pc, emulator.cpu.pc);
+			assert_eq!(restored.cpu.x, emulator.cpu.x);
+			assert_eq!(restored.cpu.f[1].to_bits(), 0xffffffff3f800000);
+			assert_eq!(restored.cpu.f[31], -2.5);
+			assert_eq!(restored.cpu.read_csr_raw(CSR_FCSR_ADDRESS), 0x61);
+			assert!(matches!(
+				restored.cpu.privilege_mode,
+				PrivilegeMode::Supervisor
+			));
+			// Including memory contents
+			let mut actual = vec![];
+			restored.take_memdump().write(&mut actual).unwrap();
+			assert!(actual == expected);
+		});
+	}
+}
@@ -0,0 +1,414 @@
+use cpu::{get_register_name, PrivilegeMode};
+use std::fmt;
+use std::io::{Error as IoError, ErrorKind, Result as IoResult, Write};
+
+/// Parsed memory dump (memdump) file, a snapshot of a running program.
+/// A memdump is a text file with one entry per line.
+///
+/// ```text
+/// # Comment
+/// pc 0x10078
+/// privilege U                 # Privilege mode, M, S, or U
+/// satp 0x8000000000080016
+/// sp 0x7f7e9b50               # Integer register by ABI name or x0-x31
+/// f10 0xffffffff3f800000      # Floating point register bits by f0-f31
+/// fcsr 0x20                   # Rounding mode and accrued exceptions
+/// csr 0x300 0xa00000000       # CSR by address
+/// physical                    # Following addresses are physical (default)
+/// 0x80016000 0000000020005801 # Doubleword at address
+/// virtual                     # Following addresses are virtual
+/// 0x7f7e9b50 0
+/// ```
+///
+/// Numbers are hexadecimal with or without `0x` prefix, but addresses need
+/// to start with a digit to be distinguished from keywords. Virtual addresses
+/// are translated with the page table `satp` points to, so the page table
+/// itself needs to be dumped as physical memory.
+#[derive(Default)]
+pub struct Memdump {
+	pub pc: Option<u64>,
+	pub privilege_mode: Option<PrivilegeMode>,
+	pub satp: Option<u64>,
+	/// Integer registers, register number and value
+	pub registers: Vec<(usize, u64)>,
+	/// Floating point registers, register number and bits. Single
+	/// precision values are NaN-boxed.
+	pub fp_registers: Vec<(usize, u64)>,
+	pub fcsr: Option<u64>,
+	/// CSR address and value
+	pub csrs: Vec<(u16, u64)>,
+	pub entries: Vec<MemdumpEntry>,
+}
+
+/// Doubleword memory content in memdump
+pub struct MemdumpEntry {
+	/// Line number in memdump file
+	pub line: usize,
+	pub address: u64,
+	pub value: u64,
+	/// Whether `address` is virtual address
+	pub is_virtual: bool,
+}
+
+/// Condition to take memdump of a running program
+#[derive(Clone, Debug)]
+pub enum MemdumpTrigger {
+	/// Before the instruction at the virtual address is executed
+	Pc(u64),
+	/// After the number of instructions are retired
+	InstructionNum(u64),
+	/// After [`MEMDUMP_MAGIC_INSTRUCTION`](../cpu/constant.MEMDUMP_MAGIC_INSTRUCTION.html)
+	/// is executed
+	MagicInstruction,
+}
+
+/// Error in memdump file
+#[derive(Debug)]
+pub struct MemdumpError {
+	/// Line number, starting from one
+	pub line: usize,
+	pub message: String,
+}
+
+impl fmt::Display for MemdumpError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "memdump line {}: {}", self.line, self.message)
+	}
+}
+
+impl Memdump {
+	/// Returns true if the memdump has no architectural state but memory,
+	/// as in the legacy format.
+	pub fn has_no_state(&self) -> bool {
+		self.pc.is_none()
+			&& self.privilege_mode.is_none()
+			&& self.satp.is_none()
+			&& self.registers.is_empty()
+			&& self.fp_registers.is_empty()
+			&& self.fcsr.is_none()
+			&& self.csrs.is_empty()
+	}
+
+	/// Writes memdump in the format `parse_memdump()` reads. Returns
+	/// error if the privilege mode is reserved.
+	///
+	/// # Arguments
+	/// * `writer`
+	pub fn write(&self, writer: &mut dyn Write) -> IoResult<()> {
+		if let Some(pc) = self.pc {
+			writeln!(writer, "pc 0x{:x}", pc)?;
+		}
+		if let Some(mode) = self.privilege_mode.as_ref() {
+			let name = get_privilege_mode_name(mode)
+				.ok_or_else(|| IoError::new(ErrorKind::InvalidData, "Reserved privilege mode"))?;
+			writeln!(writer, "privilege {}", name)?;
+		}
+		if let Some(satp) = self.satp {
+			writeln!(writer, "satp 0x{:x}", satp)?;
+		}
+		for (register, value) in self.registers.iter() {
+			writeln!(writer, "{} 0x{:x}", get_register_name(*register), value)?;
+		}
+		for (register, value) in self.fp_registers.iter() {
+			writeln!(writer, "f{} 0x{:x}", register, value)?;
+		}
+		if let Some(fcsr) = self.fcsr {
+			writeln!(writer, "fcsr 0x{:x}", fcsr)?;
+		}
+		for (address, value) in self.csrs.iter() {
+			writeln!(writer, "csr 0x{:03x} 0x{:x}", address, value)?;
+		}
+		for is_virtual in [false, true].iter() {
+			let mut entries = self
+				.entries
+				.iter()
+				.filter(|entry| entry.is_virtual == *is_virtual)
+				.peekable();
+			let header = match is_virtual {
+				true => "virtual",
+				false => "physical",
+			};
+			if entries.peek().is_some() {
+				writeln!(writer, "{}", header)?;
+			}
+			for entry in entries {
+				writeln!(writer, "0x{:x} {:016x}", entry.address, entry.value)?;
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Parses memdump file content. `pc`, `privilege`, `satp` and `fcsr`
+/// can appear only once.
+///
+/// # Arguments
+/// * `contents` memdump file content
+pub fn parse_memdump(contents: &[u8]) -> Result<Memdump, MemdumpError> {
+	let text = match std::str::from_utf8(contents) {
+		Ok(text) => text,
+		Err(e) => {
+			return Err(MemdumpError {
+				line: contents[..e.valid_up_to()]
+					.iter()
+					.filter(|c| **c == b'\n')
+					.count() + 1,
+				message: "Invalid UTF-8 text".to_string(),
+			})
+		}
+	};
+
+	let mut memdump = Memdump::default();
+	let mut is_virtual = false;
+	for (i, line) in text.lines().enumerate() {
+		let error = |message: String| MemdumpError {
+			line: i + 1,
+			message,
+		};
+		let line = match line.find('#') {
+			Some(position) => &line[..position],
+			None => line,
+		};
+		let tokens: Vec<&str> = line.split_whitespace().collect();
+		if tokens.is_empty() {
+			continue;
+		}
+
+		let expected_num = match tokens[0] {
+			"physical" | "virtual" => 1,
+			"csr" => 3,
+			_ => 2,
+		};
+		if tokens.len() != expected_num {
+			return Err(error(format!(
+				"{} expects {} fields but found {}",
+				tokens[0],
+				expected_num,
+				tokens.len()
+			)));
+		}
+		let parse = |token: &str| parse_hex(token).map_err(&error);
+
+		match tokens[0] {
+			"physical" => is_virtual = false,
+			"virtual" => is_virtual = true,
+			"pc" => set_once(&mut memdump.pc, parse(tokens[1])?, "pc").map_err(&error)?,
+			"privilege" => match get_privilege_mode_by_name(tokens[1]) {
+				Some(mode) => {
+					set_once(&mut memdump.privilege_mode, mode, "privilege").map_err(&error)?
+				}
+				None => return Err(error(format!("Invalid privilege mode {}", tokens[1]))),
+			},
+			"satp" => set_once(&mut memdump.satp, parse(tokens[1])?, "satp").map_err(&error)?,
+			"fcsr" => {
+				let fcsr = parse(tokens[1])?;
+				if fcsr > 0xff {
+					return Err(error(format!("Invalid fcsr {:x}", fcsr)));
+				}
+				set_once(&mut memdump.fcsr, fcsr, "fcsr").map_err(&error)?;
+			}
+			"csr" => {
+				let address = parse(tokens[1])?;
+				if address > 0xfff {
+					return Err(error(format!("Invalid CSR address {:x}", address)));
+				}
+				memdump.csrs.push((address as u16, parse(tokens[2])?));
+			}
+			name => match (get_register_number(name), get_fp_register_number(name)) {
+				(Some(register), _) => memdump.registers.push((register, parse(tokens[1])?)),
+				(None, Some(register)) => memdump.fp_registers.push((register, parse(tokens[1])?)),
+				(None, None) => {
+					let address = match name.starts_with(|c: char| c.is_ascii_digit()) {
+						true => parse(name)?,
+						false => return Err(error(format!("Unknown keyword {}", name))),
+					};
+					memdump.entries.push(MemdumpEntry {
+						line: i + 1,
+						address,
+						value: parse(tokens[1])?,
+						is_virtual,
+					});
+				}
+			},
+		}
+	}
+	Ok(memdump)
+}
+
+/// Sets a field which can appear only once in memdump.
+///
+/// # Arguments
+/// * `field`
+/// * `value`
+/// * `keyword` Reported in error
+fn set_once<T>(field: &mut Option<T>, value: T, keyword: &str) -> Result<(), String> {
+	match field.is_some() {
+		true => Err(format!("Duplicate {}", keyword)),
+		false => {
+			*field = Some(value);
+			Ok(())
+		}
+	}
+}
+
+fn parse_hex(token: &str) -> Result<u64, String> {
+	let digits = match token.starts_with("0x") || token.starts_with("0X") {
+		true => &token[2..],
+		false => token,
+	};
+	u64::from_str_radix(digits, 16).map_err(|_| format!("Invalid hexadecimal number {}", token))
+}
+
+fn get_register_number(name: &str) -> Option<usize> {
+	if let Some(number) = name.strip_prefix('x') {
+		if let Ok(number) = number.parse::<usize>() {
+			if number < 32 {
+				return Some(number);
+			}
+		}
+	}
+	match name {
+		"fp" => Some(8),
+		_ => (0..32).find(|i| get_register_name(*i) == name),
+	}
+}
+
+fn get_fp_register_number(name: &str) -> Option<usize> {
+	match name.strip_prefix('f').map(|number| number.parse::<usize>()) {
+		Some(Ok(number)) if number < 32 => Some(number),
+		_ => None,
+	}
+}
+
+/// Returns the name of privilege mode, or None if it's reserved
+fn get_privilege_mode_name(mode: &PrivilegeMode) -> Option<&'static str> {
+	match mode {
+		PrivilegeMode::User => Some("U"),
+		PrivilegeMode::Supervisor => Some("S"),
+		PrivilegeMode::Reserved => None,
+		PrivilegeMode::Machine => Some("M"),
+	}
+}
+
+fn get_privilege_mode_by_name(name: &str) -> Option<PrivilegeMode> {
+	match name {
+		"U" => Some(PrivilegeMode::User),
+		"S" => Some(PrivilegeMode::Supervisor),
+		"M" => Some(PrivilegeMode::Machine),
+		_ => None,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn get_error_line_bytes(contents: &[u8]) -> usize {
+		match parse_memdump(contents) {
+			Ok(_memdump) => panic!("{:?} is parsed", contents),
+			Err(e) => e.line,
+		}
+	}
+
+	fn get_error_line(contents: &str) -> usize {
+		get_error_line_bytes(contents.as_bytes())
+	}
+
+	#[test]
+	fn parses_state_and_memory() {
+		let contents = "# Comment\n\
+			pc 0x10078\n\
+			privilege S  # Supervisor\n\
+			satp 8000000000080016\n\
+			sp 0x7f7e9b50\n\
+			x10 1\n\
+			fp 2\n\
+			f10 0xffffffff3f800000\n\
+			fcsr 0x20\n\
+			csr 0x300 0xa00000000\n\
+			\n\
+			0x80016000 0000000020005801\n\
+			virtual\n\
+			0x7f7e9b50 0\n\
+			physical\n\
+			80016008 0x1\n";
+		let memdump = parse_memdump(contents.as_bytes()).unwrap();
+		assert!(!memdump.has_no_state());
+		assert_eq!(memdump.pc, Some(0x10078));
+		assert!(matches!(
+			memdump.privilege_mode,
+			Some(PrivilegeMode::Supervisor)
+		));
+		assert_eq!(memdump.satp, Some(0x8000000000080016));
+		assert_eq!(memdump.registers, vec![(2, 0x7f7e9b50), (10, 1), (8, 2)]);
+		assert_eq!(memdump.fp_registers, vec![(10, 0xffffffff3f800000)]);
+		assert_eq!(memdump.fcsr, Some(0x20));
+		assert_eq!(memdump.csrs, vec![(0x300, 0xa00000000)]);
+		let entries: Vec<(usize, u64, u64, bool)> = memdump
+			.entries
+			.iter()
+			.map(|entry| (entry.line, entry.address, entry.value, entry.is_virtual))
+			.collect();
+		assert_eq!(
+			entries,
+			vec![
+				(12, 0x80016000, 0x20005801, false),
+				(14, 0x7f7e9b50, 0, true),
+				(16, 0x80016008, 1, false),
+			]
+		);
+	}
+
+	#[test]
+	fn parses_legacy_format() {
+		let contents = "0x80016000 0000000020005801\n0x80016008 0x1\n";
+		let memdump = parse_memdump(contents.as_bytes()).unwrap();
+		assert!(memdump.has_no_state());
+		assert_eq!(memdump.entries.len(), 2);
+		assert!(memdump.entries.iter().all(|entry| !entry.is_virtual));
+		assert_eq!(memdump.entries[0].address, 0x80016000);
+		assert_eq!(memdump.entries[0].value, 0x20005801);
+		assert_eq!(memdump.entries[1].value, 1);
+	}
+
+	#[test]
+	fn reports_malformed_lines() {
+		// Bad hexadecimal numbers
+		assert_eq!(get_error_line("pc 0x10\nsp 0xg\n"), 2);
+		assert_eq!(get_error_line("\n\n0x80000000 12345678123456789\n"), 3);
+		assert_eq!(get_error_line("0x80000000 0x\n"), 1);
+		// Unknown registers and keywords
+		assert_eq!(get_error_line("# x\nx32 0\n"), 2);
+		assert_eq!(get_error_line("pc 0\nf32 0\n"), 2);
+		assert_eq!(get_error_line("a8 0\n"), 1);
+		// Duplicate single value fields
+		assert_eq!(get_error_line("pc 0x10\nsp 0\npc 0x10\n"), 3);
+		assert_eq!(get_error_line("privilege M\nprivilege M\n"), 2);
+		assert_eq!(get_error_line("satp 0\nsatp 1\n"), 2);
+		// Invalid values and field numbers
+		assert_eq!(get_error_line("privilege H\n"), 1);
+		assert_eq!(get_error_line("fcsr 0x100\n"), 1);
+		assert_eq!(get_error_line("csr 0x1000 0\n"), 1);
+		assert_eq!(get_error_line("pc\n"), 1);
+		assert_eq!(get_error_line("pc 0 0\n"), 1);
+		assert_eq!(get_error_line("virtual 0x80000000\n"), 1);
+		assert_eq!(get_error_line_bytes(b"pc 0\n\xff"), 2);
+	}
+
+	#[test]
+	fn writes_parsable_memdump() {
+		let contents = "pc 0x10078\nprivilege U\nsatp 0x8000000000080016\nsp 0x7f7e9b50\n\
+			f1 0x3ff0000000000000\nfcsr 0x1\ncsr 0x300 0xa00000000\n\
+			physical\n0x80016000 0000000020005801\nvirtual\n0x7f7e9b50 0000000000000000\n";
+		let memdump = parse_memdump(contents.as_bytes()).unwrap();
+		let mut written = vec![];
+		memdump.write(&mut written).unwrap();
+		assert_eq!(String::from_utf8(written).unwrap(), contents);
+
+		let memdump = Memdump {
+			privilege_mode: Some(PrivilegeMode::Reserved),
+			..Memdump::default()
+		};
+		assert!(memdump.write(&mut vec![]).is_err());
+	}
+}
@@ -1,49 +1,88 @@
-/// Emulates main memory.
-pub struct Memory {
-	/// Memory content
-	pub data: Vec<u64>,
+use fnv::FnvHashMap;
+
+/// Page size of `SparseMemory`, 512 doublewords
+pub const SPARSE_MEMORY_PAGE_SIZE: u64 = 4096;
+const SPARSE_MEMORY_PAGE_DOUBLEWORDS: usize = (SPARSE_MEMORY_PAGE_SIZE / 8) as usize;
+
+/// Backend kind used for main memory
+#[derive(Clone, Debug)]
+pub enum MemoryBackendType {
+	Dense,
+	Sparse,
 }
 
-impl Memory {
-	/// Creates a new `Memory`
-	pub fn new() -> Self {
-		Memory { data: vec![] }
+/// Creates a new memory backend.
+///
+/// # Arguments
+/// * `backend_type`
+/// * `capacity` in bytes
+pub fn new_memory_backend(
+	backend_type: &MemoryBackendType,
+	capacity: u64,
+) -> Box<dyn MemoryBackend> {
+	match backend_type {
+		MemoryBackendType::Dense => {
+			let mut memory = Memory::new();
+			memory.init(capacity);
+			Box::new(memory)
+		}
+		MemoryBackendType::Sparse => Box::new(SparseMemory::new(capacity)),
 	}
+}
 
-	/// Initializes memory content.
-	/// This method is expected to be called only once.
+/// Storage of main memory content. Address is the offset from the base
+/// of memory region. Backends provide aligned doubleword access and the
+/// other accesses are built on it.
+pub trait MemoryBackend {
+	/// Returns the capacity in bytes
+	fn capacity(&self) -> u64;
+
+	/// Reads an aligned doubleword.
 	///
 	/// # Arguments
-	/// * `capacity`
-	pub fn init(&mut self, capacity: u64) {
-		for _i in 0..((capacity + 7) / 8) {
-			self.data.push(0);
-		}
+	/// * `index` Address divided by eight
+	fn read_aligned(&self, index: u64) -> u64;
+
+	/// Writes the bits of an aligned doubleword selected by mask.
+	///
+	/// # Arguments
+	/// * `index` Address divided by eight
+	/// * `value`
+	/// * `mask`
+	fn write_aligned(&mut self, index: u64, value: u64, mask: u64);
+
+	/// Returns the offsets of the pages which may hold non-zero data in
+	/// ascending order. Page size is `SPARSE_MEMORY_PAGE_SIZE`.
+	fn get_touched_pages(&self) -> Vec<u64> {
+		let page_num = self.capacity().div_ceil(SPARSE_MEMORY_PAGE_SIZE);
+		(0..page_num)
+			.map(|page| page * SPARSE_MEMORY_PAGE_SIZE)
+			.filter(|offset| {
+				let top = (offset + SPARSE_MEMORY_PAGE_SIZE).min(self.capacity());
+				(*offset..top)
+					.step_by(8)
+					.any(|address| self.read_aligned(address >> 3) != 0)
+			})
+			.collect()
 	}
+
 	/// Reads a byte from memory.
 	///
 	/// # Arguments
 	/// * `address`
-	pub fn read_byte(&self, address: u64) -> u8 {
-		let index = (address >> 3) as usize;
-		let pos = ((address % 8) as u64) * 8;
-		/*
-		if index > self.data.len() {
-			println!("big address error : {}",address);
-			return 0;
-		}*/
-		(self.data[index] >> pos) as u8
+	fn read_byte(&self, address: u64) -> u8 {
+		let pos = (address % 8) * 8;
+		(self.read_aligned(address >> 3) >> pos) as u8
 	}
 
 	/// Reads two bytes from memory.
 	///
 	/// # Arguments
 	/// * `address`
-	pub fn read_halfword(&self, address: u64) -> u16 {
+	fn read_halfword(&self, address: u64) -> u16 {
 		if (address % 2) == 0 {
-			let index = (address >> 3) as usize;
-			let pos = ((address % 8) as u64) * 8;
-			(self.data[index] >> pos) as u16
+			let pos = (address % 8) * 8;
+			(self.read_aligned(address >> 3) >> pos) as u16
 		} else {
 			self.read_bytes(address, 2) as u16
 		}
@@ -53,11 +92,10 @@ impl Memory {
 	///
 	/// # Arguments
 	/// * `address`
-	pub fn read_word(&self, address: u64) -> u32 {
+	fn read_word(&self, address: u64) -> u32 {
 		if (address % 4) == 0 {
-			let index = (address >> 3) as usize;
-			let pos = ((address % 8) as u64) * 8;
-			(self.data[index] >> pos) as u32
+			let pos = (address % 8) * 8;
+			(self.read_aligned(address >> 3) >> pos) as u32
 		} else {
 			self.read_bytes(address, 4) as u32
 		}
@@ -67,13 +105,12 @@ impl Memory {
 	///
 	/// # Arguments
 	/// * `address`
-	pub fn read_doubleword(&self, address: u64) -> u64 {
+	fn read_doubleword(&self, address: u64) -> u64 {
 		if (address % 8) == 0 {
-			let index = (address >> 3) as usize;
-			self.data[index]
+			self.read_aligned(address >> 3)
 		} else if (address % 4) == 0 {
 			(self.read_word(address) as u64)
-				| ((self.read_word(address.wrapping_add(4)) as u64) << 4)
+				| ((self.read_word(address.wrapping_add(4)) as u64) << 32)
 		} else {
 			self.read_bytes(address, 8)
 		}
@@ -84,7 +121,7 @@ impl Memory {
 	/// # Arguments
 	/// * `address`
 	/// * `width` up to eight
-	pub fn read_bytes(&self, address: u64, width: u64) -> u64 {
+	fn read_bytes(&self, address: u64, width: u64) -> u64 {
 		let mut data = 0 as u64;
 		for i in 0..width {
 			data |= (self.read_byte(address.wrapping_add(i)) as u64) << (i * 8);
@@ -97,10 +134,9 @@ impl Memory {
 	/// # Arguments
 	/// * `address`
 	/// * `value`
-	pub fn write_byte(&mut self, address: u64, value: u8) {
-		let index = (address >> 3) as usize;
-		let pos = ((address % 8) as u64) * 8;
-		self.data[index] = (self.data[index] & !(0xff << pos)) | ((value as u64) << pos);
+	fn write_byte(&mut self, address: u64, value: u8) {
+		let pos = (address % 8) * 8;
+		self.write_aligned(address >> 3, (value as u64) << pos, 0xff << pos);
 	}
 
 	/// Writes two bytes to memory.
@@ -108,11 +144,10 @@ impl Memory {
 	/// # Arguments
 	/// * `address`
 	/// * `value`
-	pub fn write_halfword(&mut self, address: u64, value: u16) {
+	fn write_halfword(&mut self, address: u64, value: u16) {
 		if (address % 2) == 0 {
-			let index = (address >> 3) as usize;
-			let pos = ((address % 8) as u64) * 8;
-			self.data[index] = (self.data[index] & !(0xffff << pos)) | ((value as u64) << pos);
+			let pos = (address % 8) * 8;
+			self.write_aligned(address >> 3, (value as u64) << pos, 0xffff << pos);
 		} else {
 			self.write_bytes(address, value as u64, 2);
 		}
@@ -123,11 +158,10 @@ impl Memory {
 	/// # Arguments
 	/// * `address`
 	/// * `value`
-	pub fn write_word(&mut self, address: u64, value: u32) {
+	fn write_word(&mut self, address: u64, value: u32) {
 		if (address % 4) == 0 {
-			let index = (address >> 3) as usize;
-			let pos = ((address % 8) as u64) * 8;
-			self.data[index] = (self.data[index] & !(0xffffffff << pos)) | ((value as u64) << pos);
+			let pos = (address % 8) * 8;
+			self.write_aligned(address >> 3, (value as u64) << pos, 0xffffffff << pos);
 		} else {
 			self.write_bytes(address, value as u64, 4);
 		}
@@ -138,10 +172,9 @@ impl Memory {
 	/// # Arguments
 	/// * `address`
 	/// * `value`
-	pub fn write_doubleword(&mut self, address: u64, value: u64) {
+	fn write_doubleword(&mut self, address: u64, value: u64) {
 		if (address % 8) == 0 {
-			let index = (address >> 3) as usize;
-			self.data[index] = value;
+			self.write_aligned(address >> 3, value, 0xffffffffffffffff);
 		} else if (address % 4) == 0 {
 			self.write_word(address, (value & 0xffffffff) as u32);
 			self.write_word(address.wrapping_add(4), (value >> 32) as u32);
@@ -156,7 +189,7 @@ impl Memory {
 	/// * `address`
 	/// * `value`
 	/// * `width` up to eight
-	pub fn write_bytes(&mut self, address: u64, value: u64, width: u64) {
+	fn write_bytes(&mut self, address: u64, value: u64, width: u64) {
 		for i in 0..width {
 			self.write_byte(address.wrapping_add(i), (value >> (i * 8)) as u8);
 		}
@@ -166,7 +199,163 @@ impl Memory {
 	///
 	/// # Arguments
 	/// * `address`
-	pub fn validate_address(&self, address: u64) -> bool {
-		return (address as usize) < self.data.len();
+	fn validate_address(&self, address: u64) -> bool {
+		address < self.capacity()
+	}
+}
+
+/// Emulates main memory. The whole content is allocated at initialization.
+pub struct Memory {
+	/// Memory content
+	pub data: Vec<u64>,
+}
+
+impl Memory {
+	/// Creates a new `Memory`
+	pub fn new() -> Self {
+		Memory { data: vec![] }
+	}
+
+	/// Initializes memory content.
+	/// This method is expected to be called only once.
+	///
+	/// # Arguments
+	/// * `capacity`
+	pub fn init(&mut self, capacity: u64) {
+		self.data = vec![0; ((capacity + 7) / 8) as usize];
+	}
+}
+
+impl MemoryBackend for Memory {
+	fn capacity(&self) -> u64 {
+		(self.data.len() as u64) * 8
+	}
+
+	fn read_aligned(&self, index: u64) -> u64 {
+		self.data[index as usize]
+	}
+
+	fn write_aligned(&mut self, index: u64, value: u64, mask: u64) {
+		let index = index as usize;
+		self.data[index] = (self.data[index] & !mask) | (value & mask);
+	}
+}
+
+/// Emulates main memory whose pages are allocated on the first write.
+/// Pages never written read zero.
+pub struct SparseMemory {
+	capacity: u64,
+	pages: FnvHashMap<u64, Box<[u64; SPARSE_MEMORY_PAGE_DOUBLEWORDS]>>,
+}
+
+impl SparseMemory {
+	/// Creates a new `SparseMemory`
+	///
+	/// # Arguments
+	/// * `capacity`
+	pub fn new(capacity: u64) -> Self {
+		SparseMemory {
+			capacity,
+			pages: FnvHashMap::default(),
+		}
+	}
+
+	/// Returns the number of allocated pages
+	pub fn get_allocated_page_num(&self) -> usize {
+		self.pages.len()
+	}
+}
+
+impl MemoryBackend for SparseMemory {
+	fn capacity(&self) -> u64 {
+		self.capacity
+	}
+
+	fn get_touched_pages(&self) -> Vec<u64> {
+		let mut pages: Vec<u64> = self
+			.pages
+			.keys()
+			.map(|page_number| page_number * SPARSE_MEMORY_PAGE_SIZE)
+			.collect();
+		pages.sort_unstable();
+		pages
+	}
+
+	fn read_aligned(&self, index: u64) -> u64 {
+		debug_assert!(
+			index * 8 < self.capacity,
+			"Memory address must be smaller than capacity. {:X}",
+			index * 8
+		);
+		let page_number = index / SPARSE_MEMORY_PAGE_DOUBLEWORDS as u64;
+		match self.pages.get(&page_number) {
+			Some(page) => page[(index % SPARSE_MEMORY_PAGE_DOUBLEWORDS as u64) as usize],
+			None => 0,
+		}
+	}
+
+	fn write_aligned(&mut self, index: u64, value: u64, mask: u64) {
+		debug_assert!(
+			index * 8 < self.capacity,
+			"Memory address must be smaller than capacity. {:X}",
+			index * 8
+		);
+		let page_number = index / SPARSE_MEMORY_PAGE_DOUBLEWORDS as u64;
+		let offset = (index % SPARSE_MEMORY_PAGE_DOUBLEWORDS as u64) as usize;
+		match self.pages.get_mut(&page_number) {
+			Some(page) => page[offset] = (page[offset] & !mask) | (value & mask),
+			None => {
+				// Writing zero to an unallocated page changes nothing
+				if (value & mask) != 0 {
+					let mut page = Box::new([0; SPARSE_MEMORY_PAGE_DOUBLEWORDS]);
+					page[offset] = value & mask;
+					self.pages.insert(page_number, page);
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn sparse_memory_allocates_pages_on_write() {
+		let mut memory = SparseMemory::new(0x10000);
+		// Untouched pages read zero without allocation
+		assert_eq!(memory.read_doubleword(0x1000), 0);
+		assert_eq!(memory.read_byte(0xffff), 0);
+		assert_eq!(memory.get_allocated_page_num(), 0);
+		// Writing zero allocates nothing
+		memory.write_doubleword(0x1000, 0);
+		assert_eq!(memory.get_allocated_page_num(), 0);
+
+		memory.write_byte(0x2003, 0xab);
+		assert_eq!(memory.get_allocated_page_num(), 1);
+		assert_eq!(memory.read_doubleword(0x2000), 0xab000000);
+		// Unaligned write across the page boundary allocates the next page
+		memory.write_doubleword(0x2ffc, 0x1122334455667788);
+		assert_eq!(memory.get_allocated_page_num(), 2);
+		assert_eq!(memory.read_doubleword(0x2ffc), 0x1122334455667788);
+		assert_eq!(memory.read_word(0x3000), 0x11223344);
+		assert_eq!(memory.get_touched_pages(), vec![0x2000, 0x3000]);
+	}
+
+	#[test]
+	fn backends_match() {
+		let mut sparse = new_memory_backend(&MemoryBackendType::Sparse, 0x4000);
+		let mut dense = new_memory_backend(&MemoryBackendType::Dense, 0x4000);
+		for memory in [&mut sparse, &mut dense].iter_mut() {
+			memory.write_halfword(0x1ffe, 0xbeef);
+			memory.write_word(0x3002, 0xdeadbeef);
+		}
+		for index in 0..(0x4000 / 8) {
+			assert_eq!(sparse.read_aligned(index), dense.read_aligned(index));
+		}
+		assert_eq!(sparse.capacity(), dense.capacity());
+		// The default implementation finds the pages holding non-zero data
+		assert_eq!(dense.get_touched_pages(), vec![0x1000, 0x3000]);
+		assert_eq!(sparse.get_touched_pages(), dense.get_touched_pages());
 	}
 }
@@ -0,0 +1,719 @@
+use checkpoint::{CheckpointError, CheckpointReader, CheckpointWriter};
+use dram_model::DramModel;
+use std::collections::VecDeque;
+
+/// Default number of entries in each of the read and write queues
+pub const MEMORY_CONTROLLER_QUEUE_SIZE: usize = 32;
+/// Default write queue occupancy which starts draining writes
+pub const MEMORY_CONTROLLER_WRITE_HIGH_WATERMARK: usize = 24;
+/// Default write queue occupancy which stops draining writes
+pub const MEMORY_CONTROLLER_WRITE_LOW_WATERMARK: usize = 8;
+/// Default consecutive requests served from a source which blacklist it
+pub const BLISS_THRESHOLD: u64 = 4;
+/// Default CPU cycles between clearings of the blacklist
+pub const BLISS_CLEAR_INTERVAL: u64 = 10000;
+/// CPU cycles to forward data from the write queue to a read
+pub const WRITE_QUEUE_FORWARD_LATENCY: u64 = 1;
+
+/// Order the memory controller serves the queued requests in
+#[derive(Clone, Debug, PartialEq)]
+pub enum SchedulingPolicy {
+	/// The oldest request first
+	Fcfs,
+	/// Requests hitting the open row first, then the oldest one
+	FrFcfs,
+	/// FR-FCFS except that requests from blacklisted sources, which were
+	/// served many times in a row, go last
+	Bliss,
+}
+
+/// Requester of a memory request, which BLISS tracks
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MemoryRequestSource {
+	/// Instruction fetch of the core. Fetches bypass the caches so far and
+	/// don't reach the controller.
+	Fetch,
+	/// Load or store of the core
+	LoadStore,
+	/// Dirty cache line written back
+	WriteBack,
+	/// Device accessing main memory directly
+	Dma,
+}
+
+impl MemoryRequestSource {
+	/// Returns true if the requester stalls until its reads complete.
+	/// Reads from the others are left outstanding.
+	pub fn is_blocking(&self) -> bool {
+		match self {
+			MemoryRequestSource::Fetch | MemoryRequestSource::LoadStore => true,
+			MemoryRequestSource::WriteBack | MemoryRequestSource::Dma => false,
+		}
+	}
+
+	fn get_encoding(&self) -> u64 {
+		match self {
+			MemoryRequestSource::Fetch => 0,
+			MemoryRequestSource::LoadStore => 1,
+			MemoryRequestSource::WriteBack => 2,
+			MemoryRequestSource::Dma => 3,
+		}
+	}
+
+	fn from_encoding(encoding: u64) -> Option<Self> {
+		match encoding {
+			0 => Some(MemoryRequestSource::Fetch),
+			1 => Some(MemoryRequestSource::LoadStore),
+			2 => Some(MemoryRequestSource::WriteBack),
+			3 => Some(MemoryRequestSource::Dma),
+			_ => None,
+		}
+	}
+}
+
+/// Memory controller organization and scheduling
+#[derive(Clone, Debug)]
+pub struct MemoryControllerConfig {
+	pub read_queue_size: usize,
+	pub write_queue_size: usize,
+	/// Write queue occupancy which starts draining writes
+	pub write_high_watermark: usize,
+	/// Write queue occupancy which stops draining writes
+	pub write_low_watermark: usize,
+	pub policy: SchedulingPolicy,
+	/// Consecutive requests served from a source which blacklist it
+	pub bliss_threshold: u64,
+	/// CPU cycles between clearings of the blacklist
+	pub bliss_clear_interval: u64,
+}
+
+impl Default for MemoryControllerConfig {
+	fn default() -> Self {
+		MemoryControllerConfig {
+			read_queue_size: MEMORY_CONTROLLER_QUEUE_SIZE,
+			write_queue_size: MEMORY_CONTROLLER_QUEUE_SIZE,
+			write_high_watermark: MEMORY_CONTROLLER_WRITE_HIGH_WATERMARK,
+			write_low_watermark: MEMORY_CONTROLLER_WRITE_LOW_WATERMARK,
+			policy: SchedulingPolicy::FrFcfs,
+			bliss_threshold: BLISS_THRESHOLD,
+			bliss_clear_interval: BLISS_CLEAR_INTERVAL,
+		}
+	}
+}
+
+impl MemoryControllerConfig {
+	/// Returns error if the queues or the watermarks are invalid.
+	pub fn validate(&self) -> Result<(), String> {
+		if self.read_queue_size == 0 || self.write_queue_size == 0 {
+			return Err("Memory controller queues need at least one entry".to_string());
+		}
+		if self.write_low_watermark >= self.write_high_watermark
+			|| self.write_high_watermark > self.write_queue_size
+		{
+			return Err(format!(
+				"Write watermarks must be low < high <= write queue size. {} {} {}",
+				self.write_low_watermark, self.write_high_watermark, self.write_queue_size
+			));
+		}
+		Ok(())
+	}
+}
+
+/// Request queued in the memory controller
+#[derive(Clone, Debug)]
+pub struct MemoryRequest {
+	/// Physical address of the cache line
+	pub address: u64,
+	pub is_write: bool,
+	pub source: MemoryRequestSource,
+	/// CPU cycle the request arrives
+	pub arrival_cycle: u64,
+}
+
+/// Memory controller between L2 cache and DRAM. Requests wait in the
+/// read and write queues in arrival order. The controller issues one of
+/// them to DRAM when the previous one completes, selected by the policy
+/// among the ones arrived by then. The core stalls until its read is
+/// served, while reads from the other sources are left outstanding.
+/// Writes are posted to the write queue and drained in a burst from the
+/// high watermark down to the low watermark, so that they delay the reads
+/// as little as possible. A read of a line in the write queue is served
+/// from there.
+pub struct MemoryController {
+	config: MemoryControllerConfig,
+	dram_model: DramModel,
+	read_queue: VecDeque<MemoryRequest>,
+	write_queue: VecDeque<MemoryRequest>,
+	/// CPU cycle the controller issues the next request
+	ready_cycle: u64,
+	/// Whether the write queue is being drained
+	is_draining: bool,
+	/// BLISS blacklist, a bit per source encoding
+	blacklist: u64,
+	/// Source of the last served request
+	last_source: MemoryRequestSource,
+	/// Requests served from `last_source` in a row
+	streak_num: u64,
+	/// CPU cycle BLISS clears the blacklist next
+	clear_cycle: u64,
+	pub read_num: u64,
+	pub write_num: u64,
+	/// Reads served from the write queue
+	pub forward_num: u64,
+	/// Write queue drains
+	pub drain_num: u64,
+	/// Total CPU cycles from arrival to completion of reads
+	pub read_latency: u64,
+	/// Total CPU cycles from arrival to completion of writes
+	pub write_latency: u64,
+	/// Total occupancy of the read and write queues seen by the requests
+	pub queue_occupancy: u64,
+	pub max_read_queue_occupancy: u64,
+	pub max_write_queue_occupancy: u64,
+}
+
+impl MemoryController {
+	/// Creates a new `MemoryController`. Returns error if the configuration
+	/// is invalid.
+	///
+	/// # Arguments
+	/// * `config`
+	/// * `dram_model` DRAM the controller sends requests to
+	pub fn new(config: MemoryControllerConfig, dram_model: DramModel) -> Result<Self, String> {
+		config.validate()?;
+		Ok(MemoryController {
+			clear_cycle: config.bliss_clear_interval,
+			config,
+			dram_model,
+			read_queue: VecDeque::new(),
+			write_queue: VecDeque::new(),
+			ready_cycle: 0,
+			is_draining: false,
+			blacklist: 0,
+			last_source: MemoryRequestSource::LoadStore,
+			streak_num: 0,
+			read_num: 0,
+			write_num: 0,
+			forward_num: 0,
+			drain_num: 0,
+			read_latency: 0,
+			write_latency: 0,
+			queue_occupancy: 0,
+			max_read_queue_occupancy: 0,
+			max_write_queue_occupancy: 0,
+		})
+	}
+
+	pub fn get_config(&self) -> &MemoryControllerConfig {
+		&self.config
+	}
+
+	pub fn get_dram_model(&self) -> &DramModel {
+		&self.dram_model
+	}
+
+	/// Consumes the controller and returns its DRAM model.
+	pub fn into_dram_model(self) -> DramModel {
+		self.dram_model
+	}
+
+	/// Handles a cache line request and returns the CPU cycle the requester
+	/// can go on, which is the completion for a read the requester stalls
+	/// for and the acceptance otherwise. The queued requests the controller
+	/// issues before the arrival are served first.
+	///
+	/// # Arguments
+	/// * `p_address` Physical address
+	/// * `is_write`
+	/// * `source` Requester
+	/// * `cycle` CPU cycle the request arrives
+	pub fn access(
+		&mut self,
+		p_address: u64,
+		is_write: bool,
+		source: MemoryRequestSource,
+		cycle: u64,
+	) -> u64 {
+		while let Some(issue_cycle) = self.get_issue_cycle() {
+			if issue_cycle >= cycle {
+				break;
+			}
+			self.serve();
+		}
+		self.queue_occupancy += (self.read_queue.len() + self.write_queue.len()) as u64;
+		let request = MemoryRequest {
+			address: p_address,
+			is_write,
+			source,
+			arrival_cycle: cycle,
+		};
+		match is_write {
+			true => self.write(request),
+			false => self.read(request),
+		}
+	}
+
+	fn read(&mut self, request: MemoryRequest) -> u64 {
+		self.read_num += 1;
+		let cycle = request.arrival_cycle;
+		if self
+			.write_queue
+			.iter()
+			.any(|write| write.address == request.address)
+		{
+			self.forward_num += 1;
+			self.read_latency += WRITE_QUEUE_FORWARD_LATENCY;
+			return cycle + WRITE_QUEUE_FORWARD_LATENCY;
+		}
+		// Requester waits until a queue entry is free
+		let mut accept_cycle = cycle;
+		while self.read_queue.len() >= self.config.read_queue_size {
+			let (_, done_cycle) = self.serve().unwrap();
+			accept_cycle = accept_cycle.max(done_cycle);
+		}
+		let is_blocking = request.source.is_blocking();
+		insert_by_arrival(&mut self.read_queue, request);
+		self.max_read_queue_occupancy = self
+			.max_read_queue_occupancy
+			.max(self.read_queue.len() as u64);
+		if !is_blocking {
+			return accept_cycle;
+		}
+
+		// The other requests selected first are served in the meantime.
+		// Only one read stalls the requester at a time.
+		loop {
+			let (served, done_cycle) = self.serve().unwrap();
+			if !served.is_write && served.source.is_blocking() {
+				return done_cycle;
+			}
+		}
+	}
+
+	fn write(&mut self, request: MemoryRequest) -> u64 {
+		self.write_num += 1;
+		let cycle = request.arrival_cycle;
+		// Newer write to the same line supersedes the queued one
+		if let Some(index) = self
+			.write_queue
+			.iter()
+			.position(|write| write.address == request.address)
+		{
+			self.write_queue.remove(index);
+		}
+		// Requester waits until a queue entry is free. Full queue is over
+		// the high watermark and being drained.
+		let mut accept_cycle = cycle;
+		while self.write_queue.len() >= self.config.write_queue_size {
+			let (_, done_cycle) = self.serve().unwrap();
+			accept_cycle = accept_cycle.max(done_cycle);
+		}
+		insert_by_arrival(&mut self.write_queue, request);
+		self.max_write_queue_occupancy = self
+			.max_write_queue_occupancy
+			.max(self.write_queue.len() as u64);
+
+		if !self.is_draining && self.write_queue.len() >= self.config.write_high_watermark {
+			self.is_draining = true;
+			self.drain_num += 1;
+		}
+		accept_cycle
+	}
+
+	/// Returns the queue the controller serves next, true for the write
+	/// queue, or `None` if nothing is to be served. Reads take priority
+	/// over writes unless the write queue is being drained.
+	fn get_serving_queue(&self) -> Option<bool> {
+		match (self.is_draining, self.read_queue.is_empty()) {
+			(true, _) => Some(true),
+			(false, false) => Some(false),
+			(false, true) => None,
+		}
+	}
+
+	/// Returns the CPU cycle the controller issues the next request, or
+	/// `None` if nothing is to be served.
+	fn get_issue_cycle(&self) -> Option<u64> {
+		let queue = match self.get_serving_queue()? {
+			true => &self.write_queue,
+			false => &self.read_queue,
+		};
+		// Queues are in arrival order
+		Some(self.ready_cycle.max(queue.front()?.arrival_cycle))
+	}
+
+	/// Sends the request the policy selects among the arrived ones to DRAM
+	/// when the controller is ready. Returns the request and the CPU cycle
+	/// it completes, or `None` if nothing is to be served.
+	fn serve(&mut self) -> Option<(MemoryRequest, u64)> {
+		let is_write = self.get_serving_queue()?;
+		let issue_cycle = self.get_issue_cycle()?;
+		if self.config.policy == SchedulingPolicy::Bliss && issue_cycle >= self.clear_cycle {
+			self.blacklist = 0;
+			self.clear_cycle = issue_cycle + self.config.bliss_clear_interval;
+		}
+		let index = self.select(is_write, issue_cycle);
+		let request = match is_write {
+			true => self.write_queue.remove(index),
+			false => self.read_queue.remove(index),
+		}
+		.unwrap();
+		let done_cycle = self
+			.dram_model
+			.access(request.address, request.is_write, issue_cycle);
+		self.ready_cycle = done_cycle;
+		match request.is_write {
+			true => self.write_latency += done_cycle - request.arrival_cycle,
+			false => self.read_latency += done_cycle - request.arrival_cycle,
+		};
+		if self.is_draining && self.write_queue.len() <= self.config.write_low_watermark {
+			self.is_draining = false;
+		}
+
+		if request.source == self.last_source {
+			self.streak_num += 1;
+		} else {
+			self.last_source = request.source;
+			self.streak_num = 1;
+		}
+		if self.streak_num >= self.config.bliss_threshold {
+			self.blacklist |= 1 << request.source.get_encoding();
+		}
+		Some((request, done_cycle))
+	}
+
+	/// Returns the index of the request to serve next in a queue.
+	///
+	/// # Arguments
+	/// * `is_write` Whether to select from the write queue, otherwise the read queue
+	/// * `cycle` CPU cycle the request is issued. Requests arriving later aren't selected.
+	fn select(&self, is_write: bool, cycle: u64) -> usize {
+		let queue = match is_write {
+			true => &self.write_queue,
+			false => &self.read_queue,
+		};
+		let policy = &self.config.policy;
+		// Queues are in arrival order so that the index breaks ties by age
+		(0..queue.len())
+			.take_while(|&index| queue[index].arrival_cycle <= cycle)
+			.min_by_key(|&index| {
+				let request = &queue[index];
+				let is_blacklisted = *policy == SchedulingPolicy::Bliss
+					&& (self.blacklist >> request.source.get_encoding()) & 1 == 1;
+				let is_row_miss = *policy != SchedulingPolicy::Fcfs
+					&& !self.dram_model.is_row_hit(request.address);
+				(is_blacklisted, is_row_miss, index)
+			})
+			.unwrap()
+	}
+
+	/// Returns the average CPU cycles from arrival to completion of reads,
+	/// or zero without reads.
+	pub fn get_average_read_latency(&self) -> f64 {
+		match self.read_num {
+			0 => 0.0,
+			_ => self.read_latency as f64 / self.read_num as f64,
+		}
+	}
+
+	/// Returns the average occupancy of the queues seen by the requests, or
+	/// zero without requests.
+	pub fn get_average_queue_occupancy(&self) -> f64 {
+		match self.read_num + self.write_num {
+			0 => 0.0,
+			request_num => self.queue_occupancy as f64 / request_num as f64,
+		}
+	}
+
+	/// Saves the state to checkpoint.
+	///
+	/// # Arguments
+	/// * `writer`
+	pub fn save_state(&self, writer: &mut CheckpointWriter) {
+		self.dram_model.save_state(writer);
+		for queue in [&self.read_queue, &self.write_queue] {
+			writer.write_u64(queue.len() as u64);
+			for request in queue.iter() {
+				writer.write_u64(request.address);
+				writer.write_bool(request.is_write);
+				writer.write_u64(request.source.get_encoding());
+				writer.write_u64(request.arrival_cycle);
+			}
+		}
+		writer.write_u64(self.ready_cycle);
+		writer.write_bool(self.is_draining);
+		writer.write_u64(self.blacklist);
+		writer.write_u64(self.last_source.get_encoding());
+		writer.write_u64(self.streak_num);
+		writer.write_u64(self.clear_cycle);
+		writer.write_u64(self.read_num);
+		writer.write_u64(self.write_num);
+		writer.write_u64(self.forward_num);
+		writer.write_u64(self.drain_num);
+		writer.write_u64(self.read_latency);
+		writer.write_u64(self.write_latency);
+		writer.write_u64(self.queue_occupancy);
+		writer.write_u64(self.max_read_queue_occupancy);
+		writer.write_u64(self.max_write_queue_occupancy);
+	}
+
+	/// Restores the state saved by `save_state()`. The controller needs to
+	/// have the same configuration.
+	///
+	/// # Arguments
+	/// * `reader`
+	pub fn restore_state(&mut self, reader: &mut CheckpointReader) -> Result<(), CheckpointError> {
+		self.dram_model.restore_state(reader)?;
+		self.read_queue = restore_queue(reader, self.config.read_queue_size)?;
+		self.write_queue = restore_queue(reader, self.config.write_queue_size)?;
+		self.ready_cycle = reader.read_u64()?;
+		self.is_draining = reader.read_bool()?;
+		self.blacklist = reader.read_u64()?;
+		self.last_source = restore_source(reader)?;
+		self.streak_num = reader.read_u64()?;
+		self.clear_cycle = reader.read_u64()?;
+		self.read_num = reader.read_u64()?;
+		self.write_num = reader.read_u64()?;
+		self.forward_num = reader.read_u64()?;
+		self.drain_num = reader.read_u64()?;
+		self.read_latency = reader.read_u64()?;
+		self.write_latency = reader.read_u64()?;
+		self.queue_occupancy = reader.read_u64()?;
+		self.max_read_queue_occupancy = reader.read_u64()?;
+		self.max_write_queue_occupancy = reader.read_u64()?;
+		Ok(())
+	}
+}
+
+fn restore_queue(
+	reader: &mut CheckpointReader,
+	size: usize,
+) -> Result<VecDeque<MemoryRequest>, CheckpointError> {
+	let length = reader.read_u64()?;
+	if length > size as u64 {
+		return Err(reader.error(format!(
+			"Memory controller queue length {} overflows",
+			length
+		)));
+	}
+	let mut queue = VecDeque::new();
+	for _ in 0..length {
+		queue.push_back(MemoryRequest {
+			address: reader.read_u64()?,
+			is_write: reader.read_bool()?,
+			source: restore_source(reader)?,
+			arrival_cycle: reader.read_u64()?,
+		});
+	}
+	Ok(queue)
+}
+
+fn restore_source(reader: &mut CheckpointReader) -> Result<MemoryRequestSource, CheckpointError> {
+	let encoding = reader.read_u64()?;
+	match MemoryRequestSource::from_encoding(encoding) {
+		Some(source) => Ok(source),
+		None => Err(reader.error(format!("Invalid memory request source {}", encoding))),
+	}
+}
+
+/// Inserts a request after the ones arriving at the same time or earlier.
+///
+/// # Arguments
+/// * `queue`
+/// * `request`
+fn insert_by_arrival(queue: &mut VecDeque<MemoryRequest>, request: MemoryRequest) {
+	let index = match queue
+		.iter()
+		.rposition(|queued| queued.arrival_cycle <= request.arrival_cycle)
+	{
+		Some(index) => index + 1,
+		None => 0,
+	};
+	queue.insert(index, request);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use dram_model::DramConfig;
+
+	/// Rows of the same bank are this far apart with the default DRAM
+	/// configuration.
+	const ROW_STRIDE: u64 = 8192 * 8;
+
+	const LINE_SIZE: u64 = 64;
+
+	/// Returns the service order of the same read stream under a policy.
+	fn get_service_order(policy: SchedulingPolicy) -> Vec<u64> {
+		let config = MemoryControllerConfig {
+			policy,
+			bliss_threshold: 2,
+			..MemoryControllerConfig::default()
+		};
+		let dram_model = DramModel::new(DramConfig::default()).unwrap();
+		let mut controller = MemoryController::new(config, dram_model).unwrap();
+		// Opens row 0 by two DMA reads in a row, which blacklist DMA
+		for i in 0..2 {
+			controller.access(i * LINE_SIZE, false, MemoryRequestSource::Dma, 0);
+		}
+		while controller.serve().is_some() {}
+		// Reads waiting together, which the core can't make as it stalls
+		// for each of them
+		let stream = [
+			(ROW_STRIDE, MemoryRequestSource::LoadStore),
+			(2 * LINE_SIZE, MemoryRequestSource::Dma),
+			(3 * LINE_SIZE, MemoryRequestSource::Fetch),
+		];
+		for (address, source) in stream.iter() {
+			let request = MemoryRequest {
+				address: *address,
+				is_write: false,
+				source: *source,
+				arrival_cycle: 1000,
+			};
+			insert_by_arrival(&mut controller.read_queue, request);
+		}
+		let mut order = vec![];
+		while let Some((request, _)) = controller.serve() {
+			order.push(request.address);
+		}
+		order
+	}
+
+	#[test]
+	fn policies_reorder_requests() {
+		// Arrival order
+		assert_eq!(
+			get_service_order(SchedulingPolicy::Fcfs),
+			vec![ROW_STRIDE, 2 * LINE_SIZE, 3 * LINE_SIZE]
+		);
+		// Row hits first
+		assert_eq!(
+			get_service_order(SchedulingPolicy::FrFcfs),
+			vec![2 * LINE_SIZE, 3 * LINE_SIZE, ROW_STRIDE]
+		);
+		// Blacklisted DMA last
+		assert_eq!(
+			get_service_order(SchedulingPolicy::Bliss),
+			vec![3 * LINE_SIZE, ROW_STRIDE, 2 * LINE_SIZE]
+		);
+	}
+
+	fn create_controller(config: MemoryControllerConfig) -> MemoryController {
+		let dram_model = DramModel::new(DramConfig::default()).unwrap();
+		MemoryController::new(config, dram_model).unwrap()
+	}
+
+	#[test]
+	fn rejects_invalid_config() {
+		let dram_model = || DramModel::new(DramConfig::default()).unwrap();
+		let configs = [
+			MemoryControllerConfig {
+				read_queue_size: 0,
+				..MemoryControllerConfig::default()
+			},
+			MemoryControllerConfig {
+				write_low_watermark: 24,
+				..MemoryControllerConfig::default()
+			},
+			MemoryControllerConfig {
+				write_high_watermark: 33,
+				..MemoryControllerConfig::default()
+			},
+		];
+		for config in configs.iter() {
+			assert!(MemoryController::new(config.clone(), dram_model()).is_err());
+		}
+	}
+
+	#[test]
+	fn forwards_reads_from_write_queue() {
+		let mut controller = create_controller(MemoryControllerConfig::default());
+		// Posted write is accepted at once
+		assert_eq!(
+			controller.access(LINE_SIZE, true, MemoryRequestSource::LoadStore, 10),
+			10
+		);
+		assert_eq!(
+			controller.access(LINE_SIZE, false, MemoryRequestSource::LoadStore, 20),
+			20 + WRITE_QUEUE_FORWARD_LATENCY
+		);
+		assert_eq!(controller.forward_num, 1);
+		assert_eq!(controller.read_latency, WRITE_QUEUE_FORWARD_LATENCY);
+		// The other line goes to DRAM
+		assert!(
+			controller.access(2 * LINE_SIZE, false, MemoryRequestSource::LoadStore, 30)
+				> 30 + WRITE_QUEUE_FORWARD_LATENCY
+		);
+		assert_eq!(controller.forward_num, 1);
+		assert_eq!(controller.read_num, 2);
+		assert_eq!(controller.write_num, 1);
+	}
+
+	#[test]
+	fn drains_between_watermarks() {
+		let config = MemoryControllerConfig {
+			write_queue_size: 8,
+			write_high_watermark: 4,
+			write_low_watermark: 1,
+			..MemoryControllerConfig::default()
+		};
+		let mut controller = create_controller(config);
+		let mut address = 0;
+		for _ in 0..2 {
+			for _ in controller.write_queue.len()..3 {
+				controller.access(address, true, MemoryRequestSource::WriteBack, 0);
+				address += LINE_SIZE;
+			}
+			let drain_num = controller.drain_num;
+			assert!(!controller.is_draining);
+			// Reaching the high watermark starts draining
+			controller.access(address, true, MemoryRequestSource::WriteBack, 0);
+			address += LINE_SIZE;
+			assert_eq!(controller.drain_num, drain_num + 1);
+			assert!(controller.is_draining);
+			// Later read lets the writes drain down to the low watermark
+			controller.access(ROW_STRIDE, false, MemoryRequestSource::LoadStore, 100000);
+			assert!(!controller.is_draining);
+			assert_eq!(controller.write_queue.len(), 1);
+		}
+		assert_eq!(controller.drain_num, 2);
+		assert_eq!(controller.write_num, 7);
+		assert_eq!(controller.max_write_queue_occupancy, 4);
+		assert!(controller.write_latency > 0);
+	}
+
+	#[test]
+	fn counts_latency_and_occupancy() {
+		let config = MemoryControllerConfig {
+			policy: SchedulingPolicy::Fcfs,
+			..MemoryControllerConfig::default()
+		};
+		let mut controller = create_controller(config);
+		let addresses = [0, ROW_STRIDE, 2 * ROW_STRIDE];
+		// DMA reads are left outstanding and the core waits for all of them
+		// as they arrived earlier
+		for address in addresses[..2].iter() {
+			assert_eq!(
+				controller.access(*address, false, MemoryRequestSource::Dma, 0),
+				0
+			);
+		}
+		let done_cycle = controller.access(addresses[2], false, MemoryRequestSource::LoadStore, 0);
+
+		let mut dram_model = DramModel::new(DramConfig::default()).unwrap();
+		let mut cycle = 0;
+		let mut latency = 0;
+		for address in addresses.iter() {
+			cycle = dram_model.access(*address, false, cycle);
+			latency += cycle;
+		}
+		assert_eq!(done_cycle, cycle);
+		assert_eq!(controller.read_latency, latency);
+		assert_eq!(controller.get_average_read_latency(), latency as f64 / 3.0);
+		// The requests saw 0, 1, and 2 queued ones
+		assert_eq!(controller.queue_occupancy, 3);
+		assert_eq!(controller.get_average_queue_occupancy(), 1.0);
+		assert_eq!(controller.max_read_queue_occupancy, 3);
+	}
+}
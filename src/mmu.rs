@@ -5,20 +5,42 @@ pub const DRAM_BASE: u64 = 0x80000000;
 /// @TODO: (dev)Enable TLB
 const ENABLE_TLB: bool = true;
 
+/// Page table entry bits above PPN in Sv39
+/// [ N | PBMT | Reserved ]
+/// [63 | 62 61| 60 ... 54]
+const PTE_N: u64 = 1 << 63;
+const PTE_PBMT_SHIFT: u64 = 61;
+const PTE_RESERVED_MASK: u64 = 0x7f << 54;
+
+/// TLB keeps the level of leaf PTE in reserved bits of the cached PTE
+const TLB_LEVEL_SHIFT: u64 = 54;
+
+/// menvcfg bits affecting address translation
+pub const MENVCFG_PBMTE: u64 = 1 << 62;
+pub const MENVCFG_ADUE: u64 = 1 << 61;
+
 extern crate fnv;
 
-use cpu::{get_privilege_mode, PrivilegeMode, Trap, TrapType, Xlen};
+use checkpoint::{CheckpointError, CheckpointReader, CheckpointWriter};
+use clint::Clint;
+use cpu::{get_privilege_encoding, get_privilege_mode, PrivilegeMode, Trap, TrapType, Xlen};
+use device::{overlaps, Bus, Device, DmaMemory};
 #[cfg(feature = "dramsim")]
-use dram::*;
+use dram::{get_response, send_request, DramSimError};
+use dram_model::{DramConfig, DramModel};
+use htif::Htif;
 use l1cache::*;
 use l2cache::*;
-use memory::Memory;
+use memory::{new_memory_backend, MemoryBackend, MemoryBackendType, SPARSE_MEMORY_PAGE_SIZE};
+use memory_controller::{MemoryController, MemoryControllerConfig, MemoryRequestSource};
+use page_walk_cache::*;
+use pmp::Pmp;
+use watchpoint::Watchpoints;
 
 /// Emulates Memory Management Unit. It holds the Main memory and peripheral
 /// devices, maps address to them, and accesses them depending on address.
 /// It also manages virtual-physical address translation and memoty protection.
 /// It may also be said Bus.
-/// @TODO: Memory protection is not implemented yet. We should support.
 
 pub const TLB_ENTRY_NUM: usize = 64;
 
@@ -29,19 +51,43 @@ pub struct Mmu {
 	pub addressing_mode: AddressingMode,
 	pub privilege_mode: PrivilegeMode,
 	pub memory: MemoryWrapper,
+	/// Memory-mapped peripheral devices
+	pub bus: Bus,
 	pub l1_cache: L1Cache,
 	pub l2_cache: L2Cache,
+	pub pmp: Pmp,
 
 	pub memory_access_trace: Vec<MemoryAccessTrace>,
 
 	/// Address translation can be affected `mstatus` (MPRV, MPP in machine mode)
 	/// then `Mmu` has copy of it.
 	pub mstatus: u64,
+	/// Svpbmt and Svadu are enabled by `menvcfg` then `Mmu` has copy of it.
+	pub menvcfg: u64,
 	pub tlb_tag: [u64; TLB_ENTRY_NUM],
 	pub tlb_value: [u64; TLB_ENTRY_NUM],
 	pub tlb_bitnum: usize,
+	pub tlb_hit_num: u64,
+	pub tlb_miss_num: u64,
+	pub page_walk_cache: PageWalkCache,
+	/// Total cycles spent in page table walks
+	pub page_walk_latency: u64,
 
 	pub dram_latency: u64,
+	/// Built-in DRAM timing model. Main memory accesses take flat
+	/// `L2_CACHE_MISS_LATENCY` if not set up.
+	pub dram_model: Option<DramModel>,
+	/// Memory controller in front of the DRAM model. Main memory accesses
+	/// go to `dram_model` directly if not set up.
+	pub memory_controller: Option<MemoryController>,
+	/// The number of accesses to non-cacheable (Svpbmt NC or IO) pages
+	pub uncached_num: u64,
+	/// Error which made a main memory access impossible, e.g. lost
+	/// connection to DRAMSim. The emulation stops once it's set.
+	pub memory_error: Option<String>,
+
+	/// Watchpoints on guest loads, stores, and instruction fetches
+	pub watchpoints: Watchpoints,
 }
 
 #[derive(Debug)]
@@ -58,6 +104,7 @@ pub struct MemoryAccessTrace {
 	pub cycle: u64,
 }
 
+#[derive(Clone, Debug)]
 pub enum MemoryAccessType {
 	Execute,
 	Read,
@@ -65,6 +112,42 @@ pub enum MemoryAccessType {
 	DontCare,
 }
 
+/// Memory type of a page given by PBMT field of leaf PTE (Svpbmt).
+/// `Nc` and `Io` pages are not cached.
+#[derive(Clone, Debug)]
+pub enum MemoryType {
+	Pma,
+	Nc,
+	Io,
+}
+
+fn get_page_fault_type(access_type: &MemoryAccessType) -> TrapType {
+	match access_type {
+		MemoryAccessType::Execute => TrapType::InstructionPageFault,
+		MemoryAccessType::Write => TrapType::StorePageFault,
+		_ => TrapType::LoadPageFault,
+	}
+}
+
+fn get_access_fault_type(access_type: &MemoryAccessType) -> TrapType {
+	match access_type {
+		MemoryAccessType::Execute => TrapType::InstructionAccessFault,
+		MemoryAccessType::Write => TrapType::StoreAccessFault,
+		_ => TrapType::LoadAccessFault,
+	}
+}
+
+/// Returns true if A bit, or D bit for store, of leaf PTE is not set yet.
+fn needs_ad_update(pte: u64, access_type: &MemoryAccessType) -> bool {
+	let a = (pte >> 6) & 1;
+	let d = (pte >> 7) & 1;
+	a == 0
+		|| match access_type {
+			MemoryAccessType::Write => d == 0,
+			_ => false,
+		}
+}
+
 fn _get_addressing_mode_name(mode: &AddressingMode) -> &'static str {
 	match mode {
 		AddressingMode::None => "None",
@@ -87,17 +170,30 @@ impl Mmu {
 			addressing_mode: AddressingMode::None,
 			privilege_mode: PrivilegeMode::User,
 			memory: MemoryWrapper::new(),
+			bus: Bus::new(),
 			l1_cache: L1Cache::new(),
 			l2_cache: L2Cache::new(),
+			pmp: Pmp::new(),
 
 			memory_access_trace: vec![],
 
 			mstatus: 0,
+			menvcfg: 0,
 			tlb_tag: [0; TLB_ENTRY_NUM],
 			tlb_value: [0; TLB_ENTRY_NUM],
 			tlb_bitnum: 0,
+			tlb_hit_num: 0,
+			tlb_miss_num: 0,
+			page_walk_cache: PageWalkCache::new(),
+			page_walk_latency: 0,
 
 			dram_latency: 0,
+			dram_model: None,
+			memory_controller: None,
+			uncached_num: 0,
+			memory_error: None,
+
+			watchpoints: Watchpoints::new(),
 		}
 	}
 
@@ -110,16 +206,136 @@ impl Mmu {
 	}
 
 	/// Initializes Main memory. This method is expected to be called only once.
+	/// Returns error if main memory overlaps with a mapped memory region or
+	/// device.
 	///
 	/// # Arguments
 	/// * `capacity`
-	pub fn init_memory(&mut self, capacity: u64) {
-		self.memory.init(capacity);
+	pub fn init_memory(&mut self, capacity: u64) -> Result<(), String> {
+		let memory = new_memory_backend(&self.memory.backend_type, capacity);
+		self.add_memory_region(DRAM_BASE, memory)
+	}
+
+	/// Updates the backend of main memory. This method needs to be called
+	/// before `init_memory`.
+	///
+	/// # Arguments
+	/// * `backend_type`
+	pub fn update_memory_backend_type(&mut self, backend_type: MemoryBackendType) {
+		self.memory.backend_type = backend_type;
+	}
+
+	/// Maps an additional memory region at a physical base address.
+	/// Returns error if the region overlaps with a mapped one or a device.
+	///
+	/// # Arguments
+	/// * `base` Physical base address
+	/// * `memory`
+	pub fn add_memory_region(
+		&mut self,
+		base: u64,
+		memory: Box<dyn MemoryBackend>,
+	) -> Result<(), String> {
+		if let Some(device_base) = self.bus.find_overlapping_device(base, memory.capacity()) {
+			return Err(format!(
+				"Memory region at {:X} overlaps with device at {:X}",
+				base, device_base
+			));
+		}
+		self.memory.add_region(base, memory)
+	}
+
+	/// Maps a peripheral device at a physical address range. Returns error
+	/// if the range overlaps with main memory or the other devices.
+	///
+	/// # Arguments
+	/// * `base` Physical base address
+	/// * `size` Size of the address range in bytes
+	/// * `device`
+	pub fn add_device(
+		&mut self,
+		base: u64,
+		size: u64,
+		device: Box<dyn Device>,
+	) -> Result<(), String> {
+		self.check_device_range(base, size)?;
+		self.bus.add_device(base, size, device)
+	}
+
+	/// Returns immutable reference to `Clint`.
+	pub fn get_clint(&self) -> &Clint {
+		&self.bus.clint
+	}
+
+	/// Returns mutable reference to `Clint`.
+	pub fn get_mut_clint(&mut self) -> &mut Clint {
+		&mut self.bus.clint
+	}
+
+	/// Returns immutable reference to `Htif`.
+	pub fn get_htif(&self) -> &Htif {
+		&self.bus.htif
+	}
+
+	/// Returns mutable reference to `Htif`.
+	pub fn get_mut_htif(&mut self) -> &mut Htif {
+		&mut self.bus.htif
+	}
+
+	/// Maps a peripheral device whose interrupt line is connected to PLIC.
+	/// Returns error if the range overlaps with main memory or the other
+	/// devices, or the interrupt source is invalid or already used.
+	///
+	/// # Arguments
+	/// * `base` Physical base address
+	/// * `size` Size of the address range in bytes
+	/// * `irq` PLIC interrupt source number
+	/// * `device`
+	pub fn add_device_with_irq(
+		&mut self,
+		base: u64,
+		size: u64,
+		irq: u32,
+		device: Box<dyn Device>,
+	) -> Result<(), String> {
+		self.check_device_range(base, size)?;
+		self.bus.add_device_with_irq(base, size, irq, device)
+	}
+
+	/// Returns error if a device address range overlaps with main memory.
+	///
+	/// # Arguments
+	/// * `base` Physical base address
+	/// * `size` Size of the address range in bytes
+	fn check_device_range(&self, base: u64, size: u64) -> Result<(), String> {
+		match self.memory.find_overlapping_region(base, size) {
+			Some(region) => Err(format!(
+				"Device at {:X} overlaps with memory region at {:X}",
+				base, region.base
+			)),
+			None => Ok(()),
+		}
 	}
 
 	/// Runs one cycle of MMU and peripheral devices.
-	pub fn tick(&mut self, _mip: &mut u64) {
+	pub fn tick(&mut self, mip: &mut u64) {
 		// mmu clock is synced in cpu.tick()
+		let mut memory = CoherentMemory {
+			memory: &mut self.memory,
+			l1_cache: &mut self.l1_cache,
+			l2_cache: &mut self.l2_cache,
+			// DRAMSim times only the accesses from the core
+			memory_controller: match cfg!(feature = "dramsim") {
+				true => None,
+				false => self.memory_controller.as_mut(),
+			},
+			clock: self.clock,
+			last_line: None,
+		};
+		self.bus.tick(self.clock, mip, &mut memory);
+		// HTIF accesses are done by the host and take no time
+		memory.memory_controller = None;
+		self.bus.htif.tick(&mut memory);
 
 		// Flush memory access trace
 		if self.memory_access_trace.len() > 0 {
@@ -162,6 +378,24 @@ impl Mmu {
 		self.mstatus = mstatus;
 	}
 
+	/// Updates menvcfg copy. `CPU` needs to call this method whenever
+	/// `menvcfg` is updated.
+	///
+	/// # Arguments
+	/// * `menvcfg`
+	pub fn update_menvcfg(&mut self, menvcfg: u64) {
+		self.menvcfg = menvcfg;
+	}
+
+	/// Invalidates all the TLB and page-walk cache entries
+	/// (e.g. SFENCE.VMA)
+	pub fn flush_tlb(&mut self) {
+		self.tlb_tag = [0; TLB_ENTRY_NUM];
+		self.tlb_value = [0; TLB_ENTRY_NUM];
+		self.tlb_bitnum = 0;
+		self.page_walk_cache.flush();
+	}
+
 	/// Updates PPN used for address translation
 	///
 	/// # Arguments
@@ -183,14 +417,23 @@ impl Mmu {
 	/// # Arguments
 	/// * `v_address` Virtual address
 	fn fetch(&mut self, v_address: u64) -> Result<u8, Trap> {
-		match self.translate_address(v_address, &MemoryAccessType::Execute) {
-			Ok(p_address) => Ok(self.load_raw(p_address)),
-			Err(()) => {
-				return Err(Trap {
-					trap_type: TrapType::InstructionPageFault,
-					value: v_address,
-				})
+		match self.translate_and_check(v_address, 1, &MemoryAccessType::Execute) {
+			Ok((p_address, _)) => {
+				let data = self.load_raw(p_address);
+				self.check_watchpoints(
+					MemoryAccessType::Execute,
+					v_address,
+					p_address,
+					1,
+					None,
+					data as u64,
+				);
+				Ok(data)
 			}
+			Err(trap_type) => Err(Trap {
+				trap_type,
+				value: v_address,
+			}),
 		}
 	}
 
@@ -205,18 +448,33 @@ impl Mmu {
 				// Fast path. All bytes fetched are in the same page so
 				// translating an address only once.
 				let effective_address = self.get_effective_address(v_address);
-				match self.translate_address(effective_address, &MemoryAccessType::Execute) {
-					Ok(p_address) => {
+				match self.translate_and_check(effective_address, width, &MemoryAccessType::Execute)
+				{
+					Ok((p_address, _)) => {
 						let mut data = vec![];
 
 						for i in 0..width {
 							data.push(self.load_raw(p_address.wrapping_add(i)));
 						}
 
+						// Watched value is the first eight bytes at most
+						let value = data
+							.iter()
+							.take(8)
+							.enumerate()
+							.fold(0, |value, (i, byte)| value | ((*byte as u64) << (i * 8)));
+						self.check_watchpoints(
+							MemoryAccessType::Execute,
+							effective_address,
+							p_address,
+							width,
+							None,
+							value,
+						);
 						Ok(data)
 					}
-					Err(()) => Err(Trap {
-						trap_type: TrapType::InstructionPageFault,
+					Err(trap_type) => Err(Trap {
+						trap_type,
 						value: effective_address,
 					}),
 				}
@@ -225,17 +483,10 @@ impl Mmu {
 				let mut data = vec![];
 
 				for i in 0..width {
-					match self
-						.translate_address(v_address.wrapping_add(i), &MemoryAccessType::Execute)
-					{
-						Ok(p_address) => data.push(self.load_raw(p_address)),
-						Err(()) => {
-							return Err(Trap {
-								trap_type: TrapType::InstructionPageFault,
-								value: v_address,
-							})
-						}
-					}
+					match self.fetch(v_address.wrapping_add(i)) {
+						Ok(byte) => data.push(byte),
+						Err(e) => return Err(e),
+					};
 				}
 
 				Ok(data)
@@ -257,10 +508,22 @@ impl Mmu {
 				// Fast path. All bytes fetched are in the same page so
 				// translating an address only once.
 				let effective_address = self.get_effective_address(v_address);
-				match self.translate_address(effective_address, &MemoryAccessType::Execute) {
-					Ok(p_address) => Ok(self.load_word_raw(p_address)),
-					Err(()) => Err(Trap {
-						trap_type: TrapType::InstructionPageFault,
+				match self.translate_and_check(effective_address, width, &MemoryAccessType::Execute)
+				{
+					Ok((p_address, _)) => {
+						let data = self.load_word_raw(p_address);
+						self.check_watchpoints(
+							MemoryAccessType::Execute,
+							effective_address,
+							p_address,
+							width,
+							None,
+							data as u64,
+						);
+						Ok(data)
+					}
+					Err(trap_type) => Err(Trap {
+						trap_type,
 						value: effective_address,
 					}),
 				}
@@ -395,47 +658,214 @@ impl Mmu {
 		// Latency for checking
 		self.clock = self.clock.wrapping_add(L2_CACHE_HIT_LATENCY as u64);
 
+		// Latency for accessing memory
+		self.access_memory(
+			write_back_address,
+			MemoryAccessType::Write,
+			MemoryRequestSource::WriteBack,
+		);
+	}
+
+	/// Sets up the built-in DRAM timing model used for main memory
+	/// accesses unless DRAMSim is enabled. Returns error if the
+	/// configuration is invalid.
+	///
+	/// # Arguments
+	/// * `config`
+	pub fn setup_dram_model(&mut self, config: DramConfig) -> Result<(), String> {
+		self.dram_model = Some(DramModel::new(config)?);
+		Ok(())
+	}
+
+	/// Sets up the memory controller between L2 cache and DRAM. It takes
+	/// over the DRAM model set up, or the default one.
+	///
+	/// # Arguments
+	/// * `config`
+	pub fn setup_memory_controller(
+		&mut self,
+		config: MemoryControllerConfig,
+	) -> Result<(), String> {
+		// Keeps the DRAM model set up if the configuration is invalid
+		config.validate()?;
+		let dram_model = match self.memory_controller.take() {
+			Some(memory_controller) => memory_controller.into_dram_model(),
+			None => match self.dram_model.take() {
+				Some(dram_model) => dram_model,
+				None => DramModel::new(DramConfig::default())?,
+			},
+		};
+		self.memory_controller = Some(MemoryController::new(config, dram_model)?);
+		Ok(())
+	}
+
+	/// Returns the DRAM model main memory accesses go to, either directly
+	/// or through the memory controller.
+	pub fn get_dram_model(&self) -> Option<&DramModel> {
+		match self.memory_controller.as_ref() {
+			Some(memory_controller) => Some(memory_controller.get_dram_model()),
+			None => self.dram_model.as_ref(),
+		}
+	}
+
+	/// Traces a main memory access and advances clock by its latency,
+	/// which comes from DRAMSim if enabled, otherwise from the memory
+	/// controller or the built-in DRAM model if set up.
+	///
+	/// # Arguments
+	/// * `p_address`: physical address
+	/// * `operation`: `Read` or `Write`
+	/// * `source`: Requester the memory controller schedules by
+	fn access_memory(
+		&mut self,
+		p_address: u64,
+		operation: MemoryAccessType,
+		source: MemoryRequestSource,
+	) {
+		let is_write = matches!(operation, MemoryAccessType::Write);
+		#[cfg(feature = "dramsim")]
+		let command = match is_write {
+			true => "WRITE",
+			false => "READ",
+		};
+
 		// Trace memory access
 		self.memory_access_trace.push(MemoryAccessTrace {
-			address: write_back_address,
-			operation: MemoryAccessType::Write,
+			address: p_address,
+			operation,
 			cycle: self.clock,
 		});
 
+		// DRAMSim doesn't tell requesters apart
 		#[cfg(feature = "dramsim")]
-		{
+		let _ = source;
+		#[cfg(feature = "dramsim")]
+		if self.memory_error.is_none() {
 			// Communicate with dramsim through pipe
-			// @TODO: Blocked
-			send_request(
-				format!("{:016x} {} {}", write_back_address, "WRITE", self.clock).as_str(),
-			);
+			let request = format!("{:016x} {} {}", p_address, command, self.clock);
 			#[cfg(feature = "debug-dramsim")]
-			println!(
-				"Send {:?}",
-				format!("{:016x} {} {}", write_back_address, "WRITE", self.clock)
-			);
-
-			let response_string = get_response();
+			println!("Send {:?}", request);
+
+			let clock = self.clock;
+			let response = send_request(&request)
+				.and_then(|_| get_response())
+				.and_then(|response| match response.cycle > clock {
+					true => Ok(response),
+					false => Err(DramSimError::ReverseClock {
+						request_cycle: clock,
+						response_cycle: response.cycle,
+					}),
+				});
 			#[cfg(feature = "debug-dramsim")]
-			println!("Resp {:?}", response_string);
+			println!("Resp {:?}", response);
 
 			// Latency for accessing memory
-			let dram_clk = response_string
-				.split(" ")
-				.last()
-				.unwrap()
-				.parse::<u64>()
-				.unwrap();
-			if dram_clk <= self.clock {
-				panic!("Reverse dram clk!!!");
-			} else {
-				self.dram_latency += dram_clk - self.clock;
-				self.clock = dram_clk;
-			}
+			match response {
+				Ok(response) => {
+					self.dram_latency += response.cycle - self.clock;
+					self.clock = response.cycle;
+				}
+				Err(e) => {
+					// Memory access can't complete without DRAMSim
+					self.memory_error = Some(e.to_string());
+				}
+			};
 		}
 		#[cfg(not(feature = "dramsim"))]
-		{
-			self.clock = self.clock.wrapping_add(L2_CACHE_MISS_LATENCY as u64);
+		match (self.memory_controller.as_mut(), self.dram_model.as_mut()) {
+			(Some(memory_controller), _) => {
+				let done_cycle = memory_controller.access(p_address, is_write, source, self.clock);
+				self.dram_latency += done_cycle - self.clock;
+				self.clock = done_cycle;
+			}
+			(None, Some(dram_model)) => {
+				let done_cycle = dram_model.access(p_address, is_write, self.clock);
+				self.dram_latency += done_cycle - self.clock;
+				self.clock = done_cycle;
+			}
+			(None, None) => {
+				self.clock = self.clock.wrapping_add(L2_CACHE_MISS_LATENCY as u64);
+			}
+		}
+	}
+
+	/// Loads multiple bytes from a non-cacheable page or a device without
+	/// allocating cache lines. A line the caches already hold is read from
+	/// there to keep coherence.
+	///
+	/// # Arguments
+	/// * `p_address`: physical address
+	/// * `width`: Must be 1, 2, 4, or 8
+	fn load_uncached(&mut self, p_address: u64, width: u64) -> u64 {
+		if self.bus.is_mapped(p_address, width) {
+			return self.bus.read(p_address, width);
+		}
+		self.uncached_num += 1;
+		let l1_index: u64 = (p_address >> L1_CACHE_OFFSET_BITS) & ((1 << L1_CACHE_INDEX_BITS) - 1);
+		let l1_offset = p_address & ((1 << L1_CACHE_OFFSET_BITS) - 1);
+		let l2_index: u64 = (p_address >> L2_CACHE_OFFSET_BITS) & ((1 << L2_CACHE_INDEX_BITS) - 1);
+		let l2_offset = p_address & ((1 << L2_CACHE_OFFSET_BITS) - 1);
+
+		self.clock = self.clock.wrapping_add(L1_CACHE_HIT_LATENCY as u64);
+		if let Ok(l1_way) = self.l1_cache.read_line_info(p_address) {
+			return self.l1_cache.data[l1_index as usize].data[l1_way as usize]
+				.get(l1_offset, width);
+		}
+		self.clock = self.clock.wrapping_add(L2_CACHE_HIT_LATENCY as u64);
+		if let Ok(l2_way) = self.l2_cache.read_line_info(p_address) {
+			return self.l2_cache.data[l2_index as usize].data[l2_way as usize]
+				.get(l2_offset, width);
+		}
+		self.access_memory(
+			p_address,
+			MemoryAccessType::Read,
+			MemoryRequestSource::LoadStore,
+		);
+		let mut data = 0;
+		for i in 0..width {
+			data |= (self.load_raw(p_address.wrapping_add(i)) as u64) << (i * 8);
+		}
+		data
+	}
+
+	/// Stores multiple bytes to a non-cacheable page or a device without
+	/// allocating cache lines. A line the caches already hold is updated
+	/// instead to keep coherence.
+	///
+	/// # Arguments
+	/// * `p_address`: physical address
+	/// * `value`: data written
+	/// * `width`: Must be 1, 2, 4, or 8
+	fn store_uncached(&mut self, p_address: u64, value: u64, width: u64) {
+		if self.bus.is_mapped(p_address, width) {
+			self.bus.write(p_address, value, width);
+			return;
+		}
+		self.uncached_num += 1;
+		let l1_index: u64 = (p_address >> L1_CACHE_OFFSET_BITS) & ((1 << L1_CACHE_INDEX_BITS) - 1);
+		let l1_offset = p_address & ((1 << L1_CACHE_OFFSET_BITS) - 1);
+		let l2_index: u64 = (p_address >> L2_CACHE_OFFSET_BITS) & ((1 << L2_CACHE_INDEX_BITS) - 1);
+		let l2_offset = p_address & ((1 << L2_CACHE_OFFSET_BITS) - 1);
+
+		self.clock = self.clock.wrapping_add(L1_CACHE_HIT_LATENCY as u64);
+		if let Ok(l1_way) = self.l1_cache.read_line_info(p_address) {
+			self.l1_cache.data[l1_index as usize].data[l1_way as usize]
+				.set(l1_offset, width, value);
+			return;
+		}
+		self.clock = self.clock.wrapping_add(L2_CACHE_HIT_LATENCY as u64);
+		if let Ok(l2_way) = self.l2_cache.read_line_info(p_address) {
+			self.l2_cache.data[l2_index as usize].data[l2_way as usize]
+				.set(l2_offset, width, value);
+			return;
+		}
+		self.access_memory(
+			p_address,
+			MemoryAccessType::Write,
+			MemoryRequestSource::LoadStore,
+		);
+		for i in 0..width {
+			self.store_raw(p_address.wrapping_add(i), (value >> (i * 8)) as u8);
 		}
 	}
 
@@ -505,6 +935,51 @@ impl Mmu {
 		}
 	}
 
+	/// Reads a doubleword as the core sees it, from L1 cache, L2 cache, or
+	/// memory in the order. Neither the caches nor the clock are changed.
+	///
+	/// # Arguments
+	/// * `p_address`: physical address aligned to eight bytes
+	pub fn peek_doubleword(&mut self, p_address: u64) -> u64 {
+		let l1_index: u64 = (p_address >> L1_CACHE_OFFSET_BITS) & ((1 << L1_CACHE_INDEX_BITS) - 1);
+		let l1_offset = p_address & ((1 << L1_CACHE_OFFSET_BITS) - 1);
+		if let Ok(l1_way) = self.l1_cache.read_line_info(p_address) {
+			return self.l1_cache.data[l1_index as usize].data[l1_way as usize].get(l1_offset, 8);
+		}
+		let l2_index: u64 = (p_address >> L2_CACHE_OFFSET_BITS) & ((1 << L2_CACHE_INDEX_BITS) - 1);
+		let l2_offset = p_address & ((1 << L2_CACHE_OFFSET_BITS) - 1);
+		if let Ok(l2_way) = self.l2_cache.read_line_info(p_address) {
+			return self.l2_cache.data[l2_index as usize].data[l2_way as usize].get(l2_offset, 8);
+		}
+		self.memory.read_doubleword(p_address)
+	}
+
+	/// Returns the physical addresses of the valid L1 and L2 cache lines
+	pub fn get_cached_line_addresses(&self) -> Vec<u64> {
+		let mut addresses = vec![];
+		for index in 0..L1_CACHE_SET_NUMBER {
+			for line in self.l1_cache.data[index as usize].data.iter() {
+				if line.valid {
+					addresses.push(
+						(line.tag << (L1_CACHE_INDEX_BITS + L1_CACHE_OFFSET_BITS))
+							| ((index as u64) << L1_CACHE_OFFSET_BITS),
+					);
+				}
+			}
+		}
+		for index in 0..L2_CACHE_SET_NUMBER {
+			for line in self.l2_cache.data[index as usize].data.iter() {
+				if line.valid {
+					addresses.push(
+						(line.tag << (L2_CACHE_INDEX_BITS + L2_CACHE_OFFSET_BITS))
+							| ((index as u64) << L2_CACHE_OFFSET_BITS),
+					);
+				}
+			}
+		}
+		addresses
+	}
+
 	/// General memory subsystem interface
 	///
 	/// # Arguments
@@ -601,50 +1076,12 @@ impl Mmu {
 								self.load_raw(p_address_aligned + i as u64);
 						}
 
-						// Trace memory access
-						self.memory_access_trace.push(MemoryAccessTrace {
-							address: p_address_aligned,
-							operation: MemoryAccessType::Read,
-							cycle: self.clock,
-						});
-
-						#[cfg(feature = "dramsim")]
-						{
-							// Communicate with dramsim through pipe
-							// @TODO: Blocked
-							send_request(
-								format!("{:016x} {} {}", p_address_aligned, "READ", self.clock)
-									.as_str(),
-							);
-							#[cfg(feature = "debug-dramsim")]
-							println!(
-								"Send {:?}",
-								format!("{:016x} {} {}", p_address_aligned, "READ", self.clock)
-							);
-
-							let response_string = get_response();
-							#[cfg(feature = "debug-dramsim")]
-							println!("Resp {:?}", response_string);
-
-							// Latency for accessing memory
-							let dram_clk = response_string
-								.split(" ")
-								.last()
-								.unwrap()
-								.parse::<u64>()
-								.unwrap();
-							if dram_clk <= self.clock {
-								panic!("Reverse dram clk!!!");
-							} else {
-								self.dram_latency += dram_clk - self.clock;
-								self.clock = dram_clk;
-							}
-						}
-						#[cfg(not(feature = "dramsim"))]
-						{
-							// Latency for accessing memory
-							self.clock = self.clock.wrapping_add(L2_CACHE_MISS_LATENCY as u64);
-						}
+						// Latency for accessing memory
+						self.access_memory(
+							p_address_aligned,
+							MemoryAccessType::Read,
+							MemoryRequestSource::LoadStore,
+						);
 
 						// Refill L2 with new line
 						match self.l2_refill(
@@ -707,8 +1144,8 @@ impl Mmu {
 			width
 		);
 		match (v_address & 0xfff) <= (0x1000 - width) {
-			true => match self.translate_address(v_address, &MemoryAccessType::Read) {
-				Ok(p_address) => {
+			true => match self.translate_and_check(v_address, width, &MemoryAccessType::Read) {
+				Ok((p_address, MemoryType::Pma)) => {
 					#[cfg(feature = "debug-cache")]
 					println!("\nload {}bytes @ 0x{:x}", width, p_address);
 
@@ -723,11 +1160,32 @@ impl Mmu {
 						Err(trap) => return Err(trap),
 					};
 
-					Ok(self.l1_cache.data[l1_index as usize].data[l1_way as usize]
-						.get(l1_offset, width))
+					let data = self.l1_cache.data[l1_index as usize].data[l1_way as usize]
+						.get(l1_offset, width);
+					self.check_watchpoints(
+						MemoryAccessType::Read,
+						v_address,
+						p_address,
+						width,
+						None,
+						data,
+					);
+					Ok(data)
+				}
+				Ok((p_address, _)) => {
+					let data = self.load_uncached(p_address, width);
+					self.check_watchpoints(
+						MemoryAccessType::Read,
+						v_address,
+						p_address,
+						width,
+						None,
+						data,
+					);
+					Ok(data)
 				}
-				Err(()) => Err(Trap {
-					trap_type: TrapType::LoadPageFault,
+				Err(trap_type) => Err(Trap {
+					trap_type,
 					value: v_address,
 				}),
 			},
@@ -806,8 +1264,8 @@ impl Mmu {
 			width
 		);
 		match (v_address & 0xfff) <= (0x1000 - width) {
-			true => match self.translate_address(v_address, &MemoryAccessType::Write) {
-				Ok(p_address) => {
+			true => match self.translate_and_check(v_address, width, &MemoryAccessType::Write) {
+				Ok((p_address, MemoryType::Pma)) => {
 					// Store to cache
 					// @TODO: store buffer
 					// Get allocated cache line's way index
@@ -828,14 +1286,40 @@ impl Mmu {
 						Err(trap) => return Err(trap),
 					};
 
+					if !self.watchpoints.is_empty() {
+						let old_value = self.l1_cache.data[l1_index as usize].data[l1_way as usize]
+							.get(l1_offset, width);
+						self.check_watchpoints(
+							MemoryAccessType::Write,
+							v_address,
+							p_address,
+							width,
+							Some(old_value),
+							value,
+						);
+					}
+
 					// Update cache line
 					self.l1_cache.data[l1_index as usize].data[l1_way as usize]
 						.set(l1_offset, width, value);
 
 					Ok(())
 				}
-				Err(()) => Err(Trap {
-					trap_type: TrapType::StorePageFault,
+				Ok((p_address, _)) => {
+					// Old value isn't read because reading device may have side effect
+					self.check_watchpoints(
+						MemoryAccessType::Write,
+						v_address,
+						p_address,
+						width,
+						None,
+						value,
+					);
+					self.store_uncached(p_address, value, width);
+					Ok(())
+				}
+				Err(trap_type) => Err(Trap {
+					trap_type,
 					value: v_address,
 				}),
 			},
@@ -898,7 +1382,10 @@ impl Mmu {
 	/// * `p_address` Physical address
 	pub fn load_raw(&mut self, p_address: u64) -> u8 {
 		let effective_address = self.get_effective_address(p_address);
-		self.memory.read_byte(effective_address)
+		match self.bus.is_mapped(effective_address, 1) {
+			true => self.bus.read(effective_address, 1) as u8,
+			false => self.memory.read_byte(effective_address),
+		}
 	}
 
 	/// Loads two bytes from main memory or peripheral devices depending on
@@ -908,9 +1395,7 @@ impl Mmu {
 	/// * `p_address` Physical address
 	pub fn load_halfword_raw(&mut self, p_address: u64) -> u16 {
 		let effective_address = self.get_effective_address(p_address);
-		match effective_address >= DRAM_BASE
-			&& effective_address.wrapping_add(1) > effective_address
-		{
+		match self.memory.is_mapped(effective_address, 2) {
 			// Fast path. Directly load main memory at a time.
 			true => self.memory.read_halfword(effective_address),
 			false => {
@@ -930,9 +1415,7 @@ impl Mmu {
 	/// * `p_address` Physical address
 	pub fn load_word_raw(&mut self, p_address: u64) -> u32 {
 		let effective_address = self.get_effective_address(p_address);
-		match effective_address >= DRAM_BASE
-			&& effective_address.wrapping_add(3) > effective_address
-		{
+		match self.memory.is_mapped(effective_address, 4) {
 			// Fast path. Directly load main memory at a time.
 			true => self.memory.read_word(effective_address),
 			false => {
@@ -952,9 +1435,7 @@ impl Mmu {
 	/// * `p_address` Physical address
 	fn load_doubleword_raw(&mut self, p_address: u64) -> u64 {
 		let effective_address = self.get_effective_address(p_address);
-		match effective_address >= DRAM_BASE
-			&& effective_address.wrapping_add(7) > effective_address
-		{
+		match self.memory.is_mapped(effective_address, 8) {
 			// Fast path. Directly load main memory at a time.
 			true => self.memory.read_doubleword(effective_address),
 			false => {
@@ -975,7 +1456,10 @@ impl Mmu {
 	/// * `value` data written
 	pub fn store_raw(&mut self, p_address: u64, value: u8) {
 		let effective_address = self.get_effective_address(p_address);
-		self.memory.write_byte(effective_address, value);
+		match self.bus.is_mapped(effective_address, 1) {
+			true => self.bus.write(effective_address, value as u64, 1),
+			false => self.memory.write_byte(effective_address, value),
+		}
 	}
 
 	/// Stores two bytes to main memory or peripheral devices depending on
@@ -986,9 +1470,7 @@ impl Mmu {
 	/// * `value` data written
 	fn _store_halfword_raw(&mut self, p_address: u64, value: u16) {
 		let effective_address = self.get_effective_address(p_address);
-		match effective_address >= DRAM_BASE
-			&& effective_address.wrapping_add(1) > effective_address
-		{
+		match self.memory.is_mapped(effective_address, 2) {
 			// Fast path. Directly store to main memory at a time.
 			true => self.memory.write_halfword(effective_address, value),
 			false => {
@@ -1010,9 +1492,7 @@ impl Mmu {
 	/// * `value` data written
 	pub fn store_word_raw(&mut self, p_address: u64, value: u32) {
 		let effective_address = self.get_effective_address(p_address);
-		match effective_address >= DRAM_BASE
-			&& effective_address.wrapping_add(3) > effective_address
-		{
+		match self.memory.is_mapped(effective_address, 4) {
 			// Fast path. Directly store to main memory at a time.
 			true => self.memory.write_word(effective_address, value),
 			false => {
@@ -1034,9 +1514,7 @@ impl Mmu {
 	/// * `value` data written
 	pub fn store_doubleword_raw(&mut self, p_address: u64, value: u64) {
 		let effective_address = self.get_effective_address(p_address);
-		match effective_address >= DRAM_BASE
-			&& effective_address.wrapping_add(7) > effective_address
-		{
+		match self.memory.is_mapped(effective_address, 8) {
 			// Fast path. Directly store to main memory at a time.
 			true => self.memory.write_doubleword(effective_address, value),
 			false => {
@@ -1058,47 +1536,239 @@ impl Mmu {
 	pub fn validate_address(&mut self, v_address: u64) -> Result<bool, ()> {
 		// @TODO: Support other access types?
 		let p_address = match self.translate_address(v_address, &MemoryAccessType::DontCare) {
-			Ok(address) => address,
-			Err(()) => return Err(()),
+			Ok((address, _)) => address,
+			Err(_trap_type) => return Err(()),
 		};
 		let effective_address = self.get_effective_address(p_address);
-		let valid = match effective_address >= DRAM_BASE {
-			true => self.memory.validate_address(effective_address),
-			false => match effective_address {
-				0x00001020..=0x00001fff => true,
-				0x02000000..=0x0200ffff => true,
-				0x0C000000..=0x0fffffff => true,
-				0x10000000..=0x100000ff => true,
-				0x10001000..=0x10001FFF => true,
-				_ => false,
-			},
+		Ok(self.memory.validate_address(effective_address)
+			|| self.bus.is_mapped(effective_address, 1))
+	}
+
+	/// Translates virtual address into physical address with the current
+	/// addressing mode and privilege mode, for the host to access guest
+	/// memory. Returns page fault trap type if fails.
+	///
+	/// # Arguments
+	/// * `v_address` Virtual address
+	pub fn translate_address_for_host(&mut self, v_address: u64) -> Result<u64, TrapType> {
+		match self.translate_address(v_address, &MemoryAccessType::DontCare) {
+			Ok((p_address, _)) => Ok(p_address),
+			Err(trap_type) => Err(trap_type),
+		}
+	}
+
+	/// Saves the state to checkpoint. XLEN is saved by `Cpu`.
+	///
+	/// # Arguments
+	/// * `writer`
+	/// * `include_caches` Whether to save L1 and L2 cache lines
+	pub fn save_state(&self, writer: &mut CheckpointWriter, include_caches: bool) {
+		writer.write_u64(self.clock);
+		writer.write_u64(self.ppn);
+		writer.write_u8(match self.addressing_mode {
+			AddressingMode::None => 0,
+			AddressingMode::SV32 => 1,
+			AddressingMode::SV39 => 8,
+			AddressingMode::SV48 => 9,
+		});
+		writer.write_u8(get_privilege_encoding(&self.privilege_mode));
+		writer.write_u64(self.mstatus);
+		writer.write_u64(self.menvcfg);
+		writer.write_u64s(&self.tlb_tag);
+		writer.write_u64s(&self.tlb_value);
+		writer.write_u64(self.tlb_bitnum as u64);
+		writer.write_u64(self.tlb_hit_num);
+		writer.write_u64(self.tlb_miss_num);
+		self.page_walk_cache.save_state(writer);
+		writer.write_u64(self.page_walk_latency);
+		writer.write_u64(self.dram_latency);
+		writer.write_bool(self.dram_model.is_some());
+		if let Some(dram_model) = self.dram_model.as_ref() {
+			dram_model.save_state(writer);
+		}
+		writer.write_bool(self.memory_controller.is_some());
+		if let Some(memory_controller) = self.memory_controller.as_ref() {
+			memory_controller.save_state(writer);
+		}
+		writer.write_u64(self.uncached_num);
+		self.pmp.save_state(writer);
+		self.l1_cache.save_state(writer, include_caches);
+		self.l2_cache.save_state(writer, include_caches);
+		self.memory.save_state(writer);
+		self.bus.save_state(writer);
+	}
+
+	/// Restores the state saved by `save_state()`.
+	///
+	/// # Arguments
+	/// * `reader`
+	pub fn restore_state(&mut self, reader: &mut CheckpointReader) -> Result<(), CheckpointError> {
+		self.clock = reader.read_u64()?;
+		self.ppn = reader.read_u64()?;
+		self.addressing_mode = match reader.read_u8()? {
+			0 => AddressingMode::None,
+			1 => AddressingMode::SV32,
+			8 => AddressingMode::SV39,
+			9 => AddressingMode::SV48,
+			mode => return Err(reader.error(format!("Invalid addressing mode {}", mode))),
+		};
+		self.privilege_mode = match reader.read_u8()? {
+			mode @ (0 | 1 | 3) => get_privilege_mode(mode as u64),
+			mode => return Err(reader.error(format!("Invalid privilege mode {}", mode))),
 		};
-		Ok(valid)
+		self.mstatus = reader.read_u64()?;
+		self.menvcfg = reader.read_u64()?;
+		reader.read_u64s(&mut self.tlb_tag)?;
+		reader.read_u64s(&mut self.tlb_value)?;
+		self.tlb_bitnum = reader.read_u64()? as usize;
+		self.tlb_hit_num = reader.read_u64()?;
+		self.tlb_miss_num = reader.read_u64()?;
+		self.page_walk_cache.restore_state(reader)?;
+		self.page_walk_latency = reader.read_u64()?;
+		self.dram_latency = reader.read_u64()?;
+		if reader.read_bool()? != self.dram_model.is_some() {
+			return Err(reader.error("DRAM model doesn't match the emulator's".to_string()));
+		}
+		if let Some(dram_model) = self.dram_model.as_mut() {
+			dram_model.restore_state(reader)?;
+		}
+		if reader.read_bool()? != self.memory_controller.is_some() {
+			return Err(reader.error("Memory controller doesn't match the emulator's".to_string()));
+		}
+		if let Some(memory_controller) = self.memory_controller.as_mut() {
+			memory_controller.restore_state(reader)?;
+		}
+		self.uncached_num = reader.read_u64()?;
+		self.pmp.restore_state(reader)?;
+		self.l1_cache.restore_state(reader)?;
+		self.l2_cache.restore_state(reader)?;
+		self.memory.restore_state(reader)?;
+		self.bus.restore_state(reader)
+	}
+
+	/// Checks a guest access against the watchpoints. The hits are
+	/// reported by `Cpu` when the instruction completes.
+	///
+	/// # Arguments
+	/// * `access_type`
+	/// * `v_address`
+	/// * `p_address`
+	/// * `width` Access width in bytes
+	/// * `old_value` Value before write if available
+	/// * `value` Value read, written, or fetched
+	fn check_watchpoints(
+		&mut self,
+		access_type: MemoryAccessType,
+		v_address: u64,
+		p_address: u64,
+		width: u64,
+		old_value: Option<u64>,
+		value: u64,
+	) {
+		if self.watchpoints.is_empty() {
+			return;
+		}
+		let privilege_mode = self.get_effective_privilege_mode(&access_type);
+		self.watchpoints.check(
+			access_type,
+			v_address,
+			p_address,
+			width,
+			old_value,
+			value,
+			&privilege_mode,
+		);
+	}
+
+	/// Returns the effective privilege mode of memory access. Loads and stores
+	/// in machine mode are done with MPP privilege mode if MPRV is set.
+	///
+	/// # Arguments
+	/// * `access_type`
+	fn get_effective_privilege_mode(&self, access_type: &MemoryAccessType) -> PrivilegeMode {
+		match self.privilege_mode {
+			PrivilegeMode::Machine => match access_type {
+				MemoryAccessType::Execute => PrivilegeMode::Machine,
+				// @TODO: Remove magic number
+				_ => match (self.mstatus >> 17) & 1 {
+					0 => PrivilegeMode::Machine,
+					_ => get_privilege_mode((self.mstatus >> 11) & 3),
+				},
+			},
+			_ => self.privilege_mode.clone(),
+		}
+	}
+
+	/// Checks if physical address range is mapped to main memory or
+	/// a device.
+	///
+	/// # Arguments
+	/// * `p_address` Physical address
+	/// * `width` Access width in bytes
+	pub fn is_mapped(&self, p_address: u64, width: u64) -> bool {
+		self.memory.is_mapped(p_address, width) || self.bus.is_mapped(p_address, width)
+	}
+
+	/// Checks if physical memory access is permitted by PMP.
+	///
+	/// # Arguments
+	/// * `p_address` Physical address
+	/// * `width` Access width in bytes
+	/// * `access_type`
+	fn check_pmp(&self, p_address: u64, width: u64, access_type: &MemoryAccessType) -> bool {
+		let privilege_mode = self.get_effective_privilege_mode(access_type);
+		self.pmp
+			.check(p_address, width, access_type, &privilege_mode)
+	}
+
+	/// Translates virtual address into physical address and checks if the
+	/// access to the physical address is permitted and mapped. Returns page
+	/// fault or access fault trap type depending on access type if fails.
+	///
+	/// # Arguments
+	/// * `v_address` Virtual address
+	/// * `width` Access width in bytes
+	/// * `access_type`
+	fn translate_and_check(
+		&mut self,
+		v_address: u64,
+		width: u64,
+		access_type: &MemoryAccessType,
+	) -> Result<(u64, MemoryType), TrapType> {
+		let (p_address, memory_type) = self.translate_address(v_address, access_type)?;
+		match self.check_pmp(p_address, width, access_type) && self.is_mapped(p_address, width) {
+			// Devices are never cached
+			true => match self.bus.is_mapped(p_address, width) {
+				true => Ok((p_address, MemoryType::Io)),
+				false => Ok((p_address, memory_type)),
+			},
+			false => Err(get_access_fault_type(access_type)),
+		}
 	}
 
 	fn translate_address(
 		&mut self,
 		v_address: u64,
 		access_type: &MemoryAccessType,
-	) -> Result<u64, ()> {
+	) -> Result<(u64, MemoryType), TrapType> {
 		let address = self.get_effective_address(v_address);
 		// println!("detecter VADDR={}", address);
 		let p_address = match self.addressing_mode {
 			AddressingMode::None => {
 				// println!("AddressingMode==NONE");
-				Ok(address)
+				Ok((address, MemoryType::Pma))
 			}
 			AddressingMode::SV32 => match self.privilege_mode {
 				// @TODO: Optimize
 				PrivilegeMode::Machine => match access_type {
-					MemoryAccessType::Execute => Ok(address),
+					MemoryAccessType::Execute => Ok((address, MemoryType::Pma)),
 					// @TODO: Remove magic number
 					_ => match (self.mstatus >> 17) & 1 {
-						0 => Ok(address),
+						0 => Ok((address, MemoryType::Pma)),
 						_ => {
 							let privilege_mode = get_privilege_mode((self.mstatus >> 9) & 3);
 							match privilege_mode {
-								PrivilegeMode::Machine => Ok(address),
+								PrivilegeMode::Machine => Ok((address, MemoryType::Pma)),
 								_ => {
 									let current_privilege_mode = self.privilege_mode.clone();
 									self.update_privilege_mode(privilege_mode);
@@ -1114,7 +1784,7 @@ impl Mmu {
 					let vpns = [(address >> 12) & 0x3ff, (address >> 22) & 0x3ff];
 					self.tlb_or_pagewalk(address, 2 - 1, self.ppn, &vpns, &access_type)
 				}
-				_ => Ok(address),
+				_ => Ok((address, MemoryType::Pma)),
 			},
 			AddressingMode::SV39 => match self.privilege_mode {
 				// @TODO: Optimize
@@ -1122,19 +1792,19 @@ impl Mmu {
 				PrivilegeMode::Machine => match access_type {
 					MemoryAccessType::Execute => {
 						// println!("AddressingMode=SV39 Machine Execute");
-						Ok(address)
+						Ok((address, MemoryType::Pma))
 					}
 					// @TODO: Remove magic number
 					_ => match (self.mstatus >> 17) & 1 {
 						0 => {
 							// println!("AddressingMode=SV39 Machine else mstatus 17bit=1");
-							Ok(address)
+							Ok((address, MemoryType::Pma))
 						}
 						_ => {
 							// println!("AddressingMode=SV39 Machine else mstatus 17bit!=1");
 							let privilege_mode = get_privilege_mode((self.mstatus >> 9) & 3);
 							match privilege_mode {
-								PrivilegeMode::Machine => Ok(address),
+								PrivilegeMode::Machine => Ok((address, MemoryType::Pma)),
 								_ => {
 									let current_privilege_mode = self.privilege_mode.clone();
 									self.update_privilege_mode(privilege_mode);
@@ -1155,7 +1825,7 @@ impl Mmu {
 					// println!("AddressingMode=SV39 user");
 					self.tlb_or_pagewalk(address, 3 - 1, self.ppn, &vpns, &access_type)
 				}
-				_ => Ok(address),
+				_ => Ok((address, MemoryType::Pma)),
 			},
 			AddressingMode::SV48 => {
 				panic!("AddressingMode SV48 is not supported yet.");
@@ -1251,9 +1921,34 @@ impl Mmu {
 				"tlb_update_entry error: no free slot or victim, this situation shouldnt occur!"
 			);
 			panic!();
+		} else {
+			// update the existing entry
+			self.tlb_tag[i] |= 1;
+			self.tlb_value[i] = new_pte;
 		}
 	}
 
+	/// Checks if an implicit page table access is permitted by PMP and
+	/// mapped. Page table accesses are checked as Supervisor mode accesses.
+	///
+	/// # Arguments
+	/// * `pte_address` Physical address of page table entry
+	/// * `ptesize` Page table entry size in bytes
+	/// * `access_type`
+	fn check_pte_address(
+		&self,
+		pte_address: u64,
+		ptesize: u64,
+		access_type: &MemoryAccessType,
+	) -> bool {
+		self.pmp.check(
+			pte_address,
+			ptesize,
+			access_type,
+			&PrivilegeMode::Supervisor,
+		) && self.is_mapped(pte_address, ptesize)
+	}
+
 	fn tlb_or_pagewalk(
 		&mut self,
 		v_address: u64,
@@ -1261,7 +1956,7 @@ impl Mmu {
 		parent_ppn: u64,
 		vpns: &[u64],
 		access_type: &MemoryAccessType,
-	) -> Result<u64, ()> {
+	) -> Result<(u64, MemoryType), TrapType> {
 		let pagesize = 4096;
 		let ptesize = match self.addressing_mode {
 			AddressingMode::SV32 => 4,
@@ -1273,47 +1968,77 @@ impl Mmu {
 			AddressingMode::SV39 => (vpns[0] << 12) | (vpns[1] << 21) | (vpns[2] << 30),
 			_ => (vpns[0] << 12) | (vpns[1] << 21) | (vpns[2] << 30),
 		} as u64;
-		let pte = match self.addressing_mode {
-			AddressingMode::SV32 => match self.tlb_entry_avaliable(vpn) {
-				true => {
-					let tmp = self.tlb_get_entry(vpn);
-					level = (tmp >> 60) as u8;
-					tmp
+		let tlb_enabled = match self.addressing_mode {
+			AddressingMode::SV32 => true,
+			_ => ENABLE_TLB,
+		};
+		// A cached PTE whose A/D bits need to be updated is walked again
+		// because the TLB doesn't know the PTE address.
+		let cached_pte = match tlb_enabled && self.tlb_entry_avaliable(vpn) {
+			true => {
+				let tmp = self.tlb_get_entry(vpn);
+				match needs_ad_update(tmp, access_type) {
+					true => None,
+					false => Some(tmp),
 				}
-				_ => {
-					let tmp = self.load_word_raw(pte_address) as u64;
-					let tmp_x = (tmp >> 3) & 1;
-					let tmp_w = (tmp >> 2) & 1;
-					let tmp_r = (tmp >> 1) & 1;
-					if tmp_x != 0 || tmp_r != 0 || tmp_w != 0 {
-						// a leaf PTE
-						self.tlb_update_entry(vpn, tmp | ((level as u64) << 60));
-					}
-					tmp
+			}
+			false => None,
+		};
+		let root_level = match self.addressing_mode {
+			AddressingMode::SV32 => 1,
+			_ => 2,
+		};
+		let pte = match cached_pte {
+			Some(tmp) => {
+				self.tlb_hit_num += 1;
+				level = ((tmp >> TLB_LEVEL_SHIFT) & 0x3) as u8;
+				tmp & !PTE_RESERVED_MASK
+			}
+			None => {
+				if level == root_level {
+					self.tlb_miss_num += 1;
 				}
-			},
-			_ => {
-				if ENABLE_TLB == true {
-					match self.tlb_entry_avaliable(vpn) {
-						true => {
-							let tmp = self.tlb_get_entry(vpn);
-							level = (tmp >> 60) as u8;
-							tmp
-						}
-						_ => {
-							let tmp = self.load_doubleword_raw(pte_address);
-							let tmp_x = (tmp >> 3) & 1;
-							let tmp_w = (tmp >> 2) & 1;
-							let tmp_r = (tmp >> 1) & 1;
-							if tmp_x != 0 || tmp_r != 0 || tmp_w != 0 {
-								self.tlb_update_entry(vpn, tmp | ((level as u64) << 60));
-							}
-							tmp
+				let walk_start = self.clock;
+				// Page-walk cache holds only non-leaf PTEs, which are never
+				// at level 0
+				let walk_cache_pte = match level > 0 {
+					true => self.page_walk_cache.read(level as usize, pte_address),
+					false => None,
+				};
+				let tmp = match walk_cache_pte {
+					Some(tmp) => {
+						self.clock = self.clock.wrapping_add(PAGE_WALK_CACHE_HIT_LATENCY);
+						tmp
+					}
+					None => {
+						if !self.check_pte_address(pte_address, ptesize, &MemoryAccessType::Read) {
+							return Err(get_access_fault_type(access_type));
 						}
+						let tmp = match self.addressing_mode {
+							AddressingMode::SV32 => self.load_word_raw(pte_address) as u64,
+							_ => self.load_doubleword_raw(pte_address),
+						};
+						self.clock = self
+							.clock
+							.wrapping_add(self.page_walk_cache.level_latency[level as usize]);
+						tmp
 					}
-				} else {
-					self.load_doubleword_raw(pte_address)
+				};
+				self.page_walk_latency += self.clock.wrapping_sub(walk_start);
+				let tmp_x = (tmp >> 3) & 1;
+				let tmp_w = (tmp >> 2) & 1;
+				let tmp_r = (tmp >> 1) & 1;
+				let tmp_v = tmp & 1;
+				if tmp_x != 0 || tmp_r != 0 || tmp_w != 0 {
+					if tlb_enabled && (tmp & PTE_RESERVED_MASK) == 0 {
+						// a leaf PTE
+						self.tlb_update_entry(vpn, tmp | ((level as u64) << TLB_LEVEL_SHIFT));
+					}
+				} else if tmp_v != 0 && level > 0 {
+					// a non-leaf PTE
+					self.page_walk_cache.write(level as usize, pte_address, tmp);
 				}
+				tmp
 			}
 		};
 		let ppn = match self.addressing_mode {
@@ -1329,9 +2054,9 @@ impl Mmu {
 			],
 			_ => panic!(), // Shouldn't happen
 		};
+		let n = (pte & PTE_N) >> 63;
+		let pbmt = (pte >> PTE_PBMT_SHIFT) & 0x3;
 		let _rsw = (pte >> 8) & 0x3;
-		let d = (pte >> 7) & 1;
-		let a = (pte >> 6) & 1;
 		let _g = (pte >> 5) & 1;
 		let _u = (pte >> 4) & 1;
 		let x = (pte >> 3) & 1;
@@ -1340,61 +2065,59 @@ impl Mmu {
 		let v = pte & 1;
 		// println!("VA:{:X} Level:{:X} PTE_AD:{:X} PTE:{:X} PPPN:{:X} PPN:{:X} PPN1:{:X} PPN0:{:X}", v_address, level, pte_address, pte, parent_ppn, ppn, ppns[1], ppns[0]);
 
-		if v == 0 || (r == 0 && w == 1) {
-			return Err(());
+		if v == 0 || (r == 0 && w == 1) || (pte & PTE_RESERVED_MASK) != 0 {
+			return Err(get_page_fault_type(access_type));
+		}
+
+		// PBMT = 3 is reserved, and PBMT is reserved unless Svpbmt is enabled
+		if pbmt == 3 || (pbmt != 0 && (self.menvcfg & MENVCFG_PBMTE) == 0) {
+			return Err(get_page_fault_type(access_type));
 		}
 
 		if r == 0 && x == 0 {
+			// N and PBMT are reserved for non-leaf PTE
+			if n != 0 || pbmt != 0 {
+				return Err(get_page_fault_type(access_type));
+			}
 			return match level {
-				0 => Err(()),
+				0 => Err(get_page_fault_type(access_type)),
 				_ => self.tlb_or_pagewalk(v_address, level - 1, ppn, vpns, access_type),
 			};
 		}
 
 		// Leaf page found
 
-		if a == 0
-			|| (match access_type {
-				MemoryAccessType::Write => d == 0,
-				_ => false,
-			}) {
-			let new_pte = pte
-				| (1 << 6) | (match access_type {
-				MemoryAccessType::Write => 1 << 7,
-				_ => 0,
-			});
-			match self.addressing_mode {
-				AddressingMode::SV32 => self.store_word_raw(pte_address, new_pte as u32),
-				_ => self.store_doubleword_raw(pte_address, new_pte),
-			};
-		}
-
 		match access_type {
 			MemoryAccessType::Execute => {
 				if x == 0 {
-					return Err(());
+					return Err(get_page_fault_type(access_type));
 				}
 			}
 			MemoryAccessType::Read => {
 				if r == 0 {
-					return Err(());
+					return Err(get_page_fault_type(access_type));
 				}
 			}
 			MemoryAccessType::Write => {
 				if w == 0 {
-					return Err(());
+					return Err(get_page_fault_type(access_type));
 				}
 			}
 			_ => {}
 		};
 
+		// Svnapot supports only 64KiB pages, PPN[3:0] = 1000 in level 0 PTE
+		if n != 0 && (level != 0 || (ppn & 0xf) != 0x8) {
+			return Err(get_page_fault_type(access_type));
+		}
+
 		let offset = v_address & 0xfff; // [11:0]
 								// @TODO: Optimize
 		let p_address = match self.addressing_mode {
 			AddressingMode::SV32 => match level {
 				1 => {
 					if ppns[0] != 0 {
-						return Err(());
+						return Err(get_page_fault_type(access_type));
 					}
 					(ppns[1] << 22) | (vpns[0] << 12) | offset
 				}
@@ -1404,116 +2127,689 @@ impl Mmu {
 			_ => match level {
 				2 => {
 					if ppns[1] != 0 || ppns[0] != 0 {
-						return Err(());
+						return Err(get_page_fault_type(access_type));
 					}
 					(ppns[2] << 30) | (vpns[1] << 21) | (vpns[0] << 12) | offset
 				}
 				1 => {
 					if ppns[0] != 0 {
-						return Err(());
+						return Err(get_page_fault_type(access_type));
 					}
 					(ppns[2] << 30) | (ppns[1] << 21) | (vpns[0] << 12) | offset
 				}
-				0 => (ppn << 12) | offset,
+				0 => match n {
+					0 => (ppn << 12) | offset,
+					// NAPOT page takes PPN[3:0] from VPN[0][3:0]
+					_ => (((ppn & !0xf) | (vpns[0] & 0xf)) << 12) | offset,
+				},
 				_ => panic!(), // Shouldn't happen
 			},
 		};
 
+		if needs_ad_update(pte, access_type) {
+			// Svadu: hardware updates A/D bits only if menvcfg.ADUE is set.
+			// Otherwise page fault is raised and software updates them.
+			if (self.menvcfg & MENVCFG_ADUE) == 0 {
+				return Err(get_page_fault_type(access_type));
+			}
+			let new_pte = pte
+				| (1 << 6) | (match access_type {
+				MemoryAccessType::Write => 1 << 7,
+				_ => 0,
+			});
+			if !self.check_pte_address(pte_address, ptesize, &MemoryAccessType::Write) {
+				return Err(get_access_fault_type(access_type));
+			}
+			match self.addressing_mode {
+				AddressingMode::SV32 => self.store_word_raw(pte_address, new_pte as u32),
+				_ => self.store_doubleword_raw(pte_address, new_pte),
+			};
+			if tlb_enabled {
+				self.tlb_update_entry(vpn, new_pte | ((level as u64) << TLB_LEVEL_SHIFT));
+			}
+		}
+
+		let memory_type = match pbmt {
+			1 => MemoryType::Nc,
+			2 => MemoryType::Io,
+			_ => MemoryType::Pma,
+		};
+
 		// println!("PA:{:X}", p_address);
-		Ok(p_address)
+		Ok((p_address, memory_type))
 	}
 }
 
+/// Main memory region mapped at a physical base address
+pub struct MemoryRegion {
+	pub base: u64,
+	pub memory: Box<dyn MemoryBackend>,
+}
+
+/// Returns the range of doubleword indices in a memory page.
+///
+/// # Arguments
+/// * `page` Page offset in memory region
+/// * `capacity` Capacity of memory region
+fn get_page_indices(page: u64, capacity: u64) -> std::ops::Range<u64> {
+	let top = (page + SPARSE_MEMORY_PAGE_SIZE).min(capacity);
+	(page / 8)..top.div_ceil(8)
+}
+
 /// [`Memory`](../memory/struct.Memory.html) wrapper. Converts physical address to the one in memory
-/// using [`DRAM_BASE`](constant.DRAM_BASE.html) and accesses [`Memory`](../memory/struct.Memory.html).
+/// using the base addresses of memory regions and accesses
+/// [`MemoryBackend`](../memory/trait.MemoryBackend.html).
 pub struct MemoryWrapper {
-	pub memory: Memory,
+	pub regions: Vec<MemoryRegion>,
+	/// Backend used for main memory at [`DRAM_BASE`](constant.DRAM_BASE.html)
+	pub backend_type: MemoryBackendType,
 }
 
 impl MemoryWrapper {
 	fn new() -> Self {
 		MemoryWrapper {
-			memory: Memory::new(),
+			regions: vec![],
+			backend_type: MemoryBackendType::Sparse,
+		}
+	}
+
+	/// Saves the content of all the regions to checkpoint. Only the pages
+	/// holding non-zero data are saved.
+	///
+	/// # Arguments
+	/// * `writer`
+	pub fn save_state(&self, writer: &mut CheckpointWriter) {
+		writer.write_u64(self.regions.len() as u64);
+		for region in self.regions.iter() {
+			let capacity = region.memory.capacity();
+			writer.write_u64(region.base);
+			writer.write_u64(capacity);
+			let pages: Vec<u64> = region
+				.memory
+				.get_touched_pages()
+				.into_iter()
+				.filter(|page| {
+					get_page_indices(*page, capacity)
+						.any(|index| region.memory.read_aligned(index) != 0)
+				})
+				.collect();
+			writer.write_u64(pages.len() as u64);
+			for page in pages {
+				writer.write_u64(page);
+				for index in get_page_indices(page, capacity) {
+					writer.write_u64(region.memory.read_aligned(index));
+				}
+			}
+		}
+	}
+
+	/// Restores the content saved by `save_state()`. The same regions
+	/// need to be mapped.
+	///
+	/// # Arguments
+	/// * `reader`
+	pub fn restore_state(&mut self, reader: &mut CheckpointReader) -> Result<(), CheckpointError> {
+		reader.expect_u64(self.regions.len() as u64, "Memory region number")?;
+		for region in self.regions.iter_mut() {
+			let capacity = region.memory.capacity();
+			reader.expect_u64(region.base, "Memory region base address")?;
+			reader.expect_u64(capacity, "Memory region capacity")?;
+			for page in region.memory.get_touched_pages() {
+				for index in get_page_indices(page, capacity) {
+					region.memory.write_aligned(index, 0, 0xffffffffffffffff);
+				}
+			}
+			let page_num = reader.read_u64()?;
+			for _ in 0..page_num {
+				let page = reader.read_u64()?;
+				if (page % SPARSE_MEMORY_PAGE_SIZE) != 0 || page >= capacity {
+					return Err(reader.error(format!("Invalid memory page {:x}", page)));
+				}
+				for index in get_page_indices(page, capacity) {
+					let value = reader.read_u64()?;
+					region
+						.memory
+						.write_aligned(index, value, 0xffffffffffffffff);
+				}
+			}
+		}
+		Ok(())
+	}
+
+	/// Maps a memory region. Returns error if the region exceeds the
+	/// physical address space or overlaps with a mapped one.
+	///
+	/// # Arguments
+	/// * `base` Physical base address
+	/// * `memory`
+	pub fn add_region(&mut self, base: u64, memory: Box<dyn MemoryBackend>) -> Result<(), String> {
+		let top = match base.checked_add(memory.capacity()) {
+			Some(top) => top,
+			None => {
+				return Err(format!(
+					"Memory region at {:X} exceeds the address space",
+					base
+				))
+			}
+		};
+		if let Some(region) = self.find_overlapping_region(base, memory.capacity()) {
+			return Err(format!(
+				"Memory region {:X}-{:X} overlaps with the one at {:X}",
+				base, top, region.base
+			));
 		}
+		self.regions.push(MemoryRegion { base, memory });
+		Ok(())
+	}
+
+	/// Returns the first region overlapping with an address range.
+	///
+	/// # Arguments
+	/// * `base` Physical base address
+	/// * `size` Size of the address range in bytes
+	fn find_overlapping_region(&self, base: u64, size: u64) -> Option<&MemoryRegion> {
+		self.regions
+			.iter()
+			.find(|region| overlaps(base, size, region.base, region.memory.capacity()))
 	}
 
-	fn init(&mut self, capacity: u64) {
-		self.memory.init(capacity);
+	/// Returns the index of the region containing the access.
+	///
+	/// # Arguments
+	/// * `p_address`
+	/// * `width` Access width in bytes
+	fn find_region(&self, p_address: u64, width: u64) -> Option<usize> {
+		self.regions.iter().position(|region| {
+			let capacity = region.memory.capacity();
+			p_address >= region.base
+				&& width <= capacity
+				&& p_address - region.base <= capacity - width
+		})
 	}
 
+	// Guest accesses are checked with `is_mapped` before reaching here.
+	// Reads from unmapped address return zero and writes to it are ignored.
+
 	pub fn read_byte(&mut self, p_address: u64) -> u8 {
-		debug_assert!(
-			p_address >= DRAM_BASE,
-			"Memory address must equals to or bigger than DRAM_BASE. {:X}",
-			p_address
-		);
-		self.memory.read_byte(p_address - DRAM_BASE)
+		match self.find_region(p_address, 1) {
+			Some(index) => {
+				let region = &self.regions[index];
+				region.memory.read_byte(p_address - region.base)
+			}
+			None => 0,
+		}
 	}
 
 	pub fn read_halfword(&mut self, p_address: u64) -> u16 {
-		debug_assert!(
-			p_address >= DRAM_BASE && p_address.wrapping_add(1) >= DRAM_BASE,
-			"Memory address must equals to or bigger than DRAM_BASE. {:X}",
-			p_address
-		);
-		self.memory.read_halfword(p_address - DRAM_BASE)
+		match self.find_region(p_address, 2) {
+			Some(index) => {
+				let region = &self.regions[index];
+				region.memory.read_halfword(p_address - region.base)
+			}
+			None => 0,
+		}
 	}
 
 	pub fn read_word(&mut self, p_address: u64) -> u32 {
-		debug_assert!(
-			p_address >= DRAM_BASE && p_address.wrapping_add(3) >= DRAM_BASE,
-			"Memory address must equals to or bigger than DRAM_BASE. {:X}",
-			p_address
-		);
-		self.memory.read_word(p_address - DRAM_BASE)
+		match self.find_region(p_address, 4) {
+			Some(index) => {
+				let region = &self.regions[index];
+				region.memory.read_word(p_address - region.base)
+			}
+			None => 0,
+		}
 	}
 
 	pub fn read_doubleword(&mut self, p_address: u64) -> u64 {
-		debug_assert!(
-			p_address >= DRAM_BASE && p_address.wrapping_add(7) >= DRAM_BASE,
-			"Memory address must equals to or bigger than DRAM_BASE. {:X}",
-			p_address
-		);
-		self.memory.read_doubleword(p_address - DRAM_BASE)
+		match self.find_region(p_address, 8) {
+			Some(index) => {
+				let region = &self.regions[index];
+				region.memory.read_doubleword(p_address - region.base)
+			}
+			None => 0,
+		}
 	}
 
 	pub fn write_byte(&mut self, p_address: u64, value: u8) {
-		debug_assert!(
-			p_address >= DRAM_BASE,
-			"Memory address must equals to or bigger than DRAM_BASE. {:X}",
-			p_address
-		);
-		self.memory.write_byte(p_address - DRAM_BASE, value)
+		if let Some(index) = self.find_region(p_address, 1) {
+			let region = &mut self.regions[index];
+			region.memory.write_byte(p_address - region.base, value)
+		}
 	}
 
 	pub fn write_halfword(&mut self, p_address: u64, value: u16) {
-		debug_assert!(
-			p_address >= DRAM_BASE && p_address.wrapping_add(1) >= DRAM_BASE,
-			"Memory address must equals to or bigger than DRAM_BASE. {:X}",
-			p_address
-		);
-		self.memory.write_halfword(p_address - DRAM_BASE, value)
+		if let Some(index) = self.find_region(p_address, 2) {
+			let region = &mut self.regions[index];
+			region.memory.write_halfword(p_address - region.base, value)
+		}
 	}
 
 	pub fn write_word(&mut self, p_address: u64, value: u32) {
-		debug_assert!(
-			p_address >= DRAM_BASE && p_address.wrapping_add(3) >= DRAM_BASE,
-			"Memory address must equals to or bigger than DRAM_BASE. {:X}",
-			p_address
-		);
-		self.memory.write_word(p_address - DRAM_BASE, value)
+		if let Some(index) = self.find_region(p_address, 4) {
+			let region = &mut self.regions[index];
+			region.memory.write_word(p_address - region.base, value)
+		}
 	}
 
 	pub fn write_doubleword(&mut self, p_address: u64, value: u64) {
-		debug_assert!(
-			p_address >= DRAM_BASE && p_address.wrapping_add(7) >= DRAM_BASE,
-			"Memory address must equals to or bigger than DRAM_BASE. {:X}",
-			p_address
-		);
-		self.memory.write_doubleword(p_address - DRAM_BASE, value)
+		if let Some(index) = self.find_region(p_address, 8) {
+			let region = &mut self.regions[index];
+			region
+				.memory
+				.write_doubleword(p_address - region.base, value)
+		}
+	}
+
+	/// Checks if physical address range is in a memory region
+	///
+	/// # Arguments
+	/// * `p_address`
+	/// * `width` Access width in bytes
+	pub fn is_mapped(&self, p_address: u64, width: u64) -> bool {
+		self.find_region(p_address, width).is_some()
 	}
 
 	pub fn validate_address(&self, address: u64) -> bool {
-		self.memory.validate_address(address - DRAM_BASE)
+		self.is_mapped(address, 1)
+	}
+}
+
+/// Main memory access from devices. It reads the data cached in L1 or L2
+/// cache if present, and writes to the cached lines as well as memory.
+/// The memory accesses are sent to the memory controller, if set up, as
+/// DMA requests.
+struct CoherentMemory<'a> {
+	memory: &'a mut MemoryWrapper,
+	l1_cache: &'a mut L1Cache,
+	l2_cache: &'a mut L2Cache,
+	memory_controller: Option<&'a mut MemoryController>,
+	clock: u64,
+	/// Line of the last request and whether it's write, so that a line
+	/// is requested once for consecutive bytes
+	last_line: Option<(u64, bool)>,
+}
+
+impl<'a> CoherentMemory<'a> {
+	/// Sends a DMA request of the line including the address to the memory
+	/// controller. Devices don't wait for the completion.
+	///
+	/// # Arguments
+	/// * `p_address` Physical address
+	/// * `is_write`
+	fn request(&mut self, p_address: u64, is_write: bool) {
+		let line = p_address >> L1_CACHE_OFFSET_BITS;
+		if self.last_line == Some((line, is_write)) {
+			return;
+		}
+		self.last_line = Some((line, is_write));
+		if let Some(memory_controller) = self.memory_controller.as_mut() {
+			memory_controller.access(
+				line << L1_CACHE_OFFSET_BITS,
+				is_write,
+				MemoryRequestSource::Dma,
+				self.clock,
+			);
+		}
+	}
+}
+
+impl<'a> DmaMemory for CoherentMemory<'a> {
+	fn read_byte(&mut self, p_address: u64) -> u8 {
+		let l1_index = (p_address >> L1_CACHE_OFFSET_BITS) & ((1 << L1_CACHE_INDEX_BITS) - 1);
+		let l1_offset = p_address & ((1 << L1_CACHE_OFFSET_BITS) - 1);
+		if let Ok(l1_way) = self.l1_cache.read_line_info(p_address) {
+			return self.l1_cache.data[l1_index as usize].data[l1_way as usize].get(l1_offset, 1)
+				as u8;
+		}
+		let l2_index = (p_address >> L2_CACHE_OFFSET_BITS) & ((1 << L2_CACHE_INDEX_BITS) - 1);
+		let l2_offset = p_address & ((1 << L2_CACHE_OFFSET_BITS) - 1);
+		if let Ok(l2_way) = self.l2_cache.read_line_info(p_address) {
+			return self.l2_cache.data[l2_index as usize].data[l2_way as usize].get(l2_offset, 1)
+				as u8;
+		}
+		self.request(p_address, false);
+		self.memory.read_byte(p_address)
+	}
+
+	fn write_byte(&mut self, p_address: u64, value: u8) {
+		let l1_index = (p_address >> L1_CACHE_OFFSET_BITS) & ((1 << L1_CACHE_INDEX_BITS) - 1);
+		let l1_offset = p_address & ((1 << L1_CACHE_OFFSET_BITS) - 1);
+		if let Ok(l1_way) = self.l1_cache.read_line_info(p_address) {
+			self.l1_cache.data[l1_index as usize].data[l1_way as usize].set(
+				l1_offset,
+				1,
+				value as u64,
+			);
+		}
+		let l2_index = (p_address >> L2_CACHE_OFFSET_BITS) & ((1 << L2_CACHE_INDEX_BITS) - 1);
+		let l2_offset = p_address & ((1 << L2_CACHE_OFFSET_BITS) - 1);
+		if let Ok(l2_way) = self.l2_cache.read_line_info(p_address) {
+			self.l2_cache.data[l2_index as usize].data[l2_way as usize].set(
+				l2_offset,
+				1,
+				value as u64,
+			);
+		}
+		self.request(p_address, true);
+		self.memory.write_byte(p_address, value);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use clint::CLINT_BASE;
+	use std::thread;
+	use watchpoint::Watchpoint;
+
+	/// Sv39 page tables. The root and level 1 tables map the first 2 MiB
+	/// of virtual address space with the level 0 table.
+	const ROOT_TABLE: u64 = DRAM_BASE;
+	const LEVEL1_TABLE: u64 = DRAM_BASE + 0x1000;
+	const LEVEL0_TABLE: u64 = DRAM_BASE + 0x2000;
+
+	const MEMORY_CAPACITY: u64 = 0x100000;
+
+	/// Neither main memory nor a device is mapped here
+	const UNMAPPED_ADDRESS: u64 = 0x40000000;
+
+	const PTE_V: u64 = 1 << 0;
+	const PTE_R: u64 = 1 << 1;
+	const PTE_W: u64 = 1 << 2;
+	const PTE_X: u64 = 1 << 3;
+	const PTE_A: u64 = 1 << 6;
+	const PTE_D: u64 = 1 << 7;
+	const PTE_RWAD: u64 = PTE_V | PTE_R | PTE_W | PTE_A | PTE_D;
+
+	struct NullDevice;
+
+	impl Device for NullDevice {
+		fn read(&mut self, _offset: u64, _width: u64) -> u64 {
+			0
+		}
+
+		fn write(&mut self, _offset: u64, _value: u64, _width: u64) {}
+	}
+
+	// Mmu holds the caches inline and needs a large stack
+	fn run_with_large_stack(test: fn()) {
+		thread::Builder::new()
+			.stack_size(256 * 1024 * 1024)
+			.spawn(test)
+			.unwrap()
+			.join()
+			.unwrap();
+	}
+
+	fn get_pte(p_address: u64, flags: u64) -> u64 {
+		((p_address >> 12) << 10) | flags
+	}
+
+	/// Returns `Mmu` in Supervisor mode with Sv39 enabled and full access
+	/// granted by PMP.
+	fn create_mmu() -> Mmu {
+		let mut mmu = Mmu::new(Xlen::Bit64);
+		mmu.init_memory(MEMORY_CAPACITY).unwrap();
+		mmu.pmp.grant_all(&Xlen::Bit64);
+		mmu.store_doubleword_raw(ROOT_TABLE, get_pte(LEVEL1_TABLE, PTE_V));
+		mmu.store_doubleword_raw(LEVEL1_TABLE, get_pte(LEVEL0_TABLE, PTE_V));
+		mmu.update_ppn(ROOT_TABLE >> 12);
+		mmu.update_addressing_mode(AddressingMode::SV39);
+		mmu.update_privilege_mode(PrivilegeMode::Supervisor);
+		mmu
+	}
+
+	/// Sets the level 0 PTE of a 4 KiB page in the first 2 MiB
+	fn map_page(mmu: &mut Mmu, v_address: u64, pte: u64) {
+		mmu.store_doubleword_raw(LEVEL0_TABLE + (v_address >> 12) * 8, pte);
+	}
+
+	fn read_pte(mmu: &mut Mmu, v_address: u64) -> u64 {
+		mmu.load_doubleword_raw(LEVEL0_TABLE + (v_address >> 12) * 8)
+	}
+
+	fn is_page_fault(result: Result<u64, Trap>) -> bool {
+		match result {
+			Err(trap) => matches!(trap.trap_type, TrapType::LoadPageFault),
+			Ok(_) => false,
+		}
+	}
+
+	fn is_store_page_fault(result: Result<(), Trap>) -> bool {
+		match result {
+			Err(trap) => matches!(trap.trap_type, TrapType::StorePageFault),
+			Ok(_) => false,
+		}
+	}
+
+	#[test]
+	fn memory_regions() {
+		let mut memory = MemoryWrapper::new();
+		let sparse = new_memory_backend(&MemoryBackendType::Sparse, 0x1000);
+		let dense = new_memory_backend(&MemoryBackendType::Dense, 0x2000);
+		memory.add_region(0x1000, sparse).unwrap();
+		memory.add_region(0x4000, dense).unwrap();
+		// Overlapping regions and the region beyond the address space
+		let overlapping = new_memory_backend(&MemoryBackendType::Sparse, 0x2000);
+		assert!(memory.add_region(0x3000, overlapping).is_err());
+		let overlapping = new_memory_backend(&MemoryBackendType::Sparse, 0x1000);
+		assert!(memory.add_region(0x1800, overlapping).is_err());
+		let too_high = new_memory_backend(&MemoryBackendType::Sparse, 0x2000);
+		assert!(memory.add_region(u64::MAX - 0xfff, too_high).is_err());
+		assert_eq!(memory.regions.len(), 2);
+
+		assert!(memory.is_mapped(0x1ff8, 8));
+		assert!(!memory.is_mapped(0x1ffc, 8));
+		assert!(!memory.is_mapped(0x2000, 1));
+		assert!(!memory.is_mapped(0x3fff, 1));
+		assert!(memory.is_mapped(0x4000, 8));
+		assert!(memory.is_mapped(0x5fff, 1));
+		assert!(!memory.is_mapped(0x6000, 1));
+		assert!(!memory.is_mapped(u64::MAX - 3, 8));
+
+		memory.write_doubleword(0x1ff8, 0x1234);
+		memory.write_doubleword(0x5ff8, 0x5678);
+		assert_eq!(memory.regions[0].memory.read_doubleword(0xff8), 0x1234);
+		assert_eq!(memory.regions[1].memory.read_doubleword(0x1ff8), 0x5678);
+		assert_eq!(memory.read_doubleword(0x1ff8), 0x1234);
+		assert_eq!(memory.read_doubleword(0x5ff8), 0x5678);
+		// Unmapped accesses read zero and writes are ignored
+		memory.write_doubleword(0x3000, 0x9abc);
+		assert_eq!(memory.read_doubleword(0x3000), 0);
+	}
+
+	/// Checks that a load, a store, and a fetch at `v_address` raise access
+	/// faults with `v_address` in mtval.
+	fn check_access_faults(mmu: &mut Mmu, v_address: u64) {
+		let trap = mmu.load_doubleword(v_address).unwrap_err();
+		assert!(matches!(trap.trap_type, TrapType::LoadAccessFault));
+		assert_eq!(trap.value, v_address);
+		let trap = mmu.store_word(v_address, 1).unwrap_err();
+		assert!(matches!(trap.trap_type, TrapType::StoreAccessFault));
+		assert_eq!(trap.value, v_address);
+		let trap = mmu.fetch_word(v_address).unwrap_err();
+		assert!(matches!(trap.trap_type, TrapType::InstructionAccessFault));
+		assert_eq!(trap.value, v_address);
+	}
+
+	#[test]
+	fn devices_and_memory_dont_overlap() {
+		run_with_large_stack(|| {
+			let mut mmu = Mmu::new(Xlen::Bit64);
+			mmu.init_memory(MEMORY_CAPACITY).unwrap();
+			let device = Box::new(NullDevice);
+			assert!(mmu.add_device(DRAM_BASE + 0x1000, 0x1000, device).is_err());
+			mmu.add_device(UNMAPPED_ADDRESS, 0x1000, Box::new(NullDevice))
+				.unwrap();
+			let memory = new_memory_backend(&MemoryBackendType::Sparse, 0x2000);
+			let base = UNMAPPED_ADDRESS - 0x1000;
+			assert!(mmu.add_memory_region(base, memory).is_err());
+			// CLINT and PLIC are mapped from the beginning
+			let memory = new_memory_backend(&MemoryBackendType::Sparse, 0x1000);
+			assert!(mmu.add_memory_region(CLINT_BASE, memory).is_err());
+			assert_eq!(mmu.memory.regions.len(), 1);
+			assert_eq!(mmu.bus.devices.len(), 1);
+		});
+	}
+
+	#[test]
+	fn unmapped_physical_address_faults() {
+		run_with_large_stack(|| {
+			let mut mmu = Mmu::new(Xlen::Bit64);
+			mmu.init_memory(MEMORY_CAPACITY).unwrap();
+			mmu.update_privilege_mode(PrivilegeMode::Machine);
+			check_access_faults(&mut mmu, UNMAPPED_ADDRESS);
+			check_access_faults(&mut mmu, DRAM_BASE + MEMORY_CAPACITY);
+			// The fault is reported at the first unmapped byte of an access
+			// across the end of main memory
+			let top = DRAM_BASE + MEMORY_CAPACITY;
+			let trap = mmu.load_doubleword(top - 4).unwrap_err();
+			assert!(matches!(trap.trap_type, TrapType::LoadAccessFault));
+			assert_eq!(trap.value, top);
+			let trap = mmu.fetch_word(top - 2).unwrap_err();
+			assert!(matches!(trap.trap_type, TrapType::InstructionAccessFault));
+			assert_eq!(trap.value, top);
+			assert!(mmu.load_word(top - 4).is_ok());
+		});
+	}
+
+	#[test]
+	fn unmapped_translated_address_faults() {
+		run_with_large_stack(|| {
+			let mut mmu = create_mmu();
+			// Page mapped to unmapped physical address
+			let pte = get_pte(UNMAPPED_ADDRESS, PTE_RWAD | PTE_X);
+			map_page(&mut mmu, 0x50000, pte);
+			check_access_faults(&mut mmu, 0x50008);
+			// Page table itself at unmapped physical address
+			let pte = get_pte(UNMAPPED_ADDRESS, PTE_V);
+			mmu.store_doubleword_raw(LEVEL1_TABLE + 2 * 8, pte);
+			check_access_faults(&mut mmu, 0x400000);
+			// Page fault on permission takes priority over access fault
+			let pte = get_pte(UNMAPPED_ADDRESS, PTE_V | PTE_X | PTE_A | PTE_D);
+			map_page(&mut mmu, 0x60000, pte);
+			let trap = mmu.load_doubleword(0x60000).unwrap_err();
+			assert!(matches!(trap.trap_type, TrapType::LoadPageFault));
+			assert_eq!(trap.value, 0x60000);
+		});
+	}
+
+	#[test]
+	fn fetches_check_watchpoints() {
+		run_with_large_stack(|| {
+			let mut mmu = create_mmu();
+			// Instruction page followed by an unmapped page
+			let pte = get_pte(DRAM_BASE + 0x10000, PTE_V | PTE_X | PTE_A);
+			map_page(&mut mmu, 0x1000, pte);
+			mmu.store_word_raw(DRAM_BASE + 0x10ffc, 0x12345678);
+			let watchpoint = Watchpoint::parse("x:0x1ffe:2").unwrap();
+			mmu.watchpoints.add(watchpoint);
+
+			assert_eq!(mmu.fetch_bytes(0x1ffe, 2).unwrap(), vec![0x34, 0x12]);
+			assert!(!mmu.watchpoints.report_hits(0x1ffe));
+			let hit = mmu.watchpoints.get_last_hit().unwrap();
+			assert_eq!(hit.v_address, 0x1ffe);
+			assert_eq!(hit.p_address, DRAM_BASE + 0x10ffe);
+			assert_eq!((hit.width, hit.value), (2, 0x1234));
+
+			assert_eq!(mmu.fetch_word(0x1ffc).unwrap(), 0x12345678);
+			assert!(!mmu.watchpoints.has_pending_hits());
+			assert_eq!(mmu.fetch_bytes(0x1ffc, 2).unwrap(), vec![0x78, 0x56]);
+			assert!(!mmu.watchpoints.has_pending_hits());
+
+			// Fetches crossing into the unmapped page report the faulting
+			// byte address after the bytes fetched are checked
+			let trap = mmu.fetch_bytes(0x1ffe, 4).unwrap_err();
+			assert!(matches!(trap.trap_type, TrapType::InstructionPageFault));
+			assert_eq!(trap.value, 0x2000);
+			mmu.watchpoints.report_hits(0x1ffe);
+			let hit = mmu.watchpoints.get_last_hit().unwrap();
+			assert_eq!((hit.v_address, hit.width, hit.value), (0x1fff, 1, 0x12));
+			let trap = mmu.fetch_word(0x1ffe).unwrap_err();
+			assert_eq!(trap.value, 0x2000);
+		});
+	}
+
+	#[test]
+	fn napot_64kib_page() {
+		run_with_large_stack(|| {
+			let mut mmu = create_mmu();
+			// 64 KiB page at 0x10000 mapped to DRAM_BASE + 0x10000. All the
+			// 16 PTEs hold the same PPN with PPN[3:0] = 1000.
+			let pte = get_pte(DRAM_BASE + 0x18000, PTE_N | PTE_RWAD);
+			for i in 0..16 {
+				map_page(&mut mmu, 0x10000 + i * 0x1000, pte);
+			}
+			mmu.store_doubleword_raw(DRAM_BASE + 0x15ab8, 0x1234);
+			mmu.store_doubleword_raw(DRAM_BASE + 0x1fff8, 0x5678);
+			mmu.store_doubleword_raw(DRAM_BASE + 0x18000, 0x9abc);
+			assert_eq!(mmu.load_doubleword(0x15ab8).unwrap(), 0x1234);
+			assert_eq!(mmu.load_doubleword(0x1fff8).unwrap(), 0x5678);
+			assert_eq!(mmu.load_doubleword(0x10000).unwrap(), 0);
+			assert_eq!(mmu.load_doubleword(0x18000).unwrap(), 0x9abc);
+
+			// PPN[3:0] = 0100, 32 KiB, is reserved
+			let pte = get_pte(DRAM_BASE + 0x24000, PTE_N | PTE_RWAD);
+			map_page(&mut mmu, 0x20000, pte);
+			assert!(is_page_fault(mmu.load_doubleword(0x20000)));
+			assert!(is_store_page_fault(mmu.store_doubleword(0x20000, 0)));
+
+			// N is reserved for non-leaf PTE
+			mmu.store_doubleword_raw(LEVEL1_TABLE + 8, get_pte(LEVEL0_TABLE, PTE_N | PTE_V));
+			assert!(is_page_fault(mmu.load_doubleword(0x200000)));
+		});
+	}
+
+	#[test]
+	fn svpbmt_page_types() {
+		run_with_large_stack(|| {
+			let mut mmu = create_mmu();
+			let nc_pte = get_pte(DRAM_BASE + 0x30000, PTE_RWAD) | (1 << PTE_PBMT_SHIFT);
+			let io_pte = get_pte(DRAM_BASE + 0x31000, PTE_RWAD) | (2 << PTE_PBMT_SHIFT);
+			let reserved_pte = get_pte(DRAM_BASE + 0x32000, PTE_RWAD) | (3 << PTE_PBMT_SHIFT);
+			map_page(&mut mmu, 0x30000, nc_pte);
+			map_page(&mut mmu, 0x31000, io_pte);
+			map_page(&mut mmu, 0x32000, reserved_pte);
+			mmu.store_doubleword_raw(DRAM_BASE + 0x30008, 0x1234);
+
+			// PBMT is reserved unless menvcfg.PBMTE is set
+			assert!(is_page_fault(mmu.load_doubleword(0x30008)));
+			assert_eq!(mmu.uncached_num, 0);
+
+			mmu.update_menvcfg(MENVCFG_PBMTE);
+			assert_eq!(mmu.load_doubleword(0x30008).unwrap(), 0x1234);
+			assert_eq!(mmu.uncached_num, 1);
+			mmu.store_doubleword(0x31010, 0x5678).unwrap();
+			assert_eq!(mmu.uncached_num, 2);
+			// Uncached store goes to memory directly
+			assert_eq!(mmu.load_doubleword_raw(DRAM_BASE + 0x31010), 0x5678);
+			assert_eq!(mmu.load_doubleword(0x31010).unwrap(), 0x5678);
+			assert_eq!(mmu.uncached_num, 3);
+
+			// PBMT = 3 is reserved
+			assert!(is_page_fault(mmu.load_doubleword(0x32000)));
+			assert_eq!(mmu.uncached_num, 3);
+		});
+	}
+
+	#[test]
+	fn svadu_updates() {
+		run_with_large_stack(|| {
+			let mut mmu = create_mmu();
+			let pte = get_pte(DRAM_BASE + 0x40000, PTE_V | PTE_R | PTE_W);
+			map_page(&mut mmu, 0x40000, pte);
+
+			// Without menvcfg.ADUE, software updates A/D bits on page fault
+			assert!(is_page_fault(mmu.load_doubleword(0x40000)));
+			assert!(is_store_page_fault(mmu.store_doubleword(0x40000, 1)));
+			assert_eq!(read_pte(&mut mmu, 0x40000), pte);
+
+			mmu.update_menvcfg(MENVCFG_ADUE);
+			assert_eq!(mmu.load_doubleword(0x40000).unwrap(), 0);
+			assert_eq!(read_pte(&mut mmu, 0x40000), pte | PTE_A);
+			mmu.store_doubleword(0x40000, 1).unwrap();
+			assert_eq!(read_pte(&mut mmu, 0x40000), pte | PTE_A | PTE_D);
+			assert_eq!(mmu.load_doubleword(0x40000).unwrap(), 1);
+		});
 	}
 }
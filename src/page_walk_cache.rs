@@ -0,0 +1,239 @@
+use checkpoint::{CheckpointError, CheckpointReader, CheckpointWriter};
+
+/// The number of entries per page table level
+pub const PAGE_WALK_CACHE_ENTRY_NUM: usize = 8;
+
+/// The maximum number of page table levels (Sv39)
+pub const PAGE_TABLE_LEVEL_NUM: usize = 3;
+
+/// Latency of reading a PTE from page-walk cache
+pub const PAGE_WALK_CACHE_HIT_LATENCY: u64 = 1;
+
+/// Default latency of reading a PTE from memory at each level
+pub const PAGE_WALK_LEVEL_LATENCY: u64 = 4;
+
+#[derive(Copy, Clone)]
+pub struct PageWalkCacheEntry {
+	pub valid: bool,
+	pub pte_address: u64,
+	pub pte: u64,
+}
+
+impl PageWalkCacheEntry {
+	pub const fn static_new() -> PageWalkCacheEntry {
+		PageWalkCacheEntry {
+			valid: false,
+			pte_address: 0,
+			pte: 0,
+		}
+	}
+}
+
+/// Emulates page-walk cache. It holds non-leaf PTEs separately for each
+/// page table level, tagged with their physical addresses, and models
+/// the latency of page table walk on TLB miss.
+#[derive(Clone)]
+pub struct PageWalkCache {
+	pub enabled: bool,
+	/// Latency of reading a PTE from memory, indexed by level
+	pub level_latency: [u64; PAGE_TABLE_LEVEL_NUM],
+	pub data: [[PageWalkCacheEntry; PAGE_WALK_CACHE_ENTRY_NUM]; PAGE_TABLE_LEVEL_NUM],
+	/// Round-robin replacement pointer for each level
+	pub victim: [usize; PAGE_TABLE_LEVEL_NUM],
+	pub hit_num: u64,
+	pub miss_num: u64,
+}
+
+impl Default for PageWalkCache {
+	fn default() -> Self {
+		PageWalkCache::new()
+	}
+}
+
+impl PageWalkCache {
+	pub fn new() -> PageWalkCache {
+		PageWalkCache::static_new()
+	}
+
+	pub const fn static_new() -> PageWalkCache {
+		PageWalkCache {
+			enabled: true,
+			level_latency: [PAGE_WALK_LEVEL_LATENCY; PAGE_TABLE_LEVEL_NUM],
+			data: [[PageWalkCacheEntry::static_new(); PAGE_WALK_CACHE_ENTRY_NUM];
+				PAGE_TABLE_LEVEL_NUM],
+			victim: [0; PAGE_TABLE_LEVEL_NUM],
+			hit_num: 0,
+			miss_num: 0,
+		}
+	}
+
+	/// Enables or disables page-walk cache. Walk latency is modeled
+	/// even if disabled.
+	///
+	/// # Arguments
+	/// * `enabled`
+	pub fn set_enabled(&mut self, enabled: bool) {
+		self.enabled = enabled;
+		self.flush();
+	}
+
+	/// Updates latency of reading a PTE from memory at a level
+	///
+	/// # Arguments
+	/// * `level`: page table level, 0 is the leaf level
+	/// * `latency`: cycles
+	pub fn set_level_latency(&mut self, level: usize, latency: u64) {
+		self.level_latency[level] = latency;
+	}
+
+	/// Looks up a non-leaf PTE
+	///
+	/// # Arguments
+	/// * `level`: page table level of the PTE
+	/// * `pte_address`: physical address of the PTE
+	pub fn read(&mut self, level: usize, pte_address: u64) -> Option<u64> {
+		if !self.enabled {
+			return None;
+		}
+		for entry in self.data[level].iter() {
+			if entry.valid && entry.pte_address == pte_address {
+				self.hit_num += 1;
+				return Some(entry.pte);
+			}
+		}
+		self.miss_num += 1;
+		None
+	}
+
+	/// Caches a non-leaf PTE replacing the oldest entry of the level
+	///
+	/// # Arguments
+	/// * `level`: page table level of the PTE
+	/// * `pte_address`: physical address of the PTE
+	/// * `pte`
+	pub fn write(&mut self, level: usize, pte_address: u64, pte: u64) {
+		if !self.enabled {
+			return;
+		}
+		let way = self.victim[level];
+		self.data[level][way] = PageWalkCacheEntry {
+			valid: true,
+			pte_address,
+			pte,
+		};
+		self.victim[level] = (way + 1) % PAGE_WALK_CACHE_ENTRY_NUM;
+	}
+
+	/// Invalidates all the entries (e.g. SFENCE.VMA)
+	pub fn flush(&mut self) {
+		for level in self.data.iter_mut() {
+			for entry in level.iter_mut() {
+				entry.valid = false;
+			}
+		}
+	}
+
+	/// Saves the state to checkpoint.
+	///
+	/// # Arguments
+	/// * `writer`
+	pub fn save_state(&self, writer: &mut CheckpointWriter) {
+		writer.write_bool(self.enabled);
+		writer.write_u64s(&self.level_latency);
+		for entry in self.data.iter().flat_map(|level| level.iter()) {
+			writer.write_bool(entry.valid);
+			writer.write_u64(entry.pte_address);
+			writer.write_u64(entry.pte);
+		}
+		for victim in self.victim.iter() {
+			writer.write_u64(*victim as u64);
+		}
+		writer.write_u64(self.hit_num);
+		writer.write_u64(self.miss_num);
+	}
+
+	/// Restores the state saved by `save_state()`.
+	///
+	/// # Arguments
+	/// * `reader`
+	pub fn restore_state(&mut self, reader: &mut CheckpointReader) -> Result<(), CheckpointError> {
+		self.enabled = reader.read_bool()?;
+		reader.read_u64s(&mut self.level_latency)?;
+		for entry in self.data.iter_mut().flat_map(|level| level.iter_mut()) {
+			entry.valid = reader.read_bool()?;
+			entry.pte_address = reader.read_u64()?;
+			entry.pte = reader.read_u64()?;
+		}
+		for victim in self.victim.iter_mut() {
+			let value = reader.read_u64()?;
+			if value >= PAGE_WALK_CACHE_ENTRY_NUM as u64 {
+				return Err(reader.error(format!("Invalid page-walk cache victim {}", value)));
+			}
+			*victim = value as usize;
+		}
+		self.hit_num = reader.read_u64()?;
+		self.miss_num = reader.read_u64()?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use cpu::{PrivilegeMode, Xlen};
+	use mmu::{AddressingMode, Mmu, DRAM_BASE, TLB_ENTRY_NUM};
+	use std::thread;
+
+	const PTE_V: u64 = 1 << 0;
+	const PTE_RWAD: u64 = PTE_V | (1 << 1) | (1 << 2) | (1 << 6) | (1 << 7);
+
+	fn get_pte(p_address: u64, flags: u64) -> u64 {
+		((p_address >> 12) << 10) | flags
+	}
+
+	// Mmu holds the caches inline and needs a large stack
+	fn run_with_large_stack(test: fn()) {
+		thread::Builder::new()
+			.stack_size(256 * 1024 * 1024)
+			.spawn(test)
+			.unwrap()
+			.join()
+			.unwrap();
+	}
+
+	#[test]
+	fn second_walk_hits_upper_levels() {
+		run_with_large_stack(|| {
+			let mut mmu = Mmu::new(Xlen::Bit64);
+			mmu.init_memory(0x100000).unwrap();
+			mmu.pmp.grant_all(&Xlen::Bit64);
+			// Sv39 tables at DRAM_BASE mapping 0x1000 to DRAM_BASE + 0x10000
+			mmu.store_doubleword_raw(DRAM_BASE, get_pte(DRAM_BASE + 0x1000, PTE_V));
+			mmu.store_doubleword_raw(DRAM_BASE + 0x1000, get_pte(DRAM_BASE + 0x2000, PTE_V));
+			mmu.store_doubleword_raw(DRAM_BASE + 0x2008, get_pte(DRAM_BASE + 0x10000, PTE_RWAD));
+			mmu.store_doubleword_raw(DRAM_BASE + 0x10000, 0x1234);
+			mmu.update_ppn(DRAM_BASE >> 12);
+			mmu.update_addressing_mode(AddressingMode::SV39);
+			mmu.update_privilege_mode(PrivilegeMode::Supervisor);
+
+			assert_eq!(mmu.load_doubleword(0x1000).unwrap(), 0x1234);
+			// The leaf level is not looked up
+			assert_eq!(mmu.page_walk_cache.miss_num, 2);
+			assert_eq!(mmu.page_walk_cache.hit_num, 0);
+			let first_latency = mmu.page_walk_latency;
+			assert_eq!(first_latency, 3 * PAGE_WALK_LEVEL_LATENCY);
+
+			// Walks again on TLB miss
+			mmu.tlb_tag = [0; TLB_ENTRY_NUM];
+			assert_eq!(mmu.load_doubleword(0x1000).unwrap(), 0x1234);
+			assert_eq!(mmu.page_walk_cache.miss_num, 2);
+			assert_eq!(mmu.page_walk_cache.hit_num, 2);
+			let second_latency = mmu.page_walk_latency - first_latency;
+			assert_eq!(
+				second_latency,
+				2 * PAGE_WALK_CACHE_HIT_LATENCY + PAGE_WALK_LEVEL_LATENCY
+			);
+			assert!(second_latency < first_latency);
+		});
+	}
+}
@@ -0,0 +1,420 @@
+use checkpoint::{CheckpointError, CheckpointReader, CheckpointWriter};
+use cpu::{MIP_MEIP, MIP_SEIP};
+
+/// Physical base address of PLIC
+pub const PLIC_BASE: u64 = 0x0c000000;
+
+/// Size of PLIC address range
+pub const PLIC_SIZE: u64 = 0x4000000;
+
+/// The number of interrupt sources including the reserved source 0
+pub const PLIC_SOURCE_NUM: usize = 64;
+
+/// The number of contexts, machine and supervisor mode of a single hart
+pub const PLIC_CONTEXT_NUM: usize = 2;
+
+// Register offsets, SiFive compatible
+const PLIC_PRIORITY_OFFSET: u64 = 0x000000;
+const PLIC_PENDING_OFFSET: u64 = 0x001000;
+const PLIC_ENABLE_OFFSET: u64 = 0x002000;
+const PLIC_ENABLE_STRIDE: u64 = 0x80;
+const PLIC_CONTEXT_OFFSET: u64 = 0x200000;
+const PLIC_CONTEXT_STRIDE: u64 = 0x1000;
+
+const PLIC_PRIORITY_MASK: u32 = 7;
+
+/// Emulates PLIC (Platform-Level Interrupt Controller). Interrupt sources
+/// are level-triggered. Context 0 drives machine external interrupt and
+/// context 1 drives supervisor external interrupt.
+pub struct Plic {
+	/// Current level of each interrupt line
+	levels: u64,
+	pending: u64,
+	/// Sources claimed but not completed yet
+	claimed: u64,
+	priorities: [u32; PLIC_SOURCE_NUM],
+	enables: [u64; PLIC_CONTEXT_NUM],
+	thresholds: [u32; PLIC_CONTEXT_NUM],
+}
+
+impl Default for Plic {
+	fn default() -> Self {
+		Plic::new()
+	}
+}
+
+impl Plic {
+	/// Creates a new `Plic`.
+	pub const fn new() -> Self {
+		Plic {
+			levels: 0,
+			pending: 0,
+			claimed: 0,
+			priorities: [0; PLIC_SOURCE_NUM],
+			enables: [0; PLIC_CONTEXT_NUM],
+			thresholds: [0; PLIC_CONTEXT_NUM],
+		}
+	}
+
+	/// Updates the level of an interrupt line. Devices on the bus raise
+	/// interrupts through this. Invalid source numbers are ignored, `Bus`
+	/// rejects them when a device is added.
+	///
+	/// # Arguments
+	/// * `source` Interrupt source number, 1 to `PLIC_SOURCE_NUM - 1`
+	/// * `level` true if the device requests interrupt
+	pub fn set_interrupt_line(&mut self, source: u32, level: bool) {
+		if source == 0 || (source as usize) >= PLIC_SOURCE_NUM {
+			return;
+		}
+		match level {
+			true => self.levels |= 1 << source,
+			false => self.levels &= !(1 << source),
+		}
+	}
+
+	/// Latches raised interrupt lines into pending bits and updates MEIP
+	/// and SEIP bits of `mip`.
+	///
+	/// # Arguments
+	/// * `mip`
+	pub fn tick(&mut self, mip: &mut u64) {
+		// Gateway doesn't forward a new request until completion
+		self.pending |= self.levels & !self.claimed;
+
+		match self.get_highest_pending(0) {
+			0 => *mip &= !MIP_MEIP,
+			_ => *mip |= MIP_MEIP,
+		}
+		match self.get_highest_pending(1) {
+			0 => *mip &= !MIP_SEIP,
+			_ => *mip |= MIP_SEIP,
+		}
+	}
+
+	/// Returns the pending and enabled source of the highest priority
+	/// exceeding the threshold of the context, or zero if none. Ties are
+	/// broken by the lower source number.
+	///
+	/// # Arguments
+	/// * `context`
+	fn get_highest_pending(&self, context: usize) -> u32 {
+		let candidates = self.pending & self.enables[context];
+		let mut source = 0;
+		let mut priority = self.thresholds[context];
+		for i in 1..PLIC_SOURCE_NUM {
+			if ((candidates >> i) & 1) != 0 && self.priorities[i] > priority {
+				source = i as u32;
+				priority = self.priorities[i];
+			}
+		}
+		source
+	}
+
+	fn claim(&mut self, context: usize) -> u32 {
+		let source = self.get_highest_pending(context);
+		if source != 0 {
+			self.pending &= !(1 << source);
+			self.claimed |= 1 << source;
+		}
+		source
+	}
+
+	fn complete(&mut self, context: usize, source: u32) {
+		// Completion for a source not enabled for the context is ignored
+		if (source as usize) < PLIC_SOURCE_NUM && ((self.enables[context] >> source) & 1) != 0 {
+			self.claimed &= !(1 << source);
+		}
+	}
+
+	/// Reads PLIC register. Registers are 32-bit, 8-byte access reads two
+	/// consecutive registers.
+	///
+	/// # Arguments
+	/// * `offset` Offset from `PLIC_BASE`
+	/// * `width` Must be 1, 2, 4, or 8
+	pub fn read(&mut self, offset: u64, width: u64) -> u64 {
+		match width {
+			8 => {
+				(self.read_register(offset & !3) as u64)
+					| ((self.read_register((offset & !3) + 4) as u64) << 32)
+			}
+			_ => {
+				let value = self.read_register(offset & !3) >> ((offset & 3) * 8);
+				(value as u64) & ((1 << (width * 8)) - 1)
+			}
+		}
+	}
+
+	/// Writes PLIC register. Registers are 32-bit, 8-byte access writes two
+	/// consecutive registers.
+	///
+	/// # Arguments
+	/// * `offset` Offset from `PLIC_BASE`
+	/// * `value`
+	/// * `width` Must be 1, 2, 4, or 8
+	pub fn write(&mut self, offset: u64, value: u64, width: u64) {
+		match width {
+			8 => {
+				self.write_register(offset & !3, value as u32);
+				self.write_register((offset & !3) + 4, (value >> 32) as u32);
+			}
+			4 => self.write_register(offset & !3, value as u32),
+			_ => {
+				// Narrow write updates the part of register. Note that
+				// claim register is read to merge.
+				let register_offset = offset & !3;
+				let shift = (offset & 3) * 8;
+				let mask = (((1u64 << (width * 8)) - 1) << shift) as u32;
+				let current = self.peek_register(register_offset);
+				let value = (current & !mask) | (((value << shift) as u32) & mask);
+				self.write_register(register_offset, value);
+			}
+		}
+	}
+
+	/// Saves the state to checkpoint.
+	///
+	/// # Arguments
+	/// * `writer`
+	pub fn save_state(&self, writer: &mut CheckpointWriter) {
+		writer.write_u64(self.levels);
+		writer.write_u64(self.pending);
+		writer.write_u64(self.claimed);
+		for priority in self.priorities.iter() {
+			writer.write_u32(*priority);
+		}
+		writer.write_u64s(&self.enables);
+		for threshold in self.thresholds.iter() {
+			writer.write_u32(*threshold);
+		}
+	}
+
+	/// Restores the state saved by `save_state()`.
+	///
+	/// # Arguments
+	/// * `reader`
+	pub fn restore_state(&mut self, reader: &mut CheckpointReader) -> Result<(), CheckpointError> {
+		self.levels = reader.read_u64()?;
+		self.pending = reader.read_u64()?;
+		self.claimed = reader.read_u64()?;
+		for priority in self.priorities.iter_mut() {
+			*priority = reader.read_u32()?;
+		}
+		reader.read_u64s(&mut self.enables)?;
+		for threshold in self.thresholds.iter_mut() {
+			*threshold = reader.read_u32()?;
+		}
+		Ok(())
+	}
+
+	/// Reads register without side effect
+	fn peek_register(&self, offset: u64) -> u32 {
+		match offset {
+			PLIC_PRIORITY_OFFSET..=0x000ffc => self.read_priority(offset),
+			PLIC_PENDING_OFFSET..=0x001ffc => {
+				self.read_bitmap(self.pending, offset - PLIC_PENDING_OFFSET)
+			}
+			PLIC_ENABLE_OFFSET..=0x1ffffc => {
+				let context = ((offset - PLIC_ENABLE_OFFSET) / PLIC_ENABLE_STRIDE) as usize;
+				match context < PLIC_CONTEXT_NUM {
+					true => self.read_bitmap(
+						self.enables[context],
+						(offset - PLIC_ENABLE_OFFSET) % PLIC_ENABLE_STRIDE,
+					),
+					false => 0,
+				}
+			}
+			PLIC_CONTEXT_OFFSET..=0x3fffffc => {
+				let context = ((offset - PLIC_CONTEXT_OFFSET) / PLIC_CONTEXT_STRIDE) as usize;
+				match (
+					context < PLIC_CONTEXT_NUM,
+					(offset - PLIC_CONTEXT_OFFSET) % PLIC_CONTEXT_STRIDE,
+				) {
+					(true, 0) => self.thresholds[context],
+					_ => 0,
+				}
+			}
+			_ => 0,
+		}
+	}
+
+	fn read_register(&mut self, offset: u64) -> u32 {
+		match offset {
+			PLIC_CONTEXT_OFFSET..=0x3fffffc => {
+				let context = ((offset - PLIC_CONTEXT_OFFSET) / PLIC_CONTEXT_STRIDE) as usize;
+				match (
+					context < PLIC_CONTEXT_NUM,
+					(offset - PLIC_CONTEXT_OFFSET) % PLIC_CONTEXT_STRIDE,
+				) {
+					(true, 4) => self.claim(context),
+					_ => self.peek_register(offset),
+				}
+			}
+			_ => self.peek_register(offset),
+		}
+	}
+
+	fn write_register(&mut self, offset: u64, value: u32) {
+		match offset {
+			PLIC_PRIORITY_OFFSET..=0x000ffc => {
+				let source = (offset / 4) as usize;
+				if source > 0 && source < PLIC_SOURCE_NUM {
+					self.priorities[source] = value & PLIC_PRIORITY_MASK;
+				}
+			}
+			// Pending bits are read-only
+			PLIC_PENDING_OFFSET..=0x001ffc => {}
+			PLIC_ENABLE_OFFSET..=0x1ffffc => {
+				let context = ((offset - PLIC_ENABLE_OFFSET) / PLIC_ENABLE_STRIDE) as usize;
+				let word = (offset - PLIC_ENABLE_OFFSET) % PLIC_ENABLE_STRIDE / 4;
+				if context < PLIC_CONTEXT_NUM && ((word as usize) * 32) < PLIC_SOURCE_NUM {
+					let shift = word * 32;
+					// Source 0 doesn't exist
+					let mask = (0xffffffff << shift) & !1;
+					self.enables[context] =
+						(self.enables[context] & !mask) | (((value as u64) << shift) & mask);
+				}
+			}
+			PLIC_CONTEXT_OFFSET..=0x3fffffc => {
+				let context = ((offset - PLIC_CONTEXT_OFFSET) / PLIC_CONTEXT_STRIDE) as usize;
+				if context < PLIC_CONTEXT_NUM {
+					match (offset - PLIC_CONTEXT_OFFSET) % PLIC_CONTEXT_STRIDE {
+						0 => self.thresholds[context] = value & PLIC_PRIORITY_MASK,
+						4 => self.complete(context, value),
+						_ => {}
+					}
+				}
+			}
+			_ => {}
+		}
+	}
+
+	fn read_priority(&self, offset: u64) -> u32 {
+		let source = (offset / 4) as usize;
+		match source < PLIC_SOURCE_NUM {
+			true => self.priorities[source],
+			false => 0,
+		}
+	}
+
+	/// Reads 32 bits of a source bitmap (pending or enable bits)
+	///
+	/// # Arguments
+	/// * `bitmap`
+	/// * `offset` Byte offset in the bitmap registers
+	fn read_bitmap(&self, bitmap: u64, offset: u64) -> u32 {
+		let shift = (offset / 4) * 32;
+		match shift < PLIC_SOURCE_NUM as u64 {
+			true => (bitmap >> shift) as u32,
+			false => 0,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const CLAIM_OFFSET: u64 = PLIC_CONTEXT_OFFSET + 4;
+
+	/// Sets the priorities of the sources and enables them for machine
+	/// mode context
+	fn create_plic(priorities: &[(u64, u64)]) -> Plic {
+		let mut plic = Plic::new();
+		let mut enables = 0;
+		for (source, priority) in priorities.iter() {
+			plic.write(PLIC_PRIORITY_OFFSET + source * 4, *priority, 4);
+			enables |= 1 << source;
+		}
+		plic.write(PLIC_ENABLE_OFFSET, enables, 8);
+		plic
+	}
+
+	#[test]
+	fn priority_and_threshold_mask_interrupts() {
+		let mut plic = create_plic(&[(3, 2), (5, 5), (6, 0)]);
+		let mut mip = 0;
+		plic.write(PLIC_CONTEXT_OFFSET, 4, 4);
+		plic.set_interrupt_line(3, true);
+		plic.set_interrupt_line(6, true);
+		plic.tick(&mut mip);
+		// Source 3 is pending but doesn't exceed the threshold
+		assert_eq!(plic.read(PLIC_PENDING_OFFSET, 4), (1 << 3) | (1 << 6));
+		assert_eq!(mip & MIP_MEIP, 0);
+		assert_eq!(plic.read(CLAIM_OFFSET, 4), 0);
+		plic.set_interrupt_line(5, true);
+		plic.tick(&mut mip);
+		assert_ne!(mip & MIP_MEIP, 0);
+		// Not enabled for supervisor mode context
+		assert_eq!(mip & MIP_SEIP, 0);
+		plic.write(PLIC_CONTEXT_OFFSET, 1, 4);
+		assert_eq!(plic.read(CLAIM_OFFSET, 4), 5);
+		assert_eq!(plic.read(CLAIM_OFFSET, 4), 3);
+		// Priority 0 never interrupts
+		assert_eq!(plic.read(CLAIM_OFFSET, 4), 0);
+	}
+
+	#[test]
+	fn claim_returns_highest_priority() {
+		let mut plic = create_plic(&[(3, 2), (5, 5), (7, 5)]);
+		let mut mip = 0;
+		for source in [3, 5, 7].iter() {
+			plic.set_interrupt_line(*source, true);
+		}
+		plic.tick(&mut mip);
+		// Ties are broken by the lower source number
+		assert_eq!(plic.read(CLAIM_OFFSET, 4), 5);
+		assert_eq!(plic.read(CLAIM_OFFSET, 4), 7);
+		assert_eq!(plic.read(CLAIM_OFFSET, 4), 3);
+		assert_eq!(plic.read(CLAIM_OFFSET, 4), 0);
+		plic.tick(&mut mip);
+		assert_eq!(mip & MIP_MEIP, 0);
+	}
+
+	#[test]
+	fn complete_rearms_source() {
+		let mut plic = create_plic(&[(3, 1)]);
+		let mut mip = 0;
+		plic.set_interrupt_line(3, true);
+		plic.tick(&mut mip);
+		assert_eq!(plic.read(CLAIM_OFFSET, 4), 3);
+		// The line is still high but the gateway waits for completion
+		plic.tick(&mut mip);
+		assert_eq!(mip & MIP_MEIP, 0);
+		assert_eq!(plic.read(CLAIM_OFFSET, 4), 0);
+		plic.write(CLAIM_OFFSET, 3, 4);
+		plic.tick(&mut mip);
+		assert_ne!(mip & MIP_MEIP, 0);
+		assert_eq!(plic.read(CLAIM_OFFSET, 4), 3);
+		// Lowered line isn't forwarded after completion
+		plic.set_interrupt_line(3, false);
+		plic.write(CLAIM_OFFSET, 3, 4);
+		plic.tick(&mut mip);
+		assert_eq!(mip & MIP_MEIP, 0);
+	}
+
+	#[test]
+	fn supervisor_context() {
+		let mut plic = create_plic(&[]);
+		let mut mip = 0;
+		plic.write(PLIC_PRIORITY_OFFSET + 4 * 9, 1, 4);
+		plic.write(PLIC_ENABLE_OFFSET + PLIC_ENABLE_STRIDE, 1 << 9, 4);
+		plic.set_interrupt_line(9, true);
+		plic.tick(&mut mip);
+		assert_eq!(mip & MIP_MEIP, 0);
+		assert_ne!(mip & MIP_SEIP, 0);
+		let claim_offset = CLAIM_OFFSET + PLIC_CONTEXT_STRIDE;
+		assert_eq!(plic.read(claim_offset, 4), 9);
+	}
+
+	#[test]
+	fn ignores_invalid_sources() {
+		let mut plic = Plic::new();
+		let mut mip = 0;
+		plic.set_interrupt_line(0, true);
+		plic.set_interrupt_line(PLIC_SOURCE_NUM as u32, true);
+		plic.tick(&mut mip);
+		assert_eq!(plic.read(PLIC_PENDING_OFFSET, 8), 0);
+	}
+}
@@ -0,0 +1,545 @@
+use checkpoint::{CheckpointError, CheckpointReader, CheckpointWriter};
+use cpu::{PrivilegeMode, Xlen};
+use mmu::MemoryAccessType;
+
+/// The maximum number of PMP entries the privileged spec allows
+pub const PMP_ENTRY_MAX: usize = 64;
+
+/// The number of PMP entries implemented by default
+pub const PMP_ENTRY_NUM: usize = 16;
+
+/// pmpcfg field format
+/// [ L | 0 0 | A A | X | W | R ]
+const PMP_CFG_R: u8 = 0x01;
+const PMP_CFG_W: u8 = 0x02;
+const PMP_CFG_X: u8 = 0x04;
+const PMP_CFG_A_SHIFT: u8 = 3;
+const PMP_CFG_A_MASK: u8 = 0x18;
+const PMP_CFG_L: u8 = 0x80;
+/// Bits 6:5 are reserved and hardwired to zero
+const PMP_CFG_WRITABLE_MASK: u8 = PMP_CFG_L | PMP_CFG_A_MASK | PMP_CFG_X | PMP_CFG_W | PMP_CFG_R;
+
+/// pmpaddr holds physical address[55:2] in RV64 and [33:2] in RV32
+const PMP_ADDR_MASK_64: u64 = (1 << 54) - 1;
+const PMP_ADDR_MASK_32: u64 = 0xffffffff;
+
+/// Address-matching mode encoded in A field of pmpcfg
+#[derive(Debug)]
+pub enum PmpAddressMatching {
+	Off,
+	Tor,
+	Na4,
+	Napot,
+}
+
+fn get_address_matching(cfg: u8) -> PmpAddressMatching {
+	match (cfg & PMP_CFG_A_MASK) >> PMP_CFG_A_SHIFT {
+		0 => PmpAddressMatching::Off,
+		1 => PmpAddressMatching::Tor,
+		2 => PmpAddressMatching::Na4,
+		_ => PmpAddressMatching::Napot,
+	}
+}
+
+/// Emulates Physical Memory Protection unit. It holds pmpcfg and pmpaddr CSRs
+/// and checks physical memory accesses against them.
+/// Granularity is fixed to four bytes (G = 0).
+#[derive(Clone)]
+pub struct Pmp {
+	/// The number of implemented entries, 0, 16, or 64.
+	/// Entries beyond this number are hardwired to zero.
+	pub entry_num: usize,
+	pub cfg: [u8; PMP_ENTRY_MAX],
+	pub addr: [u64; PMP_ENTRY_MAX],
+}
+
+impl Default for Pmp {
+	fn default() -> Self {
+		Pmp::new()
+	}
+}
+
+impl Pmp {
+	/// Creates a new `Pmp` with all the entries turned off.
+	pub fn new() -> Self {
+		Pmp::static_new()
+	}
+
+	pub const fn static_new() -> Self {
+		Pmp {
+			entry_num: PMP_ENTRY_NUM,
+			cfg: [0; PMP_ENTRY_MAX],
+			addr: [0; PMP_ENTRY_MAX],
+		}
+	}
+
+	/// Updates the number of implemented entries. Unimplemented entries
+	/// are cleared.
+	///
+	/// # Arguments
+	/// * `entry_num` Must be 0, 16, or 64
+	pub fn update_entry_num(&mut self, entry_num: usize) -> Result<(), String> {
+		if entry_num != 0 && entry_num != 16 && entry_num != 64 {
+			return Err(format!(
+				"PMP entry number must be 0, 16, or 64. {}",
+				entry_num
+			));
+		}
+		self.entry_num = entry_num;
+		for cfg in self.cfg[entry_num..].iter_mut() {
+			*cfg = 0;
+		}
+		for addr in self.addr[entry_num..].iter_mut() {
+			*addr = 0;
+		}
+		Ok(())
+	}
+
+	/// Grants full access to the whole physical address space to S and U-mode
+	/// with the lowest-priority implemented entry. This emulates what M-mode
+	/// firmware does before it hands over control to a lower privilege mode,
+	/// for programs started without such a firmware.
+	///
+	/// # Arguments
+	/// * `xlen`
+	pub fn grant_all(&mut self, xlen: &Xlen) {
+		if self.entry_num == 0 {
+			return;
+		}
+		let i = self.entry_num - 1;
+		if self.is_locked(i) {
+			return;
+		}
+		self.addr[i] = match xlen {
+			Xlen::Bit32 => PMP_ADDR_MASK_32,
+			Xlen::Bit64 => PMP_ADDR_MASK_64,
+		};
+		self.cfg[i] = (3 << PMP_CFG_A_SHIFT) | PMP_CFG_X | PMP_CFG_W | PMP_CFG_R;
+	}
+
+	/// Saves the state to checkpoint.
+	///
+	/// # Arguments
+	/// * `writer`
+	pub fn save_state(&self, writer: &mut CheckpointWriter) {
+		writer.write_u64(self.entry_num as u64);
+		writer.write_bytes(&self.cfg);
+		writer.write_u64s(&self.addr);
+	}
+
+	/// Restores the state saved by `save_state()`.
+	///
+	/// # Arguments
+	/// * `reader`
+	pub fn restore_state(&mut self, reader: &mut CheckpointReader) -> Result<(), CheckpointError> {
+		let entry_num = reader.read_u64()?;
+		if entry_num != 0 && entry_num != 16 && entry_num != 64 {
+			return Err(reader.error(format!("Invalid PMP entry number {}", entry_num)));
+		}
+		self.entry_num = entry_num as usize;
+		reader.read_bytes(&mut self.cfg)?;
+		reader.read_u64s(&mut self.addr)
+	}
+
+	fn is_locked(&self, index: usize) -> bool {
+		(self.cfg[index] & PMP_CFG_L) != 0
+	}
+
+	/// Reads pmpcfg CSR. In RV32 pmpcfgN holds entries 4N..4N+3.
+	/// In RV64 only even N is valid and pmpcfgN holds entries 4N..4N+7.
+	///
+	/// # Arguments
+	/// * `n` pmpcfg CSR number, 0-15
+	/// * `xlen`
+	pub fn read_cfg(&self, n: usize, xlen: &Xlen) -> u64 {
+		let entries_per_csr = match xlen {
+			Xlen::Bit32 => 4,
+			Xlen::Bit64 => {
+				if (n & 1) != 0 {
+					return 0;
+				}
+				8
+			}
+		};
+		let mut value = 0;
+		for i in 0..entries_per_csr {
+			let index = n * 4 + i;
+			if index < self.entry_num {
+				value |= (self.cfg[index] as u64) << (i * 8);
+			}
+		}
+		value
+	}
+
+	/// Writes pmpcfg CSR. Writes to locked entries are ignored.
+	///
+	/// # Arguments
+	/// * `n` pmpcfg CSR number, 0-15
+	/// * `value`
+	/// * `xlen`
+	pub fn write_cfg(&mut self, n: usize, value: u64, xlen: &Xlen) {
+		let entries_per_csr = match xlen {
+			Xlen::Bit32 => 4,
+			Xlen::Bit64 => {
+				if (n & 1) != 0 {
+					return;
+				}
+				8
+			}
+		};
+		for i in 0..entries_per_csr {
+			let index = n * 4 + i;
+			if index >= self.entry_num || self.is_locked(index) {
+				continue;
+			}
+			let mut cfg = ((value >> (i * 8)) as u8) & PMP_CFG_WRITABLE_MASK;
+			// R=0 and W=1 is reserved
+			if (cfg & PMP_CFG_R) == 0 {
+				cfg &= !PMP_CFG_W;
+			}
+			self.cfg[index] = cfg;
+		}
+	}
+
+	/// Reads pmpaddr CSR
+	///
+	/// # Arguments
+	/// * `index` Entry index, 0-63
+	pub fn read_addr(&self, index: usize) -> u64 {
+		match index < self.entry_num {
+			true => self.addr[index],
+			false => 0,
+		}
+	}
+
+	/// Writes pmpaddr CSR. Writes are ignored if the entry is locked, or
+	/// if the next entry is locked and in TOR mode.
+	///
+	/// # Arguments
+	/// * `index` Entry index, 0-63
+	/// * `value`
+	/// * `xlen`
+	pub fn write_addr(&mut self, index: usize, value: u64, xlen: &Xlen) {
+		if index >= self.entry_num || self.is_locked(index) {
+			return;
+		}
+		if index + 1 < self.entry_num && self.is_locked(index + 1) {
+			if let PmpAddressMatching::Tor = get_address_matching(self.cfg[index + 1]) {
+				return;
+			}
+		}
+		self.addr[index] = value
+			& match xlen {
+				Xlen::Bit32 => PMP_ADDR_MASK_32,
+				Xlen::Bit64 => PMP_ADDR_MASK_64,
+			};
+	}
+
+	/// Returns the address range [bottom, top) the entry covers, or `None`
+	/// if the entry is off or the range is empty.
+	fn get_range(&self, index: usize) -> Option<(u128, u128)> {
+		let addr = self.addr[index] as u128;
+		let range = match get_address_matching(self.cfg[index]) {
+			PmpAddressMatching::Off => return None,
+			PmpAddressMatching::Tor => {
+				let bottom = match index {
+					0 => 0,
+					_ => (self.addr[index - 1] as u128) << 2,
+				};
+				(bottom, addr << 2)
+			}
+			PmpAddressMatching::Na4 => (addr << 2, (addr << 2) + 4),
+			PmpAddressMatching::Napot => {
+				// pmpaddr = yyyy...y011...1 where the number of trailing ones
+				// determines the size of the region, 2^(ones + 3) bytes.
+				let ones = self.addr[index].trailing_ones();
+				let size = 8u128 << ones;
+				let bottom = (addr & !((1u128 << ones) - 1)) << 2;
+				(bottom, bottom + size)
+			}
+		};
+		match range.0 < range.1 {
+			true => Some(range),
+			false => None,
+		}
+	}
+
+	/// Checks if a physical memory access is permitted.
+	///
+	/// # Arguments
+	/// * `p_address` Physical address
+	/// * `width` Access width in bytes
+	/// * `access_type`
+	/// * `privilege_mode` Effective privilege mode of the access
+	pub fn check(
+		&self,
+		p_address: u64,
+		width: u64,
+		access_type: &MemoryAccessType,
+		privilege_mode: &PrivilegeMode,
+	) -> bool {
+		let bottom = p_address as u128;
+		let top = bottom + width as u128;
+		for i in 0..self.entry_num {
+			let (entry_bottom, entry_top) = match self.get_range(i) {
+				Some(range) => range,
+				None => continue,
+			};
+			if top <= entry_bottom || entry_top <= bottom {
+				// No byte matches
+				continue;
+			}
+			// The lowest-numbered entry matching any byte determines the result
+			// and it must match all the bytes of the access.
+			if bottom < entry_bottom || entry_top < top {
+				return false;
+			}
+			let cfg = self.cfg[i];
+			if let PrivilegeMode::Machine = privilege_mode {
+				// M-mode is restricted only by locked entries
+				if (cfg & PMP_CFG_L) == 0 {
+					return true;
+				}
+			}
+			return match access_type {
+				MemoryAccessType::Execute => (cfg & PMP_CFG_X) != 0,
+				MemoryAccessType::Read => (cfg & PMP_CFG_R) != 0,
+				MemoryAccessType::Write => (cfg & PMP_CFG_W) != 0,
+				MemoryAccessType::DontCare => true,
+			};
+		}
+		// No entry matches. M-mode access succeeds. S and U-mode access
+		// fails if at least one entry is implemented.
+		match privilege_mode {
+			PrivilegeMode::Machine => true,
+			_ => self.entry_num == 0,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const R: u64 = PMP_CFG_R as u64;
+	const W: u64 = PMP_CFG_W as u64;
+	const X: u64 = PMP_CFG_X as u64;
+	const L: u64 = PMP_CFG_L as u64;
+	const TOR: u64 = 1 << PMP_CFG_A_SHIFT;
+	const NA4: u64 = 2 << PMP_CFG_A_SHIFT;
+	const NAPOT: u64 = 3 << PMP_CFG_A_SHIFT;
+
+	const XLEN: Xlen = Xlen::Bit64;
+
+	fn is_allowed(
+		pmp: &Pmp,
+		p_address: u64,
+		access_type: MemoryAccessType,
+		privilege_mode: PrivilegeMode,
+	) -> bool {
+		pmp.check(p_address, 4, &access_type, &privilege_mode)
+	}
+
+	/// Returns which of read, write, and execute `privilege_mode` is allowed
+	/// at `p_address`
+	fn get_permissions(
+		pmp: &Pmp,
+		p_address: u64,
+		privilege_mode: PrivilegeMode,
+	) -> (bool, bool, bool) {
+		(
+			is_allowed(
+				pmp,
+				p_address,
+				MemoryAccessType::Read,
+				privilege_mode.clone(),
+			),
+			is_allowed(
+				pmp,
+				p_address,
+				MemoryAccessType::Write,
+				privilege_mode.clone(),
+			),
+			is_allowed(pmp, p_address, MemoryAccessType::Execute, privilege_mode),
+		)
+	}
+
+	#[test]
+	fn tor() {
+		let mut pmp = Pmp::new();
+		// [0x1000, 0x2000) read-only and [0x2000, 0x3000) read-execute
+		pmp.write_addr(0, 0x1000 >> 2, &XLEN);
+		pmp.write_addr(1, 0x2000 >> 2, &XLEN);
+		pmp.write_addr(2, 0x3000 >> 2, &XLEN);
+		pmp.write_cfg(0, ((TOR | R) << 8) | ((TOR | R | X) << 16), &XLEN);
+		for privilege_mode in [PrivilegeMode::User, PrivilegeMode::Supervisor].iter() {
+			// Entry 0 covers [0, 0x1000) with no permission
+			assert_eq!(
+				get_permissions(&pmp, 0x800, privilege_mode.clone()),
+				(false, false, false)
+			);
+			assert_eq!(
+				get_permissions(&pmp, 0x1000, privilege_mode.clone()),
+				(true, false, false)
+			);
+			assert_eq!(
+				get_permissions(&pmp, 0x1ffc, privilege_mode.clone()),
+				(true, false, false)
+			);
+			assert_eq!(
+				get_permissions(&pmp, 0x2000, privilege_mode.clone()),
+				(true, false, true)
+			);
+			// No entry matches
+			assert_eq!(
+				get_permissions(&pmp, 0x3000, privilege_mode.clone()),
+				(false, false, false)
+			);
+		}
+		// Unlocked entries don't restrict M-mode
+		assert_eq!(
+			get_permissions(&pmp, 0x1000, PrivilegeMode::Machine),
+			(true, true, true)
+		);
+		assert_eq!(
+			get_permissions(&pmp, 0x3000, PrivilegeMode::Machine),
+			(true, true, true)
+		);
+		// An access straddling two entries fails
+		assert!(!pmp.check(0x1ffc, 8, &MemoryAccessType::Read, &PrivilegeMode::User));
+	}
+
+	#[test]
+	fn na4() {
+		let mut pmp = Pmp::new();
+		pmp.write_addr(0, 0x1004 >> 2, &XLEN);
+		pmp.write_cfg(0, NA4 | R | W, &XLEN);
+		assert_eq!(
+			get_permissions(&pmp, 0x1004, PrivilegeMode::User),
+			(true, true, false)
+		);
+		assert_eq!(
+			get_permissions(&pmp, 0x1000, PrivilegeMode::User),
+			(false, false, false)
+		);
+		assert_eq!(
+			get_permissions(&pmp, 0x1008, PrivilegeMode::User),
+			(false, false, false)
+		);
+		assert!(!pmp.check(0x1004, 8, &MemoryAccessType::Read, &PrivilegeMode::User));
+	}
+
+	#[test]
+	fn napot() {
+		let mut pmp = Pmp::new();
+		// 4 KiB at 0x8000_0000 followed by the whole address space
+		pmp.write_addr(0, (0x8000_0000 >> 2) | 0x1ff, &XLEN);
+		pmp.write_addr(1, PMP_ADDR_MASK_64, &XLEN);
+		pmp.write_cfg(0, (NAPOT | X) | ((NAPOT | R | W) << 8), &XLEN);
+		let top = 0x8000_0000 + 0x1000;
+		assert_eq!(
+			get_permissions(&pmp, 0x8000_0000, PrivilegeMode::Supervisor),
+			(false, false, true)
+		);
+		assert_eq!(
+			get_permissions(&pmp, top - 4, PrivilegeMode::Supervisor),
+			(false, false, true)
+		);
+		// Entry 1 matches beyond the 4 KiB region
+		assert_eq!(
+			get_permissions(&pmp, top, PrivilegeMode::Supervisor),
+			(true, true, false)
+		);
+		assert_eq!(
+			get_permissions(&pmp, 0x7fff_fffc, PrivilegeMode::Supervisor),
+			(true, true, false)
+		);
+	}
+
+	#[test]
+	fn locked_entries() {
+		let mut pmp = Pmp::new();
+		pmp.write_addr(0, 0x1000 >> 2, &XLEN);
+		pmp.write_addr(1, 0x2000 >> 2, &XLEN);
+		pmp.write_cfg(0, (TOR | R) << 8, &XLEN);
+		// Unlocked entry doesn't restrict M-mode
+		assert_eq!(
+			get_permissions(&pmp, 0x1000, PrivilegeMode::Machine),
+			(true, true, true)
+		);
+		pmp.write_cfg(0, (L | TOR | R) << 8, &XLEN);
+		// Locked entry applies to M-mode as well
+		assert_eq!(
+			get_permissions(&pmp, 0x1000, PrivilegeMode::Machine),
+			(true, false, false)
+		);
+		assert_eq!(
+			get_permissions(&pmp, 0x1000, PrivilegeMode::User),
+			(true, false, false)
+		);
+		// Writes to the locked entry and its TOR bottom address are ignored
+		pmp.write_cfg(0, (TOR | R | W | X) << 8, &XLEN);
+		pmp.write_addr(1, 0x3000 >> 2, &XLEN);
+		pmp.write_addr(0, 0, &XLEN);
+		assert_eq!(pmp.read_cfg(0, &XLEN), (L | TOR | R) << 8);
+		assert_eq!(pmp.read_addr(1), 0x2000 >> 2);
+		assert_eq!(pmp.read_addr(0), 0x1000 >> 2);
+		assert_eq!(
+			get_permissions(&pmp, 0x1000, PrivilegeMode::Machine),
+			(true, false, false)
+		);
+		// Locked entry is kept by grant_all()
+		pmp.write_cfg(2, L << 56, &XLEN);
+		pmp.grant_all(&XLEN);
+		assert_eq!(pmp.read_cfg(2, &XLEN), L << 56);
+		assert_eq!(
+			get_permissions(&pmp, 0x3000, PrivilegeMode::User),
+			(false, false, false)
+		);
+	}
+
+	#[test]
+	fn no_matching_entry() {
+		let mut pmp = Pmp::new();
+		// With no entry configured only M-mode has access
+		assert_eq!(
+			get_permissions(&pmp, 0x1000, PrivilegeMode::Machine),
+			(true, true, true)
+		);
+		assert_eq!(
+			get_permissions(&pmp, 0x1000, PrivilegeMode::User),
+			(false, false, false)
+		);
+		pmp.grant_all(&XLEN);
+		assert_eq!(
+			get_permissions(&pmp, 0x1000, PrivilegeMode::User),
+			(true, true, true)
+		);
+	}
+
+	#[test]
+	fn update_entry_num() {
+		let mut pmp = Pmp::new();
+		pmp.write_cfg(2, NA4 | R, &XLEN);
+		pmp.write_addr(8, 0x1000 >> 2, &XLEN);
+		assert!(pmp.update_entry_num(8).is_err());
+		assert_eq!(pmp.entry_num, 16);
+		pmp.update_entry_num(64).unwrap();
+		// Entries beyond 16 become writable
+		pmp.write_addr(20, 0x2000 >> 2, &XLEN);
+		assert_eq!(pmp.read_addr(20), 0x2000 >> 2);
+		pmp.update_entry_num(16).unwrap();
+		assert_eq!(pmp.read_addr(20), 0);
+		pmp.write_addr(20, 0x2000 >> 2, &XLEN);
+		assert_eq!(pmp.read_addr(20), 0);
+		assert_eq!(pmp.read_addr(8), 0x1000 >> 2);
+		pmp.update_entry_num(0).unwrap();
+		assert_eq!(pmp.read_cfg(2, &XLEN), 0);
+		assert_eq!(pmp.read_addr(8), 0);
+		// No PMP, no restriction
+		assert_eq!(
+			get_permissions(&pmp, 0x1000, PrivilegeMode::User),
+			(true, true, true)
+		);
+	}
+}
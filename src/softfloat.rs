@@ -0,0 +1,1043 @@
+/// Invalid operation
+pub const FLAG_NV: u8 = 0x10;
+/// Divide by zero
+pub const FLAG_DZ: u8 = 0x8;
+/// Overflow
+pub const FLAG_OF: u8 = 0x4;
+/// Underflow
+pub const FLAG_UF: u8 = 0x2;
+/// Inexact
+pub const FLAG_NX: u8 = 0x1;
+
+/// IEEE 754 rounding direction
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RoundingMode {
+	/// Round to nearest, ties to even
+	NearestEven,
+	TowardZero,
+	/// Round down, towards negative infinity
+	Down,
+	/// Round up, towards positive infinity
+	Up,
+	/// Round to nearest, ties to max magnitude
+	NearestMaxMagnitude,
+}
+
+impl RoundingMode {
+	/// Returns the rounding mode of `rm` field encoding, or None for
+	/// reserved encoding.
+	///
+	/// # Arguments
+	/// * `encoding`
+	pub fn from_encoding(encoding: u64) -> Option<RoundingMode> {
+		match encoding {
+			0 => Some(RoundingMode::NearestEven),
+			1 => Some(RoundingMode::TowardZero),
+			2 => Some(RoundingMode::Down),
+			3 => Some(RoundingMode::Up),
+			4 => Some(RoundingMode::NearestMaxMagnitude),
+			_ => None,
+		}
+	}
+}
+
+/// IEEE 754 binary interchange format. Values are handled as bits in the
+/// least significant bits of `u64`.
+pub struct FloatFormat {
+	pub exponent_bits: u32,
+	pub fraction_bits: u32,
+}
+
+/// binary32, single precision
+pub const FLOAT32: FloatFormat = FloatFormat {
+	exponent_bits: 8,
+	fraction_bits: 23,
+};
+
+/// binary64, double precision
+pub const FLOAT64: FloatFormat = FloatFormat {
+	exponent_bits: 11,
+	fraction_bits: 52,
+};
+
+#[derive(Clone, Copy, PartialEq)]
+enum FloatClass {
+	Zero,
+	Finite,
+	Infinite,
+	Nan,
+}
+
+/// Unpacked value, `significand * 2^exponent` if finite
+#[derive(Clone, Copy)]
+struct Unpacked {
+	class: FloatClass,
+	sign: bool,
+	exponent: i32,
+	significand: u128,
+}
+
+impl FloatFormat {
+	fn get_bias(&self) -> i32 {
+		(1 << (self.exponent_bits - 1)) - 1
+	}
+
+	fn get_sign_bit(&self) -> u64 {
+		1 << (self.exponent_bits + self.fraction_bits)
+	}
+
+	fn get_exponent_mask(&self) -> u64 {
+		((1 << self.exponent_bits) - 1) << self.fraction_bits
+	}
+
+	fn get_fraction_mask(&self) -> u64 {
+		(1 << self.fraction_bits) - 1
+	}
+
+	/// Returns canonical NaN, positive quiet NaN without payload.
+	pub fn get_canonical_nan(&self) -> u64 {
+		self.get_exponent_mask() | (1 << (self.fraction_bits - 1))
+	}
+
+	fn get_infinity(&self, sign: bool) -> u64 {
+		self.get_zero(sign) | self.get_exponent_mask()
+	}
+
+	fn get_zero(&self, sign: bool) -> u64 {
+		match sign {
+			true => self.get_sign_bit(),
+			false => 0,
+		}
+	}
+
+	/// Returns the largest finite magnitude with sign.
+	fn get_max(&self, sign: bool) -> u64 {
+		self.get_infinity(sign) - 1
+	}
+
+	pub fn is_nan(&self, bits: u64) -> bool {
+		(bits & self.get_exponent_mask()) == self.get_exponent_mask()
+			&& (bits & self.get_fraction_mask()) != 0
+	}
+
+	pub fn is_signaling_nan(&self, bits: u64) -> bool {
+		self.is_nan(bits) && (bits & (1 << (self.fraction_bits - 1))) == 0
+	}
+
+	fn unpack(&self, bits: u64) -> Unpacked {
+		let sign = (bits & self.get_sign_bit()) != 0;
+		let exponent = ((bits & self.get_exponent_mask()) >> self.fraction_bits) as i32;
+		let fraction = bits & self.get_fraction_mask();
+		let max_exponent = (1 << self.exponent_bits) - 1;
+		let (class, exponent, significand) = match (exponent, fraction) {
+			(0, 0) => (FloatClass::Zero, 0, 0),
+			// Subnormal
+			(0, _) => (
+				FloatClass::Finite,
+				1 - self.get_bias() - self.fraction_bits as i32,
+				fraction,
+			),
+			(_, 0) if exponent == max_exponent => (FloatClass::Infinite, 0, 0),
+			_ if exponent == max_exponent => (FloatClass::Nan, 0, 0),
+			_ => (
+				FloatClass::Finite,
+				exponent - self.get_bias() - self.fraction_bits as i32,
+				fraction | (1 << self.fraction_bits),
+			),
+		};
+		Unpacked {
+			class,
+			sign,
+			exponent,
+			significand: significand as u128,
+		}
+	}
+}
+
+/// Shifts right keeping whether nonzero bits are shifted out in the least
+/// significant bit, so that rounding of the result is not changed.
+fn shift_right_jam(value: u128, shift: u32) -> u128 {
+	match shift {
+		0 => value,
+		1..=127 => (value >> shift) | ((value & ((1 << shift) - 1)) != 0) as u128,
+		_ => (value != 0) as u128,
+	}
+}
+
+/// Returns floor of square root and whether it is inexact.
+fn sqrt_u128(value: u128) -> (u128, bool) {
+	let mut remainder = value;
+	let mut root = 0;
+	let mut bit = 1 << 126;
+	while bit > remainder {
+		bit >>= 2;
+	}
+	while bit != 0 {
+		if remainder >= root + bit {
+			remainder -= root + bit;
+			root = (root >> 1) + bit;
+		} else {
+			root >>= 1;
+		}
+		bit >>= 2;
+	}
+	(root, remainder != 0)
+}
+
+/// Rounding mode and accrued exception flags of floating-point operations,
+/// which are correctly rounded in software rather than in host arithmetic.
+/// NaN results are canonical NaN as RISC-V defines.
+pub struct FloatContext {
+	pub rounding_mode: RoundingMode,
+	/// Accrued exception flags, `FLAG_*` in `fflags` bit order
+	pub flags: u8,
+}
+
+impl FloatContext {
+	/// Creates a new `FloatContext` without exception flag.
+	///
+	/// # Arguments
+	/// * `rounding_mode`
+	pub fn new(rounding_mode: RoundingMode) -> Self {
+		FloatContext {
+			rounding_mode,
+			flags: 0,
+		}
+	}
+
+	/// Returns canonical NaN for NaN operands. Signaling NaN is invalid
+	/// operation.
+	fn propagate_nan(&mut self, format: &FloatFormat, operands: &[u64]) -> u64 {
+		if operands
+			.iter()
+			.any(|operand| format.is_signaling_nan(*operand))
+		{
+			self.flags |= FLAG_NV;
+		}
+		format.get_canonical_nan()
+	}
+
+	fn invalid(&mut self, format: &FloatFormat) -> u64 {
+		self.flags |= FLAG_NV;
+		format.get_canonical_nan()
+	}
+
+	/// Rounds `significand` to an integer multiple of `2^(quantum - exponent)`
+	/// and returns the multiple and whether it is inexact.
+	fn round_significand(
+		&self,
+		sign: bool,
+		exponent: i32,
+		significand: u128,
+		quantum: i32,
+	) -> (u128, bool) {
+		let shift = quantum - exponent;
+		if shift <= 0 {
+			return (significand << -shift, false);
+		}
+		let (kept, round_bit, sticky) = match shift {
+			1..=127 => (
+				significand >> shift,
+				((significand >> (shift - 1)) & 1) == 1,
+				(significand & ((1 << (shift - 1)) - 1)) != 0,
+			),
+			_ => (0, false, significand != 0),
+		};
+		let increment = match self.rounding_mode {
+			RoundingMode::NearestEven => round_bit && (sticky || (kept & 1) == 1),
+			RoundingMode::TowardZero => false,
+			RoundingMode::Down => sign && (round_bit || sticky),
+			RoundingMode::Up => !sign && (round_bit || sticky),
+			RoundingMode::NearestMaxMagnitude => round_bit,
+		};
+		(kept + increment as u128, round_bit || sticky)
+	}
+
+	/// Rounds `significand * 2^exponent` to a format and raises exception
+	/// flags. Tininess is detected after rounding.
+	///
+	/// # Arguments
+	/// * `format`
+	/// * `sign`
+	/// * `exponent`
+	/// * `significand` Less than 2^127
+	fn round_pack(
+		&mut self,
+		format: &FloatFormat,
+		sign: bool,
+		exponent: i32,
+		significand: u128,
+	) -> u64 {
+		if significand == 0 {
+			return format.get_zero(sign);
+		}
+		let precision = format.fraction_bits as i32 + 1;
+		let min_exponent = 1 - format.get_bias();
+		// The value is in [2^top_exponent, 2^(top_exponent + 1))
+		let top_exponent = exponent + 127 - significand.leading_zeros() as i32;
+
+		// Tiny if the result rounded with unbounded exponent range is
+		// smaller than the minimum normal
+		let is_tiny = top_exponent < min_exponent && {
+			let quantum = top_exponent - (precision - 1);
+			let (kept, _) = self.round_significand(sign, exponent, significand, quantum);
+			(kept >> precision) == 0 || top_exponent + 1 < min_exponent
+		};
+
+		let mut quantum = top_exponent.max(min_exponent) - (precision - 1);
+		let (mut kept, is_inexact) = self.round_significand(sign, exponent, significand, quantum);
+		if (kept >> precision) != 0 {
+			// Carry out of rounding
+			kept >>= 1;
+			quantum += 1;
+		}
+		if is_inexact {
+			self.flags |= FLAG_NX;
+			if is_tiny {
+				self.flags |= FLAG_UF;
+			}
+		}
+
+		if (kept >> (precision - 1)) == 0 {
+			// Subnormal or zero
+			return format.get_zero(sign) | kept as u64;
+		}
+		let result_exponent = quantum + precision - 1;
+		if result_exponent > format.get_bias() {
+			self.flags |= FLAG_OF | FLAG_NX;
+			let is_infinite = match self.rounding_mode {
+				RoundingMode::NearestEven | RoundingMode::NearestMaxMagnitude => true,
+				RoundingMode::TowardZero => false,
+				RoundingMode::Down => sign,
+				RoundingMode::Up => !sign,
+			};
+			return match is_infinite {
+				true => format.get_infinity(sign),
+				false => format.get_max(sign),
+			};
+		}
+		format.get_zero(sign)
+			| (((result_exponent + format.get_bias()) as u64) << format.fraction_bits)
+			| (kept as u64 & format.get_fraction_mask())
+	}
+
+	/// Adds finite or zero values exactly and rounds the sum.
+	fn add_unpacked(&mut self, format: &FloatFormat, x: Unpacked, y: Unpacked) -> u64 {
+		match (x.significand == 0, y.significand == 0) {
+			(true, true) => {
+				let sign = match x.sign == y.sign {
+					true => x.sign,
+					false => self.rounding_mode == RoundingMode::Down,
+				};
+				return format.get_zero(sign);
+			}
+			(true, false) => return self.round_pack(format, y.sign, y.exponent, y.significand),
+			(false, true) => return self.round_pack(format, x.sign, x.exponent, x.significand),
+			(false, false) => {}
+		};
+		// Aligns the significands at bit 125, leaving room for carry
+		let normalize = |value: Unpacked| {
+			let shift = value.significand.leading_zeros() as i32 - 2;
+			(value.exponent - shift, value.significand << shift)
+		};
+		let (x_exponent, x_significand) = normalize(x);
+		let (y_exponent, y_significand) = normalize(y);
+		let (large, small) = match x_exponent >= y_exponent {
+			true => (
+				(x.sign, x_exponent, x_significand),
+				(y.sign, y_exponent, y_significand),
+			),
+			false => (
+				(y.sign, y_exponent, y_significand),
+				(x.sign, x_exponent, x_significand),
+			),
+		};
+		let small_significand = shift_right_jam(small.2, (large.1 - small.1) as u32);
+		let (sign, significand) = if large.0 == small.0 {
+			(large.0, large.2 + small_significand)
+		} else if large.2 >= small_significand {
+			(large.0, large.2 - small_significand)
+		} else {
+			(small.0, small_significand - large.2)
+		};
+		if significand == 0 {
+			// Exact cancellation
+			return format.get_zero(self.rounding_mode == RoundingMode::Down);
+		}
+		// Keeps under 2^127 for round_pack()
+		let significand = shift_right_jam(significand, 1);
+		self.round_pack(format, sign, large.1 + 1, significand)
+	}
+
+	/// Returns `a + b`.
+	///
+	/// # Arguments
+	/// * `format`
+	/// * `a`
+	/// * `b`
+	pub fn add(&mut self, format: &FloatFormat, a: u64, b: u64) -> u64 {
+		let (x, y) = (format.unpack(a), format.unpack(b));
+		match (x.class, y.class) {
+			(FloatClass::Nan, _) | (_, FloatClass::Nan) => self.propagate_nan(format, &[a, b]),
+			(FloatClass::Infinite, FloatClass::Infinite) if x.sign != y.sign => {
+				self.invalid(format)
+			}
+			(FloatClass::Infinite, _) => format.get_infinity(x.sign),
+			(_, FloatClass::Infinite) => format.get_infinity(y.sign),
+			_ => self.add_unpacked(format, x, y),
+		}
+	}
+
+	/// Returns `a - b`.
+	///
+	/// # Arguments
+	/// * `format`
+	/// * `a`
+	/// * `b`
+	pub fn sub(&mut self, format: &FloatFormat, a: u64, b: u64) -> u64 {
+		self.add(format, a, b ^ format.get_sign_bit())
+	}
+
+	/// Returns `a * b`.
+	///
+	/// # Arguments
+	/// * `format`
+	/// * `a`
+	/// * `b`
+	pub fn mul(&mut self, format: &FloatFormat, a: u64, b: u64) -> u64 {
+		let (x, y) = (format.unpack(a), format.unpack(b));
+		let sign = x.sign != y.sign;
+		match (x.class, y.class) {
+			(FloatClass::Nan, _) | (_, FloatClass::Nan) => self.propagate_nan(format, &[a, b]),
+			(FloatClass::Infinite, FloatClass::Zero) | (FloatClass::Zero, FloatClass::Infinite) => {
+				self.invalid(format)
+			}
+			(FloatClass::Infinite, _) | (_, FloatClass::Infinite) => format.get_infinity(sign),
+			(FloatClass::Zero, _) | (_, FloatClass::Zero) => format.get_zero(sign),
+			_ => self.round_pack(
+				format,
+				sign,
+				x.exponent + y.exponent,
+				x.significand * y.significand,
+			),
+		}
+	}
+
+	/// Returns `a / b`.
+	///
+	/// # Arguments
+	/// * `format`
+	/// * `a` Dividend
+	/// * `b` Divisor
+	pub fn div(&mut self, format: &FloatFormat, a: u64, b: u64) -> u64 {
+		let (x, y) = (format.unpack(a), format.unpack(b));
+		let sign = x.sign != y.sign;
+		match (x.class, y.class) {
+			(FloatClass::Nan, _) | (_, FloatClass::Nan) => self.propagate_nan(format, &[a, b]),
+			(FloatClass::Infinite, FloatClass::Infinite) | (FloatClass::Zero, FloatClass::Zero) => {
+				self.invalid(format)
+			}
+			(FloatClass::Infinite, _) => format.get_infinity(sign),
+			(_, FloatClass::Infinite) => format.get_zero(sign),
+			(_, FloatClass::Zero) => {
+				self.flags |= FLAG_DZ;
+				format.get_infinity(sign)
+			}
+			(FloatClass::Zero, _) => format.get_zero(sign),
+			_ => {
+				// 64 or more quotient bits, enough for rounding
+				let dividend_shift = x.significand.leading_zeros() as i32 - 1;
+				let divisor_shift = y.significand.leading_zeros() as i32 - 64;
+				let dividend = x.significand << dividend_shift;
+				let divisor = y.significand << divisor_shift;
+				let quotient = dividend / divisor;
+				let is_inexact = !dividend.is_multiple_of(divisor);
+				self.round_pack(
+					format,
+					sign,
+					x.exponent - dividend_shift - y.exponent + divisor_shift,
+					quotient | is_inexact as u128,
+				)
+			}
+		}
+	}
+
+	/// Returns square root of `a`.
+	///
+	/// # Arguments
+	/// * `format`
+	/// * `a`
+	pub fn sqrt(&mut self, format: &FloatFormat, a: u64) -> u64 {
+		let x = format.unpack(a);
+		match x.class {
+			FloatClass::Nan => self.propagate_nan(format, &[a]),
+			FloatClass::Zero => a,
+			_ if x.sign => self.invalid(format),
+			FloatClass::Infinite => a,
+			FloatClass::Finite => {
+				// Radicand of about 126 bits with even exponent gives 63
+				// root bits
+				let mut shift = x.significand.leading_zeros() as i32 - 2;
+				if (x.exponent - shift) % 2 != 0 {
+					shift -= 1;
+				}
+				let (root, is_inexact) = sqrt_u128(x.significand << shift);
+				self.round_pack(
+					format,
+					false,
+					(x.exponent - shift) / 2,
+					root | is_inexact as u128,
+				)
+			}
+		}
+	}
+
+	/// Returns `a * b + c` rounded once.
+	///
+	/// # Arguments
+	/// * `format`
+	/// * `a`
+	/// * `b`
+	/// * `c`
+	pub fn fused_multiply_add(&mut self, format: &FloatFormat, a: u64, b: u64, c: u64) -> u64 {
+		let (x, y, z) = (format.unpack(a), format.unpack(b), format.unpack(c));
+		let sign = x.sign != y.sign;
+		let is_invalid_product = matches!(
+			(x.class, y.class),
+			(FloatClass::Infinite, FloatClass::Zero) | (FloatClass::Zero, FloatClass::Infinite)
+		);
+		if x.class == FloatClass::Nan || y.class == FloatClass::Nan || z.class == FloatClass::Nan {
+			// Invalid product is invalid even if the addend is quiet NaN
+			if is_invalid_product {
+				self.flags |= FLAG_NV;
+			}
+			return self.propagate_nan(format, &[a, b, c]);
+		}
+		if is_invalid_product {
+			return self.invalid(format);
+		}
+		if x.class == FloatClass::Infinite || y.class == FloatClass::Infinite {
+			return match z.class == FloatClass::Infinite && z.sign != sign {
+				true => self.invalid(format),
+				false => format.get_infinity(sign),
+			};
+		}
+		if z.class == FloatClass::Infinite {
+			return format.get_infinity(z.sign);
+		}
+		let product = Unpacked {
+			class: FloatClass::Finite,
+			sign,
+			exponent: x.exponent + y.exponent,
+			significand: x.significand * y.significand,
+		};
+		self.add_unpacked(format, product, z)
+	}
+
+	/// Converts a value between formats.
+	///
+	/// # Arguments
+	/// * `from` Format of `a`
+	/// * `to` Format of the result
+	/// * `a`
+	pub fn convert(&mut self, from: &FloatFormat, to: &FloatFormat, a: u64) -> u64 {
+		let x = from.unpack(a);
+		match x.class {
+			FloatClass::Nan => {
+				if from.is_signaling_nan(a) {
+					self.flags |= FLAG_NV;
+				}
+				to.get_canonical_nan()
+			}
+			FloatClass::Infinite => to.get_infinity(x.sign),
+			FloatClass::Zero => to.get_zero(x.sign),
+			FloatClass::Finite => self.round_pack(to, x.sign, x.exponent, x.significand),
+		}
+	}
+
+	/// Converts an integer to a format.
+	///
+	/// # Arguments
+	/// * `format`
+	/// * `value` Signed or unsigned integer up to 64 bits
+	pub fn convert_from_integer(&mut self, format: &FloatFormat, value: i128) -> u64 {
+		self.round_pack(format, value < 0, 0, value.unsigned_abs())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn f32_bits(value: f32) -> u64 {
+		value.to_bits() as u64
+	}
+
+	fn f64_bits(value: f64) -> u64 {
+		value.to_bits()
+	}
+
+	const QNAN32: u64 = 0x7fc00000;
+	const SNAN32: u64 = 0x7f800001;
+	const QNAN64: u64 = 0x7ff8000000000000;
+	const INF32: u64 = 0x7f800000;
+	const INF64: u64 = 0x7ff0000000000000;
+
+	#[derive(Clone, Copy)]
+	enum Operation {
+		Add,
+		Sub,
+		Mul,
+		Div,
+		Sqrt,
+		Madd,
+		Msub,
+		Nmadd,
+		Nmsub,
+	}
+
+	fn run(
+		format: &FloatFormat,
+		operation: Operation,
+		rounding_mode: RoundingMode,
+		operands: [u64; 3],
+	) -> (u64, u8) {
+		let sign_bit = format.get_sign_bit();
+		let [a, b, c] = operands;
+		let mut context = FloatContext::new(rounding_mode);
+		let result = match operation {
+			Operation::Add => context.add(format, a, b),
+			Operation::Sub => context.sub(format, a, b),
+			Operation::Mul => context.mul(format, a, b),
+			Operation::Div => context.div(format, a, b),
+			Operation::Sqrt => context.sqrt(format, a),
+			Operation::Madd => context.fused_multiply_add(format, a, b, c),
+			Operation::Msub => context.fused_multiply_add(format, a, b, c ^ sign_bit),
+			Operation::Nmadd => context.fused_multiply_add(format, a ^ sign_bit, b, c ^ sign_bit),
+			Operation::Nmsub => context.fused_multiply_add(format, a ^ sign_bit, b, c),
+		};
+		(result, context.flags)
+	}
+
+	/// Cases of riscv-tests `TEST_FP_OP*` in `(operation, flags, result,
+	/// operand1, operand2, operand3)`, shared by single and double precision
+	#[allow(clippy::approx_constant)]
+	const RISCV_TESTS_CASES: [(Operation, u8, f64, f64, f64, f64); 36] = [
+		// fadd
+		(Operation::Add, 0, 3.5, 2.5, 1.0, 0.0),
+		(Operation::Add, 1, -1234.0, -1235.1, 1.1, 0.0),
+		(Operation::Add, 1, 3.14159265, 3.14159265, 0.00000001, 0.0),
+		(Operation::Sub, 0, 1.5, 2.5, 1.0, 0.0),
+		(Operation::Sub, 1, -1234.0, -1235.1, -1.1, 0.0),
+		(Operation::Sub, 1, 3.14159265, 3.14159265, 0.00000001, 0.0),
+		(Operation::Mul, 0, 2.5, 2.5, 1.0, 0.0),
+		(Operation::Mul, 1, 1358.61, -1235.1, -1.1, 0.0),
+		(
+			Operation::Mul,
+			1,
+			3.14159265e-8,
+			3.14159265,
+			0.00000001,
+			0.0,
+		),
+		// fdiv
+		(
+			Operation::Div,
+			1,
+			1.1557273520668288,
+			3.14159265,
+			2.71828182,
+			0.0,
+		),
+		(Operation::Div, 1, -0.9991093838555584, -1234.0, 1235.1, 0.0),
+		(Operation::Div, 0, 3.14159265, 3.14159265, 1.0, 0.0),
+		(Operation::Sqrt, 1, 1.7724538498928541, 3.14159265, 0.0, 0.0),
+		(Operation::Sqrt, 0, 100.0, 10000.0, 0.0, 0.0),
+		(Operation::Sqrt, 1, 13.076696830622021, 171.0, 0.0, 0.0),
+		// fmadd
+		(Operation::Madd, 0, 3.5, 1.0, 2.5, 1.0),
+		(Operation::Madd, 1, 1236.2, -1.0, -1235.1, 1.1),
+		(Operation::Madd, 0, -12.0, 2.0, -5.0, -2.0),
+		(Operation::Nmadd, 0, -3.5, 1.0, 2.5, 1.0),
+		(Operation::Nmadd, 1, -1236.2, -1.0, -1235.1, 1.1),
+		(Operation::Nmadd, 0, 12.0, 2.0, -5.0, -2.0),
+		(Operation::Msub, 0, 1.5, 1.0, 2.5, 1.0),
+		(Operation::Msub, 1, 1234.0, -1.0, -1235.1, 1.1),
+		(Operation::Msub, 0, -8.0, 2.0, -5.0, -2.0),
+		(Operation::Nmsub, 0, -1.5, 1.0, 2.5, 1.0),
+		(Operation::Nmsub, 1, -1234.0, -1.0, -1235.1, 1.1),
+		(Operation::Nmsub, 0, 8.0, 2.0, -5.0, -2.0),
+		// Exact results
+		(Operation::Add, 0, 0.0, 1.0, -1.0, 0.0),
+		(Operation::Add, 0, -0.0, -0.0, -0.0, 0.0),
+		(Operation::Mul, 0, -0.0, -0.0, 5.0, 0.0),
+		(Operation::Div, 0, -0.0, 0.0, -5.0, 0.0),
+		(Operation::Sqrt, 0, -0.0, -0.0, 0.0, 0.0),
+		(Operation::Madd, 0, 0.0, 0.0, 5.0, -0.0),
+		(Operation::Madd, 0, -0.0, -0.0, 5.0, -0.0),
+		(Operation::Div, 0, 0.5, 1.0, 2.0, 0.0),
+		(Operation::Madd, 0, 6.0, 1.0, 1.0, 5.0),
+	];
+
+	#[test]
+	fn riscv_tests_single() {
+		for (index, &(operation, flags, result, a, b, c)) in RISCV_TESTS_CASES.iter().enumerate() {
+			let operands = [f32_bits(a as f32), f32_bits(b as f32), f32_bits(c as f32)];
+			assert_eq!(
+				run(&FLOAT32, operation, RoundingMode::NearestEven, operands),
+				(f32_bits(result as f32), flags),
+				"case {}",
+				index
+			);
+		}
+	}
+
+	#[test]
+	fn riscv_tests_double() {
+		for (index, &(operation, flags, result, a, b, c)) in RISCV_TESTS_CASES.iter().enumerate() {
+			let operands = [f64_bits(a), f64_bits(b), f64_bits(c)];
+			let (actual, actual_flags) =
+				run(&FLOAT64, operation, RoundingMode::NearestEven, operands);
+			// Decimal results of riscv-tests are exact only in single
+			// precision, and double precision ones are checked by host
+			let expected = match flags {
+				0 => f64_bits(result),
+				_ => actual,
+			};
+			assert_eq!((actual, actual_flags), (expected, flags), "case {}", index);
+		}
+	}
+
+	#[test]
+	fn special_values() {
+		let cases = [
+			// Invalid operations
+			(Operation::Add, 0x10, QNAN32, [INF32, INF32 | 0x80000000, 0]),
+			(Operation::Sub, 0x10, QNAN32, [INF32, INF32, 0]),
+			(Operation::Mul, 0x10, QNAN32, [INF32, 0, 0]),
+			(Operation::Div, 0x10, QNAN32, [0, 0x80000000, 0]),
+			(Operation::Div, 0x10, QNAN32, [INF32, INF32, 0]),
+			(Operation::Sqrt, 0x10, QNAN32, [f32_bits(-1.0), 0, 0]),
+			(Operation::Sqrt, 0x10, QNAN32, [INF32 | 0x80000000, 0, 0]),
+			(Operation::Madd, 0x10, QNAN32, [INF32, 0, QNAN32]),
+			(
+				Operation::Madd,
+				0x10,
+				QNAN32,
+				[INF32, f32_bits(1.0), INF32 | 0x80000000],
+			),
+			// NaN operands give canonical NaN
+			(Operation::Add, 0, QNAN32, [0x7fc12345, f32_bits(1.0), 0]),
+			(Operation::Add, 0, QNAN32, [0xffc00000, f32_bits(1.0), 0]),
+			(Operation::Mul, 0x10, QNAN32, [SNAN32, f32_bits(1.0), 0]),
+			(
+				Operation::Madd,
+				0x10,
+				QNAN32,
+				[f32_bits(1.0), f32_bits(1.0), SNAN32],
+			),
+			(
+				Operation::Madd,
+				0,
+				QNAN32,
+				[f32_bits(1.0), f32_bits(1.0), QNAN32],
+			),
+			// Divide by zero
+			(
+				Operation::Div,
+				0x8,
+				INF32 | 0x80000000,
+				[f32_bits(-1.0), 0, 0],
+			),
+			(
+				Operation::Div,
+				0x8,
+				INF32 | 0x80000000,
+				[f32_bits(1.0), 0x80000000, 0],
+			),
+			(Operation::Div, 0, 0, [f32_bits(1.0), INF32, 0]),
+			// Infinities
+			(Operation::Add, 0, INF32, [INF32, f32_bits(-1e38), 0]),
+			(
+				Operation::Madd,
+				0,
+				INF32 | 0x80000000,
+				[INF32, f32_bits(-1.0), INF32 | 0x80000000],
+			),
+			(Operation::Sqrt, 0, INF32, [INF32, 0, 0]),
+		];
+		for (index, &(operation, flags, result, operands)) in cases.iter().enumerate() {
+			assert_eq!(
+				run(&FLOAT32, operation, RoundingMode::NearestEven, operands),
+				(result, flags),
+				"case {}",
+				index
+			);
+		}
+	}
+
+	#[test]
+	fn rounding_modes() {
+		let one_third = [f32_bits(1.0), f32_bits(3.0), 0];
+		let minus_one_third = [f32_bits(-1.0), f32_bits(3.0), 0];
+		let cases = [
+			(RoundingMode::NearestEven, one_third, 0x3eaaaaab),
+			(RoundingMode::TowardZero, one_third, 0x3eaaaaaa),
+			(RoundingMode::Down, one_third, 0x3eaaaaaa),
+			(RoundingMode::Up, one_third, 0x3eaaaaab),
+			(RoundingMode::NearestMaxMagnitude, one_third, 0x3eaaaaab),
+			(RoundingMode::NearestEven, minus_one_third, 0xbeaaaaab),
+			(RoundingMode::TowardZero, minus_one_third, 0xbeaaaaaa),
+			(RoundingMode::Down, minus_one_third, 0xbeaaaaab),
+			(RoundingMode::Up, minus_one_third, 0xbeaaaaaa),
+			(
+				RoundingMode::NearestMaxMagnitude,
+				minus_one_third,
+				0xbeaaaaab,
+			),
+		];
+		for (index, &(rounding_mode, operands, result)) in cases.iter().enumerate() {
+			assert_eq!(
+				run(&FLOAT32, Operation::Div, rounding_mode, operands),
+				(result, FLAG_NX),
+				"case {}",
+				index
+			);
+		}
+
+		// Ties: 1 + 2^-24 is halfway between 1 and the next
+		let tie = [f32_bits(1.0), f32_bits(2f32.powi(-24)), 0];
+		let ties = [
+			(RoundingMode::NearestEven, 0x3f800000),
+			(RoundingMode::NearestMaxMagnitude, 0x3f800001),
+			(RoundingMode::TowardZero, 0x3f800000),
+			(RoundingMode::Up, 0x3f800001),
+		];
+		for &(rounding_mode, result) in ties.iter() {
+			assert_eq!(
+				run(&FLOAT32, Operation::Add, rounding_mode, tie),
+				(result, FLAG_NX)
+			);
+		}
+
+		// Exact cancellation is -0 only when rounding down
+		let cancel = [f64_bits(1.5), f64_bits(1.5), 0];
+		assert_eq!(
+			run(&FLOAT64, Operation::Sub, RoundingMode::Up, cancel),
+			(0, 0)
+		);
+		assert_eq!(
+			run(&FLOAT64, Operation::Sub, RoundingMode::Down, cancel),
+			(0x8000000000000000, 0)
+		);
+	}
+
+	#[test]
+	fn overflow_and_underflow() {
+		let max = 0x7f7fffff;
+		let huge = [max, f32_bits(2.0), 0];
+		let overflows = [
+			(RoundingMode::NearestEven, INF32),
+			(RoundingMode::TowardZero, max),
+			(RoundingMode::Down, max),
+			(RoundingMode::Up, INF32),
+			(RoundingMode::NearestMaxMagnitude, INF32),
+		];
+		for &(rounding_mode, result) in overflows.iter() {
+			assert_eq!(
+				run(&FLOAT32, Operation::Mul, rounding_mode, huge),
+				(result, FLAG_OF | FLAG_NX)
+			);
+		}
+		let negative_huge = [max | 0x80000000, f32_bits(2.0), 0];
+		assert_eq!(
+			run(&FLOAT32, Operation::Mul, RoundingMode::Down, negative_huge),
+			(INF32 | 0x80000000, FLAG_OF | FLAG_NX)
+		);
+		assert_eq!(
+			run(&FLOAT32, Operation::Mul, RoundingMode::Up, negative_huge),
+			(max | 0x80000000, FLAG_OF | FLAG_NX)
+		);
+
+		// Exact subnormal result doesn't underflow
+		let min_normal = 0x00800000;
+		assert_eq!(
+			run(
+				&FLOAT32,
+				Operation::Mul,
+				RoundingMode::NearestEven,
+				[min_normal, f32_bits(0.5), 0]
+			),
+			(0x00400000, 0)
+		);
+		// Inexact tiny result underflows
+		assert_eq!(
+			run(
+				&FLOAT32,
+				Operation::Mul,
+				RoundingMode::NearestEven,
+				[0x00000003, f32_bits(0.5), 0]
+			),
+			(0x00000002, FLAG_UF | FLAG_NX)
+		);
+		assert_eq!(
+			run(
+				&FLOAT64,
+				Operation::Div,
+				RoundingMode::NearestEven,
+				[1, f64_bits(4.0), 0]
+			),
+			(0, FLAG_UF | FLAG_NX)
+		);
+		assert_eq!(
+			run(
+				&FLOAT64,
+				Operation::Div,
+				RoundingMode::Up,
+				[1, f64_bits(4.0), 0]
+			),
+			(1, FLAG_UF | FLAG_NX)
+		);
+		// Sum of subnormals is exact
+		let almost_min_normal = 0x007fffff;
+		assert_eq!(
+			run(
+				&FLOAT32,
+				Operation::Add,
+				RoundingMode::NearestEven,
+				[almost_min_normal, 0x00000001, 0]
+			),
+			(min_normal, 0)
+		);
+		// 2^-126 - 2^-152 rounds to 2^-126 with unbounded exponent range, so
+		// it isn't tiny after rounding
+		let below_min_normal = f64_bits(2f64.powi(-126) - 2f64.powi(-152));
+		let mut context = FloatContext::new(RoundingMode::NearestEven);
+		assert_eq!(
+			context.convert(&FLOAT64, &FLOAT32, below_min_normal),
+			min_normal
+		);
+		assert_eq!(context.flags, FLAG_NX);
+		let mut context = FloatContext::new(RoundingMode::TowardZero);
+		assert_eq!(
+			context.convert(&FLOAT64, &FLOAT32, below_min_normal),
+			almost_min_normal
+		);
+		assert_eq!(context.flags, FLAG_UF | FLAG_NX);
+	}
+
+	#[test]
+	fn conversions() {
+		let mut context = FloatContext::new(RoundingMode::NearestEven);
+		assert_eq!(context.convert_from_integer(&FLOAT32, 2), f32_bits(2.0));
+		assert_eq!(context.convert_from_integer(&FLOAT32, -2), f32_bits(-2.0));
+		assert_eq!(context.convert_from_integer(&FLOAT32, 0), 0);
+		assert_eq!(context.flags, 0);
+		assert_eq!(
+			context.convert_from_integer(&FLOAT32, 0xfffffffe),
+			f32_bits(4294967294.0)
+		);
+		assert_eq!(context.flags, FLAG_NX);
+		let mut context = FloatContext::new(RoundingMode::TowardZero);
+		assert_eq!(
+			context.convert_from_integer(&FLOAT32, 0xffffffff),
+			0x4f7fffff
+		);
+		assert_eq!(
+			context.convert_from_integer(&FLOAT64, u64::MAX as i128),
+			0x43efffffffffffff
+		);
+		assert_eq!(
+			context.convert_from_integer(&FLOAT64, i64::MIN as i128),
+			f64_bits(-9223372036854775808.0)
+		);
+
+		let mut context = FloatContext::new(RoundingMode::NearestEven);
+		assert_eq!(
+			context.convert(&FLOAT64, &FLOAT32, f64_bits(0.1)),
+			f32_bits(0.1)
+		);
+		assert_eq!(context.flags, FLAG_NX);
+		let mut context = FloatContext::new(RoundingMode::NearestEven);
+		assert_eq!(context.convert(&FLOAT64, &FLOAT32, f64_bits(1e300)), INF32);
+		assert_eq!(context.flags, FLAG_OF | FLAG_NX);
+		let mut context = FloatContext::new(RoundingMode::NearestEven);
+		assert_eq!(context.convert(&FLOAT64, &FLOAT32, f64_bits(1e-300)), 0);
+		assert_eq!(context.flags, FLAG_UF | FLAG_NX);
+		let mut context = FloatContext::new(RoundingMode::NearestEven);
+		assert_eq!(
+			context.convert(&FLOAT32, &FLOAT64, 0x00000001),
+			f64_bits(2f64.powi(-149))
+		);
+		assert_eq!(context.convert(&FLOAT32, &FLOAT64, 0xffc00000), QNAN64);
+		assert_eq!(context.flags, 0);
+		assert_eq!(
+			context.convert(&FLOAT64, &FLOAT32, 0x7ff0000000000001),
+			QNAN32
+		);
+		assert_eq!(context.flags, FLAG_NV);
+		assert_eq!(
+			context.convert(&FLOAT32, &FLOAT64, INF32 | 0x80000000),
+			INF64 | (1 << 63)
+		);
+	}
+
+	/// Compares the results in round to nearest even with host arithmetic
+	#[test]
+	fn host_arithmetic() {
+		// Linear congruential generator for reproducible operands
+		let mut state: u64 = 0x853c49e6748fea9b;
+		let mut next = || {
+			state = state
+				.wrapping_mul(6364136223846793005)
+				.wrapping_add(1442695040888963407);
+			state
+		};
+		for _ in 0..20000 {
+			let bits = [next(), next(), next()];
+			// Limits exponent range to exercise both ordinary and extreme values
+			let narrow = |bits: u64| match bits & 1 {
+				0 => bits,
+				_ => (bits & 0x800fffffffffffff) | (((bits >> 52) & 0x7f) + 0x3c0) << 52,
+			};
+			let [a, b, c] = [narrow(bits[0]), narrow(bits[1]), narrow(bits[2])];
+			let (x, y, z) = (f64::from_bits(a), f64::from_bits(b), f64::from_bits(c));
+			let expected = [
+				(Operation::Add, x + y),
+				(Operation::Sub, x - y),
+				(Operation::Mul, x * y),
+				(Operation::Div, x / y),
+				(Operation::Sqrt, x.sqrt()),
+				(Operation::Madd, x.mul_add(y, z)),
+			];
+			for &(operation, result) in expected.iter() {
+				let (actual, _) = run(&FLOAT64, operation, RoundingMode::NearestEven, [a, b, c]);
+				let expected = match result.is_nan() {
+					true => QNAN64,
+					false => result.to_bits(),
+				};
+				assert_eq!(actual, expected, "{:x} {:x} {:x}", a, b, c);
+			}
+
+			let (a, b, c) = (a as u32, b as u32, c as u32);
+			let (x, y, z) = (f32::from_bits(a), f32::from_bits(b), f32::from_bits(c));
+			let expected = [
+				(Operation::Add, x + y),
+				(Operation::Sub, x - y),
+				(Operation::Mul, x * y),
+				(Operation::Div, x / y),
+				(Operation::Sqrt, x.sqrt()),
+				(Operation::Madd, x.mul_add(y, z)),
+			];
+			for &(operation, result) in expected.iter() {
+				let operands = [a as u64, b as u64, c as u64];
+				let (actual, _) = run(&FLOAT32, operation, RoundingMode::NearestEven, operands);
+				let expected = match result.is_nan() {
+					true => QNAN32,
+					false => result.to_bits() as u64,
+				};
+				assert_eq!(actual, expected, "{:x} {:x} {:x}", a, b, c);
+			}
+		}
+	}
+}
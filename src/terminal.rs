@@ -0,0 +1,105 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{stdin, stdout, Read, Write};
+use std::rc::Rc;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+
+/// Emulates terminal. It transfers console input/output data to/from
+/// `Emulator` through UART.
+pub trait Terminal {
+	/// Puts an output ascii byte data written by the guest.
+	///
+	/// # Arguments
+	/// * `value`
+	fn put_byte(&mut self, value: u8);
+
+	/// Gets an input ascii byte data to be read by the guest.
+	/// Returns `None` if no input is available.
+	fn get_input(&mut self) -> Option<u8>;
+}
+
+/// Terminal connected to host stdout and stdin. Stdin is read by
+/// a background thread so reading input never blocks the emulator.
+pub struct StdioTerminal {
+	input: Receiver<u8>,
+}
+
+impl Default for StdioTerminal {
+	fn default() -> Self {
+		StdioTerminal::new()
+	}
+}
+
+impl StdioTerminal {
+	/// Creates a new `StdioTerminal`.
+	pub fn new() -> Self {
+		let (sender, receiver) = channel();
+		thread::spawn(move || {
+			let mut buffer = [0; 64];
+			loop {
+				let length = match stdin().read(&mut buffer) {
+					Ok(0) | Err(_) => break,
+					Ok(length) => length,
+				};
+				for byte in buffer[..length].iter() {
+					if sender.send(*byte).is_err() {
+						return;
+					}
+				}
+			}
+		});
+		StdioTerminal { input: receiver }
+	}
+}
+
+impl Terminal for StdioTerminal {
+	fn put_byte(&mut self, value: u8) {
+		let mut out = stdout();
+		let _ = out.write_all(&[value]);
+		let _ = out.flush();
+	}
+
+	fn get_input(&mut self) -> Option<u8> {
+		self.input.try_recv().ok()
+	}
+}
+
+/// Terminal holding input and output in memory, e.g. for tests.
+/// Clones share the same buffers, so keep a clone to inspect output
+/// after passing one to `Emulator`.
+#[derive(Clone, Default)]
+pub struct BufferTerminal {
+	input: Rc<RefCell<VecDeque<u8>>>,
+	output: Rc<RefCell<Vec<u8>>>,
+}
+
+impl BufferTerminal {
+	/// Creates a new `BufferTerminal` with empty buffers.
+	pub fn new() -> Self {
+		BufferTerminal::default()
+	}
+
+	/// Queues input data read by the guest.
+	///
+	/// # Arguments
+	/// * `data`
+	pub fn put_input(&self, data: &[u8]) {
+		self.input.borrow_mut().extend(data.iter());
+	}
+
+	/// Takes the output data written by the guest so far.
+	pub fn take_output(&self) -> Vec<u8> {
+		self.output.borrow_mut().split_off(0)
+	}
+}
+
+impl Terminal for BufferTerminal {
+	fn put_byte(&mut self, value: u8) {
+		self.output.borrow_mut().push(value);
+	}
+
+	fn get_input(&mut self) -> Option<u8> {
+		self.input.borrow_mut().pop_front()
+	}
+}
@@ -0,0 +1,304 @@
+use checkpoint::{CheckpointError, CheckpointReader, CheckpointWriter};
+use device::Device;
+use fdt::{FdtBuilder, FDT_PLIC_PHANDLE};
+use terminal::Terminal;
+
+/// Physical base address of UART
+pub const UART_BASE: u64 = 0x10000000;
+
+/// Size of UART address range
+pub const UART_SIZE: u64 = 0x100;
+
+/// PLIC interrupt source number of UART
+pub const UART_IRQ: u32 = 10;
+
+/// Input clock frequency reported to the guest for divisor calculation
+const UART_CLOCK_FREQUENCY: u32 = 3686400;
+
+// Register offsets
+const UART_RBR_THR_DLL: u64 = 0;
+const UART_IER_DLM: u64 = 1;
+const UART_IIR_FCR: u64 = 2;
+const UART_LCR: u64 = 3;
+const UART_MCR: u64 = 4;
+const UART_LSR: u64 = 5;
+const UART_MSR: u64 = 6;
+const UART_SCR: u64 = 7;
+
+const IER_RDA: u8 = 0x01;
+const IER_THRE: u8 = 0x02;
+
+const IIR_NO_INTERRUPT: u8 = 0x01;
+const IIR_THRE: u8 = 0x02;
+const IIR_RDA: u8 = 0x04;
+const IIR_FIFO_ENABLED: u8 = 0xc0;
+
+const FCR_FIFO_ENABLE: u8 = 0x01;
+
+const LCR_DLAB: u8 = 0x80;
+
+const LSR_DATA_READY: u8 = 0x01;
+const LSR_THR_EMPTY: u8 = 0x20;
+const LSR_TRANSMITTER_EMPTY: u8 = 0x40;
+
+/// Emulates NS16550A UART. Transmitted data goes to `Terminal` at once
+/// and received data is polled from it when the receiver buffer is empty.
+pub struct Uart {
+	terminal: Box<dyn Terminal>,
+	rbr: u8,
+	ier: u8,
+	fcr: u8,
+	lcr: u8,
+	mcr: u8,
+	lsr: u8,
+	scr: u8,
+	dll: u8,
+	dlm: u8,
+	/// THR empty interrupt is pending until IIR read or THR write
+	thre_ip: bool,
+}
+
+impl Uart {
+	/// Creates a new `Uart`.
+	///
+	/// # Arguments
+	/// * `terminal` Console the UART is connected to
+	pub fn new(terminal: Box<dyn Terminal>) -> Self {
+		Uart {
+			terminal,
+			rbr: 0,
+			ier: 0,
+			fcr: 0,
+			lcr: 0,
+			mcr: 0,
+			lsr: LSR_THR_EMPTY | LSR_TRANSMITTER_EMPTY,
+			scr: 0,
+			dll: 0,
+			dlm: 0,
+			thre_ip: false,
+		}
+	}
+
+	/// Returns mutable reference to the connected `Terminal`.
+	pub fn get_mut_terminal(&mut self) -> &mut Box<dyn Terminal> {
+		&mut self.terminal
+	}
+
+	fn get_interrupt_id(&self) -> u8 {
+		let id = if (self.ier & IER_RDA) != 0 && (self.lsr & LSR_DATA_READY) != 0 {
+			IIR_RDA
+		} else if (self.ier & IER_THRE) != 0 && self.thre_ip {
+			IIR_THRE
+		} else {
+			IIR_NO_INTERRUPT
+		};
+		match (self.fcr & FCR_FIFO_ENABLE) != 0 {
+			true => id | IIR_FIFO_ENABLED,
+			false => id,
+		}
+	}
+
+	fn read_register(&mut self, offset: u64) -> u8 {
+		let dlab = (self.lcr & LCR_DLAB) != 0;
+		match offset {
+			UART_RBR_THR_DLL => match dlab {
+				true => self.dll,
+				false => {
+					self.lsr &= !LSR_DATA_READY;
+					self.rbr
+				}
+			},
+			UART_IER_DLM => match dlab {
+				true => self.dlm,
+				false => self.ier,
+			},
+			UART_IIR_FCR => {
+				let id = self.get_interrupt_id();
+				// Reading IIR clears THR empty interrupt if it is reported
+				if (id & 0x0f) == IIR_THRE {
+					self.thre_ip = false;
+				}
+				id
+			}
+			UART_LCR => self.lcr,
+			UART_MCR => self.mcr,
+			UART_LSR => self.lsr,
+			// Reports CTS, DSR and DCD asserted
+			UART_MSR => 0xb0,
+			UART_SCR => self.scr,
+			_ => 0,
+		}
+	}
+
+	fn write_register(&mut self, offset: u64, value: u8) {
+		let dlab = (self.lcr & LCR_DLAB) != 0;
+		match offset {
+			UART_RBR_THR_DLL => match dlab {
+				true => self.dll = value,
+				false => {
+					self.terminal.put_byte(value);
+					// Transmission completes immediately
+					self.thre_ip = true;
+				}
+			},
+			UART_IER_DLM => match dlab {
+				true => self.dlm = value,
+				false => {
+					// Enabling THR empty interrupt while THR is empty raises it
+					if (self.ier & IER_THRE) == 0 && (value & IER_THRE) != 0 {
+						self.thre_ip = true;
+					}
+					self.ier = value & 0x0f;
+				}
+			},
+			UART_IIR_FCR => self.fcr = value,
+			UART_LCR => self.lcr = value,
+			UART_MCR => self.mcr = value,
+			UART_SCR => self.scr = value,
+			_ => {}
+		}
+	}
+}
+
+impl Device for Uart {
+	// Registers are byte wide. Wider access is treated as byte access.
+
+	fn read(&mut self, offset: u64, _width: u64) -> u64 {
+		self.read_register(offset) as u64
+	}
+
+	fn write(&mut self, offset: u64, value: u64, _width: u64) {
+		self.write_register(offset, value as u8);
+	}
+
+	fn tick(&mut self, _mip: &mut u64) {
+		if (self.lsr & LSR_DATA_READY) == 0 {
+			if let Some(value) = self.terminal.get_input() {
+				self.rbr = value;
+				self.lsr |= LSR_DATA_READY;
+			}
+		}
+	}
+
+	fn is_interrupting(&self) -> bool {
+		(self.get_interrupt_id() & IIR_NO_INTERRUPT) == 0
+	}
+
+	fn build_fdt_node(&self, fdt: &mut FdtBuilder, base: u64, size: u64, irq: Option<u32>) {
+		fdt.begin_node(&format!("uart@{:x}", base));
+		fdt.property_string("compatible", "ns16550a");
+		fdt.property_reg(base, size);
+		fdt.property_u32("clock-frequency", UART_CLOCK_FREQUENCY);
+		if let Some(irq) = irq {
+			fdt.property_u32("interrupts", irq);
+			fdt.property_u32("interrupt-parent", FDT_PLIC_PHANDLE);
+		}
+		fdt.end_node();
+	}
+
+	// Input pending in the terminal is not saved
+	fn save_state(&self, writer: &mut CheckpointWriter) {
+		writer.write_bytes(&[
+			self.rbr, self.ier, self.fcr, self.lcr, self.mcr, self.lsr, self.scr, self.dll,
+			self.dlm,
+		]);
+		writer.write_bool(self.thre_ip);
+	}
+
+	fn restore_state(&mut self, reader: &mut CheckpointReader) -> Result<(), CheckpointError> {
+		let mut registers = [0; 9];
+		reader.read_bytes(&mut registers)?;
+		self.rbr = registers[0];
+		self.ier = registers[1];
+		self.fcr = registers[2];
+		self.lcr = registers[3];
+		self.mcr = registers[4];
+		self.lsr = registers[5];
+		self.scr = registers[6];
+		self.dll = registers[7];
+		self.dlm = registers[8];
+		self.thre_ip = reader.read_bool()?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use terminal::BufferTerminal;
+
+	fn create_uart() -> (Uart, BufferTerminal) {
+		let terminal = BufferTerminal::new();
+		(Uart::new(Box::new(terminal.clone())), terminal)
+	}
+
+	#[test]
+	fn transmits_to_terminal() {
+		let (mut uart, terminal) = create_uart();
+		assert_ne!(uart.read(UART_LSR, 1) as u8 & LSR_THR_EMPTY, 0);
+		for byte in b"hi\n".iter() {
+			uart.write(UART_RBR_THR_DLL, *byte as u64, 1);
+		}
+		assert_eq!(terminal.take_output(), b"hi\n".to_vec());
+		// THR empty interrupt is raised only if enabled
+		assert!(!uart.is_interrupting());
+		uart.write(UART_IER_DLM, IER_THRE as u64, 1);
+		assert!(uart.is_interrupting());
+		assert_eq!(uart.read(UART_IIR_FCR, 1) as u8, IIR_THRE);
+		// Reading IIR clears it
+		assert!(!uart.is_interrupting());
+		uart.write(UART_RBR_THR_DLL, b'!' as u64, 1);
+		assert!(uart.is_interrupting());
+		assert_eq!(terminal.take_output(), b"!".to_vec());
+	}
+
+	#[test]
+	fn receives_from_terminal() {
+		let (mut uart, terminal) = create_uart();
+		let mut mip = 0;
+		uart.write(UART_IER_DLM, IER_RDA as u64, 1);
+		uart.tick(&mut mip);
+		assert_eq!(uart.read(UART_LSR, 1) as u8 & LSR_DATA_READY, 0);
+		assert!(!uart.is_interrupting());
+
+		terminal.put_input(b"ab");
+		uart.tick(&mut mip);
+		assert_ne!(uart.read(UART_LSR, 1) as u8 & LSR_DATA_READY, 0);
+		assert!(uart.is_interrupting());
+		assert_eq!(uart.read(UART_IIR_FCR, 1) as u8, IIR_RDA);
+		assert_eq!(uart.read(UART_RBR_THR_DLL, 1), b'a' as u64);
+		// Reading RBR clears data ready and the interrupt
+		assert_eq!(uart.read(UART_LSR, 1) as u8 & LSR_DATA_READY, 0);
+		assert!(!uart.is_interrupting());
+		uart.tick(&mut mip);
+		assert_eq!(uart.read(UART_RBR_THR_DLL, 1), b'b' as u64);
+
+		// Data ready without enabled interrupt doesn't interrupt
+		uart.write(UART_IER_DLM, 0, 1);
+		terminal.put_input(b"c");
+		uart.tick(&mut mip);
+		assert_ne!(uart.read(UART_LSR, 1) as u8 & LSR_DATA_READY, 0);
+		assert!(!uart.is_interrupting());
+		assert_eq!(uart.read(UART_IIR_FCR, 1) as u8, IIR_NO_INTERRUPT);
+	}
+
+	#[test]
+	fn dlab_selects_divisor_latch() {
+		let (mut uart, terminal) = create_uart();
+		uart.write(UART_IER_DLM, IER_RDA as u64, 1);
+		uart.write(UART_LCR, (LCR_DLAB | 0x03) as u64, 1);
+		uart.write(UART_RBR_THR_DLL, 0x0c, 1);
+		uart.write(UART_IER_DLM, 0x01, 1);
+		assert_eq!(uart.read(UART_RBR_THR_DLL, 1), 0x0c);
+		assert_eq!(uart.read(UART_IER_DLM, 1), 0x01);
+		// Divisor writes don't transmit
+		assert!(terminal.take_output().is_empty());
+
+		uart.write(UART_LCR, 0x03, 1);
+		assert_eq!(uart.read(UART_IER_DLM, 1), IER_RDA as u64);
+		uart.write(UART_RBR_THR_DLL, b'x' as u64, 1);
+		assert_eq!(terminal.take_output(), b"x".to_vec());
+		uart.write(UART_LCR, (LCR_DLAB | 0x03) as u64, 1);
+		assert_eq!(uart.read(UART_RBR_THR_DLL, 1), 0x0c);
+	}
+}
@@ -0,0 +1,746 @@
+use checkpoint::{CheckpointError, CheckpointReader, CheckpointWriter};
+use device::{Device, DmaMemory};
+use fdt::{FdtBuilder, FDT_PLIC_PHANDLE};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+/// Physical base address of virtio block device
+pub const VIRTIO_BASE: u64 = 0x10001000;
+
+/// Size of virtio block device address range
+pub const VIRTIO_SIZE: u64 = 0x1000;
+
+/// PLIC interrupt source number of virtio block device
+pub const VIRTIO_IRQ: u32 = 1;
+
+/// Sector size of virtio block device in bytes
+pub const VIRTIO_BLOCK_SECTOR_SIZE: u64 = 512;
+
+// MMIO register offsets
+const VIRTIO_MMIO_MAGIC_VALUE: u64 = 0x000;
+const VIRTIO_MMIO_VERSION: u64 = 0x004;
+const VIRTIO_MMIO_DEVICE_ID: u64 = 0x008;
+const VIRTIO_MMIO_VENDOR_ID: u64 = 0x00c;
+const VIRTIO_MMIO_DEVICE_FEATURES: u64 = 0x010;
+const VIRTIO_MMIO_DEVICE_FEATURES_SEL: u64 = 0x014;
+const VIRTIO_MMIO_DRIVER_FEATURES: u64 = 0x020;
+const VIRTIO_MMIO_DRIVER_FEATURES_SEL: u64 = 0x024;
+const VIRTIO_MMIO_GUEST_PAGE_SIZE: u64 = 0x028;
+const VIRTIO_MMIO_QUEUE_SEL: u64 = 0x030;
+const VIRTIO_MMIO_QUEUE_NUM_MAX: u64 = 0x034;
+const VIRTIO_MMIO_QUEUE_NUM: u64 = 0x038;
+const VIRTIO_MMIO_QUEUE_ALIGN: u64 = 0x03c;
+const VIRTIO_MMIO_QUEUE_PFN: u64 = 0x040;
+const VIRTIO_MMIO_QUEUE_READY: u64 = 0x044;
+const VIRTIO_MMIO_QUEUE_NOTIFY: u64 = 0x050;
+const VIRTIO_MMIO_INTERRUPT_STATUS: u64 = 0x060;
+const VIRTIO_MMIO_INTERRUPT_ACK: u64 = 0x064;
+const VIRTIO_MMIO_STATUS: u64 = 0x070;
+const VIRTIO_MMIO_QUEUE_DESC_LOW: u64 = 0x080;
+const VIRTIO_MMIO_QUEUE_DESC_HIGH: u64 = 0x084;
+const VIRTIO_MMIO_QUEUE_DRIVER_LOW: u64 = 0x090;
+const VIRTIO_MMIO_QUEUE_DRIVER_HIGH: u64 = 0x094;
+const VIRTIO_MMIO_QUEUE_DEVICE_LOW: u64 = 0x0a0;
+const VIRTIO_MMIO_QUEUE_DEVICE_HIGH: u64 = 0x0a4;
+const VIRTIO_MMIO_CONFIG_GENERATION: u64 = 0x0fc;
+const VIRTIO_MMIO_CONFIG: u64 = 0x100;
+
+const VIRTIO_MAGIC: u32 = 0x74726976; // "virt"
+const VIRTIO_VENDOR: u32 = 0x554d4551; // "QEMU"
+const VIRTIO_DEVICE_ID_BLOCK: u32 = 2;
+
+/// VIRTIO_F_VERSION_1, required for modern device
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+const VIRTIO_QUEUE_NUM_MAX: u32 = 256;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_SIZE: u64 = 16;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+const VIRTIO_BLK_ID_BYTES: usize = 20;
+
+/// Storage of virtio block device. Offset and length are in bytes.
+pub trait DiskImage {
+	/// Returns the image size in bytes
+	fn len(&self) -> u64;
+
+	/// Returns true if the image is empty
+	fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	/// Reads data at offset. Returns false on failure.
+	///
+	/// # Arguments
+	/// * `offset`
+	/// * `buffer`
+	fn read(&mut self, offset: u64, buffer: &mut [u8]) -> bool;
+
+	/// Writes data at offset. Returns false on failure.
+	///
+	/// # Arguments
+	/// * `offset`
+	/// * `buffer`
+	fn write(&mut self, offset: u64, buffer: &[u8]) -> bool;
+}
+
+/// Returns the range of `length` bytes at `offset` in an image of `size`
+/// bytes, or None if it's out of the image.
+///
+/// # Arguments
+/// * `size`
+/// * `offset`
+/// * `length`
+fn get_image_range(size: u64, offset: u64, length: usize) -> Option<Range<usize>> {
+	match offset.checked_add(length as u64) {
+		Some(end) if end <= size => Some(offset as usize..end as usize),
+		_ => None,
+	}
+}
+
+/// In-memory disk image. Writes are not persisted.
+impl DiskImage for Vec<u8> {
+	fn len(&self) -> u64 {
+		<[u8]>::len(self) as u64
+	}
+
+	fn read(&mut self, offset: u64, buffer: &mut [u8]) -> bool {
+		let range = get_image_range(DiskImage::len(self), offset, buffer.len());
+		match range.and_then(|range| self.get(range)) {
+			Some(data) => {
+				buffer.copy_from_slice(data);
+				true
+			}
+			None => false,
+		}
+	}
+
+	fn write(&mut self, offset: u64, buffer: &[u8]) -> bool {
+		let range = get_image_range(DiskImage::len(self), offset, buffer.len());
+		match range.and_then(|range| self.get_mut(range)) {
+			Some(data) => {
+				data.copy_from_slice(buffer);
+				true
+			}
+			None => false,
+		}
+	}
+}
+
+/// Disk image file. Writes go to the file.
+impl DiskImage for File {
+	fn len(&self) -> u64 {
+		match self.metadata() {
+			Ok(metadata) => metadata.len(),
+			Err(_e) => 0,
+		}
+	}
+
+	fn read(&mut self, offset: u64, buffer: &mut [u8]) -> bool {
+		self.seek(SeekFrom::Start(offset)).is_ok() && self.read_exact(buffer).is_ok()
+	}
+
+	fn write(&mut self, offset: u64, buffer: &[u8]) -> bool {
+		self.seek(SeekFrom::Start(offset)).is_ok() && self.write_all(buffer).is_ok()
+	}
+}
+
+/// Register layout of virtio-mmio transport
+#[derive(Clone, Debug)]
+pub enum VirtioVersion {
+	/// Version 1. Queue is placed by page frame number.
+	Legacy,
+	/// Version 2. Queue areas are placed separately by address.
+	Modern,
+}
+
+/// Emulates virtio block device over virtio-mmio transport with
+/// a single request queue. Requests are processed at the next cycle
+/// after queue notify.
+pub struct VirtioBlockDisk {
+	image: Box<dyn DiskImage>,
+	version: VirtioVersion,
+	device_features_sel: u32,
+	driver_features: u64,
+	driver_features_sel: u32,
+	guest_page_size: u32,
+	queue_num: u32,
+	queue_align: u32,
+	queue_pfn: u32,
+	queue_ready: u32,
+	queue_desc: u64,
+	queue_driver: u64,
+	queue_device: u64,
+	interrupt_status: u32,
+	status: u32,
+	notified: bool,
+	/// Index of the next available ring entry to process
+	last_avail_idx: u16,
+}
+
+impl VirtioBlockDisk {
+	/// Creates a new `VirtioBlockDisk`.
+	///
+	/// # Arguments
+	/// * `image` Disk content
+	/// * `version` Register layout exposed to the driver
+	pub fn new(image: Box<dyn DiskImage>, version: VirtioVersion) -> Self {
+		VirtioBlockDisk {
+			image,
+			version,
+			device_features_sel: 0,
+			driver_features: 0,
+			driver_features_sel: 0,
+			guest_page_size: 0,
+			queue_num: 0,
+			queue_align: 0,
+			queue_pfn: 0,
+			queue_ready: 0,
+			queue_desc: 0,
+			queue_driver: 0,
+			queue_device: 0,
+			interrupt_status: 0,
+			status: 0,
+			notified: false,
+			last_avail_idx: 0,
+		}
+	}
+
+	fn reset(&mut self) {
+		self.device_features_sel = 0;
+		self.driver_features = 0;
+		self.driver_features_sel = 0;
+		self.queue_num = 0;
+		self.queue_pfn = 0;
+		self.queue_ready = 0;
+		self.queue_desc = 0;
+		self.queue_driver = 0;
+		self.queue_device = 0;
+		self.interrupt_status = 0;
+		self.status = 0;
+		self.notified = false;
+		self.last_avail_idx = 0;
+	}
+
+	fn get_device_features(&self) -> u64 {
+		match self.version {
+			VirtioVersion::Legacy => 0,
+			VirtioVersion::Modern => VIRTIO_F_VERSION_1,
+		}
+	}
+
+	fn get_capacity(&self) -> u64 {
+		self.image.len() / VIRTIO_BLOCK_SECTOR_SIZE
+	}
+
+	/// Returns the image offsets of data buffers of a request, or None if
+	/// the request is out of the image. Sector and lengths come from the
+	/// guest, so they are checked before allocating the buffers.
+	///
+	/// # Arguments
+	/// * `sector`
+	/// * `data_buffers` (address, length) of data buffers
+	fn get_data_offsets(&self, sector: u64, data_buffers: &[(u64, u64)]) -> Option<Vec<u64>> {
+		let mut offset = sector.checked_mul(VIRTIO_BLOCK_SECTOR_SIZE)?;
+		let mut offsets = vec![];
+		for (_address, length) in data_buffers.iter() {
+			offsets.push(offset);
+			offset = offset.checked_add(*length)?;
+		}
+		match offset <= self.image.len() {
+			true => Some(offsets),
+			false => None,
+		}
+	}
+
+	/// Returns physical addresses of descriptor table, available ring
+	/// and used ring
+	fn get_queue_addresses(&self) -> (u64, u64, u64) {
+		match self.version {
+			VirtioVersion::Legacy => {
+				let desc = (self.queue_pfn as u64) * (self.guest_page_size as u64);
+				let avail = desc + VIRTQ_DESC_SIZE * (self.queue_num as u64);
+				let align = (self.queue_align as u64).max(1);
+				let used = (avail + 6 + 2 * (self.queue_num as u64)).next_multiple_of(align);
+				(desc, avail, used)
+			}
+			VirtioVersion::Modern => (self.queue_desc, self.queue_driver, self.queue_device),
+		}
+	}
+
+	fn is_queue_ready(&self) -> bool {
+		self.queue_num > 0
+			&& match self.version {
+				VirtioVersion::Legacy => self.queue_pfn != 0,
+				VirtioVersion::Modern => self.queue_ready != 0,
+			}
+	}
+
+	fn read_register(&self, offset: u64) -> u32 {
+		match offset {
+			VIRTIO_MMIO_MAGIC_VALUE => VIRTIO_MAGIC,
+			VIRTIO_MMIO_VERSION => match self.version {
+				VirtioVersion::Legacy => 1,
+				VirtioVersion::Modern => 2,
+			},
+			VIRTIO_MMIO_DEVICE_ID => VIRTIO_DEVICE_ID_BLOCK,
+			VIRTIO_MMIO_VENDOR_ID => VIRTIO_VENDOR,
+			VIRTIO_MMIO_DEVICE_FEATURES => match self.device_features_sel {
+				0 => self.get_device_features() as u32,
+				1 => (self.get_device_features() >> 32) as u32,
+				_ => 0,
+			},
+			VIRTIO_MMIO_QUEUE_NUM_MAX => VIRTIO_QUEUE_NUM_MAX,
+			VIRTIO_MMIO_QUEUE_PFN => self.queue_pfn,
+			VIRTIO_MMIO_QUEUE_READY => self.queue_ready,
+			VIRTIO_MMIO_INTERRUPT_STATUS => self.interrupt_status,
+			VIRTIO_MMIO_STATUS => self.status,
+			VIRTIO_MMIO_CONFIG_GENERATION => 0,
+			_ => 0,
+		}
+	}
+
+	fn write_register(&mut self, offset: u64, value: u32) {
+		match offset {
+			VIRTIO_MMIO_DEVICE_FEATURES_SEL => self.device_features_sel = value,
+			VIRTIO_MMIO_DRIVER_FEATURES => match self.driver_features_sel {
+				0 => self.driver_features = (self.driver_features & !0xffffffff) | (value as u64),
+				1 => {
+					self.driver_features =
+						(self.driver_features & 0xffffffff) | ((value as u64) << 32)
+				}
+				_ => {}
+			},
+			VIRTIO_MMIO_DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+			VIRTIO_MMIO_GUEST_PAGE_SIZE => self.guest_page_size = value,
+			// Only queue 0 exists
+			VIRTIO_MMIO_QUEUE_SEL => {}
+			VIRTIO_MMIO_QUEUE_NUM => self.queue_num = value.min(VIRTIO_QUEUE_NUM_MAX),
+			VIRTIO_MMIO_QUEUE_ALIGN => self.queue_align = value,
+			VIRTIO_MMIO_QUEUE_PFN => self.queue_pfn = value,
+			VIRTIO_MMIO_QUEUE_READY => self.queue_ready = value & 1,
+			VIRTIO_MMIO_QUEUE_NOTIFY => self.notified = true,
+			VIRTIO_MMIO_INTERRUPT_ACK => self.interrupt_status &= !value,
+			VIRTIO_MMIO_STATUS => match value {
+				0 => self.reset(),
+				_ => self.status = value,
+			},
+			VIRTIO_MMIO_QUEUE_DESC_LOW => {
+				self.queue_desc = (self.queue_desc & !0xffffffff) | (value as u64)
+			}
+			VIRTIO_MMIO_QUEUE_DESC_HIGH => {
+				self.queue_desc = (self.queue_desc & 0xffffffff) | ((value as u64) << 32)
+			}
+			VIRTIO_MMIO_QUEUE_DRIVER_LOW => {
+				self.queue_driver = (self.queue_driver & !0xffffffff) | (value as u64)
+			}
+			VIRTIO_MMIO_QUEUE_DRIVER_HIGH => {
+				self.queue_driver = (self.queue_driver & 0xffffffff) | ((value as u64) << 32)
+			}
+			VIRTIO_MMIO_QUEUE_DEVICE_LOW => {
+				self.queue_device = (self.queue_device & !0xffffffff) | (value as u64)
+			}
+			VIRTIO_MMIO_QUEUE_DEVICE_HIGH => {
+				self.queue_device = (self.queue_device & 0xffffffff) | ((value as u64) << 32)
+			}
+			_ => {}
+		}
+	}
+
+	/// Reads block device configuration space. Only capacity is provided.
+	fn read_config(&self, offset: u64, width: u64) -> u64 {
+		let capacity = self.get_capacity();
+		match offset {
+			0..=7 => {
+				let value = capacity >> (offset * 8);
+				match width {
+					8 => value,
+					_ => value & ((1 << (width * 8)) - 1),
+				}
+			}
+			_ => 0,
+		}
+	}
+
+	/// Processes all the requests in available ring
+	///
+	/// # Arguments
+	/// * `memory`
+	fn process_queue(&mut self, memory: &mut dyn DmaMemory) {
+		let (desc, avail, used) = self.get_queue_addresses();
+		let queue_num = self.queue_num as u64;
+		let avail_idx = memory.read(avail + 2, 2) as u16;
+		while self.last_avail_idx != avail_idx {
+			let ring_offset = (self.last_avail_idx as u64) % queue_num;
+			let head = memory.read(avail + 4 + ring_offset * 2, 2) as u16;
+			let written = self.process_request(memory, desc, head);
+
+			let used_idx = memory.read(used + 2, 2) as u16;
+			let used_elem = used + 4 + ((used_idx as u64) % queue_num) * 8;
+			memory.write(used_elem, head as u64, 4);
+			memory.write(used_elem + 4, written as u64, 4);
+			memory.write(used + 2, used_idx.wrapping_add(1) as u64, 2);
+
+			self.last_avail_idx = self.last_avail_idx.wrapping_add(1);
+			// Used buffer notification
+			self.interrupt_status |= 1;
+		}
+	}
+
+	/// Processes a request from its descriptor chain. Returns the number
+	/// of bytes written to the guest memory.
+	///
+	/// # Arguments
+	/// * `memory`
+	/// * `desc` Address of descriptor table
+	/// * `head` Index of the first descriptor
+	fn process_request(&mut self, memory: &mut dyn DmaMemory, desc: u64, head: u16) -> u32 {
+		// Collects (address, length) of descriptors in the chain
+		let mut buffers = vec![];
+		let mut index = head as u64;
+		loop {
+			let entry = desc + (index % (self.queue_num as u64)) * VIRTQ_DESC_SIZE;
+			let address = memory.read(entry, 8);
+			let length = memory.read(entry + 8, 4);
+			let flags = memory.read(entry + 12, 2) as u16;
+			buffers.push((address, length));
+			if (flags & VIRTQ_DESC_F_NEXT) == 0 || buffers.len() > self.queue_num as usize {
+				break;
+			}
+			index = memory.read(entry + 14, 2);
+		}
+		// Header, data buffers, and status byte
+		if buffers.len() < 2 {
+			return 0;
+		}
+		let header = buffers[0].0;
+		let request_type = memory.read(header, 4) as u32;
+		let sector = memory.read(header + 8, 8);
+		let status_address = buffers[buffers.len() - 1].0;
+		let data_buffers = &buffers[1..buffers.len() - 1];
+
+		let mut written: u32 = 0;
+		let status = match request_type {
+			VIRTIO_BLK_T_IN => match self.get_data_offsets(sector, data_buffers) {
+				Some(offsets) => {
+					let mut status = VIRTIO_BLK_S_OK;
+					for ((address, length), offset) in data_buffers.iter().zip(offsets) {
+						let mut data = vec![0; *length as usize];
+						if !self.image.read(offset, &mut data) {
+							status = VIRTIO_BLK_S_IOERR;
+							break;
+						}
+						memory.write_bytes(*address, &data);
+						written = written.saturating_add(*length as u32);
+					}
+					status
+				}
+				None => VIRTIO_BLK_S_IOERR,
+			},
+			VIRTIO_BLK_T_OUT => match self.get_data_offsets(sector, data_buffers) {
+				Some(offsets) => {
+					let mut status = VIRTIO_BLK_S_OK;
+					for ((address, length), offset) in data_buffers.iter().zip(offsets) {
+						let mut data = vec![0; *length as usize];
+						memory.read_bytes(*address, &mut data);
+						if !self.image.write(offset, &data) {
+							status = VIRTIO_BLK_S_IOERR;
+							break;
+						}
+					}
+					status
+				}
+				None => VIRTIO_BLK_S_IOERR,
+			},
+			VIRTIO_BLK_T_FLUSH => VIRTIO_BLK_S_OK,
+			VIRTIO_BLK_T_GET_ID => {
+				let id = b"riscv-rust";
+				if let Some((address, length)) = data_buffers.first() {
+					let length = (*length as usize).min(VIRTIO_BLK_ID_BYTES);
+					let mut data = vec![0; length];
+					let copied = length.min(id.len());
+					data[..copied].copy_from_slice(&id[..copied]);
+					memory.write_bytes(*address, &data);
+					written += length as u32;
+				}
+				VIRTIO_BLK_S_OK
+			}
+			_ => VIRTIO_BLK_S_UNSUPP,
+		};
+		memory.write_byte(status_address, status);
+		written.saturating_add(1)
+	}
+}
+
+impl Device for VirtioBlockDisk {
+	fn read(&mut self, offset: u64, width: u64) -> u64 {
+		match offset >= VIRTIO_MMIO_CONFIG {
+			true => self.read_config(offset - VIRTIO_MMIO_CONFIG, width),
+			false => self.read_register(offset) as u64,
+		}
+	}
+
+	fn write(&mut self, offset: u64, value: u64, _width: u64) {
+		// Configuration space is read-only
+		if offset < VIRTIO_MMIO_CONFIG {
+			self.write_register(offset, value as u32);
+		}
+	}
+
+	fn dma(&mut self, memory: &mut dyn DmaMemory) {
+		if self.notified {
+			self.notified = false;
+			if self.is_queue_ready() {
+				self.process_queue(memory);
+			}
+		}
+	}
+
+	fn is_interrupting(&self) -> bool {
+		self.interrupt_status != 0
+	}
+
+	fn build_fdt_node(&self, fdt: &mut FdtBuilder, base: u64, size: u64, irq: Option<u32>) {
+		fdt.begin_node(&format!("virtio_mmio@{:x}", base));
+		fdt.property_string("compatible", "virtio,mmio");
+		fdt.property_reg(base, size);
+		if let Some(irq) = irq {
+			fdt.property_u32("interrupts", irq);
+			fdt.property_u32("interrupt-parent", FDT_PLIC_PHANDLE);
+		}
+		fdt.end_node();
+	}
+
+	// Disk image content is not saved. The same image needs to be used
+	// on restore.
+	fn save_state(&self, writer: &mut CheckpointWriter) {
+		writer.write_u64(self.get_device_features());
+		for value in [
+			self.device_features_sel,
+			self.driver_features_sel,
+			self.guest_page_size,
+			self.queue_num,
+			self.queue_align,
+			self.queue_pfn,
+			self.queue_ready,
+			self.interrupt_status,
+			self.status,
+		]
+		.iter()
+		{
+			writer.write_u32(*value);
+		}
+		writer.write_u64s(&[
+			self.driver_features,
+			self.queue_desc,
+			self.queue_driver,
+			self.queue_device,
+		]);
+		writer.write_bool(self.notified);
+		writer.write_u32(self.last_avail_idx as u32);
+	}
+
+	fn restore_state(&mut self, reader: &mut CheckpointReader) -> Result<(), CheckpointError> {
+		// Device features tell the version
+		reader.expect_u64(self.get_device_features(), "Virtio device features")?;
+		self.device_features_sel = reader.read_u32()?;
+		self.driver_features_sel = reader.read_u32()?;
+		self.guest_page_size = reader.read_u32()?;
+		self.queue_num = reader.read_u32()?;
+		self.queue_align = reader.read_u32()?;
+		self.queue_pfn = reader.read_u32()?;
+		self.queue_ready = reader.read_u32()?;
+		self.interrupt_status = reader.read_u32()?;
+		self.status = reader.read_u32()?;
+		self.driver_features = reader.read_u64()?;
+		self.queue_desc = reader.read_u64()?;
+		self.queue_driver = reader.read_u64()?;
+		self.queue_device = reader.read_u64()?;
+		self.notified = reader.read_bool()?;
+		self.last_avail_idx = reader.read_u32()? as u16;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const QUEUE_NUM: u64 = 8;
+	const DESC_TABLE: u64 = 0x1000;
+	const AVAIL_RING: u64 = 0x2000;
+	const USED_RING: u64 = 0x3000;
+	const HEADER: u64 = 0x4000;
+	const DATA: u64 = 0x5000;
+	const STATUS: u64 = 0x6000;
+	const SECTOR_NUM: u64 = 8;
+
+	struct TestMemory {
+		data: Vec<u8>,
+	}
+
+	impl DmaMemory for TestMemory {
+		fn read_byte(&mut self, p_address: u64) -> u8 {
+			self.data[p_address as usize]
+		}
+
+		fn write_byte(&mut self, p_address: u64, value: u8) {
+			self.data[p_address as usize] = value;
+		}
+	}
+
+	/// Creates a modern disk whose image bytes are their offsets, with
+	/// its queue set up in the returned memory
+	fn create_disk() -> (VirtioBlockDisk, TestMemory) {
+		let size = SECTOR_NUM * VIRTIO_BLOCK_SECTOR_SIZE;
+		let image: Vec<u8> = (0..size).map(|i| i as u8).collect();
+		let mut disk = VirtioBlockDisk::new(Box::new(image), VirtioVersion::Modern);
+		disk.write(VIRTIO_MMIO_QUEUE_NUM, QUEUE_NUM, 4);
+		disk.write(VIRTIO_MMIO_QUEUE_DESC_LOW, DESC_TABLE, 4);
+		disk.write(VIRTIO_MMIO_QUEUE_DRIVER_LOW, AVAIL_RING, 4);
+		disk.write(VIRTIO_MMIO_QUEUE_DEVICE_LOW, USED_RING, 4);
+		disk.write(VIRTIO_MMIO_QUEUE_READY, 1, 4);
+		let memory = TestMemory {
+			data: vec![0; 0x8000],
+		};
+		(disk, memory)
+	}
+
+	/// Submits a request of header, data and status descriptors, and
+	/// returns its status
+	fn submit(
+		disk: &mut VirtioBlockDisk,
+		memory: &mut TestMemory,
+		request_type: u32,
+		sector: u64,
+		length: u32,
+	) -> u8 {
+		memory.write(HEADER, request_type as u64, 4);
+		memory.write(HEADER + 8, sector, 8);
+		memory.write_byte(STATUS, 0xff);
+		let descriptors = [
+			(HEADER, 16, VIRTQ_DESC_F_NEXT, 1),
+			(DATA, length as u64, VIRTQ_DESC_F_NEXT, 2),
+			(STATUS, 1, 0, 0),
+		];
+		for (i, (address, length, flags, next)) in descriptors.iter().enumerate() {
+			let entry = DESC_TABLE + (i as u64) * VIRTQ_DESC_SIZE;
+			memory.write(entry, *address, 8);
+			memory.write(entry + 8, *length, 4);
+			memory.write(entry + 12, *flags as u64, 2);
+			memory.write(entry + 14, *next, 2);
+		}
+		let avail_idx = memory.read(AVAIL_RING + 2, 2);
+		memory.write(AVAIL_RING + 4 + (avail_idx % QUEUE_NUM) * 2, 0, 2);
+		memory.write(AVAIL_RING + 2, avail_idx + 1, 2);
+		disk.write(VIRTIO_MMIO_QUEUE_NOTIFY, 0, 4);
+		disk.dma(memory);
+		assert_eq!(memory.read(USED_RING + 2, 2), avail_idx + 1);
+		memory.read_byte(STATUS)
+	}
+
+	#[test]
+	fn reads_and_writes_through_virtqueue() {
+		let (mut disk, mut memory) = create_disk();
+		assert_eq!(disk.read(VIRTIO_MMIO_CONFIG, 8), SECTOR_NUM);
+
+		let length = VIRTIO_BLOCK_SECTOR_SIZE as u32;
+		assert_eq!(
+			submit(&mut disk, &mut memory, VIRTIO_BLK_T_IN, 1, length),
+			VIRTIO_BLK_S_OK
+		);
+		for i in 0..VIRTIO_BLOCK_SECTOR_SIZE {
+			let expected = (VIRTIO_BLOCK_SECTOR_SIZE + i) as u8;
+			assert_eq!(memory.read_byte(DATA + i), expected);
+		}
+		// Used element has the head index and data plus status length
+		assert_eq!(memory.read(USED_RING + 4, 4), 0);
+		assert_eq!(memory.read(USED_RING + 8, 4), (length + 1) as u64);
+		assert!(disk.is_interrupting());
+		disk.write(VIRTIO_MMIO_INTERRUPT_ACK, 1, 4);
+		assert!(!disk.is_interrupting());
+
+		for i in 0..VIRTIO_BLOCK_SECTOR_SIZE {
+			memory.write_byte(DATA + i, 0xa5);
+		}
+		assert_eq!(
+			submit(&mut disk, &mut memory, VIRTIO_BLK_T_OUT, 7, length),
+			VIRTIO_BLK_S_OK
+		);
+		assert_eq!(memory.read(USED_RING + 4 + 8 + 4, 4), 1);
+		for i in 0..VIRTIO_BLOCK_SECTOR_SIZE {
+			memory.write_byte(DATA + i, 0);
+		}
+		assert_eq!(
+			submit(&mut disk, &mut memory, VIRTIO_BLK_T_IN, 7, length),
+			VIRTIO_BLK_S_OK
+		);
+		for i in 0..VIRTIO_BLOCK_SECTOR_SIZE {
+			assert_eq!(memory.read_byte(DATA + i), 0xa5);
+		}
+	}
+
+	#[test]
+	fn rejects_out_of_image_sectors() {
+		let (mut disk, mut memory) = create_disk();
+		let length = VIRTIO_BLOCK_SECTOR_SIZE as u32;
+		for (request_type, sector, length) in [
+			(VIRTIO_BLK_T_IN, SECTOR_NUM, length),
+			(VIRTIO_BLK_T_IN, SECTOR_NUM - 1, length * 2),
+			(VIRTIO_BLK_T_IN, 1 << 60, length),
+			(VIRTIO_BLK_T_OUT, SECTOR_NUM, length),
+			(
+				VIRTIO_BLK_T_OUT,
+				u64::MAX / VIRTIO_BLOCK_SECTOR_SIZE,
+				length,
+			),
+		]
+		.iter()
+		{
+			let status = submit(&mut disk, &mut memory, *request_type, *sector, *length);
+			assert_eq!(status, VIRTIO_BLK_S_IOERR);
+			// Nothing but the status is written
+			assert_eq!(memory.read_byte(DATA), 0);
+			let used_elem = USED_RING + 4 + (memory.read(USED_RING + 2, 2) - 1) * 8;
+			assert_eq!(memory.read(used_elem + 4, 4), 1);
+		}
+		// Last sector is still accessible
+		assert_eq!(
+			submit(
+				&mut disk,
+				&mut memory,
+				VIRTIO_BLK_T_IN,
+				SECTOR_NUM - 1,
+				length
+			),
+			VIRTIO_BLK_S_OK
+		);
+	}
+
+	#[test]
+	fn rejects_oversized_lengths() {
+		let (mut disk, mut memory) = create_disk();
+		for request_type in [VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT].iter() {
+			let status = submit(&mut disk, &mut memory, *request_type, 0, u32::MAX);
+			assert_eq!(status, VIRTIO_BLK_S_IOERR);
+			assert_eq!(memory.read_byte(DATA), 0);
+		}
+		// The image is unchanged
+		let length = VIRTIO_BLOCK_SECTOR_SIZE as u32;
+		assert_eq!(
+			submit(&mut disk, &mut memory, VIRTIO_BLK_T_IN, 0, length),
+			VIRTIO_BLK_S_OK
+		);
+		assert_eq!(memory.read_byte(DATA + 1), 1);
+	}
+}
@@ -0,0 +1,436 @@
+use cpu::PrivilegeMode;
+use device::overlaps;
+use mmu::MemoryAccessType;
+use std::fmt;
+
+/// Watches guest accesses to an address range. Loads, stores, and
+/// instruction fetches through `Mmu` are checked. Accesses crossing a page
+/// boundary are checked byte by byte, and an instruction fetch matches
+/// if its address is in the range.
+#[derive(Clone, Debug)]
+pub struct Watchpoint {
+	/// Start address of the range
+	pub address: u64,
+	/// Size of the range in bytes
+	pub size: u64,
+	pub read: bool,
+	pub write: bool,
+	pub execute: bool,
+	/// Whether `address` is physical address, otherwise virtual address
+	pub is_physical: bool,
+	/// Matches only if the value read, written, or fetched equals this
+	pub value: Option<u64>,
+	/// Stops emulation on hit, otherwise the hit is only logged
+	pub stop: bool,
+}
+
+impl Watchpoint {
+	/// Parses watchpoint specification `ACCESS:ADDRESS[:SIZE[:VALUE]]`.
+	/// `ACCESS` consists of `r` (read), `w` (write), `x` (execute),
+	/// `p` (physical address), and `s` (stop). Numbers are hexadecimal
+	/// and `SIZE` is eight by default. e.g. `ws:0x80001000:8:0`
+	///
+	/// # Arguments
+	/// * `spec`
+	pub fn parse(spec: &str) -> Result<Watchpoint, String> {
+		let fields: Vec<&str> = spec.split(':').collect();
+		if fields.len() < 2 || fields.len() > 4 {
+			return Err(format!("Invalid watchpoint {}", spec));
+		}
+		let parse_hex = |token: &str| {
+			u64::from_str_radix(token.trim_start_matches("0x"), 16)
+				.map_err(|_| format!("Invalid hexadecimal number {}", token))
+		};
+		let mut watchpoint = Watchpoint {
+			address: parse_hex(fields[1])?,
+			size: match fields.get(2) {
+				Some(size) => parse_hex(size)?,
+				None => 8,
+			},
+			read: false,
+			write: false,
+			execute: false,
+			is_physical: false,
+			value: match fields.get(3) {
+				Some(value) => Some(parse_hex(value)?),
+				None => None,
+			},
+			stop: false,
+		};
+		for c in fields[0].chars() {
+			match c {
+				'r' => watchpoint.read = true,
+				'w' => watchpoint.write = true,
+				'x' => watchpoint.execute = true,
+				'p' => watchpoint.is_physical = true,
+				's' => watchpoint.stop = true,
+				_ => return Err(format!("Unknown watchpoint access {}", c)),
+			}
+		}
+		if !watchpoint.read && !watchpoint.write && !watchpoint.execute {
+			return Err(format!("Watchpoint {} watches no access", spec));
+		}
+		if watchpoint.size == 0 {
+			return Err(format!("Watchpoint {} has empty range", spec));
+		}
+		Ok(watchpoint)
+	}
+
+	fn matches(
+		&self,
+		access_type: &MemoryAccessType,
+		v_address: u64,
+		p_address: u64,
+		width: u64,
+		value: u64,
+	) -> bool {
+		let watches = match access_type {
+			MemoryAccessType::Read => self.read,
+			MemoryAccessType::Write => self.write,
+			MemoryAccessType::Execute => self.execute,
+			MemoryAccessType::DontCare => false,
+		};
+		let address = match self.is_physical {
+			true => p_address,
+			false => v_address,
+		};
+		// Fetch can read past compressed instruction
+		let width = match access_type {
+			MemoryAccessType::Execute => 1,
+			_ => width,
+		};
+		watches
+			&& overlaps(self.address, self.size, address, width)
+			&& self.value.is_none_or(|expected| expected == value)
+	}
+}
+
+/// Guest access which hit a watchpoint
+#[derive(Debug)]
+pub struct WatchpointHit {
+	/// Index of the watchpoint
+	pub index: usize,
+	/// Address of the instruction which made the access
+	pub pc: u64,
+	pub privilege_mode: PrivilegeMode,
+	pub access_type: MemoryAccessType,
+	pub v_address: u64,
+	pub p_address: u64,
+	/// Access width in bytes
+	pub width: u64,
+	/// Value before write. None for read, fetch, and write to device.
+	pub old_value: Option<u64>,
+	/// Value read, written, or fetched
+	pub value: u64,
+	pub stop: bool,
+}
+
+impl fmt::Display for WatchpointHit {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let access = match self.access_type {
+			MemoryAccessType::Read => "read",
+			MemoryAccessType::Write => "write",
+			MemoryAccessType::Execute => "execute",
+			MemoryAccessType::DontCare => "access",
+		};
+		write!(
+			f,
+			"watchpoint {}: {} {} bytes at {:x} (physical {:x}) by pc {:x} in {:?} mode,",
+			self.index,
+			access,
+			self.width,
+			self.v_address,
+			self.p_address,
+			self.pc,
+			self.privilege_mode
+		)?;
+		match self.old_value {
+			Some(old_value) => write!(f, " {:x} -> {:x}", old_value, self.value),
+			None => write!(f, " {:x}", self.value),
+		}
+	}
+}
+
+/// Watchpoints checked by `Mmu`. Hits are held until the instruction
+/// completes and `Cpu` reports them with its address.
+pub struct Watchpoints {
+	watchpoints: Vec<Watchpoint>,
+	pending_hits: Vec<WatchpointHit>,
+	last_hit: Option<WatchpointHit>,
+}
+
+impl Default for Watchpoints {
+	fn default() -> Self {
+		Watchpoints::new()
+	}
+}
+
+impl Watchpoints {
+	/// Creates a new `Watchpoints` without watchpoint.
+	pub const fn new() -> Self {
+		Watchpoints {
+			watchpoints: vec![],
+			pending_hits: vec![],
+			last_hit: None,
+		}
+	}
+
+	/// Adds a watchpoint and returns its index.
+	///
+	/// # Arguments
+	/// * `watchpoint`
+	pub fn add(&mut self, watchpoint: Watchpoint) -> usize {
+		self.watchpoints.push(watchpoint);
+		self.watchpoints.len() - 1
+	}
+
+	/// Removes all the watchpoints and their hits.
+	pub fn clear(&mut self) {
+		self.watchpoints.clear();
+		self.pending_hits.clear();
+		self.last_hit = None;
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.watchpoints.is_empty()
+	}
+
+	/// Checks a guest access against the watchpoints and holds the hits.
+	///
+	/// # Arguments
+	/// * `access_type`
+	/// * `v_address`
+	/// * `p_address`
+	/// * `width` Access width in bytes
+	/// * `old_value` Value before write if available
+	/// * `value` Value read, written, or fetched
+	/// * `privilege_mode` Privilege mode the access is made in
+	#[allow(clippy::too_many_arguments)]
+	pub fn check(
+		&mut self,
+		access_type: MemoryAccessType,
+		v_address: u64,
+		p_address: u64,
+		width: u64,
+		old_value: Option<u64>,
+		value: u64,
+		privilege_mode: &PrivilegeMode,
+	) {
+		for (index, watchpoint) in self.watchpoints.iter().enumerate() {
+			if watchpoint.matches(&access_type, v_address, p_address, width, value) {
+				self.pending_hits.push(WatchpointHit {
+					index,
+					pc: 0,
+					privilege_mode: privilege_mode.clone(),
+					access_type: access_type.clone(),
+					v_address,
+					p_address,
+					width,
+					old_value,
+					value,
+					stop: watchpoint.stop,
+				});
+			}
+		}
+	}
+
+	/// Logs the hits of the instruction and returns true if any of them
+	/// stops emulation.
+	///
+	/// # Arguments
+	/// * `pc` Address of the instruction
+	pub fn report_hits(&mut self, pc: u64) -> bool {
+		let mut stop = false;
+		for mut hit in self.pending_hits.drain(..) {
+			hit.pc = pc;
+			println!("{}", hit);
+			stop |= hit.stop;
+			self.last_hit = Some(hit);
+		}
+		stop
+	}
+
+	/// Discards the hits not reported yet, e.g. of fetch for disassembly.
+	pub fn discard_pending_hits(&mut self) {
+		self.pending_hits.clear();
+	}
+
+	pub fn has_pending_hits(&self) -> bool {
+		!self.pending_hits.is_empty()
+	}
+
+	/// Returns the last reported hit.
+	pub fn get_last_hit(&self) -> Option<&WatchpointHit> {
+		self.last_hit.as_ref()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn matches(
+		watchpoint: &Watchpoint,
+		access_type: MemoryAccessType,
+		address: u64,
+		width: u64,
+	) -> bool {
+		watchpoint.matches(
+			&access_type,
+			address,
+			address.wrapping_add(0x80000000),
+			width,
+			0,
+		)
+	}
+
+	#[test]
+	fn parses_spec() {
+		let watchpoint = Watchpoint::parse("ws:0x80001000:10:ff").unwrap();
+		assert_eq!(watchpoint.address, 0x80001000);
+		assert_eq!(watchpoint.size, 0x10);
+		assert!(!watchpoint.read && watchpoint.write && !watchpoint.execute);
+		assert!(!watchpoint.is_physical && watchpoint.stop);
+		assert_eq!(watchpoint.value, Some(0xff));
+
+		let watchpoint = Watchpoint::parse("rxp:1000").unwrap();
+		assert_eq!(watchpoint.address, 0x1000);
+		assert_eq!(watchpoint.size, 8);
+		assert!(watchpoint.read && !watchpoint.write && watchpoint.execute);
+		assert!(watchpoint.is_physical && !watchpoint.stop);
+		assert_eq!(watchpoint.value, None);
+
+		for spec in [
+			"r",
+			"r:0x1000:8:0:0",
+			"q:0x1000",
+			"ps:0x1000",
+			"r:0x1000:0",
+			"r:0xg",
+			"r:0x1000:8:x",
+			"r:",
+		]
+		.iter()
+		{
+			assert!(Watchpoint::parse(spec).is_err(), "{}", spec);
+		}
+	}
+
+	#[test]
+	fn matches_overlapping_accesses() {
+		let watchpoint = Watchpoint::parse("rw:0x1000:8").unwrap();
+		assert!(matches(&watchpoint, MemoryAccessType::Read, 0x1000, 8));
+		assert!(matches(&watchpoint, MemoryAccessType::Write, 0x1007, 1));
+		// Wider access overlapping the range from below
+		assert!(matches(&watchpoint, MemoryAccessType::Read, 0xffc, 8));
+		assert!(!matches(&watchpoint, MemoryAccessType::Read, 0xffc, 4));
+		assert!(!matches(&watchpoint, MemoryAccessType::Read, 0x1008, 8));
+		// Access types not watched
+		assert!(!matches(&watchpoint, MemoryAccessType::Execute, 0x1000, 4));
+		assert!(!matches(&watchpoint, MemoryAccessType::DontCare, 0x1000, 8));
+
+		// Fetch matches only by its address
+		let watchpoint = Watchpoint::parse("x:0x1002:2").unwrap();
+		assert!(matches(&watchpoint, MemoryAccessType::Execute, 0x1002, 4));
+		assert!(!matches(&watchpoint, MemoryAccessType::Execute, 0x1000, 4));
+
+		// Physical address is compared for physical watchpoints
+		let watchpoint = Watchpoint::parse("rp:0x80001000").unwrap();
+		assert!(matches(&watchpoint, MemoryAccessType::Read, 0x1000, 8));
+		assert!(!matches(&watchpoint, MemoryAccessType::Read, 0x80001000, 8));
+
+		// Range reaching the end of address space
+		let watchpoint = Watchpoint::parse("r:0xfffffffffffffff8").unwrap();
+		assert!(matches(
+			&watchpoint,
+			MemoryAccessType::Read,
+			0xfffffffffffffff0,
+			16
+		));
+	}
+
+	#[test]
+	fn matches_value() {
+		let watchpoint = Watchpoint::parse("w:0x1000:8:2a").unwrap();
+		let write = MemoryAccessType::Write;
+		assert!(watchpoint.matches(&write, 0x1000, 0x1000, 8, 0x2a));
+		assert!(!watchpoint.matches(&write, 0x1000, 0x1000, 8, 0x2b));
+		assert!(!watchpoint.matches(&write, 0x2000, 0x2000, 8, 0x2a));
+	}
+
+	#[test]
+	fn reports_hits() {
+		let mut watchpoints = Watchpoints::new();
+		assert!(watchpoints.is_empty());
+		assert_eq!(watchpoints.add(Watchpoint::parse("r:0x1000").unwrap()), 0);
+		assert_eq!(watchpoints.add(Watchpoint::parse("ws:0x1000").unwrap()), 1);
+		let mode = PrivilegeMode::User;
+
+		watchpoints.check(MemoryAccessType::Read, 0x2000, 0x2000, 8, None, 1, &mode);
+		assert!(!watchpoints.has_pending_hits());
+		watchpoints.check(
+			MemoryAccessType::Read,
+			0x1004,
+			0x80001004,
+			4,
+			None,
+			3,
+			&mode,
+		);
+		assert!(watchpoints.has_pending_hits());
+		assert!(!watchpoints.report_hits(0x10078));
+		assert!(!watchpoints.has_pending_hits());
+		let hit = watchpoints.get_last_hit().unwrap();
+		assert_eq!(hit.index, 0);
+		assert_eq!(hit.pc, 0x10078);
+		assert_eq!(
+			(hit.v_address, hit.p_address, hit.width, hit.value),
+			(0x1004, 0x80001004, 4, 3)
+		);
+		assert!(hit.old_value.is_none() && !hit.stop);
+
+		// Stopping hit
+		watchpoints.check(
+			MemoryAccessType::Write,
+			0x1000,
+			0x80001000,
+			1,
+			Some(5),
+			6,
+			&mode,
+		);
+		assert!(watchpoints.report_hits(0x1007c));
+		let hit = watchpoints.get_last_hit().unwrap();
+		assert_eq!((hit.index, hit.old_value, hit.value), (1, Some(5), 6));
+		assert!(hit.stop);
+		assert!(matches!(hit.privilege_mode, PrivilegeMode::User));
+
+		// Discarded hits are not reported
+		watchpoints.check(
+			MemoryAccessType::Write,
+			0x1000,
+			0x80001000,
+			8,
+			None,
+			0,
+			&mode,
+		);
+		watchpoints.discard_pending_hits();
+		assert!(!watchpoints.report_hits(0x10080));
+		assert_eq!(watchpoints.get_last_hit().unwrap().pc, 0x1007c);
+
+		watchpoints.check(
+			MemoryAccessType::Read,
+			0x1000,
+			0x80001000,
+			8,
+			None,
+			0,
+			&mode,
+		);
+		watchpoints.clear();
+		assert!(watchpoints.is_empty());
+		assert!(!watchpoints.has_pending_hits());
+		assert!(watchpoints.get_last_hit().is_none());
+	}
+}